@@ -0,0 +1,34 @@
+//! Generates the gRPC service stubs.
+//!
+//! The `prism.v1` messages and service are generated from `proto/prism.proto` with the
+//! `protoc` vendored by `protoc-bin-vendored`. The two Arrow Flight messages served are
+//! defined in Rust in `src/flight.rs`.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+fn main() {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc");
+    // SAFETY: build scripts are single-threaded
+    unsafe { std::env::set_var("PROTOC", protoc) };
+    let include = protoc_bin_vendored::include_path().expect("no vendored protobuf includes");
+    tonic_build::configure()
+        .compile_protos(&["proto/prism.proto"], &[std::path::Path::new("proto"), &include])
+        .expect("failed to compile proto/prism.proto");
+
+    // Only the DoGet method of Arrow Flight is served
+    let flight = Service::builder()
+        .name("FlightService")
+        .package("arrow.flight.protocol")
+        .method(
+            Method::builder()
+                .name("do_get")
+                .route_name("DoGet")
+                .input_type("crate::flight::Ticket")
+                .output_type("crate::flight::FlightData")
+                .codec_path("tonic::codec::ProstCodec")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().compile(&[flight]);
+}
@@ -0,0 +1,106 @@
+//! HTTP listener for operational endpoints.
+//!
+//! Serves the following endpoints when `admin_bind_address` is configured:
+//!
+//! - `/metrics`: Prometheus metrics
+//! - `/healthz`: liveness, answering as long as the process runs
+//! - `/readyz`: readiness, failing with 503 until every required provider is
+//!   initialized and preloaded, and reachable if `readiness_ping` is set
+
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde_json::{Map, Value, json};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::metrics;
+use crate::storage::StorageService;
+
+/// Content type of the Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// State shared by the admin endpoints.
+#[derive(Clone)]
+struct AdminState {
+    storage: Arc<StorageService>,
+    /// Whether readiness pings the backend of every provider
+    readiness_ping: bool,
+}
+
+/// Serves the admin endpoints on `bind_address` until the listener fails.
+pub async fn serve(
+    bind_address: &str,
+    storage: Arc<StorageService>,
+    readiness_ping: bool,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(bind_address).await?;
+    info!("Admin endpoints listening on {}", bind_address);
+    axum::serve(listener, router(storage, readiness_ping)).await?;
+    Ok(())
+}
+
+/// Builds the routes of the admin endpoints.
+fn router(storage: Arc<StorageService>, readiness_ping: bool) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics))
+        .route("/healthz", get(get_health))
+        .route("/readyz", get(get_readiness))
+        .with_state(AdminState {
+            storage,
+            readiness_ping,
+        })
+}
+
+async fn get_metrics(State(state): State<AdminState>) -> Response {
+    match metrics::render(&state.storage).await {
+        Ok(body) => ([(header::CONTENT_TYPE, METRICS_CONTENT_TYPE)], body).into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+async fn get_health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn get_readiness(State(state): State<AdminState>) -> (StatusCode, Json<Value>) {
+    let (ready, providers) = state.storage.readiness(state.readiness_ping).await;
+    let providers: Map<String, Value> = providers
+        .into_iter()
+        .map(|(name, readiness)| (name, json!(readiness)))
+        .collect();
+    let (status, label) = if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+    };
+    (status, Json(json!({ "status": label, "providers": providers })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[tokio::test]
+    async fn test_readiness_reports_providers() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        let state = AdminState {
+            storage: Arc::new(storage),
+            readiness_ping: true,
+        };
+
+        let (status, Json(body)) = get_readiness(State(state)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["providers"]["users"]["ready"], true);
+        assert_eq!(body["providers"]["users"]["optional"], false);
+    }
+}
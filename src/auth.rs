@@ -0,0 +1,31 @@
+//! Password authentication shared by the Redis protocol and the HTTP gateway.
+//!
+//! When `requirepass` is configured, Redis clients authenticate with `AUTH` and HTTP
+//! clients send the same password as a bearer token.
+
+/// Returns whether a password given by a client matches the configured one.
+///
+/// Passwords of equal length are compared in constant time, so the comparison does
+/// not reveal how much of the password was guessed.
+pub fn password_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    expected.len() == given.len()
+        && expected
+            .iter()
+            .zip(given)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_matches() {
+        assert!(password_matches("secret", "secret"));
+        assert!(!password_matches("secret", "secreT"));
+        assert!(!password_matches("secret", "secret2"));
+        assert!(!password_matches("secret", ""));
+    }
+}
@@ -2,13 +2,17 @@
 //!
 //! This module handles Redis commands and translates them to storage operations.
 
-use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tracing::{debug, error, info, trace};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{debug, error, trace};
 
+use crate::auth::password_matches;
 use crate::redis_protocol::{RedisError, RedisFrame};
+use crate::storage::glob::glob_match;
+use crate::storage::time_travel::{AsOf, split_key};
 use crate::storage::{StorageError, StorageService};
-use serde_json::json;
 
 /// Maps a StorageError to a RedisError
 fn map_error(err: StorageError) -> RedisError {
@@ -21,6 +25,42 @@ fn map_error(err: StorageError) -> RedisError {
         StorageError::DatabaseError(msg) => RedisError::Internal(msg),
         StorageError::CacheError(msg) => RedisError::Internal(msg),
         StorageError::ConfigError(msg) => RedisError::Internal(msg),
+        e @ (StorageError::Timeout(_)
+        | StorageError::CircuitOpen(_)
+        | StorageError::LimitExceeded(_)) => {
+            RedisError::Internal(e.to_string())
+        }
+        StorageError::Busy(msg) => RedisError::Busy(msg),
+        e @ StorageError::ReadOnly(_) => RedisError::ReadOnly(e.to_string()),
+        e @ (StorageError::InvalidQuery(_)
+        | StorageError::InvalidRecord(_)
+        | StorageError::InvalidExpireTime(_)) => RedisError::Protocol(e.to_string()),
+    }
+}
+
+/// State of a client connection.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Provider selected with SELECT, which scopes FLUSHDB
+    provider: Option<String>,
+    /// Password the client must send with AUTH, if any
+    requirepass: Option<Arc<str>>,
+    /// Whether the client sent the password
+    authenticated: bool,
+}
+
+impl Session {
+    /// Creates the state of a new connection, requiring `requirepass` if set.
+    pub fn new(requirepass: Option<Arc<str>>) -> Self {
+        Self {
+            requirepass,
+            ..Self::default()
+        }
+    }
+
+    /// Returns whether the client may run commands.
+    fn is_authenticated(&self) -> bool {
+        self.requirepass.is_none() || self.authenticated
     }
 }
 
@@ -30,6 +70,7 @@ fn map_error(err: StorageError) -> RedisError {
 pub async fn handle_command(
     frame: RedisFrame,
     storage: Arc<StorageService>,
+    session: &mut Session,
 ) -> Result<Vec<u8>, RedisError> {
     let (command, args) = match frame {
         RedisFrame::Array(mut items) => {
@@ -45,21 +86,76 @@ pub async fn handle_command(
         _ => return Err(RedisError::Protocol("Expected array".into())),
     };
 
-    match command.as_str() {
+    // Unauthenticated commands are rejected before they are counted
+    if command != "AUTH" && !session.is_authenticated() {
+        return Err(RedisError::NoAuth);
+    }
+
+    let result = dispatch(&command, args, Arc::clone(&storage), session).await;
+    // Unknown names are not counted, so clients cannot add arbitrary metric labels
+    if !matches!(result, Err(RedisError::UnknownCommand(_))) {
+        storage.stats().record_command(&command);
+    }
+    result
+}
+
+/// Runs a parsed command.
+async fn dispatch(
+    command: &str,
+    args: Vec<RedisFrame>,
+    storage: Arc<StorageService>,
+    session: &mut Session,
+) -> Result<Vec<u8>, RedisError> {
+    match command {
         "PING" => Ok(RedisFrame::SimpleString("PONG".into()).to_bytes()),
+        "AUTH" => handle_auth(&args, session),
         "SET" => handle_set(&args, storage).await,
         "GET" => handle_get(&args, storage).await,
         "HGET" => handle_hget(&args, storage).await,
-        _ => Err(RedisError::UnknownCommand(command)),
+        "HSET" => handle_hset(&args, storage).await,
+        "INFO" => handle_info(&args, storage).await,
+        "PRISM.STATUS" => handle_prism_status(&args, storage).await,
+        "PRISM.GETASOF" => handle_prism_getasof(&args, storage).await,
+        "DEL" | "UNLINK" => handle_del(command, &args, storage).await,
+        "PRISM.INVALIDATE" => handle_prism_invalidate(&args, storage).await,
+        "EXISTS" => handle_exists(&args, storage).await,
+        "PRISM.CACHED" => handle_prism_cached(&args, storage).await,
+        "SCAN" => handle_scan(&args, storage, session).await,
+        "KEYS" => handle_keys(&args, storage, session).await,
+        "PRISM.SCANSOURCE" => handle_prism_scansource(&args, storage).await,
+        "PRISM.QUERY" => handle_prism_query(&args, storage).await,
+        "TTL" | "PTTL" => handle_ttl(command, &args, storage).await,
+        "EXPIRE" | "PEXPIRE" => handle_expire(command, &args, storage).await,
+        "PERSIST" => handle_persist(&args, storage).await,
+        "SELECT" => handle_select(&args, storage, session),
+        "FLUSHALL" => handle_flush(command, &args, None, storage).await,
+        "FLUSHDB" => {
+            // An explicit provider argument overrides the selected one
+            let (provider_name, args) = match args.split_first() {
+                Some((RedisFrame::BulkString(name), rest)) if !is_flush_mode(name) => {
+                    (Some(name.clone()), rest)
+                }
+                _ => (session.provider.clone(), args.as_slice()),
+            };
+            if provider_name.is_none() {
+                return Err(RedisError::Protocol(
+                    "No provider selected, use SELECT or FLUSHDB provider".into(),
+                ));
+            }
+            handle_flush(command, args, provider_name, storage).await
+        }
+        _ => Err(RedisError::UnknownCommand(command.to_string())),
     }
 }
 
 /// Handles the SET command.
 ///
 /// SET key value
+///
+/// The value is a JSON object, written through to the provider before it is cached.
 async fn handle_set(
     args: &[RedisFrame],
-    _storage: Arc<StorageService>,
+    storage: Arc<StorageService>,
 ) -> Result<Vec<u8>, RedisError> {
     if args.len() != 2 {
         return Err(RedisError::WrongArity("SET".into()));
@@ -77,11 +173,58 @@ async fn handle_set(
 
     debug!("SET {} {}", key, value);
 
-    // In a real implementation, we would store the value
-    // For now, just return OK
+    let (provider_name, id) =
+        split_key(key).ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
+    let record = match serde_json::from_str(value) {
+        Ok(record @ serde_json::Value::Object(_)) => record,
+        _ => return Err(RedisError::Protocol("Expected a JSON object value".into())),
+    };
+
+    storage
+        .write_record(provider_name, id, &record)
+        .await
+        .map_err(map_error)?;
     Ok(RedisFrame::SimpleString("OK".into()).to_bytes())
 }
 
+/// Handles the HSET command.
+///
+/// HSET key field value [field value ...]
+///
+/// Values that parse as JSON keep their type, other values are stored as strings.
+/// Only the given fields are written through to the provider; returns the number of
+/// fields that were added.
+async fn handle_hset(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let Some((RedisFrame::BulkString(key), pairs)) = args.split_first() else {
+        return Err(RedisError::WrongArity("HSET".into()));
+    };
+    if pairs.is_empty() || pairs.len() % 2 != 0 {
+        return Err(RedisError::WrongArity("HSET".into()));
+    }
+
+    let (provider_name, id) =
+        split_key(key).ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
+    let mut fields = serde_json::Map::new();
+    for pair in pairs.chunks(2) {
+        let [RedisFrame::BulkString(field), RedisFrame::BulkString(value)] = pair else {
+            return Err(RedisError::Protocol("Expected bulk strings".into()));
+        };
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.clone()));
+        fields.insert(field.clone(), value);
+    }
+    debug!("HSET provider [{}] id [{}] {} fields", provider_name, id, fields.len());
+
+    let added = storage
+        .write_fields(provider_name, id, fields)
+        .await
+        .map_err(map_error)?;
+    Ok(RedisFrame::Integer(added as i64).to_bytes())
+}
+
 /// Handles the GET command.
 ///
 /// GET key
@@ -103,9 +246,8 @@ async fn handle_get(
         _ => return Err(RedisError::Protocol("Expected bulk string for key".into())),
     };
 
-    let (provider_name, id) = key
-        .split_once(':')
-        .ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
+    let (provider_name, id) =
+        split_key(key).ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
     debug!("Processing GET request for provider [{}] with id [{}]", provider_name, id);
 
     let record = storage.fetch_record(provider_name, id).await;
@@ -150,9 +292,8 @@ async fn handle_hget(
         _ => return Err(RedisError::Protocol("Expected bulk string for field".into())),
     };
 
-    let (provider_name, id) = key
-        .split_once(':')
-        .ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
+    let (provider_name, id) =
+        split_key(key).ok_or(RedisError::Protocol("Expected provider:id format".into()))?;
     debug!("HGET provider [{}] id [{}] field [{}]", provider_name, id, field);
 
     let record = storage.fetch_record(provider_name, id).await;
@@ -179,3 +320,705 @@ async fn handle_hget(
         Err(err) => Err(map_error(err)),
     }
 }
+
+/// Handles the DEL and UNLINK commands.
+///
+/// DEL key [key ...]
+///
+/// Evicts the records from the cache, never touching the backend, and returns the
+/// number of records evicted.
+async fn handle_del(
+    command: &str,
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    if args.is_empty() {
+        return Err(RedisError::WrongArity(command.into()));
+    }
+
+    let mut evicted = 0;
+    for arg in args {
+        let (provider_name, id) = parse_key(arg)?;
+        if storage.evict_record(provider_name, id).await.map_err(map_error)? {
+            evicted += 1;
+        }
+    }
+    debug!("{} evicted {} of {} keys", command, evicted, args.len());
+    Ok(RedisFrame::Integer(evicted).to_bytes())
+}
+
+/// Splits a `provider:id` key argument.
+fn parse_key(arg: &RedisFrame) -> Result<(&str, &str), RedisError> {
+    let RedisFrame::BulkString(key) = arg else {
+        return Err(RedisError::Protocol("Expected bulk string for key".into()));
+    };
+    split_key(key).ok_or(RedisError::Protocol("Expected provider:id format".into()))
+}
+
+/// Handles the EXISTS command.
+///
+/// EXISTS key [key ...]
+///
+/// Returns the number of keys whose record is cached or exists in its provider.
+/// A key given several times is counted several times, like in Redis.
+async fn handle_exists(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    if args.is_empty() {
+        return Err(RedisError::WrongArity("EXISTS".into()));
+    }
+
+    let mut existing = 0;
+    for arg in args {
+        let (provider_name, id) = parse_key(arg)?;
+        if storage.record_exists(provider_name, id).await.map_err(map_error)? {
+            existing += 1;
+        }
+    }
+    Ok(RedisFrame::Integer(existing).to_bytes())
+}
+
+/// Handles the PRISM.CACHED command.
+///
+/// PRISM.CACHED key
+///
+/// Returns 1 if the record is cached in memory or on disk, 0 otherwise, without
+/// asking the provider.
+async fn handle_prism_cached(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity("PRISM.CACHED".into()));
+    };
+    let (provider_name, id) = parse_key(key)?;
+    let cached = storage.is_cached(provider_name, id).await.map_err(map_error)?;
+    Ok(RedisFrame::Integer(cached as i64).to_bytes())
+}
+
+/// Number of keys a SCAN page covers when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Parses an integer argument such as a cursor or a count.
+fn parse_integer<T: std::str::FromStr>(arg: &str) -> Result<T, RedisError> {
+    arg.parse()
+        .map_err(|_| RedisError::Protocol("value is not an integer or out of range".into()))
+}
+
+/// Builds the reply of a scan: the next cursor and the keys of the page.
+fn scan_reply(cursor: impl ToString, keys: Vec<String>) -> Vec<u8> {
+    RedisFrame::Array(vec![
+        RedisFrame::BulkString(cursor.to_string()),
+        RedisFrame::Array(keys.into_iter().map(RedisFrame::BulkString).collect()),
+    ])
+    .to_bytes()
+}
+
+/// Handles the SCAN command.
+///
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+///
+/// Iterates over the cached keys, of the selected provider only if one is selected.
+/// Like in Redis, MATCH and TYPE filter the keys of a page after it was taken, so a
+/// page may hold fewer keys than COUNT. Records are hashes, and can also be read as
+/// strings with GET.
+async fn handle_scan(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+    session: &Session,
+) -> Result<Vec<u8>, RedisError> {
+    let Some((RedisFrame::BulkString(cursor), options)) = args.split_first() else {
+        return Err(RedisError::WrongArity("SCAN".into()));
+    };
+    let cursor: u64 = parse_integer(cursor)?;
+
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut type_matches = true;
+    for option in options.chunks(2) {
+        let [RedisFrame::BulkString(name), RedisFrame::BulkString(value)] = option else {
+            return Err(RedisError::Protocol("syntax error".into()));
+        };
+        match name.to_uppercase().as_str() {
+            "MATCH" => pattern = Some(value.as_str()),
+            "COUNT" => {
+                count = parse_integer(value)?;
+                if count == 0 {
+                    return Err(RedisError::Protocol("COUNT must be positive".into()));
+                }
+            }
+            "TYPE" => {
+                type_matches =
+                    value.eq_ignore_ascii_case("hash") || value.eq_ignore_ascii_case("string")
+            }
+            _ => return Err(RedisError::Protocol("syntax error".into())),
+        }
+    }
+
+    let (next, keys) = storage
+        .scan_cached(session.provider.as_deref(), cursor, count)
+        .await
+        .map_err(map_error)?;
+    let keys = keys
+        .into_iter()
+        .filter(|key| type_matches && pattern.is_none_or(|pattern| glob_match(pattern, key)))
+        .collect();
+    Ok(scan_reply(next, keys))
+}
+
+/// Handles the KEYS command.
+///
+/// KEYS pattern
+///
+/// Returns every cached key matching the pattern, of the selected provider only if
+/// one is selected. Fails when more keys than the configured `keys_limit` match.
+async fn handle_keys(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+    session: &Session,
+) -> Result<Vec<u8>, RedisError> {
+    let [RedisFrame::BulkString(pattern)] = args else {
+        return Err(RedisError::WrongArity("KEYS".into()));
+    };
+    let keys = storage
+        .keys_matching(session.provider.as_deref(), pattern)
+        .await
+        .map_err(map_error)?;
+    Ok(RedisFrame::Array(keys.into_iter().map(RedisFrame::BulkString).collect()).to_bytes())
+}
+
+/// Handles the PRISM.SCANSOURCE admin command.
+///
+/// PRISM.SCANSOURCE provider cursor [COUNT count]
+///
+/// Pages over the keys of the provider's backing table, whether cached or not. The
+/// cursor is the offset of the page in key order.
+async fn handle_prism_scansource(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let (provider_name, cursor, count) = match args {
+        [RedisFrame::BulkString(name), RedisFrame::BulkString(cursor)] => {
+            (name, cursor, DEFAULT_SCAN_COUNT)
+        }
+        [
+            RedisFrame::BulkString(name),
+            RedisFrame::BulkString(cursor),
+            RedisFrame::BulkString(option),
+            RedisFrame::BulkString(count),
+        ] if option.eq_ignore_ascii_case("COUNT") => (name, cursor, parse_integer(count)?),
+        [_, _] | [_, _, _, _] => return Err(RedisError::Protocol("syntax error".into())),
+        _ => return Err(RedisError::WrongArity("PRISM.SCANSOURCE".into())),
+    };
+    if count == 0 {
+        return Err(RedisError::Protocol("COUNT must be positive".into()));
+    }
+
+    let (next, keys) = storage
+        .scan_source(provider_name, parse_integer(cursor)?, count)
+        .await
+        .map_err(map_error)?;
+    Ok(scan_reply(next, keys))
+}
+
+/// Handles the PRISM.QUERY command.
+///
+/// PRISM.QUERY provider sql [TTL seconds]
+///
+/// Runs a read-only SELECT against the provider's table and returns the rows as an
+/// array of JSON objects. Results are cached by normalized SQL for the given time to
+/// live, or the provider's default; TTL 0 bypasses the cache.
+async fn handle_prism_query(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let (provider_name, sql, ttl) = match args {
+        [RedisFrame::BulkString(name), RedisFrame::BulkString(sql)] => (name, sql, None),
+        [
+            RedisFrame::BulkString(name),
+            RedisFrame::BulkString(sql),
+            RedisFrame::BulkString(option),
+            RedisFrame::BulkString(seconds),
+        ] if option.eq_ignore_ascii_case("TTL") => {
+            (name, sql, Some(Duration::from_secs(parse_integer(seconds)?)))
+        }
+        [_, _] | [_, _, _, _] => return Err(RedisError::Protocol("syntax error".into())),
+        _ => return Err(RedisError::WrongArity("PRISM.QUERY".into())),
+    };
+    debug!("PRISM.QUERY provider [{}] sql [{}]", provider_name, sql);
+
+    let rows = storage
+        .query(provider_name, sql, ttl)
+        .await
+        .map_err(map_error)?;
+    let rows = rows
+        .iter()
+        .map(|row| RedisFrame::BulkString(row.to_string()))
+        .collect();
+    Ok(RedisFrame::Array(rows).to_bytes())
+}
+
+/// Handles the TTL and PTTL commands.
+///
+/// TTL key
+///
+/// Returns the remaining cache lifetime of the record in seconds (milliseconds for
+/// PTTL), -1 if it never expires or -2 if it is not cached.
+async fn handle_ttl(
+    command: &str,
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity(command.into()));
+    };
+    let (provider_name, id) = parse_key(key)?;
+
+    let ttl = match storage.record_ttl(provider_name, id).await.map_err(map_error)? {
+        None => -2,
+        Some(None) => -1,
+        Some(Some(ttl)) if command == "PTTL" => ttl.as_millis() as i64,
+        // Rounded like Redis does
+        Some(Some(ttl)) => ((ttl.as_millis() + 500) / 1000) as i64,
+    };
+    Ok(RedisFrame::Integer(ttl).to_bytes())
+}
+
+/// Handles the EXPIRE and PEXPIRE commands.
+///
+/// EXPIRE key seconds
+///
+/// Overrides the cache lifetime of the record (in milliseconds for PEXPIRE). A
+/// non-positive lifetime evicts the record and one too far in the future is an error.
+/// Returns 1 if the record was cached, 0 otherwise.
+async fn handle_expire(
+    command: &str,
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let [key, RedisFrame::BulkString(amount)] = args else {
+        return Err(RedisError::WrongArity(command.into()));
+    };
+    let (provider_name, id) = parse_key(key)?;
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| RedisError::Protocol("value is not an integer or out of range".into()))?;
+
+    let updated = if amount <= 0 {
+        storage.evict_record(provider_name, id).await
+    } else {
+        let ttl = if command == "PEXPIRE" {
+            Duration::from_millis(amount as u64)
+        } else {
+            Duration::from_secs(amount as u64)
+        };
+        storage.expire_record(provider_name, id, Some(ttl)).await
+    }
+    .map_err(|e| match e {
+        StorageError::InvalidExpireTime(_) => RedisError::Protocol(format!(
+            "invalid expire time in '{}' command",
+            command.to_lowercase()
+        )),
+        e => map_error(e),
+    })?;
+    Ok(RedisFrame::Integer(updated as i64).to_bytes())
+}
+
+/// Handles the PERSIST command.
+///
+/// PERSIST key
+///
+/// Keeps the record cached until it is explicitly evicted. Returns 1 if the record
+/// had an expiry, 0 if it is not cached or already never expires.
+async fn handle_persist(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let [key] = args else {
+        return Err(RedisError::WrongArity("PERSIST".into()));
+    };
+    let (provider_name, id) = parse_key(key)?;
+
+    let persisted = match storage.record_ttl(provider_name, id).await.map_err(map_error)? {
+        Some(Some(_)) => storage
+            .expire_record(provider_name, id, None)
+            .await
+            .map_err(map_error)?,
+        _ => false,
+    };
+    Ok(RedisFrame::Integer(persisted as i64).to_bytes())
+}
+
+/// Handles the PRISM.INVALIDATE admin command.
+///
+/// PRISM.INVALIDATE provider [pattern]
+///
+/// Evicts every cached record of the provider, or those whose id matches the glob
+/// pattern, and returns the number of records evicted.
+async fn handle_prism_invalidate(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let (provider_name, pattern) = match args {
+        [RedisFrame::BulkString(name)] => (name, None),
+        [RedisFrame::BulkString(name), RedisFrame::BulkString(pattern)] => {
+            (name, Some(pattern.as_str()))
+        }
+        [_] | [_, _] => return Err(RedisError::Protocol("Expected bulk strings".into())),
+        _ => return Err(RedisError::WrongArity("PRISM.INVALIDATE".into())),
+    };
+
+    let evicted = storage
+        .evict_matching(provider_name, pattern)
+        .await
+        .map_err(map_error)?;
+    Ok(RedisFrame::Integer(evicted as i64).to_bytes())
+}
+
+/// Handles the SELECT command.
+///
+/// SELECT provider|index
+///
+/// Scopes FLUSHDB on this connection to a provider; `0` clears the scope. Numeric
+/// database indices, as sent by Redis clients, select providers in name order
+/// starting at 1.
+fn handle_select(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+    session: &mut Session,
+) -> Result<Vec<u8>, RedisError> {
+    let [RedisFrame::BulkString(provider_name)] = args else {
+        return Err(RedisError::WrongArity("SELECT".into()));
+    };
+    session.provider = match provider_name.as_str() {
+        name if storage.has_provider(name) => Some(name.to_string()),
+        name => match name.parse::<usize>() {
+            Ok(0) => None,
+            Ok(index) => {
+                let provider = index
+                    .checked_sub(1)
+                    .and_then(|index| storage.provider_names().into_iter().nth(index));
+                let out_of_range = || RedisError::Protocol("DB index is out of range".into());
+                Some(provider.ok_or_else(out_of_range)?)
+            }
+            Err(_) => return Err(RedisError::NotFound(format!("Provider {} not found", name))),
+        },
+    };
+    Ok(RedisFrame::SimpleString("OK".into()).to_bytes())
+}
+
+/// Handles the AUTH command.
+///
+/// AUTH [username] password
+///
+/// Only the `default` user exists; it authenticates with `requirepass`.
+fn handle_auth(args: &[RedisFrame], session: &mut Session) -> Result<Vec<u8>, RedisError> {
+    let (username, password) = match args {
+        [RedisFrame::BulkString(password)] => ("default", password),
+        [RedisFrame::BulkString(username), RedisFrame::BulkString(password)] => {
+            (username.as_str(), password)
+        }
+        _ => return Err(RedisError::WrongArity("AUTH".into())),
+    };
+    let Some(requirepass) = &session.requirepass else {
+        return Err(RedisError::Protocol(
+            "AUTH called without any password configured".into(),
+        ));
+    };
+    if username != "default" || !password_matches(requirepass, password) {
+        session.authenticated = false;
+        return Err(RedisError::WrongPass);
+    }
+    session.authenticated = true;
+    Ok(RedisFrame::SimpleString("OK".into()).to_bytes())
+}
+
+/// Returns whether a FLUSHALL/FLUSHDB argument is a flush mode rather than a provider.
+fn is_flush_mode(arg: &str) -> bool {
+    arg.eq_ignore_ascii_case("ASYNC") || arg.eq_ignore_ascii_case("SYNC")
+}
+
+/// Handles the FLUSHALL and FLUSHDB commands.
+///
+/// FLUSHALL [ASYNC|SYNC]
+/// FLUSHDB [provider] [ASYNC|SYNC]
+///
+/// Drops cached records only, never the source data. FLUSHDB clears the given or
+/// selected provider and fails if there is neither, so that it never clears the whole
+/// cache by accident. With ASYNC the flush runs in the background and the command
+/// returns right away.
+async fn handle_flush(
+    command: &str,
+    args: &[RedisFrame],
+    provider_name: Option<String>,
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let run_async = match args {
+        [] => false,
+        [RedisFrame::BulkString(mode)] if is_flush_mode(mode) => {
+            mode.eq_ignore_ascii_case("ASYNC")
+        }
+        [RedisFrame::BulkString(mode)] => {
+            return Err(RedisError::Protocol(format!("Invalid flush mode: {}", mode)));
+        }
+        _ => return Err(RedisError::WrongArity(command.into())),
+    };
+    if let Some(name) = &provider_name
+        && !storage.has_provider(name)
+    {
+        return Err(RedisError::NotFound(format!("Provider {} not found", name)));
+    }
+    debug!("{} provider [{:?}] async [{}]", command, provider_name, run_async);
+
+    if run_async {
+        tokio::spawn(async move {
+            if let Err(e) = storage.flush(provider_name.as_deref()).await {
+                error!("Background flush failed: {}", e);
+            }
+        });
+    } else {
+        storage
+            .flush(provider_name.as_deref())
+            .await
+            .map_err(map_error)?;
+    }
+    Ok(RedisFrame::SimpleString("OK".into()).to_bytes())
+}
+
+/// Handles the PRISM.GETASOF command.
+///
+/// PRISM.GETASOF provider id version|timestamp
+///
+/// The version is given as `v42` or `42`, the timestamp in RFC 3339 or as `YYYY-MM-DD`.
+async fn handle_prism_getasof(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let [provider_name, id, as_of] = args else {
+        return Err(RedisError::WrongArity("PRISM.GETASOF".into()));
+    };
+    let (
+        RedisFrame::BulkString(provider_name),
+        RedisFrame::BulkString(id),
+        RedisFrame::BulkString(as_of),
+    ) = (provider_name, id, as_of)
+    else {
+        return Err(RedisError::Protocol("Expected bulk strings".into()));
+    };
+    let as_of = AsOf::parse(as_of)
+        .ok_or_else(|| RedisError::Protocol(format!("Invalid version or timestamp: {}", as_of)))?;
+    debug!("PRISM.GETASOF provider [{}] id [{}] as of [{}]", provider_name, id, as_of);
+
+    match storage.fetch_record_as_of(provider_name, id, &as_of).await {
+        Ok(record) => Ok(RedisFrame::BulkString(record.to_string()).to_bytes()),
+        Err(StorageError::ProviderNotFound(_) | StorageError::RecordNotInDatabase(_)) => {
+            Ok(RedisFrame::Null.to_bytes())
+        }
+        Err(err) => Err(map_error(err)),
+    }
+}
+
+/// Sections of the INFO reply, in the order they are reported
+const INFO_SECTIONS: [&str; 5] = ["server", "clients", "memory", "stats", "prism"];
+
+/// Handles the INFO command.
+///
+/// INFO [section ...]
+///
+/// Reports the server, clients, memory, stats and per-provider prism sections, all of
+/// them unless sections are named. Like in Redis, unknown sections are left out.
+async fn handle_info(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let mut sections = Vec::new();
+    for arg in args {
+        let RedisFrame::BulkString(section) = arg else {
+            return Err(RedisError::Protocol("Expected bulk string for section".into()));
+        };
+        match section.to_lowercase().as_str() {
+            "all" | "default" | "everything" => sections.extend(INFO_SECTIONS),
+            section => sections.extend(INFO_SECTIONS.iter().filter(|name| **name == section)),
+        }
+    }
+    if args.is_empty() {
+        sections.extend(INFO_SECTIONS);
+    }
+
+    let stats = storage.stats();
+    let mut info = String::new();
+    for section in INFO_SECTIONS.iter().filter(|name| sections.contains(name)) {
+        let fields: Vec<(String, String)> = match *section {
+            "server" => {
+                let uptime = stats.uptime().as_secs();
+                vec![
+                    ("prism_version".into(), env!("CARGO_PKG_VERSION").into()),
+                    ("process_id".into(), std::process::id().to_string()),
+                    ("uptime_in_seconds".into(), uptime.to_string()),
+                    ("uptime_in_days".into(), (uptime / 86400).to_string()),
+                ]
+            }
+            "clients" => vec![(
+                "connected_clients".into(),
+                stats.connected_clients.load(Ordering::Relaxed).to_string(),
+            )],
+            "memory" => {
+                let (memory, disk) = storage.cache_usage().await.map_err(map_error)?;
+                let mut fields = vec![
+                    ("cache_entries".into(), memory.entries.to_string()),
+                    ("cache_weighted_size".into(), memory.weighted_size.to_string()),
+                ];
+                if let Some(disk) = disk {
+                    fields.push(("disk_cache_entries".into(), disk.entries.to_string()));
+                    fields.push(("disk_cache_bytes".into(), disk.weighted_size.to_string()));
+                }
+                fields
+            }
+            "stats" => vec![
+                (
+                    "total_connections_received".into(),
+                    stats.total_connections.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "total_commands_processed".into(),
+                    stats.total_commands.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "keyspace_hits".into(),
+                    stats.total(|provider| &provider.cache_hits).to_string(),
+                ),
+                (
+                    "keyspace_misses".into(),
+                    stats.total(|provider| &provider.cache_misses).to_string(),
+                ),
+                (
+                    "evicted_keys".into(),
+                    stats.total(|provider| &provider.evictions).to_string(),
+                ),
+                (
+                    "expired_keys".into(),
+                    stats.total(|provider| &provider.expirations).to_string(),
+                ),
+                ("stale_served".into(), storage.stale_served().to_string()),
+            ],
+            _ => provider_info(&storage)?,
+        };
+
+        if !info.is_empty() {
+            info.push_str("\r\n");
+        }
+        let mut title = section.to_string();
+        title[..1].make_ascii_uppercase();
+        info.push_str(&format!("# {}\r\n", title));
+        for (field, value) in fields {
+            info.push_str(&format!("{}:{}\r\n", field, value));
+        }
+    }
+    Ok(RedisFrame::BulkString(info).to_bytes())
+}
+
+/// Builds the prism section of INFO, one line of comma-separated values per provider.
+fn provider_info(storage: &StorageService) -> Result<Vec<(String, String)>, RedisError> {
+    let statuses: HashMap<String, _> = storage
+        .provider_status(None)
+        .map_err(map_error)?
+        .into_iter()
+        .collect();
+    let mut fields = Vec::new();
+    for (name, stats) in storage.stats().providers() {
+        let calls = stats.backend_calls.load(Ordering::Relaxed);
+        let mut values = vec![
+            format!("cache_hits={}", stats.cache_hits.load(Ordering::Relaxed)),
+            format!("cache_misses={}", stats.cache_misses.load(Ordering::Relaxed)),
+            format!("evictions={}", stats.evictions.load(Ordering::Relaxed)),
+            format!("backend_calls={}", calls),
+            format!("backend_errors={}", stats.backend_errors.load(Ordering::Relaxed)),
+            format!("client_errors={}", stats.client_errors.load(Ordering::Relaxed)),
+        ];
+        if calls > 0 {
+            let average = stats.latency.sum().as_secs_f64() * 1000.0 / calls as f64;
+            values.push(format!("latency_avg_ms={:.2}", average));
+        }
+        for (label, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+            if let Some(ms) = stats.latency.quantile_ms(quantile) {
+                values.push(format!("latency_{}_ms={}", label, ms));
+            }
+        }
+        // Type, circuit state and table version of the provider
+        for (field, value) in statuses.get(&name).into_iter().flatten() {
+            if matches!(*field, "type" | "circuit" | "table_version") {
+                values.push(format!("{}={}", field, value));
+            }
+        }
+        fields.push((format!("provider_{}", name), values.join(",")));
+    }
+    Ok(fields)
+}
+
+/// Handles the PRISM.STATUS admin command.
+///
+/// PRISM.STATUS [provider]
+///
+/// Returns one entry per provider: the provider name followed by an array of
+/// field/value pairs (type, circuit breaker state, ...).
+async fn handle_prism_status(
+    args: &[RedisFrame],
+    storage: Arc<StorageService>,
+) -> Result<Vec<u8>, RedisError> {
+    let provider_name = match args {
+        [] => None,
+        [RedisFrame::BulkString(name)] => Some(name.as_str()),
+        [_] => return Err(RedisError::Protocol("Expected bulk string for provider".into())),
+        _ => return Err(RedisError::WrongArity("PRISM.STATUS".into())),
+    };
+
+    let statuses = storage.provider_status(provider_name).map_err(map_error)?;
+    let frames = statuses
+        .into_iter()
+        .map(|(name, fields)| {
+            let fields = fields
+                .into_iter()
+                .flat_map(|(field, value)| {
+                    [
+                        RedisFrame::BulkString(field.to_string()),
+                        RedisFrame::BulkString(value),
+                    ]
+                })
+                .collect();
+            RedisFrame::Array(vec![RedisFrame::BulkString(name), RedisFrame::Array(fields)])
+        })
+        .collect();
+    Ok(RedisFrame::Array(frames).to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    fn select(index: &str) -> Vec<RedisFrame> {
+        vec![RedisFrame::BulkString(index.to_string())]
+    }
+
+    #[tokio::test]
+    async fn test_select_by_index() {
+        let storage = Arc::new(StorageService::new(&AppConfig::default()).await.unwrap());
+        let mut session = Session::default();
+
+        handle_select(&select("1"), Arc::clone(&storage), &mut session).unwrap();
+        assert_eq!(session.provider.as_deref(), Some("users"));
+
+        // Every spelling of zero selects all providers
+        for zero in ["0", "00", "+0"] {
+            session.provider = Some("users".to_string());
+            handle_select(&select(zero), Arc::clone(&storage), &mut session).unwrap();
+            assert_eq!(session.provider, None);
+        }
+
+        let result = handle_select(&select("2"), Arc::clone(&storage), &mut session);
+        assert!(matches!(result, Err(RedisError::Protocol(msg)) if msg.contains("out of range")));
+        let result = handle_select(&select("orders"), storage, &mut session);
+        assert!(matches!(result, Err(RedisError::NotFound(_))));
+    }
+}
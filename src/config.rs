@@ -4,13 +4,10 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::error::Error;
-use std::fmt;
-use std::path::Path;
 use thiserror::Error;
-use tracing::info;
 
 /// Configuration error type
+#[allow(dead_code)]
 #[derive(Debug, Error)]
 pub enum ConfigError {
     /// Invalid database provider
@@ -31,6 +28,8 @@ pub enum DatabaseProvider {
     Postgres,
     /// Azure Delta database provider
     AzDelta,
+    /// Virtual provider answering lookups with a query template over another provider
+    Template,
 }
 
 /// Configuration for a data provider
@@ -41,7 +40,131 @@ pub struct DataProviderConfig {
     /// Type of database provider
     pub provider: DatabaseProvider,
     /// Database connection settings
+    #[serde(default)]
     pub settings: HashMap<String, String>,
+    /// Timeout, retry and circuit breaker settings for calls into the provider
+    #[serde(default)]
+    pub resilience: ResilienceConfig,
+    /// Concurrency and rate limits for calls into the provider
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Loads the whole table into the cache at startup
+    #[serde(default)]
+    pub preload: Option<PreloadConfig>,
+    /// Accepts SET and HSET, written through to the backend
+    #[serde(default)]
+    pub writable: bool,
+    /// Queues writes locally and flushes them to the backend in batches
+    #[serde(default)]
+    pub write_behind: Option<WriteBehindConfig>,
+    /// Neither startup nor readiness fail when the provider is unavailable
+    #[serde(default)]
+    pub optional: bool,
+    /// Limits and caching of ad-hoc SQL queries
+    #[serde(default)]
+    pub query: QueryConfig,
+    /// Query run by a `Template` provider
+    #[serde(default)]
+    pub template: Option<TemplateConfig>,
+}
+
+/// Parameterized query served by a `Template` provider
+///
+/// The id of a lookup holds the parameter values separated by `:`, e.g. `SFO:2015`
+/// binds `$1` and `$2`; the last parameter takes the rest of the id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TemplateConfig {
+    /// AzDelta provider whose table the query reads
+    pub source: String,
+    /// SELECT query with `$1`, `$2`, ... placeholders
+    pub sql: String,
+    /// Types of the placeholders, in order
+    #[serde(default)]
+    pub params: Vec<TemplateParam>,
+}
+
+/// Type a template parameter is parsed into before being bound
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TemplateParam {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+/// Write-behind queue of a writable provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriteBehindConfig {
+    /// Directory holding the write-ahead logs, one subdirectory per provider
+    pub path: String,
+    /// Number of queued writes that triggers a flush
+    pub batch_size: usize,
+    /// Longest time a write waits for a flush in milliseconds
+    pub flush_interval_ms: u64,
+}
+
+/// Bulk preload of a provider's table into the cache
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreloadConfig {
+    /// Column whose value is used as the record id
+    pub key_column: String,
+    /// SQL predicate restricting the preloaded rows, e.g. `"YEAR" >= 2015`
+    #[serde(default)]
+    pub filter: Option<String>,
+    /// Columns to load, all columns if empty; should match the columns of the record query
+    #[serde(default)]
+    pub columns: Vec<String>,
+    /// Time to live of preloaded records in seconds; they stay until evicted if unset
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Concurrency and rate limits applied to calls into a data provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum number of concurrent backend calls (0 disables the limit)
+    pub max_in_flight: usize,
+    /// Maximum number of calls waiting for a free slot before new calls are rejected
+    pub max_queued: usize,
+    /// Sustained backend calls per second (0 disables rate limiting)
+    pub rate_per_second: f64,
+    /// Number of calls allowed in a burst above the sustained rate
+    pub burst: u32,
+}
+
+/// Limits and caching of ad-hoc SQL queries against a data provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueryConfig {
+    /// Most rows a query may return; larger results are rejected
+    pub max_rows: usize,
+    /// Time a query may run before it is cancelled, in milliseconds
+    pub timeout_ms: u64,
+    /// Default time to live of cached results in seconds (0 disables caching)
+    pub ttl_seconds: u64,
+    /// Maximum number of rows held by the cached results together
+    pub max_cached_rows: u64,
+}
+
+/// Resilience settings applied to every call into a data provider
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResilienceConfig {
+    /// Maximum duration of a single provider call in milliseconds
+    pub timeout_ms: u64,
+    /// Number of retries for transient errors (timeouts and database errors)
+    pub max_retries: u32,
+    /// Base delay of the exponential backoff between retries in milliseconds
+    pub backoff_base_ms: u64,
+    /// Upper bound of the backoff delay in milliseconds
+    pub backoff_max_ms: u64,
+    /// Consecutive failed calls after which the circuit breaker opens
+    pub failure_threshold: u32,
+    /// Time in seconds the circuit stays open before a half-open probe is allowed
+    pub open_seconds: u64,
 }
 
 /// Database configuration
@@ -58,6 +181,42 @@ pub struct CacheConfig {
     pub max_entries: usize,
     /// Time to live in seconds
     pub ttl_seconds: u64,
+    /// How long expired entries are kept around to be served when the backend fails (0 disables)
+    #[serde(default)]
+    pub stale_if_error_seconds: u64,
+    /// How long keys missing from the backend are remembered (0 disables negative caching)
+    #[serde(default)]
+    pub negative_ttl_seconds: u64,
+    /// File the cache is snapshotted to on shutdown and restored from at startup
+    #[serde(default)]
+    pub snapshot_path: Option<String>,
+    /// Interval in seconds between periodic snapshots (0 only snapshots on shutdown)
+    #[serde(default)]
+    pub snapshot_interval_seconds: u64,
+    /// Second-tier on-disk cache holding records evicted from memory
+    #[serde(default)]
+    pub disk: Option<DiskCacheConfig>,
+    /// Most keys KEYS may return; larger keyspaces must be iterated with SCAN
+    #[serde(default = "default_keys_limit")]
+    pub keys_limit: usize,
+}
+
+fn default_keys_limit() -> usize {
+    10_000
+}
+
+/// On-disk second-tier cache configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiskCacheConfig {
+    /// Directory holding the cache segment files
+    pub path: String,
+    /// Maximum total size of the segment files in bytes
+    pub max_bytes: u64,
+    /// Size in bytes at which a new segment file is started
+    pub segment_bytes: u64,
+    /// Time to live of records in the disk cache in seconds
+    pub ttl_seconds: u64,
 }
 
 /// Server configuration
@@ -65,6 +224,25 @@ pub struct CacheConfig {
 pub struct ServerConfig {
     /// Bind address for the server
     pub bind_address: String,
+    /// Bind address of the HTTP listener serving `/metrics`, `/healthz` and `/readyz`
+    /// (disabled if unset)
+    #[serde(default)]
+    pub admin_bind_address: Option<String>,
+    /// Whether `/readyz` also pings the backend of every provider
+    #[serde(default)]
+    pub readiness_ping: bool,
+    /// Bind address of the HTTP/JSON gateway (disabled if unset)
+    #[serde(default)]
+    pub http_bind_address: Option<String>,
+    /// Bind address of the gRPC service (disabled if unset)
+    #[serde(default)]
+    pub grpc_bind_address: Option<String>,
+    /// Bind address of the Arrow Flight endpoint (disabled if unset)
+    #[serde(default)]
+    pub flight_bind_address: Option<String>,
+    /// Password required from Redis and HTTP clients (no authentication if unset)
+    #[serde(default)]
+    pub requirepass: Option<String>,
 }
 
 /// Logging configuration
@@ -75,7 +253,7 @@ pub struct LoggingConfig {
 }
 
 /// Application configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct AppConfig {
     /// Database configuration
     pub database: DatabaseConfig,
@@ -94,43 +272,108 @@ impl Default for DatabaseConfig {
                 name: "users".to_string(),
                 provider: DatabaseProvider::Mock,
                 settings: HashMap::new(),
+                resilience: ResilienceConfig::default(),
+                limits: LimitsConfig::default(),
+                preload: None,
+                writable: false,
+                write_behind: None,
+                optional: false,
+                query: QueryConfig::default(),
+                template: None,
             }],
         }
     }
 }
 
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 5000,
+            max_retries: 2,
+            backoff_base_ms: 50,
+            backoff_max_ms: 1000,
+            failure_threshold: 5,
+            open_seconds: 30,
+        }
+    }
+}
+
+impl Default for QueryConfig {
+    fn default() -> Self {
+        Self {
+            max_rows: 10_000,
+            timeout_ms: 30_000,
+            ttl_seconds: 60,
+            max_cached_rows: 100_000,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 64,
+            max_queued: 256,
+            rate_per_second: 0.0,
+            burst: 0,
+        }
+    }
+}
+
 impl Default for CacheConfig {
     fn default() -> Self {
         Self {
             max_entries: 1000,
             ttl_seconds: 60,
+            stale_if_error_seconds: 0,
+            negative_ttl_seconds: 0,
+            snapshot_path: None,
+            snapshot_interval_seconds: 0,
+            disk: None,
+            keys_limit: default_keys_limit(),
         }
     }
 }
 
-impl Default for ServerConfig {
+impl Default for WriteBehindConfig {
     fn default() -> Self {
         Self {
-            bind_address: "127.0.0.1:6379".to_string(),
+            path: "prism_wal".to_string(),
+            batch_size: 1000,
+            flush_interval_ms: 5000,
         }
     }
 }
 
-impl Default for LoggingConfig {
+impl Default for DiskCacheConfig {
     fn default() -> Self {
         Self {
-            level: "info".to_string(),
+            path: "prism_cache_l2".to_string(),
+            max_bytes: 256 * 1024 * 1024,
+            segment_bytes: 16 * 1024 * 1024,
+            ttl_seconds: 3600,
         }
     }
 }
 
-impl Default for AppConfig {
+impl Default for ServerConfig {
     fn default() -> Self {
         Self {
-            database: DatabaseConfig::default(),
-            cache: CacheConfig::default(),
-            server: ServerConfig::default(),
-            logging: LoggingConfig::default(),
+            bind_address: "127.0.0.1:6379".to_string(),
+            admin_bind_address: None,
+            readiness_ping: false,
+            http_bind_address: None,
+            grpc_bind_address: None,
+            flight_bind_address: None,
+            requirepass: None,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
         }
     }
 }
@@ -0,0 +1,297 @@
+//! Arrow Flight endpoint for bulk retrieval.
+//!
+//! Serves the `DoGet` method of the Arrow Flight protocol, so Flight clients such as
+//! `pyarrow.flight` can fetch many records at once as Arrow record batches. A ticket is
+//! a JSON object naming a provider and either a list of ids or a filter:
+//!
+//! - `{"provider": "users", "ids": ["1", "2"]}` serves the records from the cache where
+//!   possible and fetches the others from the provider in one lookup; ids that do not
+//!   exist are skipped
+//! - `{"provider": "flights", "filter": "\"YEAR\" >= 2015"}` streams the rows of a Delta
+//!   table matching the SQL filter expression from DataFusion, bypassing the cache; the
+//!   stream fails with `RESOURCE_EXHAUSTED` once more than `query.max_rows` rows match
+//!
+//! Only `DoGet` is implemented, the other Flight methods answer `UNIMPLEMENTED`.
+//! When `requirepass` is set, calls must send it as a bearer token, as for gRPC.
+
+// tonic dictates `Status` as the error of every call, however large it is
+#![allow(clippy::result_large_err)]
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::ipc::writer::{
+    DictionaryTracker, EncodedData, IpcDataGenerator, IpcWriteOptions,
+};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::DataFusionError;
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{debug, info};
+
+use crate::grpc::{authenticate, to_status};
+use crate::storage::database::json_to_record_batches;
+use crate::storage::{StorageError, StorageService};
+
+// The generated client is only used by the tests
+#[allow(dead_code)]
+mod stubs {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/arrow.flight.protocol.FlightService.rs"
+    ));
+}
+
+use stubs::flight_service_server::{FlightService, FlightServiceServer};
+
+/// Maximum number of rows in a record batch built from cached records
+const BATCH_ROWS: usize = 4096;
+
+/// Opaque ticket of a `DoGet` call, holding a JSON [`TicketRequest`].
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Ticket {
+    #[prost(bytes = "vec", tag = "1")]
+    pub ticket: Vec<u8>,
+}
+
+/// A message of a Flight stream: an IPC schema, dictionary batch or record batch.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FlightData {
+    #[prost(bytes = "vec", tag = "2")]
+    pub data_header: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub app_metadata: Vec<u8>,
+    #[prost(bytes = "vec", tag = "1000")]
+    pub data_body: Vec<u8>,
+}
+
+impl From<EncodedData> for FlightData {
+    fn from(data: EncodedData) -> Self {
+        Self {
+            data_header: data.ipc_message,
+            app_metadata: Vec::new(),
+            data_body: data.arrow_data,
+        }
+    }
+}
+
+/// Records requested by a ticket.
+#[derive(Debug, Deserialize)]
+struct TicketRequest {
+    provider: String,
+    #[serde(default)]
+    ids: Vec<String>,
+    #[serde(default)]
+    filter: Option<String>,
+}
+
+/// Stream of Flight messages answering a `DoGet`.
+type FlightDataStream = Pin<Box<dyn Stream<Item = Result<FlightData, Status>> + Send>>;
+
+/// Implementation of the Flight service over the storage service.
+struct PrismFlightService {
+    storage: Arc<StorageService>,
+}
+
+/// Serves the Flight endpoint on `bind_address` until the listener fails.
+pub async fn serve(
+    bind_address: &str,
+    storage: Arc<StorageService>,
+    requirepass: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(bind_address).await?;
+    info!("Arrow Flight endpoint listening on {}", bind_address);
+    serve_listener(listener, storage, requirepass).await?;
+    Ok(())
+}
+
+/// Serves the Flight endpoint on a bound listener.
+async fn serve_listener(
+    listener: TcpListener,
+    storage: Arc<StorageService>,
+    requirepass: Option<String>,
+) -> Result<(), tonic::transport::Error> {
+    let service = PrismFlightService { storage };
+    let service = FlightServiceServer::with_interceptor(service, move |request| {
+        authenticate(requirepass.as_deref(), request)
+    });
+    Server::builder()
+        .add_service(service)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+#[tonic::async_trait]
+impl FlightService for PrismFlightService {
+    type DoGetStream = FlightDataStream;
+
+    async fn do_get(&self, request: Request<Ticket>) -> Result<Response<FlightDataStream>, Status> {
+        let ticket: TicketRequest = serde_json::from_slice(&request.into_inner().ticket)
+            .map_err(|e| Status::invalid_argument(format!("Invalid ticket: {}", e)))?;
+        self.storage.stats().record_request("MGET");
+
+        let (schema, batches) = match (&ticket.filter, ticket.ids.is_empty()) {
+            (Some(filter), true) => {
+                debug!(
+                    "Flight DoGet provider [{}] filter [{}]",
+                    ticket.provider, filter
+                );
+                let stream = self
+                    .storage
+                    .scan_filtered(&ticket.provider, filter)
+                    .await
+                    .map_err(to_status)?;
+                let schema = stream.schema();
+                let batches = stream.map(|batch| batch.map_err(scan_status));
+                (schema, batches.boxed())
+            }
+            (None, false) => {
+                debug!(
+                    "Flight DoGet provider [{}] {} ids",
+                    ticket.provider,
+                    ticket.ids.len()
+                );
+                let records = self
+                    .storage
+                    .fetch_records(&ticket.provider, &ticket.ids)
+                    .await
+                    .map_err(to_status)?;
+                let (schema, batches) =
+                    json_to_record_batches(&records, BATCH_ROWS).map_err(to_status)?;
+                (
+                    schema,
+                    futures::stream::iter(batches.into_iter().map(Ok)).boxed(),
+                )
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "A ticket names either ids or a filter",
+                ));
+            }
+        };
+        Ok(Response::new(encode(schema, batches)))
+    }
+}
+
+/// Maps an error of a filtered scan, which carries storage errors such as the row limit.
+fn scan_status(err: DataFusionError) -> Status {
+    match err {
+        DataFusionError::External(err) => match err.downcast::<StorageError>() {
+            Ok(err) => to_status(*err),
+            Err(err) => Status::internal(err.to_string()),
+        },
+        err => Status::internal(err.to_string()),
+    }
+}
+
+/// Encodes record batches as Flight messages, starting with the schema.
+fn encode(
+    schema: SchemaRef,
+    batches: BoxStream<'static, Result<RecordBatch, Status>>,
+) -> FlightDataStream {
+    let generator = IpcDataGenerator::default();
+    let options = IpcWriteOptions::default();
+    let mut tracker = DictionaryTracker::new(false);
+    let schema = generator.schema_to_bytes_with_dictionary_tracker(&schema, &mut tracker, &options);
+
+    let messages = batches.flat_map(move |batch| {
+        let encoded = batch.and_then(|batch| {
+            generator
+                .encoded_batch(&batch, &mut tracker, &options)
+                .map_err(|e| Status::internal(e.to_string()))
+        });
+        // Dictionaries precede the batch that uses them
+        let messages: Vec<_> = match encoded {
+            Ok((dictionaries, batch)) => dictionaries
+                .into_iter()
+                .chain([batch])
+                .map(|data| Ok(FlightData::from(data)))
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+        futures::stream::iter(messages)
+    });
+    Box::pin(futures::stream::once(async move { Ok(FlightData::from(schema)) }).chain(messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stubs::flight_service_client::FlightServiceClient;
+    use super::*;
+    use crate::config::AppConfig;
+    use datafusion::arrow::ipc::reader::StreamReader;
+    use datafusion::arrow::ipc::writer::write_message;
+    use serde_json::Value;
+    use tonic::Code;
+    use tonic::transport::Channel;
+
+    /// Starts the endpoint on a local port and connects a client to it.
+    async fn client() -> FlightServiceClient<Channel> {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, Arc::new(storage), None));
+        FlightServiceClient::connect(format!("http://{}", address))
+            .await
+            .unwrap()
+    }
+
+    fn ticket(request: Value) -> Ticket {
+        Ticket {
+            ticket: request.to_string().into_bytes(),
+        }
+    }
+
+    /// Decodes the Flight messages of a `DoGet` back into record batches.
+    async fn do_get(
+        client: &mut FlightServiceClient<Channel>,
+        ticket: Ticket,
+    ) -> Result<Vec<RecordBatch>, Status> {
+        let mut stream = client.do_get(ticket).await?.into_inner();
+        let mut bytes = Vec::new();
+        let options = IpcWriteOptions::default();
+        while let Some(data) = stream.message().await? {
+            let data = EncodedData {
+                ipc_message: data.data_header,
+                arrow_data: data.data_body,
+            };
+            write_message(&mut bytes, data, &options).unwrap();
+        }
+        let reader = StreamReader::try_new(bytes.as_slice(), None).unwrap();
+        Ok(reader.collect::<Result<_, _>>().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_do_get_ids() {
+        let mut client = client().await;
+        let request = serde_json::json!({ "provider": "users", "ids": ["123", "999", "456"] });
+        let batches = do_get(&mut client, ticket(request)).await.unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 2);
+        assert!(batches[0].schema().field_with_name("id").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_do_get_rejects_invalid_tickets() {
+        let mut client = client().await;
+        let request = Ticket {
+            ticket: b"users:123".to_vec(),
+        };
+        let status = do_get(&mut client, request).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let request = serde_json::json!({ "provider": "users", "ids": ["1"], "filter": "1 = 1" });
+        let status = do_get(&mut client, ticket(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        let request = serde_json::json!({ "provider": "orders", "ids": ["1"] });
+        let status = do_get(&mut client, ticket(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
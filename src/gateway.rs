@@ -0,0 +1,363 @@
+//! HTTP/JSON gateway for clients that cannot speak the Redis protocol.
+//!
+//! Serves the same storage operations as the Redis commands, from the same cache:
+//!
+//! - `GET /v1/{provider}/{id}`: the record, like `GET provider:id`
+//! - `GET /v1/{provider}/{id}/{field}`: a field of the record, like `HGET`
+//! - `POST /v1/{provider}/_mget`: the records of `{"ids": [...]}`, `null` if missing
+//!   or failed; the errors of failed ids are returned in `errors`, keyed by id
+//!
+//! Records carry an `ETag` of their content and a `Cache-Control` max-age of their
+//! remaining time in the cache, so `If-None-Match` requests are answered with 304.
+//! When `requirepass` is set, requests must send it as a bearer token.
+
+use axum::extract::{Path, Request, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{debug, info};
+
+use crate::auth::password_matches;
+use crate::storage::{StorageError, StorageService};
+
+/// Most ids a `_mget` request may hold
+const MGET_MAX_IDS: usize = 1000;
+/// Number of records a `_mget` request fetches concurrently
+const MGET_CONCURRENCY: usize = 16;
+
+/// State shared by the gateway endpoints.
+#[derive(Clone)]
+struct GatewayState {
+    storage: Arc<StorageService>,
+    /// Password clients must send as a bearer token, if any
+    requirepass: Option<Arc<str>>,
+}
+
+/// Body of a `_mget` request.
+#[derive(Debug, Deserialize)]
+struct MgetRequest {
+    ids: Vec<String>,
+}
+
+/// Serves the gateway on `bind_address` until the listener fails.
+pub async fn serve(
+    bind_address: &str,
+    storage: Arc<StorageService>,
+    requirepass: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(bind_address).await?;
+    info!("HTTP gateway listening on {}", bind_address);
+    axum::serve(listener, router(storage, requirepass)).await?;
+    Ok(())
+}
+
+/// Builds the routes of the gateway.
+fn router(storage: Arc<StorageService>, requirepass: Option<String>) -> Router {
+    let state = GatewayState {
+        storage,
+        requirepass: requirepass.map(Arc::from),
+    };
+    Router::new()
+        .route("/v1/{provider}/_mget", post(post_mget))
+        .route("/v1/{provider}/{id}", get(get_record))
+        .route("/v1/{provider}/{id}/{field}", get(get_field))
+        .layer(middleware::from_fn_with_state(state.clone(), require_auth))
+        .with_state(state)
+}
+
+/// Rejects requests without the configured password as bearer token.
+async fn require_auth(State(state): State<GatewayState>, request: Request, next: Next) -> Response {
+    if let Some(requirepass) = &state.requirepass {
+        let token = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !token.is_some_and(|token| password_matches(requirepass, token)) {
+            let body = Json(json!({ "error": "Authentication required" }));
+            let challenge = [(header::WWW_AUTHENTICATE, "Bearer")];
+            return (StatusCode::UNAUTHORIZED, challenge, body).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+async fn get_record(
+    State(state): State<GatewayState>,
+    Path((provider, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    state.storage.stats().record_request("GET");
+    debug!("HTTP GET provider [{}] id [{}]", provider, id);
+    match state.storage.fetch_record(&provider, &id).await {
+        Ok(record) => cached_response(&state.storage, &provider, &id, &record, &headers).await,
+        Err(e) => error_response(e),
+    }
+}
+
+async fn get_field(
+    State(state): State<GatewayState>,
+    Path((provider, id, field)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    state.storage.stats().record_request("HGET");
+    debug!(
+        "HTTP GET provider [{}] id [{}] field [{}]",
+        provider, id, field
+    );
+    let record = match state.storage.fetch_record(&provider, &id).await {
+        Ok(record) => record,
+        Err(e) => return error_response(e),
+    };
+    match record.get(&field) {
+        Some(value) => cached_response(&state.storage, &provider, &id, value, &headers).await,
+        None => error_response(StorageError::FieldNotFound(format!(
+            "Field {} not found in {}:{}",
+            field, provider, id
+        ))),
+    }
+}
+
+async fn post_mget(
+    State(state): State<GatewayState>,
+    Path(provider): Path<String>,
+    Json(request): Json<MgetRequest>,
+) -> Response {
+    state.storage.stats().record_request("MGET");
+    debug!(
+        "HTTP MGET provider [{}] {} ids",
+        provider,
+        request.ids.len()
+    );
+    if request.ids.len() > MGET_MAX_IDS {
+        let message = format!("A request may hold at most {} ids", MGET_MAX_IDS);
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(json!({ "error": message }))).into_response();
+    }
+    if !state.storage.has_provider(&provider) {
+        let message = format!("Provider {} not found", provider);
+        return error_response(StorageError::ProviderNotFound(message));
+    }
+    let results: Vec<_> = futures::stream::iter(request.ids)
+        .map(|id| {
+            let storage = Arc::clone(&state.storage);
+            let provider = provider.clone();
+            async move {
+                let result = storage.fetch_record(&provider, &id).await;
+                (id, result)
+            }
+        })
+        .buffered(MGET_CONCURRENCY)
+        .collect()
+        .await;
+
+    // One failed id does not fail the others
+    let mut records = Vec::with_capacity(results.len());
+    let mut errors = serde_json::Map::new();
+    for (id, result) in results {
+        match result {
+            Ok(record) => records.push(record),
+            Err(StorageError::RecordNotInDatabase(_)) => records.push(Value::Null),
+            Err(e) => {
+                records.push(Value::Null);
+                errors.insert(id, Value::String(e.to_string()));
+            }
+        }
+    }
+    if errors.is_empty() {
+        Json(json!({ "records": records })).into_response()
+    } else {
+        Json(json!({ "records": records, "errors": errors })).into_response()
+    }
+}
+
+/// Builds the response for a value of a cached record, with validation headers.
+///
+/// The max-age is the remaining time to live of the record; records that never
+/// expire, or are not cached, must be revalidated with their ETag.
+async fn cached_response(
+    storage: &StorageService,
+    provider: &str,
+    id: &str,
+    value: &Value,
+    headers: &HeaderMap,
+) -> Response {
+    let body = value.to_string();
+    let etag = format!("\"{:08x}\"", crc32fast::hash(body.as_bytes()));
+    let cache_control = match storage.record_ttl(provider, id).await {
+        Ok(Some(Some(ttl))) => format!("max-age={}", ttl.as_secs()),
+        _ => "no-cache".to_string(),
+    };
+    let validators = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, cache_control),
+    ];
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, validators).into_response();
+    }
+    let content_type = (
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    (validators, [content_type], body).into_response()
+}
+
+/// Maps a storage error to a JSON error response.
+fn error_response(err: StorageError) -> Response {
+    let status = match &err {
+        StorageError::EntityNotFound(_)
+        | StorageError::RecordNotInDatabase(_)
+        | StorageError::RecordNotFoundInCache(_)
+        | StorageError::FieldNotFound(_)
+        | StorageError::ProviderNotFound(_) => StatusCode::NOT_FOUND,
+        StorageError::Busy(_) | StorageError::LimitExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+        StorageError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+        StorageError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        StorageError::ReadOnly(_) => StatusCode::METHOD_NOT_ALLOWED,
+        StorageError::InvalidQuery(_)
+        | StorageError::InvalidRecord(_)
+        | StorageError::InvalidExpireTime(_) => StatusCode::BAD_REQUEST,
+        StorageError::DatabaseError(_)
+        | StorageError::CacheError(_)
+        | StorageError::ConfigError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, Json(json!({ "error": err.to_string() }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    async fn state() -> GatewayState {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        GatewayState {
+            storage: Arc::new(storage),
+            requirepass: None,
+        }
+    }
+
+    async fn body_json(response: Response) -> Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_record_with_validators() {
+        let state = state().await;
+        let path = Path(("users".to_string(), "123".to_string()));
+        let response = get_record(State(state.clone()), path, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[header::ETAG].clone();
+        let cache_control = response.headers()[header::CACHE_CONTROL].to_str().unwrap();
+        assert!(cache_control.starts_with("max-age="));
+        assert_eq!(body_json(response).await["id"], "123");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let path = Path(("users".to_string(), "123".to_string()));
+        let response = get_record(State(state.clone()), path, headers).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let path = Path(("orders".to_string(), "123".to_string()));
+        let response = get_record(State(state), path, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Sends a raw HTTP request to the gateway and returns the response.
+    async fn send(address: std::net::SocketAddr, request: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_routes_require_password() {
+        let storage = state().await.storage;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = router(storage, Some("secret".to_string()));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let request = "GET /v1/users/123 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+        assert!(send(address, request).await.starts_with("HTTP/1.1 401"));
+
+        let request = "GET /v1/users/123 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\
+                       Authorization: Bearer secret\r\n\r\n";
+        assert!(send(address, request).await.starts_with("HTTP/1.1 200"));
+
+        let body = r#"{"ids":["456"]}"#;
+        let request = format!(
+            "POST /v1/users/_mget HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\
+             Authorization: Bearer secret\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let response = send(address, &request).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains(r#""id":"456""#));
+    }
+
+    #[tokio::test]
+    async fn test_get_field_and_mget() {
+        let state = state().await;
+        let path = Path(("users".to_string(), "123".to_string(), "id".to_string()));
+        let response = get_field(State(state.clone()), path, HeaderMap::new()).await;
+        assert_eq!(body_json(response).await, "123");
+
+        let path = Path(("users".to_string(), "123".to_string(), "nope".to_string()));
+        let response = get_field(State(state.clone()), path, HeaderMap::new()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let request = MgetRequest {
+            ids: vec!["123".to_string(), "999".to_string()],
+        };
+        let response = post_mget(
+            State(state.clone()),
+            Path("users".to_string()),
+            Json(request),
+        )
+        .await;
+        let body = body_json(response).await;
+        assert_eq!(body["records"][0]["id"], "123");
+        assert!(body["records"][1].is_null());
+        // A missing record is no error
+        assert!(body.get("errors").is_none());
+
+        let request = MgetRequest {
+            ids: vec!["123".to_string(); MGET_MAX_IDS + 1],
+        };
+        let response = post_mget(
+            State(state.clone()),
+            Path("users".to_string()),
+            Json(request),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            state.storage.stats().commands(),
+            vec![("HGET".to_string(), 2), ("MGET".to_string(), 2)]
+        );
+    }
+}
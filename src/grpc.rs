@@ -0,0 +1,365 @@
+//! gRPC interface for typed record lookups.
+//!
+//! Serves the `prism.v1.Prism` service described in `proto/prism.proto`, from the same
+//! cache as the Redis commands. The messages and service stubs are generated from
+//! that file by `build.rs`. Records are returned as a `google.protobuf.Struct` or as
+//! Arrow IPC stream bytes.
+//! When `requirepass` is set, calls must send it as a bearer token in the
+//! `authorization` metadata.
+
+// tonic dictates `Status` as the error of every call, however large it is
+#![allow(clippy::result_large_err)]
+
+use datafusion::arrow::ipc::writer::StreamWriter;
+use futures::{Stream, StreamExt};
+use prost_types::value::Kind;
+use prost_types::{ListValue, Struct};
+use serde_json::Value;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{debug, info};
+
+use crate::auth::password_matches;
+use crate::storage::database::json_to_record_batch;
+use crate::storage::{StorageError, StorageService};
+
+// The generated client is only used by the tests
+#[allow(dead_code)]
+mod stubs {
+    tonic::include_proto!("prism.v1");
+}
+
+use stubs::prism_server::{Prism, PrismServer};
+use stubs::{
+    BatchGetRequest, BatchGetResponse, GetRequest, InvalidateRequest, InvalidateResponse, Record,
+    RecordFormat, record,
+};
+
+/// Number of records a `BatchGet` fetches concurrently
+const BATCH_CONCURRENCY: usize = 16;
+
+/// Implementation of the `Prism` service over the storage service.
+struct PrismService {
+    storage: Arc<StorageService>,
+}
+
+/// Serves the gRPC service on `bind_address` until the listener fails.
+pub async fn serve(
+    bind_address: &str,
+    storage: Arc<StorageService>,
+    requirepass: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(bind_address).await?;
+    info!("gRPC service listening on {}", bind_address);
+    serve_listener(listener, storage, requirepass).await?;
+    Ok(())
+}
+
+/// Serves the gRPC service on a bound listener.
+async fn serve_listener(
+    listener: TcpListener,
+    storage: Arc<StorageService>,
+    requirepass: Option<String>,
+) -> Result<(), tonic::transport::Error> {
+    let service = PrismServer::with_interceptor(PrismService { storage }, move |request| {
+        authenticate(requirepass.as_deref(), request)
+    });
+    Server::builder()
+        .add_service(service)
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+/// Rejects calls without the configured password as bearer token.
+pub fn authenticate(
+    requirepass: Option<&str>,
+    request: Request<()>,
+) -> Result<Request<()>, Status> {
+    let Some(requirepass) = requirepass else {
+        return Ok(request);
+    };
+    let token = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if token.is_some_and(|token| password_matches(requirepass, token)) {
+        Ok(request)
+    } else {
+        Err(Status::unauthenticated("Authentication required"))
+    }
+}
+
+#[tonic::async_trait]
+impl Prism for PrismService {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<Record>, Status> {
+        let request = request.into_inner();
+        self.storage.stats().record_request("GET");
+        debug!(
+            "gRPC Get provider [{}] id [{}]",
+            request.provider, request.id
+        );
+        let record = self
+            .storage
+            .fetch_record(&request.provider, &request.id)
+            .await
+            .map_err(to_status)?;
+        let data = encode(&record, request.format())?;
+        Ok(Response::new(Record {
+            provider: request.provider,
+            id: request.id,
+            data: Some(data),
+        }))
+    }
+
+    type BatchGetStream = Pin<Box<dyn Stream<Item = Result<BatchGetResponse, Status>> + Send>>;
+
+    async fn batch_get(
+        &self,
+        request: Request<BatchGetRequest>,
+    ) -> Result<Response<Self::BatchGetStream>, Status> {
+        let request = request.into_inner();
+        self.storage.stats().record_request("MGET");
+        debug!(
+            "gRPC BatchGet provider [{}] {} ids",
+            request.provider,
+            request.ids.len()
+        );
+        if !self.storage.has_provider(&request.provider) {
+            let message = format!("Provider {} not found", request.provider);
+            return Err(to_status(StorageError::ProviderNotFound(message)));
+        }
+
+        let format = request.format();
+        let provider = request.provider;
+        let storage = Arc::clone(&self.storage);
+        // Records are fetched concurrently and streamed in request order
+        let responses = futures::stream::iter(request.ids)
+            .map(move |id| {
+                let storage = Arc::clone(&storage);
+                let provider = provider.clone();
+                async move {
+                    let record = match storage.fetch_record(&provider, &id).await {
+                        Ok(record) => Some(Record {
+                            data: Some(encode(&record, format)?),
+                            provider,
+                            id: id.clone(),
+                        }),
+                        Err(StorageError::RecordNotInDatabase(_)) => None,
+                        Err(e) => return Err(to_status(e)),
+                    };
+                    Ok(BatchGetResponse { id, record })
+                }
+            })
+            .buffered(BATCH_CONCURRENCY);
+        Ok(Response::new(Box::pin(responses)))
+    }
+
+    async fn invalidate(
+        &self,
+        request: Request<InvalidateRequest>,
+    ) -> Result<Response<InvalidateResponse>, Status> {
+        let request = request.into_inner();
+        self.storage.stats().record_request("DEL");
+        debug!(
+            "gRPC Invalidate provider [{}] {} ids",
+            request.provider,
+            request.ids.len()
+        );
+        let mut invalidated = 0;
+        for id in &request.ids {
+            if self
+                .storage
+                .evict_record(&request.provider, id)
+                .await
+                .map_err(to_status)?
+            {
+                invalidated += 1;
+            }
+        }
+        Ok(Response::new(InvalidateResponse { invalidated }))
+    }
+}
+
+/// Encodes a record in the requested format.
+fn encode(record: &Value, format: RecordFormat) -> Result<record::Data, Status> {
+    match format {
+        RecordFormat::Struct => Ok(record::Data::Fields(to_struct(record))),
+        RecordFormat::ArrowIpc => {
+            let batch = json_to_record_batch(std::slice::from_ref(record)).map_err(to_status)?;
+            let to_status =
+                |e: datafusion::arrow::error::ArrowError| Status::internal(e.to_string());
+            let mut writer =
+                StreamWriter::try_new(Vec::new(), &batch.schema()).map_err(to_status)?;
+            writer.write(&batch).map_err(to_status)?;
+            writer
+                .into_inner()
+                .map(record::Data::ArrowIpc)
+                .map_err(to_status)
+        }
+    }
+}
+
+/// Converts a JSON record to a protobuf struct; other values than objects give an empty struct.
+fn to_struct(record: &Value) -> Struct {
+    let fields = record
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, value)| (name.clone(), to_proto_value(value)))
+        .collect();
+    Struct { fields }
+}
+
+/// Converts a JSON value to a protobuf value.
+fn to_proto_value(value: &Value) -> prost_types::Value {
+    let kind = match value {
+        Value::Null => Kind::NullValue(0),
+        Value::Bool(value) => Kind::BoolValue(*value),
+        Value::Number(value) => Kind::NumberValue(value.as_f64().unwrap_or_default()),
+        Value::String(value) => Kind::StringValue(value.clone()),
+        Value::Array(values) => Kind::ListValue(ListValue {
+            values: values.iter().map(to_proto_value).collect(),
+        }),
+        Value::Object(_) => Kind::StructValue(to_struct(value)),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+/// Maps a storage error to a gRPC status.
+pub fn to_status(err: StorageError) -> Status {
+    let message = err.to_string();
+    match err {
+        StorageError::EntityNotFound(_)
+        | StorageError::RecordNotInDatabase(_)
+        | StorageError::RecordNotFoundInCache(_)
+        | StorageError::FieldNotFound(_)
+        | StorageError::ProviderNotFound(_) => Status::not_found(message),
+        StorageError::Busy(_) | StorageError::LimitExceeded(_) => {
+            Status::resource_exhausted(message)
+        }
+        StorageError::CircuitOpen(_) => Status::unavailable(message),
+        StorageError::Timeout(_) => Status::deadline_exceeded(message),
+        StorageError::ReadOnly(_) => Status::failed_precondition(message),
+        StorageError::InvalidQuery(_)
+        | StorageError::InvalidRecord(_)
+        | StorageError::InvalidExpireTime(_) => Status::invalid_argument(message),
+        StorageError::DatabaseError(_)
+        | StorageError::CacheError(_)
+        | StorageError::ConfigError(_) => Status::internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stubs::prism_client::PrismClient;
+    use super::*;
+    use crate::config::AppConfig;
+    use datafusion::arrow::ipc::reader::StreamReader;
+    use tonic::Code;
+    use tonic::transport::Channel;
+
+    /// Starts the service on a local port and connects a client to it.
+    async fn client(requirepass: Option<&str>) -> PrismClient<Channel> {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requirepass = requirepass.map(str::to_string);
+        tokio::spawn(serve_listener(listener, Arc::new(storage), requirepass));
+        PrismClient::connect(format!("http://{}", address))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_get_and_invalidate() {
+        let mut client = client(None).await;
+        let request = GetRequest {
+            provider: "users".to_string(),
+            id: "123".to_string(),
+            format: RecordFormat::Struct as i32,
+        };
+        let record = client.get(request.clone()).await.unwrap().into_inner();
+        let Some(record::Data::Fields(fields)) = record.data else {
+            panic!("expected a struct");
+        };
+        assert_eq!(
+            fields.fields["id"].kind,
+            Some(Kind::StringValue("123".to_string()))
+        );
+
+        let request = GetRequest {
+            format: RecordFormat::ArrowIpc as i32,
+            ..request
+        };
+        let record = client.get(request).await.unwrap().into_inner();
+        let Some(record::Data::ArrowIpc(bytes)) = record.data else {
+            panic!("expected arrow bytes");
+        };
+        let batches: Vec<_> = StreamReader::try_new(bytes.as_slice(), None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches[0].num_rows(), 1);
+
+        let request = GetRequest {
+            provider: "users".to_string(),
+            id: "999".to_string(),
+            format: 0,
+        };
+        let status = client.get(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let request = InvalidateRequest {
+            provider: "users".to_string(),
+            ids: vec!["123".to_string(), "456".to_string()],
+        };
+        let response = client.invalidate(request).await.unwrap().into_inner();
+        assert_eq!(response.invalidated, 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_get_streams_in_order() {
+        let mut client = client(None).await;
+        let request = BatchGetRequest {
+            provider: "users".to_string(),
+            ids: vec!["456".to_string(), "999".to_string(), "123".to_string()],
+            format: RecordFormat::Struct as i32,
+        };
+        let mut stream = client.batch_get(request).await.unwrap().into_inner();
+        let mut responses = Vec::new();
+        while let Some(response) = stream.message().await.unwrap() {
+            responses.push((response.id, response.record.is_some()));
+        }
+        assert_eq!(
+            responses,
+            vec![
+                ("456".to_string(), true),
+                ("999".to_string(), false),
+                ("123".to_string(), true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requires_password() {
+        let mut client = client(Some("secret")).await;
+        let request = GetRequest {
+            provider: "users".to_string(),
+            id: "123".to_string(),
+            format: 0,
+        };
+        let status = client.get(request.clone()).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(request);
+        let token = "Bearer secret".parse().unwrap();
+        request.metadata_mut().insert("authorization", token);
+        assert!(client.get(request).await.is_ok());
+    }
+}
@@ -3,16 +3,23 @@ use std::path::Path;
 use std::sync::Arc;
 use std::str::FromStr;
 
-use config::{AppConfig, ConfigError};
+use config::AppConfig;
 use server::Server;
 use storage::StorageService;
 use tracing::{debug, error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+mod admin;
+mod auth;
 mod commands;
 mod config;
+mod flight;
+mod gateway;
+mod grpc;
+mod metrics;
 mod redis_protocol;
 mod server;
+mod stats;
 mod storage;
 
 /// Initialize logging
@@ -57,17 +64,51 @@ fn load_config() -> Result<AppConfig, Box<dyn Error>> {
 async fn init_storage(config: &AppConfig) -> Result<Arc<StorageService>, Box<dyn Error>> {
     let storage = Arc::new(StorageService::new(config).await?);
     info!("Storage service initialized successfully");
+
+    // Preloads run in the background; report when the cache is fully warm
+    let ready = Arc::clone(&storage);
+    tokio::spawn(async move {
+        ready.wait_ready().await;
+        info!("All providers are ready");
+    });
     Ok(storage)
 }
 
-/// Run the server
+/// Run the server until a shutdown signal is received
 async fn run_server(config: AppConfig, storage: Arc<StorageService>) -> Result<(), Box<dyn Error>> {
     let server = Server::new(config.server.clone(), Arc::clone(&storage));
     info!("Server running on {}", config.server.bind_address);
-    server.run().await?;
+    tokio::select! {
+        result = server.run() => result?,
+        _ = shutdown_signal() => info!("Shutdown signal received"),
+    }
     Ok(())
 }
 
+/// Wait for Ctrl-C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let config = load_config()?;
@@ -83,6 +124,52 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     
     let storage = init_storage(&config).await?;
-    run_server(config, storage).await?;
+    if let Some(address) = config.server.admin_bind_address.clone() {
+        let storage = Arc::clone(&storage);
+        let readiness_ping = config.server.readiness_ping;
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(&address, storage, readiness_ping).await {
+                error!("Admin endpoints failed: {}", e);
+            }
+        });
+    }
+    if let Some(address) = config.server.http_bind_address.clone() {
+        let storage = Arc::clone(&storage);
+        let requirepass = config.server.requirepass.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gateway::serve(&address, storage, requirepass).await {
+                error!("HTTP gateway failed: {}", e);
+            }
+        });
+    }
+    if let Some(address) = config.server.grpc_bind_address.clone() {
+        let storage = Arc::clone(&storage);
+        let requirepass = config.server.requirepass.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(&address, storage, requirepass).await {
+                error!("gRPC service failed: {}", e);
+            }
+        });
+    }
+    if let Some(address) = config.server.flight_bind_address.clone() {
+        let storage = Arc::clone(&storage);
+        let requirepass = config.server.requirepass.clone();
+        tokio::spawn(async move {
+            if let Err(e) = flight::serve(&address, storage, requirepass).await {
+                error!("Arrow Flight endpoint failed: {}", e);
+            }
+        });
+    }
+    run_server(config, Arc::clone(&storage)).await?;
+
+    // Unflushed writes stay in the write-ahead log and are replayed on restart
+    if let Err(e) = storage.flush_writes().await {
+        error!("Failed to flush queued writes on shutdown: {}", e);
+    }
+
+    if let Err(e) = storage.save_snapshot().await {
+        error!("Failed to save cache snapshot on shutdown: {}", e);
+    }
+    info!("Prism Cache server stopped");
     Ok(())
 }
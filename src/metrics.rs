@@ -0,0 +1,346 @@
+//! Prometheus metrics in the text exposition format.
+//!
+//! The metric names below are part of the public interface and must stay stable:
+//!
+//! | Metric | Type | Labels | Description |
+//! |--------|------|--------|-------------|
+//! | `prism_uptime_seconds` | gauge | | Time since the server started |
+//! | `prism_connected_clients` | gauge | | Open client connections |
+//! | `prism_connections_total` | counter | | Client connections accepted |
+//! | `prism_commands_total` | counter | `command` | Commands processed, by name |
+//! | `prism_network_received_bytes_total` | counter | | Bytes read from clients |
+//! | `prism_network_sent_bytes_total` | counter | | Bytes written to clients |
+//! | `prism_cache_entries` | gauge | `tier` | Records held in `memory` or on `disk` |
+//! | `prism_cache_hits_total` | counter | `provider` | Lookups answered from the cache |
+//! | `prism_cache_misses_total` | counter | `provider` | Lookups that went to the backend |
+//! | `prism_cache_evictions_total` | counter | `provider` | Records evicted for size |
+//! | `prism_cache_expirations_total` | counter | `provider` | Records that expired |
+//! | `prism_stale_served_total` | counter | | Stale records served on backend failure |
+//! | `prism_backend_calls_total` | counter | `provider` | Backend calls, counting retries |
+//! | `prism_backend_errors_total` | counter | `provider` | Failed backend calls |
+//! | `prism_client_errors_total` | counter | `provider` | Calls rejected for the request |
+//! | `prism_backend_latency_seconds` | histogram | `provider` | Latency of backend calls |
+//! | `prism_circuit_state` | gauge | `provider` | Circuit breaker: 0 closed, 1 half-open, 2 open |
+//! | `prism_replication_confirmed_lsn` | gauge | `provider` | WAL position confirmed to Postgres |
+//! | `prism_replication_lag_bytes` | gauge | `provider` | WAL written beyond the applied changes |
+//! | `prism_write_behind_pending` | gauge | `provider` | Writes waiting to be flushed |
+//! | `prism_write_behind_failed_flushes` | gauge | `provider` | Failed flushes since last success |
+//! | `prism_write_behind_flushed_total` | counter | `provider` | Writes flushed to the backend |
+//! | `prism_write_behind_dead_letters_total` | counter | `provider` | Writes the backend rejected |
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::stats::{LATENCY_BUCKETS_MS, ProviderStats};
+use crate::storage::resilience::CircuitState;
+use crate::storage::write_behind::QueueCounts;
+use crate::storage::{StorageResult, StorageService};
+
+/// Renders every metric in the Prometheus text exposition format.
+pub async fn render(storage: &StorageService) -> StorageResult<String> {
+    let stats = storage.stats();
+    let mut out = String::new();
+
+    metric(
+        &mut out,
+        "prism_uptime_seconds",
+        "gauge",
+        "Time since the server started",
+    );
+    sample(
+        &mut out,
+        "prism_uptime_seconds",
+        &[],
+        stats.uptime().as_secs_f64(),
+    );
+    metric(
+        &mut out,
+        "prism_connected_clients",
+        "gauge",
+        "Open client connections",
+    );
+    let clients = stats.connected_clients.load(Ordering::Relaxed);
+    sample(&mut out, "prism_connected_clients", &[], clients as f64);
+    metric(
+        &mut out,
+        "prism_connections_total",
+        "counter",
+        "Client connections accepted",
+    );
+    let connections = stats.total_connections.load(Ordering::Relaxed);
+    sample(&mut out, "prism_connections_total", &[], connections as f64);
+
+    metric(
+        &mut out,
+        "prism_commands_total",
+        "counter",
+        "Commands processed, by name",
+    );
+    for (command, count) in stats.commands() {
+        sample(
+            &mut out,
+            "prism_commands_total",
+            &[("command", &command)],
+            count as f64,
+        );
+    }
+
+    let received = stats.bytes_received.load(Ordering::Relaxed);
+    let sent = stats.bytes_sent.load(Ordering::Relaxed);
+    metric(
+        &mut out,
+        "prism_network_received_bytes_total",
+        "counter",
+        "Bytes read from clients",
+    );
+    sample(
+        &mut out,
+        "prism_network_received_bytes_total",
+        &[],
+        received as f64,
+    );
+    metric(
+        &mut out,
+        "prism_network_sent_bytes_total",
+        "counter",
+        "Bytes written to clients",
+    );
+    sample(&mut out, "prism_network_sent_bytes_total", &[], sent as f64);
+
+    let (memory, disk) = storage.cache_usage().await?;
+    metric(
+        &mut out,
+        "prism_cache_entries",
+        "gauge",
+        "Records held by each cache tier",
+    );
+    sample(
+        &mut out,
+        "prism_cache_entries",
+        &[("tier", "memory")],
+        memory.entries as f64,
+    );
+    if let Some(disk) = disk {
+        sample(
+            &mut out,
+            "prism_cache_entries",
+            &[("tier", "disk")],
+            disk.entries as f64,
+        );
+    }
+
+    let providers = stats.providers();
+    let counters: [(&str, &str, ProviderCounter); 7] = [
+        (
+            "prism_cache_hits_total",
+            "Lookups answered from the cache",
+            |s| &s.cache_hits,
+        ),
+        (
+            "prism_cache_misses_total",
+            "Lookups that went to the backend",
+            |s| &s.cache_misses,
+        ),
+        (
+            "prism_cache_evictions_total",
+            "Records evicted from memory for size",
+            |s| &s.evictions,
+        ),
+        (
+            "prism_cache_expirations_total",
+            "Records removed on expiry",
+            |s| &s.expirations,
+        ),
+        (
+            "prism_backend_calls_total",
+            "Backend calls, counting retries",
+            |s| &s.backend_calls,
+        ),
+        ("prism_backend_errors_total", "Failed backend calls", |s| {
+            &s.backend_errors
+        }),
+        (
+            "prism_client_errors_total",
+            "Backend calls rejected for the request",
+            |s| &s.client_errors,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        metric(&mut out, name, "counter", help);
+        for (provider, stats) in &providers {
+            let value = counter(stats).load(Ordering::Relaxed) as f64;
+            sample(&mut out, name, &[("provider", provider)], value);
+        }
+    }
+
+    let stale = storage.stale_served() as f64;
+    metric(
+        &mut out,
+        "prism_stale_served_total",
+        "counter",
+        "Stale records served",
+    );
+    sample(&mut out, "prism_stale_served_total", &[], stale);
+
+    let name = "prism_backend_latency_seconds";
+    metric(&mut out, name, "histogram", "Latency of backend calls");
+    for (provider, stats) in &providers {
+        let counts = stats.latency.bucket_counts();
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&counts) {
+            cumulative += count;
+            let le = (*bound as f64 / 1000.0).to_string();
+            let labels = [("provider", provider.as_str()), ("le", le.as_str())];
+            sample(
+                &mut out,
+                &format!("{}_bucket", name),
+                &labels,
+                cumulative as f64,
+            );
+        }
+        let total: u64 = counts.iter().sum();
+        let labels = [("provider", provider.as_str()), ("le", "+Inf")];
+        sample(&mut out, &format!("{}_bucket", name), &labels, total as f64);
+        let labels = [("provider", provider.as_str())];
+        let sum = stats.latency.sum().as_secs_f64();
+        sample(&mut out, &format!("{}_sum", name), &labels, sum);
+        sample(&mut out, &format!("{}_count", name), &labels, total as f64);
+    }
+
+    let gauges = storage.provider_gauges();
+    metric(
+        &mut out,
+        "prism_circuit_state",
+        "gauge",
+        "Circuit breaker state: 0 closed, 1 half-open, 2 open",
+    );
+    for (provider, gauges) in &gauges {
+        let state = match gauges.circuit {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        };
+        sample(&mut out, "prism_circuit_state", &[("provider", provider)], state);
+    }
+
+    let replication: Vec<_> = gauges
+        .iter()
+        .filter_map(|(provider, gauges)| Some((provider, gauges.replication?)))
+        .collect();
+    let name = "prism_replication_confirmed_lsn";
+    metric(&mut out, name, "gauge", "WAL position confirmed to Postgres");
+    for (provider, (lsn, _)) in &replication {
+        sample(&mut out, name, &[("provider", provider)], *lsn as f64);
+    }
+    let name = "prism_replication_lag_bytes";
+    metric(&mut out, name, "gauge", "WAL written by Postgres beyond the applied changes");
+    for (provider, (_, lag)) in &replication {
+        sample(&mut out, name, &[("provider", provider)], *lag as f64);
+    }
+
+    let queues: Vec<_> = gauges
+        .iter()
+        .filter_map(|(provider, gauges)| Some((provider, gauges.write_behind?)))
+        .collect();
+    let queue_metrics: [(&str, &str, &str, QueueCount); 4] = [
+        (
+            "prism_write_behind_pending",
+            "gauge",
+            "Writes waiting to be flushed",
+            |c| c.pending,
+        ),
+        (
+            "prism_write_behind_failed_flushes",
+            "gauge",
+            "Flushes failed since the last successful one",
+            |c| c.failures,
+        ),
+        (
+            "prism_write_behind_flushed_total",
+            "counter",
+            "Writes flushed to the backend",
+            |c| c.flushed,
+        ),
+        (
+            "prism_write_behind_dead_letters_total",
+            "counter",
+            "Writes the backend rejected",
+            |c| c.dead_letters,
+        ),
+    ];
+    for (name, kind, help, count) in queue_metrics {
+        metric(&mut out, name, kind, help);
+        for (provider, counts) in &queues {
+            sample(&mut out, name, &[("provider", provider)], count(counts) as f64);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Selects a counter of a provider's statistics.
+type ProviderCounter = fn(&ProviderStats) -> &AtomicU64;
+
+/// Selects a count of a write-behind queue.
+type QueueCount = fn(&QueueCounts) -> u64;
+
+/// Writes the HELP and TYPE lines of a metric.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Writes a sample line.
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+            .collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[tokio::test]
+    async fn test_render_metrics() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        storage.stats().record_command("GET");
+        storage.fetch_record("users", "123").await.unwrap();
+        storage.run_cache_maintenance().await;
+
+        let metrics = render(&storage).await.unwrap();
+        assert!(metrics.contains("prism_commands_total{command=\"GET\"} 1\n"));
+        assert!(metrics.contains("prism_cache_misses_total{provider=\"users\"} 1\n"));
+        assert!(metrics.contains("prism_cache_entries{tier=\"memory\"} 1\n"));
+        assert!(metrics.contains("prism_circuit_state{provider=\"users\"} 0\n"));
+        // Providers without a queue or a replication slot have no samples of those
+        assert!(metrics.contains("# TYPE prism_write_behind_pending gauge\n"));
+        assert!(!metrics.contains("prism_write_behind_pending{"));
+        assert!(
+            metrics.contains(
+                "prism_backend_latency_seconds_bucket{provider=\"users\",le=\"+Inf\"} 1\n"
+            )
+        );
+        assert!(metrics.contains("# TYPE prism_backend_latency_seconds histogram\n"));
+    }
+
+    #[test]
+    fn test_escape_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
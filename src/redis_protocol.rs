@@ -3,7 +3,7 @@
 //! This module provides types and functions for working with the Redis protocol.
 
 use thiserror::Error;
-use tracing::{debug, error};
+use tracing::debug;
 
 /// Error type for Redis protocol operations.
 #[derive(Debug, Error)]
@@ -27,6 +27,35 @@ pub enum RedisError {
     /// Internal server error.
     #[error("Internal error: {0}")]
     Internal(String),
+
+    /// Server is too busy to handle the command.
+    #[error("{0}")]
+    Busy(String),
+
+    /// The target does not accept writes.
+    #[error("{0}")]
+    ReadOnly(String),
+
+    /// The client has not authenticated.
+    #[error("Authentication required.")]
+    NoAuth,
+
+    /// The client sent a wrong password.
+    #[error("invalid username-password pair or user is disabled.")]
+    WrongPass,
+}
+
+impl RedisError {
+    /// Converts the error into an error frame with the matching error prefix.
+    pub fn to_frame(&self) -> RedisFrame {
+        match self {
+            RedisError::Busy(_) => RedisFrame::Error(format!("BUSY {}", self)),
+            RedisError::ReadOnly(_) => RedisFrame::Error(format!("READONLY {}", self)),
+            RedisError::NoAuth => RedisFrame::Error(format!("NOAUTH {}", self)),
+            RedisError::WrongPass => RedisFrame::Error(format!("WRONGPASS {}", self)),
+            _ => RedisFrame::Error(format!("ERR {}", self)),
+        }
+    }
 }
 
 /// Redis frame type.
@@ -41,7 +70,6 @@ pub enum RedisFrame {
     Error(String),
 
     /// Integer response.
-    #[allow(dead_code)]
     Integer(i64),
 
     /// Bulk string response.
@@ -92,7 +120,7 @@ impl RedisFrame {
                     if s.trim()
                         .chars()
                         .next()
-                        .map_or(false, |c| c.is_ascii_alphabetic()) =>
+                        .is_some_and(|c| c.is_ascii_alphabetic()) =>
                 {
                     Self::parse_plain_text(data)
                 }
@@ -117,8 +145,7 @@ impl RedisFrame {
 
         // Clean the input: replace all carriage returns and newlines with spaces, then trim whitespace
         let cleaned_input = raw_input
-            .replace('\r', " ")
-            .replace('\n', " ")
+            .replace(['\r', '\n'], " ")
             .trim()
             .to_string();
 
@@ -176,9 +203,9 @@ impl RedisFrame {
         let mut elements = Vec::new();
         for _ in 0..length {
             if pos >= data.len() {
-                return Err(RedisError::Protocol(format!(
-                    "Unexpected end of data while parsing array element"
-                )));
+                return Err(RedisError::Protocol(
+                    "Unexpected end of data while parsing array element".into(),
+                ));
             }
 
             // Parse the element based on its type
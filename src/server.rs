@@ -4,11 +4,12 @@
 
 use std::error::Error;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
 use tracing::{error, info};
 
-use crate::commands::handle_command;
+use crate::commands::{Session, handle_command};
 use crate::config;
 use crate::redis_protocol::RedisFrame;
 use crate::storage::StorageService;
@@ -39,10 +40,15 @@ impl Server {
                 Ok((socket, addr)) => {
                     info!("Accepted connection from: {}", addr);
                     let storage = Arc::clone(&self.storage);
+                    let session = Session::new(self.config.requirepass.as_deref().map(Arc::from));
+                    let stats = Arc::clone(storage.stats());
+                    stats.total_connections.fetch_add(1, Ordering::Relaxed);
+                    stats.connected_clients.fetch_add(1, Ordering::Relaxed);
                     tokio::spawn(async move {
-                        if let Err(e) = Self::process_client(socket, storage).await {
+                        if let Err(e) = Self::process_client(socket, storage, session).await {
                             error!("Error processing client: {}", e);
                         }
+                        stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
                     });
                 }
                 Err(e) => {
@@ -59,6 +65,7 @@ impl Server {
     async fn process_client(
         mut socket: TcpStream,
         storage: Arc<StorageService>,
+        mut session: Session,
     ) -> Result<(), Box<dyn Error>> {
         let mut buffer = [0; 1024];
 
@@ -75,25 +82,33 @@ impl Server {
                 }
             };
 
+            let stats = storage.stats();
+            stats.bytes_received.fetch_add(n as u64, Ordering::Relaxed);
+
             // Try to parse the command
             match RedisFrame::parse(&buffer[..n]) {
                 Ok(frame) => {
+                    stats.total_commands.fetch_add(1, Ordering::Relaxed);
+
                     // Handle the command
-                    let response = match handle_command(frame, Arc::clone(&storage)).await {
-                        Ok(bytes) => bytes,
-                        Err(e) => {
-                            let error_response = RedisFrame::Error(format!("ERR {}", e));
-                            error_response.to_bytes()
-                        }
-                    };
+                    let response =
+                        match handle_command(frame, Arc::clone(&storage), &mut session).await {
+                            Ok(bytes) => bytes,
+                            Err(e) => e.to_frame().to_bytes(),
+                        };
 
                     // Send the response
+                    storage
+                        .stats()
+                        .bytes_sent
+                        .fetch_add(response.len() as u64, Ordering::Relaxed);
                     socket.write_all(&response).await?;
                 }
                 Err(e) => {
                     error!("Failed to parse command: {}", e);
-                    let error_response = RedisFrame::Error(format!("ERR {}", e));
-                    socket.write_all(&error_response.to_bytes()).await?;
+                    let response = e.to_frame().to_bytes();
+                    stats.bytes_sent.fetch_add(response.len() as u64, Ordering::Relaxed);
+                    socket.write_all(&response).await?;
                 }
             }
         }
@@ -0,0 +1,252 @@
+//! Runtime statistics of the server, its cache and its providers.
+//!
+//! A single registry is shared by the server and the storage service. Counters are
+//! plain atomics, so recording is cheap enough for every command and backend call.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets in milliseconds
+pub const LATENCY_BUCKETS_MS: [u64; 13] =
+    [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Histogram of call latencies over fixed buckets.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// Number of calls per bucket, the last one counting calls slower than every bound
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    /// Sum of all latencies in microseconds
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    /// Records the latency of a call.
+    pub fn record(&self, latency: Duration) {
+        let millis = latency.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| millis < *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the number of calls per bucket.
+    pub fn bucket_counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Returns the sum of all recorded latencies.
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Returns the upper bound in milliseconds of the bucket holding the given quantile.
+    ///
+    /// Returns `None` if no call was recorded, and the largest bound for calls slower
+    /// than every bucket.
+    pub fn quantile_ms(&self, quantile: f64) -> Option<u64> {
+        let counts = self.bucket_counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let last = LATENCY_BUCKETS_MS.len() - 1;
+                return Some(LATENCY_BUCKETS_MS[bucket.min(last)]);
+            }
+        }
+        None
+    }
+}
+
+/// Statistics of a single provider and its cached records.
+#[derive(Debug, Default)]
+pub struct ProviderStats {
+    /// Lookups answered from memory or the disk tier
+    pub cache_hits: AtomicU64,
+    /// Lookups that went to the backend
+    pub cache_misses: AtomicU64,
+    /// Records evicted from memory because the cache was full
+    pub evictions: AtomicU64,
+    /// Records removed from memory because they expired
+    pub expirations: AtomicU64,
+    /// Calls into the backend, counting every retry
+    pub backend_calls: AtomicU64,
+    /// Backend calls that failed, not counting records that were not found
+    pub backend_errors: AtomicU64,
+    /// Backend calls rejected for the request, such as invalid queries or records
+    pub client_errors: AtomicU64,
+    /// Latency of the backend calls
+    pub latency: LatencyHistogram,
+}
+
+/// Registry of the runtime statistics.
+#[derive(Debug)]
+pub struct Stats {
+    started_at: Instant,
+    /// Number of open client connections
+    pub connected_clients: AtomicU64,
+    /// Number of client connections accepted since startup
+    pub total_connections: AtomicU64,
+    /// Number of commands processed since startup
+    pub total_commands: AtomicU64,
+    /// Bytes read from clients
+    pub bytes_received: AtomicU64,
+    /// Bytes written to clients
+    pub bytes_sent: AtomicU64,
+    /// Number of calls of each known command, by name
+    commands: RwLock<HashMap<String, AtomicU64>>,
+    /// Statistics of each provider, by name
+    providers: RwLock<HashMap<String, Arc<ProviderStats>>>,
+}
+
+impl Stats {
+    /// Creates an empty registry, counting uptime from now.
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            total_commands: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            commands: RwLock::new(HashMap::new()),
+            providers: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the time since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Counts a call of a command.
+    ///
+    /// Only known commands should be counted, so clients cannot add arbitrary names.
+    pub fn record_command(&self, command: &str) {
+        if let Some(count) = self.commands.read().unwrap().get(command) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.commands
+            .write()
+            .unwrap()
+            .entry(command.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a request of the HTTP or gRPC interface as the Redis command it mirrors.
+    pub fn record_request(&self, command: &str) {
+        self.total_commands.fetch_add(1, Ordering::Relaxed);
+        self.record_command(command);
+    }
+
+    /// Returns the number of calls of each command, sorted by name.
+    pub fn commands(&self) -> Vec<(String, u64)> {
+        let mut commands: Vec<_> = self
+            .commands
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, count)| (name.clone(), count.load(Ordering::Relaxed)))
+            .collect();
+        commands.sort();
+        commands
+    }
+
+    /// Registers a provider and returns its statistics.
+    pub fn register_provider(&self, name: &str) -> Arc<ProviderStats> {
+        Arc::clone(
+            self.providers
+                .write()
+                .unwrap()
+                .entry(name.to_string())
+                .or_default(),
+        )
+    }
+
+    /// Returns the statistics of a registered provider.
+    pub fn provider(&self, name: &str) -> Option<Arc<ProviderStats>> {
+        self.providers.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns the statistics of every provider, sorted by name.
+    pub fn providers(&self) -> Vec<(String, Arc<ProviderStats>)> {
+        let mut providers: Vec<_> = self
+            .providers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (name.clone(), Arc::clone(stats)))
+            .collect();
+        providers.sort_by(|a, b| a.0.cmp(&b.0));
+        providers
+    }
+
+    /// Sums a counter over every provider.
+    pub fn total(&self, counter: impl Fn(&ProviderStats) -> &AtomicU64) -> u64 {
+        self.providers
+            .read()
+            .unwrap()
+            .values()
+            .map(|stats| counter(stats).load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_quantiles() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_ms(0.5), None);
+
+        for _ in 0..90 {
+            histogram.record(Duration::from_millis(3));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_millis(300));
+        }
+        assert_eq!(histogram.quantile_ms(0.5), Some(5));
+        assert_eq!(histogram.quantile_ms(0.95), Some(500));
+        assert_eq!(histogram.bucket_counts().iter().sum::<u64>(), 100);
+
+        histogram.record(Duration::from_secs(60));
+        assert_eq!(histogram.quantile_ms(1.0), Some(10000));
+    }
+
+    #[test]
+    fn test_provider_totals() {
+        let stats = Stats::new();
+        stats
+            .register_provider("a")
+            .cache_hits
+            .fetch_add(2, Ordering::Relaxed);
+        stats
+            .register_provider("b")
+            .cache_hits
+            .fetch_add(3, Ordering::Relaxed);
+        assert_eq!(stats.total(|provider| &provider.cache_hits), 5);
+        assert!(stats.provider("c").is_none());
+    }
+}
@@ -1,20 +1,87 @@
-use crate::storage::database::record_batch_to_json;
+use crate::storage::database::{record_batch_row_to_json, record_batch_to_json};
 use async_trait::async_trait;
-use datafusion::prelude::SessionContext;
-use deltalake::open_table_with_storage_options;
+use datafusion::arrow::array::{ArrayRef, BooleanArray, StringArray};
+use datafusion::arrow::compute::{CastOptions, cast_with_options};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::json::ArrayWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::common::{DFSchema, ScalarValue, plan_err};
+use datafusion::error::Result as DataFusionResult;
+use datafusion::execution::SendableRecordBatchStream;
+use datafusion::prelude::{Expr, SQLOptions, SessionContext, ident, lit};
+use datafusion::sql::parser::DFParser;
+use datafusion::sql::sqlparser::dialect::GenericDialect;
+use datafusion::sql::sqlparser::tokenizer::Token;
+use deltalake::delta_datafusion::DataFusionMixins;
+use deltalake::operations::DeltaOps;
+use deltalake::operations::merge::MergeMetrics;
 use deltalake::storage::object_store::azure::{MicrosoftAzure, MicrosoftAzureBuilder};
+use deltalake::{DeltaTable, DeltaTableBuilder, DeltaTableError};
+use serde::Deserialize;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tracing::{info, warn};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
 use url::Url;
 
-use crate::storage::{DatabaseAdapter, StorageError, StorageResult, assert_required_settings};
+use crate::config::PreloadConfig;
+use crate::storage::invalidation::{Invalidation, KeyRange};
+use crate::storage::time_travel::AsOf;
+use crate::storage::{
+    DatabaseAdapter, StorageError, StorageResult, WritableAdapter, assert_required_settings,
+    fetch_each,
+};
+
+/// Default interval between two checks for new table versions
+const DEFAULT_POLL_INTERVAL_SECONDS: u64 = 60;
+/// Maximum number of historical table versions kept registered at a time
+const MAX_HISTORY_SESSIONS: usize = 8;
+
+/// A new table version and the cached records it affects.
+#[derive(Debug)]
+pub struct TableChange {
+    pub version: i64,
+    pub invalidation: Invalidation,
+}
+
+/// Column statistics of a data file, as stored in the `stats` field of its add action
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileStats {
+    #[serde(default)]
+    min_values: HashMap<String, Value>,
+    #[serde(default)]
+    max_values: HashMap<String, Value>,
+}
 
 pub struct AzDeltaAdapter {
     session: SessionContext,
     table_name: String,
     record_query: String, // should look like "SELECT * FROM table_name WHERE id = {}"
+    /// The registered table, reloaded when new versions are committed
+    table: Mutex<DeltaTable>,
+    /// Version of the registered table
+    version: AtomicI64,
+    /// Data files as of the last polled version, kept when a merge moved the table past
+    /// commits of other writers so the next poll still diffs them
+    unpolled: std::sync::Mutex<Option<HashMap<String, Option<String>>>>,
+    /// Column holding the record key, used to invalidate only the affected keys
+    key_column: Option<String>,
+    /// Interval between checks for new table versions, `None` if polling is disabled
+    poll_interval: Option<Duration>,
+    /// Whether the table is pinned to a version or point in time, which makes it read-only
+    pinned: bool,
+    /// Store holding the table, shared with the sessions over historical versions
+    object_store: Arc<MicrosoftAzure>,
+    store_url: Url,
+    /// Sessions over historical table versions loaded for time travel, oldest first
+    history: std::sync::Mutex<VecDeque<(i64, SessionContext)>>,
+    /// Timestamps resolved to a version that later commits have made final
+    resolved: std::sync::Mutex<HashMap<chrono::DateTime<chrono::Utc>, i64>>,
 }
 
 impl AzDeltaAdapter {
@@ -32,6 +99,29 @@ impl AzDeltaAdapter {
 
         // Get optional settings
         let bearer_token = settings.get("azure_bearer_token").map(|s| s.as_str());
+        let key_column = settings.get("delta_key_column").cloned();
+        let poll_seconds = match settings.get("delta_poll_interval_seconds") {
+            Some(value) => value.parse::<u64>().map_err(|e| {
+                StorageError::ConfigError(format!("Invalid delta_poll_interval_seconds: {}", e))
+            })?,
+            None => DEFAULT_POLL_INTERVAL_SECONDS,
+        };
+
+        // A provider can be pinned to a table version or a point in time
+        let pin = match (settings.get("delta_version"), settings.get("delta_timestamp")) {
+            (Some(_), Some(_)) => {
+                return Err(StorageError::ConfigError(
+                    "Only one of delta_version and delta_timestamp can be set".to_string(),
+                ));
+            }
+            (Some(value), None) | (None, Some(value)) => {
+                let pin = AsOf::parse(value).ok_or_else(|| {
+                    StorageError::ConfigError(format!("Invalid Delta version or timestamp: {}", value))
+                })?;
+                Some(pin)
+            }
+            (None, None) => None,
+        };
 
         // Setup Azure storage
         let azure = get_azure_object_storage(&table_path, bearer_token)
@@ -46,26 +136,106 @@ impl AzDeltaAdapter {
         ctx.runtime_env()
             .register_object_store(&store_url, azure_store.clone());
 
-        register_deltalake_table(&ctx, &table_path, &table_name, bearer_token)
+        let table = register_deltalake_table(&ctx, &table_path, &table_name, bearer_token, pin)
             .await
             .map_err(|e| {
                 StorageError::DatabaseError(format!("Failed to register Delta table: {}", e))
             })?;
+        if let Some(pin) = pin {
+            info!("Delta table {} pinned to {} (version {})", table_name, pin, table.version());
+        }
 
         Ok(Self {
             session: ctx,
-            table_name: table_name,
-            record_query: record_query,
+            table_name,
+            record_query,
+            version: AtomicI64::new(table.version()),
+            table: Mutex::new(table),
+            unpolled: std::sync::Mutex::new(None),
+            key_column,
+            // A pinned table never changes
+            poll_interval: (poll_seconds > 0 && pin.is_none())
+                .then(|| Duration::from_secs(poll_seconds)),
+            pinned: pin.is_some(),
+            object_store: azure_store,
+            store_url,
+            history: std::sync::Mutex::new(VecDeque::new()),
+            resolved: std::sync::Mutex::new(HashMap::new()),
         })
     }
-}
 
-#[async_trait]
-impl DatabaseAdapter for AzDeltaAdapter {
-    async fn fetch_record(&self, entity: &str, id: &str) -> StorageResult<Vec<Value>> {
+    /// Resolves a point in the table's history to a table version.
+    pub async fn resolve_version(&self, as_of: &AsOf) -> StorageResult<i64> {
+        let timestamp = match as_of {
+            AsOf::Version(version) => return Ok(*version),
+            AsOf::Timestamp(timestamp) => *timestamp,
+        };
+        if let Some(version) = self.resolved.lock().unwrap().get(&timestamp) {
+            return Ok(*version);
+        }
+
+        let mut table = self.table.lock().await.clone();
+        table
+            .load_with_datetime(timestamp)
+            .await
+            .map_err(|e| history_error(&self.table_name, as_of, e))?;
+        let version = table.version();
+
+        // Once a later version exists, the timestamp always resolves to the same version
+        if version < self.version() {
+            self.resolved.lock().unwrap().insert(timestamp, version);
+        }
+        Ok(version)
+    }
+
+    /// Fetches a record from a historical version of the table.
+    pub async fn fetch_record_at(&self, id: &str, version: i64) -> StorageResult<Vec<Value>> {
+        let session = self.session_at(version).await?;
+        self.query_record(&session, id).await
+    }
+
+    /// Returns a session with the given table version registered, loading it on demand.
+    async fn session_at(&self, version: i64) -> StorageResult<SessionContext> {
+        if let Some((_, session)) = self
+            .history
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(loaded, _)| *loaded == version)
+        {
+            return Ok(session.clone());
+        }
+
+        let mut table = self.table.lock().await.clone();
+        table
+            .load_version(version)
+            .await
+            .map_err(|e| history_error(&self.table_name, &AsOf::Version(version), e))?;
+
+        let session = SessionContext::new();
+        session
+            .runtime_env()
+            .register_object_store(&self.store_url, self.object_store.clone());
+        session
+            .register_table(self.table_name.as_str(), Arc::new(table))
+            .map_err(|e| {
+                StorageError::DatabaseError(format!("Failed to register Delta table: {}", e))
+            })?;
+        info!("Loaded version {} of Delta table {}", version, self.table_name);
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= MAX_HISTORY_SESSIONS {
+            history.pop_front();
+        }
+        history.push_back((version, session.clone()));
+        Ok(session)
+    }
+
+    /// Runs the record query for an id against a session.
+    async fn query_record(&self, session: &SessionContext, id: &str) -> StorageResult<Vec<Value>> {
+        debug!("Querying Delta table {} for id {}", self.table_name, id);
         let query = self.record_query.replace("{}", id);
-        let df = self
-            .session
+        let df = session
             .sql(&query)
             .await
             .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?;
@@ -89,9 +259,616 @@ impl DatabaseAdapter for AzDeltaAdapter {
             }
         };
 
-        let json_value = record_batch_to_json(&batch);
+        let json_value = record_batch_to_json(batch);
         Ok(vec![json_value])
     }
+
+    /// Checks that the Delta log can be read.
+    pub async fn ping(&self) -> StorageResult<()> {
+        self.table
+            .lock()
+            .await
+            .get_latest_version()
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Delta log error: {}", e)))?;
+        Ok(())
+    }
+
+    /// Returns a page of the key column values, in key order.
+    pub async fn scan_keys(&self, offset: usize, limit: usize) -> StorageResult<Vec<String>> {
+        let key_column = self.key_column.as_deref().ok_or_else(|| {
+            StorageError::ConfigError(format!(
+                "Delta table {} has no delta_key_column to scan",
+                self.table_name
+            ))
+        })?;
+        let query = format!(
+            "SELECT \"{0}\" FROM {1} ORDER BY \"{0}\" LIMIT {2} OFFSET {3}",
+            key_column, self.table_name, limit, offset
+        );
+        debug!("Scanning keys of Delta table {}: {}", self.table_name, query);
+        let batches = self
+            .session
+            .sql(&query)
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?
+            .collect()
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Data collection error: {}", e)))?;
+
+        let mut keys = Vec::new();
+        for batch in &batches {
+            for row in 0..batch.num_rows() {
+                let record = record_batch_row_to_json(batch, row);
+                if let Some(key) = record.get(key_column).and_then(Value::as_str) {
+                    keys.push(key.to_string());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns the version of the registered table.
+    pub fn version(&self) -> i64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// Returns the interval between checks for new table versions, if polling is enabled.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval
+    }
+
+    /// Reloads the table if a new version was committed.
+    ///
+    /// Returns the new version together with the cached records it affects, including
+    /// those changed by commits a merge moved the table past.
+    pub async fn poll_changes(&self) -> StorageResult<Option<TableChange>> {
+        let mut table = self.table.lock().await;
+        let latest = table
+            .get_latest_version()
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Delta log error: {}", e)))?;
+        let merged_past = self.unpolled.lock().unwrap().is_some();
+        if latest <= table.version() && !merged_past {
+            return Ok(None);
+        }
+
+        let old_files = file_stats(&table)?;
+        if latest > table.version() {
+            table.update().await.map_err(|e| {
+                StorageError::DatabaseError(format!("Delta table update error: {}", e))
+            })?;
+        }
+        let new_files = file_stats(&table)?;
+        // Diff from the last polled version rather than from a merge that skipped past it
+        let old_files = self.unpolled.lock().unwrap().take().unwrap_or(old_files);
+
+        // Swap the new snapshot into the session; running queries keep the old one
+        self.session
+            .deregister_table(self.table_name.as_str())
+            .and_then(|_| {
+                self.session
+                    .register_table(self.table_name.as_str(), Arc::new(table.clone()))
+            })
+            .map_err(|e| {
+                StorageError::DatabaseError(format!("Failed to register Delta table: {}", e))
+            })?;
+        self.version.store(table.version(), Ordering::Release);
+
+        Ok(Some(TableChange {
+            version: table.version(),
+            invalidation: changed_key_ranges(&old_files, &new_files, self.key_column.as_deref()),
+        }))
+    }
+
+    /// Upserts records, given as id and JSON record, with a single MERGE commit.
+    ///
+    /// Records are matched on the key column and values are cast to the column types.
+    /// Existing rows keep the columns a record does not carry; new rows get null there.
+    /// Returns the new table version.
+    pub async fn merge_records(&self, records: &[(String, Value)]) -> StorageResult<i64> {
+        let key_column = self.merge_key()?;
+        let mut table = self.table.lock().await;
+        let (merged, metrics) = merge_into(table.clone(), key_column, records).await?;
+        debug!(
+            "Merged {} records into Delta table {}: {} inserted, {} updated",
+            records.len(),
+            self.table_name,
+            metrics.num_target_rows_inserted,
+            metrics.num_target_rows_updated
+        );
+
+        // Commits of other writers since the last poll are skipped by taking the merged
+        // table, so keep the files the poller last saw for it to diff them
+        if self.poll_interval.is_some() && merged.version() > table.version() + 1 {
+            let mut unpolled = self.unpolled.lock().unwrap();
+            if unpolled.is_none() {
+                *unpolled = Some(file_stats(&table)?);
+            }
+        }
+        *table = merged;
+        self.session
+            .deregister_table(self.table_name.as_str())
+            .and_then(|_| {
+                self.session
+                    .register_table(self.table_name.as_str(), Arc::new(table.clone()))
+            })
+            .map_err(|e| {
+                StorageError::DatabaseError(format!("Failed to register Delta table: {}", e))
+            })?;
+        self.version.store(table.version(), Ordering::Release);
+        Ok(table.version())
+    }
+
+    /// Returns the key column records are merged on, if the table can be written.
+    fn merge_key(&self) -> StorageResult<&str> {
+        if self.pinned {
+            return Err(StorageError::ReadOnly(format!(
+                "Delta table {} is pinned to a version",
+                self.table_name
+            )));
+        }
+        self.key_column.as_deref().ok_or_else(|| {
+            StorageError::ReadOnly(format!(
+                "Delta table {} has no delta_key_column to merge on",
+                self.table_name
+            ))
+        })
+    }
+
+    /// Streams the table rows selected by the preload configuration.
+    pub async fn scan(&self, preload: &PreloadConfig) -> StorageResult<SendableRecordBatchStream> {
+        scan_table(&self.session, &self.table_name, preload).await
+    }
+
+    /// Streams at most `limit` rows of the table matching a SQL filter expression.
+    pub async fn scan_filtered(
+        &self,
+        filter: &str,
+        limit: usize,
+    ) -> StorageResult<SendableRecordBatchStream> {
+        scan_matching(&self.session, &self.table_name, filter, limit).await
+    }
+
+    /// Runs a read-only query against the table, returning at most `limit` rows.
+    pub async fn query(&self, sql: &str, limit: usize) -> StorageResult<Vec<Value>> {
+        query_table(&self.session, sql, Vec::new(), limit).await
+    }
+
+    /// Returns the session the table is registered in, kept current as versions change.
+    pub fn session(&self) -> SessionContext {
+        self.session.clone()
+    }
+}
+
+#[async_trait]
+impl DatabaseAdapter for AzDeltaAdapter {
+    async fn fetch_record(&self, _entity: &str, id: &str) -> StorageResult<Vec<Value>> {
+        self.query_record(&self.session, id).await
+    }
+
+    async fn record_exists(&self, _entity: &str, id: &str) -> StorageResult<bool> {
+        // Only the row count is needed, so no columns are read beyond the filter
+        let query = format!(
+            "SELECT 1 FROM ({}) LIMIT 1",
+            self.record_query.replace("{}", id)
+        );
+        let batches = self
+            .session
+            .sql(&query)
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?
+            .collect()
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Data collection error: {}", e)))?;
+        Ok(batches.iter().any(|batch| batch.num_rows() > 0))
+    }
+
+    async fn fetch_records(
+        &self,
+        entity: &str,
+        ids: &[String],
+    ) -> StorageResult<Vec<(String, Value)>> {
+        match &self.key_column {
+            Some(key_column) => fetch_keys(&self.session, &self.table_name, key_column, ids).await,
+            None => fetch_each(self, entity, ids).await,
+        }
+    }
+}
+
+#[async_trait]
+impl WritableAdapter for AzDeltaAdapter {
+    async fn upsert_record(&self, _entity: &str, id: &str, record: &Value) -> StorageResult<Value> {
+        self.merge_records(&[(id.to_string(), record.clone())]).await?;
+        self.query_record(&self.session, id)
+            .await?
+            .pop()
+            .ok_or_else(|| StorageError::RecordNotInDatabase(format!("Record '{}' not found", id)))
+    }
+
+    /// Casts the record to the column types, as the merge will.
+    async fn validate_record(
+        &self,
+        _entity: &str,
+        id: &str,
+        record: &Value,
+    ) -> StorageResult<Value> {
+        let key_column = self.merge_key()?;
+        if !record.is_object() {
+            return Err(StorageError::InvalidRecord(
+                "Records must be JSON objects".to_string(),
+            ));
+        }
+        let schema = self
+            .table
+            .lock()
+            .await
+            .snapshot()
+            .and_then(|snapshot| snapshot.input_schema())
+            .map_err(|e| StorageError::DatabaseError(format!("Delta snapshot error: {}", e)))?;
+        records_to_batch(schema, key_column, &[(id.to_string(), record.clone())], &[])?;
+        Ok(record.clone())
+    }
+}
+
+/// Merges records into a table with a single MERGE commit, as in `merge_records`.
+async fn merge_into(
+    table: DeltaTable,
+    key_column: &str,
+    records: &[(String, Value)],
+) -> StorageResult<(DeltaTable, MergeMetrics)> {
+    let schema = table
+        .snapshot()
+        .and_then(|snapshot| snapshot.input_schema())
+        .map_err(|e| StorageError::DatabaseError(format!("Delta snapshot error: {}", e)))?;
+    let columns: Vec<String> = schema.fields().iter().map(|f| f.name().clone()).collect();
+    // Columns only some records carry are updated where a marker says they do
+    let carried = |column: &String| {
+        let count = records.iter().filter(|(_, r)| r.get(column).is_some()).count();
+        (column == key_column || count == records.len(), count > 0)
+    };
+    let partial: Vec<String> = columns
+        .iter()
+        .filter(|column| matches!(carried(column), (false, true)))
+        .cloned()
+        .collect();
+    let batch = records_to_batch(schema.clone(), key_column, records, &partial)?;
+    let source = SessionContext::new()
+        .read_batch(batch)
+        .map_err(|e| StorageError::DatabaseError(format!("Merge source error: {}", e)))?;
+
+    let source_column = |column: &String| format!("source.\"{}\"", column);
+    let updates: Vec<(&String, String)> = columns
+        .iter()
+        .filter_map(|column| match carried(column) {
+            (true, _) => Some((column, source_column(column))),
+            (false, true) => Some((
+                column,
+                format!(
+                    "CASE WHEN source.\"{}\" THEN source.\"{1}\" ELSE target.\"{1}\" END",
+                    marker_column(column),
+                    column
+                ),
+            )),
+            (false, false) => None,
+        })
+        .collect();
+    DeltaOps(table)
+        .merge(source, format!("target.\"{0}\" = source.\"{0}\"", key_column))
+        .with_source_alias("source")
+        .with_target_alias("target")
+        .when_matched_update(|update| {
+            updates.iter().fold(update, |update, (column, value)| {
+                update.update(column.as_str(), value.as_str())
+            })
+        })
+        .and_then(|merge| {
+            merge.when_not_matched_insert(|insert| {
+                columns.iter().fold(insert, |insert, column| {
+                    insert.set(column.as_str(), source_column(column))
+                })
+            })
+        })
+        .map_err(|e| StorageError::DatabaseError(format!("Delta merge error: {}", e)))?
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Delta merge error: {}", e)))
+}
+
+/// Builds a record batch with the table schema from JSON records.
+///
+/// The key column is set to each record's id. Values are converted from their text
+/// representation, so a value that does not fit its column type is an invalid record.
+/// Each of the `partial` columns gets a boolean marker column telling which records
+/// carry it.
+fn records_to_batch(
+    schema: SchemaRef,
+    key_column: &str,
+    records: &[(String, Value)],
+    partial: &[String],
+) -> StorageResult<RecordBatch> {
+    let options = CastOptions {
+        safe: false,
+        ..Default::default()
+    };
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let values: StringArray = records
+                .iter()
+                .map(|(id, record)| {
+                    if field.name() == key_column {
+                        return Some(id.clone());
+                    }
+                    match record.get(field.name()) {
+                        None | Some(Value::Null) => None,
+                        Some(Value::String(value)) => Some(value.clone()),
+                        Some(value) => Some(value.to_string()),
+                    }
+                })
+                .collect();
+            cast_with_options(&values, field.data_type(), &options).map_err(|e| {
+                let column = field.name();
+                StorageError::InvalidRecord(format!("Invalid value for column {}: {}", column, e))
+            })
+        })
+        .collect::<StorageResult<Vec<ArrayRef>>>()?;
+    let markers = partial.iter().map(|column| {
+        let carried: BooleanArray = records
+            .iter()
+            .map(|(_, record)| Some(record.get(column).is_some()))
+            .collect();
+        Arc::new(carried) as ArrayRef
+    });
+
+    // Columns a record does not carry are null in the source, whatever the table allows
+    let fields = schema
+        .fields()
+        .iter()
+        .map(|field| field.as_ref().clone().with_nullable(true))
+        .chain(
+            partial
+                .iter()
+                .map(|column| Field::new(marker_column(column), DataType::Boolean, false)),
+        );
+    RecordBatch::try_new(
+        Arc::new(Schema::new(fields.collect::<Vec<_>>())),
+        columns.into_iter().chain(markers).collect(),
+    )
+    .map_err(|e| StorageError::InvalidRecord(format!("Invalid records: {}", e)))
+}
+
+/// Name of the merge source column telling whether a record carries a column.
+fn marker_column(column: &str) -> String {
+    format!("__prism_set_{}", column)
+}
+
+/// Scans a registered table in streaming batches, applying the preload filter and projection.
+async fn scan_table(
+    ctx: &SessionContext,
+    table_name: &str,
+    preload: &PreloadConfig,
+) -> StorageResult<SendableRecordBatchStream> {
+    let projection = if preload.columns.is_empty() {
+        "*".to_string()
+    } else {
+        if !preload.columns.contains(&preload.key_column) {
+            return Err(StorageError::ConfigError(format!(
+                "Preload columns of {} must include the key column {}",
+                table_name, preload.key_column
+            )));
+        }
+        preload
+            .columns
+            .iter()
+            .map(|column| format!("\"{}\"", column))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut query = format!("SELECT {} FROM {}", projection, table_name);
+    if let Some(filter) = &preload.filter {
+        query.push_str(" WHERE ");
+        query.push_str(filter);
+    }
+
+    debug!("Scanning Delta table {}: {}", table_name, query);
+    let df = ctx
+        .sql_with_options(&query, read_only())
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?;
+    df.execute_stream()
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Scan error: {}", e)))
+}
+
+/// Streams at most `limit` rows of a registered table matching a filter expression.
+///
+/// The filter is parsed as a single SQL expression over the table's columns, so it
+/// cannot extend the query beyond the predicate.
+async fn scan_matching(
+    ctx: &SessionContext,
+    table_name: &str,
+    filter: &str,
+    limit: usize,
+) -> StorageResult<SendableRecordBatchStream> {
+    let df = ctx
+        .table(table_name)
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Table error: {}", e)))?;
+    debug!("Scanning Delta table {} for {}", table_name, filter);
+    let df = parse_filter(ctx, filter, df.schema())
+        .and_then(|predicate| df.filter(predicate))
+        .and_then(|df| df.limit(0, Some(limit)))
+        .map_err(|e| StorageError::InvalidQuery(format!("Invalid filter: {}", e)))?;
+    df.execute_stream()
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Scan error: {}", e)))
+}
+
+/// Parses a filter expression over a table's columns.
+fn parse_filter(ctx: &SessionContext, filter: &str, schema: &DFSchema) -> DataFusionResult<Expr> {
+    // The session's parser stops silently at the first token that does not continue the
+    // expression, so anything after it is rejected here
+    let mut parser = DFParser::new_with_dialect(filter, &GenericDialect {})?;
+    parser.parse_expr()?;
+    let next = parser.parser.peek_token();
+    if next.token != Token::EOF {
+        return plan_err!("unexpected {} after the expression", next);
+    }
+    ctx.parse_sql_expr(filter, schema)
+}
+
+/// Fetches the rows of a registered table whose key is one of `ids`, with a single scan.
+///
+/// Returns each row found with its key; ids that are not a valid key are not found.
+async fn fetch_keys(
+    ctx: &SessionContext,
+    table_name: &str,
+    key_column: &str,
+    ids: &[String],
+) -> StorageResult<Vec<(String, Value)>> {
+    let df = ctx
+        .table(table_name)
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Table error: {}", e)))?;
+    let key_type = df
+        .schema()
+        .field_with_unqualified_name(key_column)
+        .map_err(|e| StorageError::ConfigError(format!("Invalid delta_key_column: {}", e)))?
+        .data_type()
+        .clone();
+    // Keys are compared in the column's type, so files can be pruned by their statistics
+    let keys: Vec<_> = ids
+        .iter()
+        .filter_map(|id| ScalarValue::from(id.as_str()).cast_to(&key_type).ok())
+        .map(lit)
+        .collect();
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    debug!("Fetching {} keys from Delta table {}", keys.len(), table_name);
+    let batches = df
+        .filter(ident(key_column).in_list(keys, false))
+        .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?
+        .collect()
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Data collection error: {}", e)))?;
+    let mut records = Vec::new();
+    for batch in &batches {
+        for row in 0..batch.num_rows() {
+            let record = record_batch_row_to_json(batch, row);
+            if let Some(key) = record.get(key_column).and_then(Value::as_str) {
+                records.push((key.to_string(), record));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Runs a read-only query, returning at most `limit` rows as JSON objects.
+pub(crate) async fn query_table(
+    ctx: &SessionContext,
+    sql: &str,
+    params: Vec<ScalarValue>,
+    limit: usize,
+) -> StorageResult<Vec<Value>> {
+    debug!("Querying Delta table: {} {:?}", sql, params);
+    let batches = ctx
+        .sql_with_options(sql, read_only())
+        .await
+        .and_then(|df| match params.is_empty() {
+            true => Ok(df),
+            false => df.with_param_values(params),
+        })
+        .and_then(|df| df.limit(0, Some(limit)))
+        .map_err(|e| StorageError::DatabaseError(format!("SQL query error: {}", e)))?
+        .collect()
+        .await
+        .map_err(|e| StorageError::DatabaseError(format!("Data collection error: {}", e)))?;
+
+    // Unlike record lookups, query results keep their column types
+    let mut writer = ArrayWriter::new(Vec::new());
+    let to_error = |e: ArrowError| StorageError::DatabaseError(e.to_string());
+    writer
+        .write_batches(&batches.iter().collect::<Vec<_>>())
+        .map_err(to_error)?;
+    writer.finish().map_err(to_error)?;
+    let json = writer.into_inner();
+    if json.is_empty() {
+        return Ok(Vec::new());
+    }
+    serde_json::from_slice(&json).map_err(|e| StorageError::DatabaseError(e.to_string()))
+}
+
+/// Options for queries that may come from clients, which must not change any state.
+pub(crate) fn read_only() -> SQLOptions {
+    SQLOptions::new()
+        .with_allow_ddl(false)
+        .with_allow_dml(false)
+        .with_allow_statements(false)
+}
+
+/// Returns the statistics of the table's data files, keyed by file path.
+fn file_stats(table: &DeltaTable) -> StorageResult<HashMap<String, Option<String>>> {
+    let files = table
+        .snapshot()
+        .and_then(|snapshot| snapshot.file_actions())
+        .map_err(|e| StorageError::DatabaseError(format!("Delta snapshot error: {}", e)))?;
+    Ok(files.into_iter().map(|add| (add.path, add.stats)).collect())
+}
+
+/// Computes the cached records affected by the files added and removed between two versions.
+///
+/// Falls back to invalidating everything if the key column is unknown or a changed
+/// file has no statistics for it.
+fn changed_key_ranges(
+    old_files: &HashMap<String, Option<String>>,
+    new_files: &HashMap<String, Option<String>>,
+    key_column: Option<&str>,
+) -> Invalidation {
+    let Some(key_column) = key_column else {
+        return Invalidation::All;
+    };
+
+    let removed = old_files.iter().filter(|(path, _)| !new_files.contains_key(*path));
+    let added = new_files.iter().filter(|(path, _)| !old_files.contains_key(*path));
+
+    let mut ranges = Vec::new();
+    for (path, stats) in removed.chain(added) {
+        let range = stats
+            .as_deref()
+            .and_then(|stats| serde_json::from_str::<FileStats>(stats).ok())
+            .and_then(|mut stats| {
+                Some(KeyRange {
+                    min: stats.min_values.remove(key_column)?,
+                    max: stats.max_values.remove(key_column)?,
+                })
+            });
+        match range {
+            Some(range) => ranges.push(range),
+            None => {
+                debug!("No {} statistics for changed file {}", key_column, path);
+                return Invalidation::All;
+            }
+        }
+    }
+    Invalidation::KeyRanges(ranges)
+}
+
+/// Maps an error loading a historical table version to a storage error.
+///
+/// Versions that do not exist are reported as missing rather than as backend failures.
+fn history_error(table_name: &str, as_of: &AsOf, error: DeltaTableError) -> StorageError {
+    match error {
+        DeltaTableError::InvalidVersion(_) => {
+            StorageError::EntityNotFound(format!("{} of Delta table {}", as_of, table_name))
+        }
+        e => StorageError::DatabaseError(format!(
+            "Failed to load {} of Delta table {}: {}",
+            as_of, table_name, e
+        )),
+    }
 }
 
 async fn register_deltalake_table(
@@ -99,18 +876,27 @@ async fn register_deltalake_table(
     store_url_str: &str,
     table_name: &str,
     bearer_token: Option<&str>,
-) -> anyhow::Result<()> {
+    pin: Option<AsOf>,
+) -> anyhow::Result<DeltaTable> {
     info!("registering table: {}", table_name);
     let table_path = format!("{}/{}", store_url_str, table_name);
     let storage_options = match bearer_token {
         Some(token) => HashMap::from([(String::from("bearer_token"), String::from(token))]),
         None => HashMap::from([(String::from("use_azure_cli"), String::from("true"))]),
     };
-    let delta_table = open_table_with_storage_options(table_path, storage_options).await?;
+    let builder =
+        DeltaTableBuilder::from_valid_uri(table_path)?.with_storage_options(storage_options);
+    let delta_table = match pin {
+        Some(AsOf::Version(version)) => builder.with_version(version),
+        Some(AsOf::Timestamp(timestamp)) => builder.with_timestamp(timestamp),
+        None => builder,
+    }
+    .load()
+    .await?;
 
     // Now we can directly register the delta_table since we're using compatible versions
-    ctx.register_table(table_name, Arc::new(delta_table))?;
-    Ok(())
+    ctx.register_table(table_name, Arc::new(delta_table.clone()))?;
+    Ok(delta_table)
 }
 
 fn get_azure_object_storage(
@@ -128,3 +914,206 @@ fn get_azure_object_storage(
     let azure = builder.build()?;
     Ok(azure)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::database::test_support::flights_session;
+    use datafusion::arrow::array::Int64Array;
+    use datafusion::arrow::record_batch::RecordBatch;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_query_table() {
+        let ctx = flights_session();
+        let sql = "SELECT \"ORIGIN_AIRPORT\", \"YEAR\" FROM flights ORDER BY \"YEAR\" DESC";
+        let rows = query_table(&ctx, sql, Vec::new(), 2).await.unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({ "ORIGIN_AIRPORT": "SFO", "YEAR": 2016 }),
+                serde_json::json!({ "ORIGIN_AIRPORT": "JFK", "YEAR": 2015 }),
+            ]
+        );
+
+        let sql = "SELECT * FROM flights WHERE \"YEAR\" > 3000";
+        let rows = query_table(&ctx, sql, Vec::new(), 10).await;
+        assert!(rows.unwrap().is_empty());
+        assert!(query_table(&ctx, "DROP TABLE flights", Vec::new(), 10).await.is_err());
+        assert!(query_table(&ctx, "CREATE TABLE t AS SELECT 1", Vec::new(), 10).await.is_err());
+    }
+
+    /// Schema of a writable flights table, with a column records may leave out
+    fn writable_flights_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("FLIGHT_NUMBER", DataType::Int64, false),
+            Field::new("YEAR", DataType::Int64, false),
+            Field::new("ORIGIN_AIRPORT", DataType::Utf8, true),
+        ]))
+    }
+
+    #[test]
+    fn test_records_to_batch() {
+        let schema = writable_flights_schema();
+        let records = vec![
+            ("7".to_string(), serde_json::json!({ "YEAR": "2020", "ORIGIN_AIRPORT": "BOS" })),
+            ("8".to_string(), serde_json::json!({ "YEAR": 2021 })),
+        ];
+
+        let partial = ["ORIGIN_AIRPORT".to_string()];
+        let batch = records_to_batch(schema.clone(), "FLIGHT_NUMBER", &records, &partial).unwrap();
+        let json = crate::storage::database::record_batch_row_to_json(&batch, 1);
+        assert_eq!(json["FLIGHT_NUMBER"], "8");
+        assert_eq!(json["YEAR"], "2021");
+        assert_eq!(json["__prism_set_ORIGIN_AIRPORT"], "false");
+
+        let invalid = vec![("9".to_string(), serde_json::json!({ "YEAR": "soon" }))];
+        assert!(matches!(
+            records_to_batch(schema, "FLIGHT_NUMBER", &invalid, &[]),
+            Err(StorageError::InvalidRecord(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_merge_keeps_columns_records_leave_out() {
+        let schema = writable_flights_schema();
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2])),
+                Arc::new(Int64Array::from(vec![2014, 2015])),
+                Arc::new(StringArray::from(vec!["LAX", "JFK"])),
+            ],
+        )
+        .unwrap();
+        let table = DeltaOps::new_in_memory().write(vec![batch]).await.unwrap();
+
+        let records = vec![
+            ("1".to_string(), serde_json::json!({ "YEAR": 2020 })),
+            ("2".to_string(), serde_json::json!({ "ORIGIN_AIRPORT": null })),
+            ("3".to_string(), serde_json::json!({ "YEAR": 2021 })),
+        ];
+        let (table, _) = merge_into(table, "FLIGHT_NUMBER", &records).await.unwrap();
+
+        let ctx = SessionContext::new();
+        ctx.register_table("flights", Arc::new(table)).unwrap();
+        let sql = "SELECT * FROM flights ORDER BY \"FLIGHT_NUMBER\"";
+        let rows = query_table(&ctx, sql, Vec::new(), 10).await.unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({ "FLIGHT_NUMBER": 1, "YEAR": 2020, "ORIGIN_AIRPORT": "LAX" }),
+                serde_json::json!({ "FLIGHT_NUMBER": 2, "YEAR": 2015 }),
+                serde_json::json!({ "FLIGHT_NUMBER": 3, "YEAR": 2021 }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_applies_filter_and_projection() {
+        let ctx = flights_session();
+        let preload = PreloadConfig {
+            key_column: "FLIGHT_NUMBER".to_string(),
+            filter: Some("\"YEAR\" >= 2015".to_string()),
+            columns: vec!["FLIGHT_NUMBER".to_string(), "ORIGIN_AIRPORT".to_string()],
+            ttl_seconds: None,
+        };
+
+        let stream = scan_table(&ctx, "flights", &preload).await.unwrap();
+        let batches: Vec<RecordBatch> = stream.try_collect().await.unwrap();
+
+        let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+        assert_eq!(rows, 2);
+        assert_eq!(batches[0].num_columns(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_scan_matching_parses_filter_expression() {
+        let ctx = flights_session();
+        let stream = scan_matching(&ctx, "flights", "\"YEAR\" >= 2015", 10).await;
+        let batches: Vec<RecordBatch> = stream.unwrap().try_collect().await.unwrap();
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 2);
+
+        let stream = scan_matching(&ctx, "flights", "\"YEAR\" > 0", 2).await;
+        let batches: Vec<RecordBatch> = stream.unwrap().try_collect().await.unwrap();
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 2);
+
+        // Anything but a single expression over the table's columns is rejected
+        for filter in [
+            "1 = 1 UNION SELECT * FROM flights",
+            "1 = 1; DROP TABLE flights",
+            "\"MISSING\" = 1",
+        ] {
+            let result = scan_matching(&ctx, "flights", filter, 10).await;
+            assert!(matches!(result, Err(StorageError::InvalidQuery(_))), "{}", filter);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_keys() {
+        let ctx = flights_session();
+        let ids: Vec<String> = ["3", "1", "9", "x"].iter().map(|id| id.to_string()).collect();
+        let mut records = fetch_keys(&ctx, "flights", "FLIGHT_NUMBER", &ids).await.unwrap();
+        records.sort_by(|a, b| a.0.cmp(&b.0));
+        let keys: Vec<&str> = records.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, vec!["1", "3"]);
+        assert_eq!(records[1].1["ORIGIN_AIRPORT"], "SFO");
+
+        let ids = vec!["x".to_string()];
+        assert!(fetch_keys(&ctx, "flights", "FLIGHT_NUMBER", &ids).await.unwrap().is_empty());
+        let result = fetch_keys(&ctx, "flights", "MISSING", &ids).await;
+        assert!(matches!(result, Err(StorageError::ConfigError(_))));
+    }
+
+    fn files(entries: &[(&str, Option<&str>)]) -> HashMap<String, Option<String>> {
+        entries
+            .iter()
+            .map(|(path, stats)| (path.to_string(), stats.map(str::to_string)))
+            .collect()
+    }
+
+    #[test]
+    fn test_changed_key_ranges_from_file_stats() {
+        let old = files(&[
+            ("a.parquet", Some(r#"{"minValues":{"id":1},"maxValues":{"id":10}}"#)),
+            ("b.parquet", Some(r#"{"minValues":{"id":11},"maxValues":{"id":20}}"#)),
+        ]);
+        let new = files(&[
+            ("a.parquet", Some(r#"{"minValues":{"id":1},"maxValues":{"id":10}}"#)),
+            ("c.parquet", Some(r#"{"minValues":{"id":15},"maxValues":{"id":30}}"#)),
+        ]);
+
+        let invalidation = changed_key_ranges(&old, &new, Some("id"));
+        // Keys of the removed and the added file are affected, untouched files are not
+        assert!(invalidation.matches("12"));
+        assert!(invalidation.matches("25"));
+        assert!(!invalidation.matches("5"));
+    }
+
+    #[test]
+    fn test_changed_key_ranges_falls_back_to_all() {
+        let old = files(&[]);
+        let new = files(&[("a.parquet", Some(r#"{"numRecords":3}"#))]);
+        assert_eq!(changed_key_ranges(&old, &new, Some("id")), Invalidation::All);
+
+        let new = files(&[("a.parquet", None)]);
+        assert_eq!(changed_key_ranges(&old, &new, Some("id")), Invalidation::All);
+        assert_eq!(changed_key_ranges(&old, &old, None), Invalidation::All);
+    }
+
+    #[tokio::test]
+    async fn test_scan_requires_key_column_in_projection() {
+        let ctx = flights_session();
+        let preload = PreloadConfig {
+            key_column: "FLIGHT_NUMBER".to_string(),
+            filter: None,
+            columns: vec!["ORIGIN_AIRPORT".to_string()],
+            ttl_seconds: None,
+        };
+
+        assert!(matches!(
+            scan_table(&ctx, "flights", &preload).await,
+            Err(StorageError::ConfigError(_))
+        ));
+    }
+}
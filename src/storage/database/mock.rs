@@ -1,14 +1,15 @@
 //! In-memory database adapter implementation.
 
-use crate::storage::{DatabaseAdapter, StorageError, StorageResult};
+use crate::storage::{DatabaseAdapter, StorageError, StorageResult, WritableAdapter};
 use async_trait::async_trait;
 use serde_json::{Value, json};
 use std::collections::HashMap;
+use std::sync::RwLock;
 use tracing::{debug};
 
 /// Mock database adapter for testing
 pub struct MockAdapter {
-    data: HashMap<String, HashMap<String, Value>>,
+    data: RwLock<HashMap<String, HashMap<String, Value>>>,
 }
 
 impl MockAdapter {
@@ -60,7 +61,20 @@ impl MockAdapter {
         );
         data.insert("products".to_string(), products);
 
-        Self { data }
+        Self {
+            data: RwLock::new(data),
+        }
+    }
+
+    /// Returns a page of the ids of an entity, in id order.
+    pub fn scan_keys(&self, entity: &str, offset: usize, limit: usize) -> StorageResult<Vec<String>> {
+        let data = self.data.read().unwrap();
+        let entity_data = data.get(entity).ok_or_else(|| {
+            StorageError::EntityNotFound(format!("Entity '{}' not found", entity))
+        })?;
+        let mut ids: Vec<&String> = entity_data.keys().collect();
+        ids.sort();
+        Ok(ids.into_iter().skip(offset).take(limit).cloned().collect())
     }
 
     // => Example of creating a mock adapter with required settings (should import assert_required_settings)
@@ -86,7 +100,8 @@ impl DatabaseAdapter for MockAdapter {
         );
 
         // Check if the entity exists
-        let entity_data = self.data.get(entity).ok_or_else(|| {
+        let data = self.data.read().unwrap();
+        let entity_data = data.get(entity).ok_or_else(|| {
             StorageError::EntityNotFound(format!("Entity '{}' not found", entity))
         })?;
 
@@ -97,4 +112,35 @@ impl DatabaseAdapter for MockAdapter {
 
         Ok(vec![record.clone()])
     }
+
+    async fn record_exists(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        let data = self.data.read().unwrap();
+        let entity_data = data.get(entity).ok_or_else(|| {
+            StorageError::EntityNotFound(format!("Entity '{}' not found", entity))
+        })?;
+        Ok(entity_data.contains_key(id))
+    }
+}
+
+#[async_trait]
+impl WritableAdapter for MockAdapter {
+    async fn upsert_record(&self, entity: &str, id: &str, record: &Value) -> StorageResult<Value> {
+        debug!("MockAdapter: Writing record for entity={}, id={}", entity, id);
+
+        let mut data = self.data.write().unwrap();
+        let stored = data
+            .entry(entity.to_string())
+            .or_default()
+            .entry(id.to_string())
+            .or_insert_with(|| Value::Object(Default::default()));
+        // Like the real backends, only the fields of the record are written
+        match (stored.as_object_mut(), record) {
+            (Some(stored), Value::Object(fields)) => stored.extend(fields.clone()),
+            _ => *stored = record.clone(),
+        }
+        if let Some(fields) = stored.as_object_mut() {
+            fields.insert("id".to_string(), Value::String(id.to_string()));
+        }
+        Ok(stored.clone())
+    }
 }
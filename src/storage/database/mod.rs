@@ -4,27 +4,46 @@
 
 pub mod az_delta;
 pub mod mock;
+mod pgoutput;
 pub mod postgres;
+mod replication;
+pub mod template;
+#[cfg(test)]
+pub(crate) mod test_support;
 use async_trait::async_trait;
 use datafusion::arrow::array::{BooleanArray, Float64Array, Int32Array, Int64Array, StringArray};
-use datafusion::arrow::datatypes::DataType;
+use datafusion::arrow::datatypes::{DataType, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::json::ReaderBuilder;
+use datafusion::arrow::json::reader::infer_json_schema_from_iterator;
 use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::execution::SendableRecordBatchStream;
+use datafusion::prelude::SessionContext;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedReceiver;
 
-use crate::config::DatabaseProvider;
-use crate::storage::{DatabaseAdapter, StorageError, StorageResult};
-pub use az_delta::AzDeltaAdapter;
+use crate::config::{DatabaseProvider, PreloadConfig};
+use crate::storage::invalidation::CacheUpdate;
+use crate::storage::time_travel::AsOf;
+use crate::storage::{DatabaseAdapter, StorageError, StorageResult, WritableAdapter};
+pub use az_delta::{AzDeltaAdapter, TableChange};
 pub use mock::MockAdapter;
+pub use pgoutput::format_lsn;
 pub use postgres::PostgresAdapter;
+pub use template::TemplateAdapter;
 /// Database adapter type
 pub enum DatabaseType {
     /// In-memory database adapter
     Mock(MockAdapter),
     /// Postgres database adapter
-    Postgres(PostgresAdapter),
+    Postgres(Box<PostgresAdapter>),
     /// Azure Delta database adapter
-    AzDelta(AzDeltaAdapter),
+    AzDelta(Box<AzDeltaAdapter>),
+    /// Query template over the session of an AzDelta provider
+    Template(Box<TemplateAdapter>),
 }
 
 #[async_trait]
@@ -34,10 +53,203 @@ impl DatabaseAdapter for DatabaseType {
             Self::Mock(adapter) => adapter.fetch_record(entity, id).await,
             Self::Postgres(adapter) => adapter.fetch_record(entity, id).await,
             Self::AzDelta(adapter) => adapter.fetch_record(entity, id).await,
+            Self::Template(adapter) => adapter.fetch_record(entity, id).await,
+        }
+    }
+
+    async fn record_exists(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        match self {
+            Self::Mock(adapter) => adapter.record_exists(entity, id).await,
+            Self::Postgres(adapter) => adapter.record_exists(entity, id).await,
+            Self::AzDelta(adapter) => adapter.record_exists(entity, id).await,
+            Self::Template(adapter) => adapter.record_exists(entity, id).await,
+        }
+    }
+
+    async fn fetch_records(
+        &self,
+        entity: &str,
+        ids: &[String],
+    ) -> StorageResult<Vec<(String, Value)>> {
+        match self {
+            Self::Mock(adapter) => adapter.fetch_records(entity, ids).await,
+            Self::Postgres(adapter) => adapter.fetch_records(entity, ids).await,
+            Self::AzDelta(adapter) => adapter.fetch_records(entity, ids).await,
+            Self::Template(adapter) => adapter.fetch_records(entity, ids).await,
         }
     }
 }
 
+impl DatabaseType {
+    /// Streams the rows to preload into the cache.
+    pub async fn scan(&self, preload: &PreloadConfig) -> StorageResult<SendableRecordBatchStream> {
+        match self {
+            Self::AzDelta(adapter) => adapter.scan(preload).await,
+            _ => Err(scans_unsupported()),
+        }
+    }
+
+    /// Streams at most `limit` rows matching a SQL filter expression.
+    pub async fn scan_filtered(
+        &self,
+        filter: &str,
+        limit: usize,
+    ) -> StorageResult<SendableRecordBatchStream> {
+        match self {
+            Self::AzDelta(adapter) => adapter.scan_filtered(filter, limit).await,
+            _ => Err(scans_unsupported()),
+        }
+    }
+
+    /// Returns the interval between checks for changes in the backing table, if any.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        match self {
+            Self::AzDelta(adapter) => adapter.poll_interval(),
+            _ => None,
+        }
+    }
+
+    /// Starts listening for changes pushed by the backend, if configured.
+    pub fn subscribe(&self) -> Option<UnboundedReceiver<CacheUpdate>> {
+        match self {
+            Self::Postgres(adapter) => adapter.subscribe(),
+            _ => None,
+        }
+    }
+
+    /// Checks the backing table for changes since the last poll.
+    pub async fn poll_changes(&self) -> StorageResult<Option<TableChange>> {
+        match self {
+            Self::AzDelta(adapter) => adapter.poll_changes().await,
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves a point in the table's history to a table version.
+    pub async fn resolve_version(&self, as_of: &AsOf) -> StorageResult<i64> {
+        match self {
+            Self::AzDelta(adapter) => adapter.resolve_version(as_of).await,
+            _ => Err(time_travel_unsupported()),
+        }
+    }
+
+    /// Fetches a record from a historical version of the table.
+    pub async fn fetch_record_at(&self, id: &str, version: i64) -> StorageResult<Vec<Value>> {
+        match self {
+            Self::AzDelta(adapter) => adapter.fetch_record_at(id, version).await,
+            _ => Err(time_travel_unsupported()),
+        }
+    }
+
+    /// Returns the version of the backing table, for versioned tables.
+    pub fn table_version(&self) -> Option<i64> {
+        match self {
+            Self::AzDelta(adapter) => Some(adapter.version()),
+            _ => None,
+        }
+    }
+
+    /// Returns the adapter as a writable adapter, if the backend supports writes.
+    pub fn writer(&self) -> Option<&dyn WritableAdapter> {
+        match self {
+            Self::Mock(adapter) => Some(adapter),
+            Self::Postgres(adapter) => Some(adapter.as_ref()),
+            Self::AzDelta(adapter) => Some(adapter.as_ref()),
+            Self::Template(_) => None,
+        }
+    }
+
+    /// Writes a batch of records, as id and record, in as few backend calls as possible.
+    ///
+    /// Delta tables take the whole batch in a single MERGE commit.
+    pub async fn write_batch(
+        &self,
+        entity: &str,
+        records: &[(String, Value)],
+    ) -> StorageResult<()> {
+        match self {
+            Self::AzDelta(adapter) => adapter.merge_records(records).await.map(|_| ()),
+            _ => {
+                let writer = self.writer().ok_or_else(|| {
+                    StorageError::ReadOnly(format!("{} does not support writes", entity))
+                })?;
+                for (id, record) in records {
+                    writer.upsert_record(entity, id, record).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs a read-only SQL query against the backing table, returning at most `limit` rows.
+    pub async fn query(&self, sql: &str, limit: usize) -> StorageResult<Vec<Value>> {
+        match self {
+            Self::AzDelta(adapter) => adapter.query(sql, limit).await,
+            _ => Err(StorageError::ConfigError(
+                "Queries are only supported for AzDelta providers".to_string(),
+            )),
+        }
+    }
+
+    /// Checks that the backend can be reached.
+    pub async fn ping(&self) -> StorageResult<()> {
+        match self {
+            Self::Mock(_) | Self::Template(_) => Ok(()),
+            Self::Postgres(adapter) => adapter.ping().await,
+            Self::AzDelta(adapter) => adapter.ping().await,
+        }
+    }
+
+    /// Returns a page of key values of the backing table, in key order.
+    pub async fn scan_keys(
+        &self,
+        entity: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StorageResult<Vec<String>> {
+        match self {
+            Self::Mock(adapter) => adapter.scan_keys(entity, offset, limit),
+            Self::Postgres(adapter) => adapter.scan_keys(entity, offset, limit).await,
+            Self::AzDelta(adapter) => adapter.scan_keys(offset, limit).await,
+            Self::Template(_) => Err(StorageError::ConfigError(
+                "Query templates have no keys to scan".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the session of the backing table, which query templates run against.
+    pub fn session(&self) -> Option<SessionContext> {
+        match self {
+            Self::AzDelta(adapter) => Some(adapter.session()),
+            _ => None,
+        }
+    }
+
+    /// Returns the position up to which replicated changes were applied, if enabled.
+    pub fn replication_lsn(&self) -> Option<u64> {
+        match self {
+            Self::Postgres(adapter) => adapter.replication_lsn(),
+            _ => None,
+        }
+    }
+
+    /// Returns how many bytes of WAL replication is behind the server, if enabled.
+    pub fn replication_lag(&self) -> Option<u64> {
+        match self {
+            Self::Postgres(adapter) => adapter.replication_lag(),
+            _ => None,
+        }
+    }
+}
+
+fn time_travel_unsupported() -> StorageError {
+    StorageError::ConfigError("Time travel is only supported for AzDelta providers".to_string())
+}
+
+fn scans_unsupported() -> StorageError {
+    StorageError::ConfigError("Table scans are only supported for AzDelta providers".to_string())
+}
+
 /// Create a new database adapter based on configuration
 pub async fn create_database(
     provider: &DatabaseProvider,
@@ -47,16 +259,56 @@ pub async fn create_database(
         DatabaseProvider::Mock => Ok(DatabaseType::Mock(MockAdapter::new(settings))),
         DatabaseProvider::Postgres => {
             let adapter = PostgresAdapter::new(&settings).await?;
-            Ok(DatabaseType::Postgres(adapter))
+            Ok(DatabaseType::Postgres(Box::new(adapter)))
         }
         DatabaseProvider::AzDelta => {
             let adapter = AzDeltaAdapter::new(settings).await?;
-            Ok(DatabaseType::AzDelta(adapter))
+            Ok(DatabaseType::AzDelta(Box::new(adapter)))
         }
+        DatabaseProvider::Template => Err(StorageError::ConfigError(
+            "Template providers are created over their source provider".to_string(),
+        )),
     }
 }
 
+/// Converts JSON records to a record batch, inferring the schema from the records
+pub fn json_to_record_batch(records: &[Value]) -> StorageResult<RecordBatch> {
+    let (schema, batches) = json_to_record_batches(records, records.len().max(1))?;
+    Ok(batches
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| RecordBatch::new_empty(schema)))
+}
+
+/// Converts JSON records to record batches of up to `batch_size` rows, sharing a schema
+/// inferred from all records
+pub fn json_to_record_batches(
+    records: &[Value],
+    batch_size: usize,
+) -> StorageResult<(SchemaRef, Vec<RecordBatch>)> {
+    let to_error = |e: ArrowError| StorageError::DatabaseError(e.to_string());
+    let schema = infer_json_schema_from_iterator(records.iter().map(|record| Ok(record.clone())))
+        .map_err(to_error)?;
+    let schema = Arc::new(schema);
+    let mut decoder = ReaderBuilder::new(Arc::clone(&schema))
+        .with_batch_size(batch_size)
+        .build_decoder()
+        .map_err(to_error)?;
+    let mut batches = Vec::new();
+    for chunk in records.chunks(batch_size) {
+        decoder.serialize(chunk).map_err(to_error)?;
+        batches.extend(decoder.flush().map_err(to_error)?);
+    }
+    Ok((schema, batches))
+}
+
+/// Converts the first row of a record batch to JSON
 pub fn record_batch_to_json(record: &RecordBatch) -> serde_json::Value {
+    record_batch_row_to_json(record, 0)
+}
+
+/// Converts a row of a record batch to JSON
+pub fn record_batch_row_to_json(record: &RecordBatch, row: usize) -> serde_json::Value {
     let schema = record.schema();
     let mut json_map = serde_json::Map::new();
 
@@ -68,23 +320,23 @@ pub fn record_batch_to_json(record: &RecordBatch) -> serde_json::Value {
             DataType::Utf8 => col
                 .as_any()
                 .downcast_ref::<StringArray>()
-                .map(|arr| arr.value(0).to_string()),
+                .map(|arr| arr.value(row).to_string()),
             DataType::Int32 => col
                 .as_any()
                 .downcast_ref::<Int32Array>()
-                .map(|arr| arr.value(0).to_string()),
+                .map(|arr| arr.value(row).to_string()),
             DataType::Int64 => col
                 .as_any()
                 .downcast_ref::<Int64Array>()
-                .map(|arr| arr.value(0).to_string()),
+                .map(|arr| arr.value(row).to_string()),
             DataType::Float64 => col
                 .as_any()
                 .downcast_ref::<Float64Array>()
-                .map(|arr| arr.value(0).to_string()),
+                .map(|arr| arr.value(row).to_string()),
             DataType::Boolean => col
                 .as_any()
                 .downcast_ref::<BooleanArray>()
-                .map(|arr| arr.value(0).to_string()),
+                .map(|arr| arr.value(row).to_string()),
             _ => Some("Unsupported type".to_string()),
         }
         .unwrap_or_default();
@@ -127,4 +379,29 @@ mod tests {
         let json_str = json.to_string();
         assert_eq!(json_str, "{\"age\":\"30\",\"name\":\"John\"}");
     }
+
+    #[test]
+    fn test_json_to_record_batch() {
+        let records = [
+            serde_json::json!({ "id": "1", "age": 30 }),
+            serde_json::json!({ "id": "2", "age": null }),
+        ];
+        let batch = json_to_record_batch(&records).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(record_batch_row_to_json(&batch, 1)["id"], "2");
+        assert_eq!(record_batch_row_to_json(&batch, 0)["age"], "30");
+
+        let (schema, batches) = json_to_record_batches(&records, 1).unwrap();
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.schema() == schema));
+    }
+
+    #[test]
+    fn test_record_batch_row_to_json() {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2, 3]))])
+            .unwrap();
+
+        assert_eq!(record_batch_row_to_json(&batch, 2)["id"], "3");
+    }
 }
@@ -0,0 +1,485 @@
+//! Decoder for the `pgoutput` logical replication protocol (version 1).
+//!
+//! Only the messages needed to follow changes of a table are decoded; other
+//! messages are reported as `Message::Other`. All integers are big endian.
+
+use serde_json::{Map, Value};
+use std::fmt;
+
+/// Type oids mapped to JSON numbers and booleans
+const BOOL_OID: u32 = 16;
+const INT8_OID: u32 = 20;
+const INT2_OID: u32 = 21;
+const INT4_OID: u32 = 23;
+const JSON_OID: u32 = 114;
+const FLOAT4_OID: u32 = 700;
+const FLOAT8_OID: u32 = 701;
+const NUMERIC_OID: u32 = 1700;
+const JSONB_OID: u32 = 3802;
+/// Type oids whose JSON form differs from their text form
+const TIMESTAMP_OID: u32 = 1114;
+const TIMESTAMPTZ_OID: u32 = 1184;
+const TIMETZ_OID: u32 = 1266;
+/// Type oids whose JSON form is their text form as a string
+const STRING_OIDS: [u32; 13] = [
+    17,   // bytea
+    18,   // char
+    19,   // name
+    25,   // text
+    26,   // oid
+    650,  // cidr
+    869,  // inet
+    1042, // bpchar
+    1043, // varchar
+    1082, // date
+    1083, // time
+    1186, // interval
+    2950, // uuid
+];
+
+/// A decoded pgoutput message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Start of a transaction.
+    Begin,
+    /// End of a transaction; `end_lsn` is the position right after its commit record.
+    Commit { end_lsn: u64 },
+    /// Description of a relation, sent before its first change in a session.
+    Relation(Relation),
+    /// A new row.
+    Insert { relation: u32, new: Tuple },
+    /// A changed row, with the old key or row if the replica identity provides it.
+    Update {
+        relation: u32,
+        old: Option<Tuple>,
+        new: Tuple,
+    },
+    /// A deleted row, identified by its old key or row.
+    Delete { relation: u32, old: Tuple },
+    /// Truncated relations.
+    Truncate { relations: Vec<u32> },
+    /// Any other message.
+    Other(u8),
+}
+
+/// A relation (table) as described by the publisher.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relation {
+    pub id: u32,
+    pub namespace: String,
+    pub name: String,
+    pub columns: Vec<Column>,
+}
+
+/// A column of a relation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub type_oid: u32,
+    /// Whether the column is part of the replica identity key
+    pub key: bool,
+}
+
+/// Column values of a row.
+pub type Tuple = Vec<TupleValue>;
+
+/// A single column value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TupleValue {
+    Null,
+    /// A TOASTed value that did not change and is not sent.
+    Unchanged,
+    /// A value in its text representation.
+    Text(String),
+}
+
+/// Error decoding a pgoutput message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError(String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pgoutput decode error: {}", self.0)
+    }
+}
+
+/// Decodes a single pgoutput message.
+pub fn decode(data: &[u8]) -> Result<Message, DecodeError> {
+    let mut reader = Reader { data, pos: 0 };
+    let tag = reader.u8()?;
+    let message = match tag {
+        b'B' => Message::Begin,
+        b'C' => {
+            let _flags = reader.u8()?;
+            let _commit_lsn = reader.u64()?;
+            Message::Commit {
+                end_lsn: reader.u64()?,
+            }
+        }
+        b'R' => {
+            let id = reader.u32()?;
+            let namespace = reader.cstr()?;
+            let name = reader.cstr()?;
+            let _replica_identity = reader.u8()?;
+            let count = reader.u16()?;
+            let mut columns = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let flags = reader.u8()?;
+                let name = reader.cstr()?;
+                let type_oid = reader.u32()?;
+                let _type_modifier = reader.u32()?;
+                columns.push(Column {
+                    name,
+                    type_oid,
+                    key: flags & 1 == 1,
+                });
+            }
+            Message::Relation(Relation {
+                id,
+                namespace,
+                name,
+                columns,
+            })
+        }
+        b'I' => {
+            let relation = reader.u32()?;
+            reader.expect(b'N')?;
+            Message::Insert {
+                relation,
+                new: reader.tuple()?,
+            }
+        }
+        b'U' => {
+            let relation = reader.u32()?;
+            let old = match reader.u8()? {
+                b'K' | b'O' => {
+                    let old = reader.tuple()?;
+                    reader.expect(b'N')?;
+                    Some(old)
+                }
+                b'N' => None,
+                other => return Err(unexpected(other)),
+            };
+            Message::Update {
+                relation,
+                old,
+                new: reader.tuple()?,
+            }
+        }
+        b'D' => {
+            let relation = reader.u32()?;
+            match reader.u8()? {
+                b'K' | b'O' => {}
+                other => return Err(unexpected(other)),
+            }
+            Message::Delete {
+                relation,
+                old: reader.tuple()?,
+            }
+        }
+        b'T' => {
+            let count = reader.u32()?;
+            let _options = reader.u8()?;
+            let relations = (0..count).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+            Message::Truncate { relations }
+        }
+        other => Message::Other(other),
+    };
+    Ok(message)
+}
+
+/// Converts a row to JSON, keyed by column name, keeping only `columns` if given.
+///
+/// Values come out exactly as `row_to_json` renders them, so replicated rows equal
+/// fetched ones. Returns `None` if a kept column holds an unchanged TOASTed value or
+/// a type whose JSON form cannot be derived from its text form; such rows must be
+/// fetched instead.
+pub fn tuple_to_json(
+    relation: &Relation,
+    tuple: &Tuple,
+    columns: Option<&[String]>,
+) -> Option<Map<String, Value>> {
+    let mut row = Map::new();
+    for (column, value) in relation.columns.iter().zip(tuple) {
+        if columns.is_some_and(|columns| !columns.contains(&column.name)) {
+            continue;
+        }
+        let value = match value {
+            TupleValue::Null => Value::Null,
+            TupleValue::Unchanged => return None,
+            TupleValue::Text(text) => text_to_json(column.type_oid, text)?,
+        };
+        row.insert(column.name.clone(), value);
+    }
+    Some(row)
+}
+
+/// Formats an LSN the way Postgres does, e.g. `16/B374D848`.
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+/// Parses an LSN in the format used by Postgres.
+pub fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    let high = u64::from_str_radix(high, 16).ok()?;
+    let low = u64::from_str_radix(low, 16).ok()?;
+    (high <= 0xFFFF_FFFF && low <= 0xFFFF_FFFF).then_some(high << 32 | low)
+}
+
+/// Converts a value from its text form to the JSON `row_to_json` renders for it.
+///
+/// Numbers are parsed from their literal like the fetch path parses `row_to_json`
+/// output; `NaN` and infinities are strings there as well.
+fn text_to_json(type_oid: u32, text: &str) -> Option<Value> {
+    match type_oid {
+        BOOL_OID => Some(Value::Bool(text == "t")),
+        INT2_OID | INT4_OID | INT8_OID | FLOAT4_OID | FLOAT8_OID | NUMERIC_OID => {
+            Some(match serde_json::from_str(text) {
+                Ok(Value::Number(number)) => Value::Number(number),
+                _ => Value::String(text.to_string()),
+            })
+        }
+        JSON_OID | JSONB_OID => serde_json::from_str(text).ok(),
+        // Years BC are written differently, and too rare to be worth converting
+        TIMESTAMP_OID | TIMESTAMPTZ_OID | TIMETZ_OID => {
+            (!text.ends_with(" BC")).then(|| Value::String(xsd_datetime(text)))
+        }
+        oid if STRING_OIDS.contains(&oid) => Some(Value::String(text.to_string())),
+        _ => None,
+    }
+}
+
+/// Rewrites an ISO date and time in the XSD form used by JSON output: a `T` between
+/// date and time, and the minutes of the UTC offset always given.
+fn xsd_datetime(text: &str) -> String {
+    let mut value = match text.split_once(' ') {
+        Some((date, time)) if date.contains('-') => format!("{}T{}", date, time),
+        _ => text.to_string(),
+    };
+    let time = value.rsplit('T').next().unwrap_or_default();
+    if let Some(sign) = time.rfind(['+', '-'])
+        && time[sign + 1..].len() == 2
+        && time[sign + 1..].bytes().all(|b| b.is_ascii_digit())
+    {
+        value.push_str(":00");
+    }
+    value
+}
+
+fn unexpected(tag: u8) -> DecodeError {
+    DecodeError(format!("unexpected tag {:?}", tag as char))
+}
+
+/// Cursor over a message
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        let end = self.pos + len;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| DecodeError("message is truncated".to_string()))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<(), DecodeError> {
+        match self.u8()? {
+            found if found == tag => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    fn cstr(&mut self) -> Result<String, DecodeError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| DecodeError("unterminated string".to_string()))?;
+        let value = String::from_utf8_lossy(&rest[..len]).into_owned();
+        self.pos += len + 1;
+        Ok(value)
+    }
+
+    fn tuple(&mut self) -> Result<Tuple, DecodeError> {
+        let count = self.u16()?;
+        (0..count)
+            .map(|_| match self.u8()? {
+                b'n' => Ok(TupleValue::Null),
+                b'u' => Ok(TupleValue::Unchanged),
+                b't' => {
+                    let len = self.u32()? as usize;
+                    Ok(TupleValue::Text(
+                        String::from_utf8_lossy(self.take(len)?).into_owned(),
+                    ))
+                }
+                other => Err(unexpected(other)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Encodes a relation message for tests
+    pub(crate) fn relation_message(id: u32, name: &str, columns: &[(&str, u32, bool)]) -> Vec<u8> {
+        let mut data = vec![b'R'];
+        data.extend_from_slice(&id.to_be_bytes());
+        data.extend_from_slice(b"public\0");
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.push(b'd');
+        data.extend_from_slice(&(columns.len() as u16).to_be_bytes());
+        for (name, type_oid, key) in columns {
+            data.push(u8::from(*key));
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+            data.extend_from_slice(&type_oid.to_be_bytes());
+            data.extend_from_slice(&(-1i32).to_be_bytes());
+        }
+        data
+    }
+
+    /// Encodes tuple data for tests; `None` encodes a null
+    pub(crate) fn tuple_data(values: &[Option<&str>]) -> Vec<u8> {
+        let mut data = (values.len() as u16).to_be_bytes().to_vec();
+        for value in values {
+            match value {
+                Some(text) => {
+                    data.push(b't');
+                    data.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                    data.extend_from_slice(text.as_bytes());
+                }
+                None => data.push(b'n'),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_decode_relation_and_insert() {
+        let columns = [
+            ("id", INT4_OID, true),
+            ("name", 25, false),
+            ("active", BOOL_OID, false),
+        ];
+        let Message::Relation(relation) = decode(&relation_message(7, "users", &columns)).unwrap()
+        else {
+            panic!("Expected relation");
+        };
+        assert_eq!(relation.name, "users");
+        assert!(relation.columns[0].key && !relation.columns[1].key);
+
+        let mut insert = vec![b'I'];
+        insert.extend_from_slice(&7u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(tuple_data(&[Some("42"), Some("Ada"), Some("t")]));
+        let Message::Insert { relation: 7, new } = decode(&insert).unwrap() else {
+            panic!("Expected insert");
+        };
+
+        let row = tuple_to_json(&relation, &new, None).unwrap();
+        assert_eq!(
+            Value::Object(row),
+            json!({ "id": 42, "name": "Ada", "active": true })
+        );
+        let columns = ["name".to_string()];
+        let row = tuple_to_json(&relation, &new, Some(&columns)).unwrap();
+        assert_eq!(Value::Object(row), json!({ "name": "Ada" }));
+    }
+
+    #[test]
+    fn test_values_match_row_to_json() {
+        let cases = [
+            (NUMERIC_OID, "12.50", json!(12.5)),
+            (NUMERIC_OID, "9007199254740993", json!(9007199254740993u64)),
+            (NUMERIC_OID, "NaN", json!("NaN")),
+            (FLOAT8_OID, "-Infinity", json!("-Infinity")),
+            (TIMESTAMP_OID, "2024-03-01 10:15:00.5", json!("2024-03-01T10:15:00.5")),
+            (TIMESTAMPTZ_OID, "2024-03-01 10:15:00+00", json!("2024-03-01T10:15:00+00:00")),
+            (TIMESTAMPTZ_OID, "2024-03-01 10:15:00-05:30", json!("2024-03-01T10:15:00-05:30")),
+            (TIMESTAMP_OID, "infinity", json!("infinity")),
+            (TIMETZ_OID, "10:15:00+02", json!("10:15:00+02:00")),
+            (1082, "2024-03-01", json!("2024-03-01")),
+        ];
+        for (type_oid, text, expected) in cases {
+            assert_eq!(text_to_json(type_oid, text), Some(expected), "{}", text);
+        }
+        // Arrays render as JSON arrays, so their rows are fetched instead
+        assert_eq!(text_to_json(1007, "{1,2}"), None);
+    }
+
+    #[test]
+    fn test_decode_update_delete_and_commit() {
+        let mut update = vec![b'U'];
+        update.extend_from_slice(&7u32.to_be_bytes());
+        update.push(b'K');
+        update.extend(tuple_data(&[Some("1"), None]));
+        update.push(b'N');
+        update.extend(tuple_data(&[Some("2"), Some("x")]));
+        match decode(&update).unwrap() {
+            Message::Update {
+                old: Some(old),
+                new,
+                ..
+            } => {
+                assert_eq!(old[0], TupleValue::Text("1".to_string()));
+                assert_eq!(new[1], TupleValue::Text("x".to_string()));
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        let mut delete = vec![b'D'];
+        delete.extend_from_slice(&7u32.to_be_bytes());
+        delete.push(b'K');
+        delete.extend(tuple_data(&[Some("2"), None]));
+        assert!(matches!(
+            decode(&delete).unwrap(),
+            Message::Delete { relation: 7, .. }
+        ));
+
+        let mut commit = vec![b'C', 0];
+        commit.extend_from_slice(&10u64.to_be_bytes());
+        commit.extend_from_slice(&20u64.to_be_bytes());
+        commit.extend_from_slice(&0u64.to_be_bytes());
+        assert_eq!(decode(&commit).unwrap(), Message::Commit { end_lsn: 20 });
+    }
+
+    #[test]
+    fn test_truncated_message_is_an_error() {
+        assert!(decode(&[b'I', 0, 0]).is_err());
+        assert_eq!(decode(b"Y").unwrap(), Message::Other(b'Y'));
+    }
+
+    #[test]
+    fn test_lsn_roundtrip() {
+        let lsn = parse_lsn("16/B374D848").unwrap();
+        assert_eq!(lsn, 0x16_B374_D848);
+        assert_eq!(format_lsn(lsn), "16/B374D848");
+        assert_eq!(parse_lsn("nope"), None);
+    }
+}
@@ -1,15 +1,53 @@
-use async_trait::async_trait;
+//! Postgres database adapter implementation.
+//!
+//! Records are read with `row_to_json`, so column types map to their natural JSON
+//! representation. When `notify_channel` is set, the adapter listens for change
+//! notifications and turns them into cache updates. A trigger like the following
+//! publishes the changed rows:
+//!
+//! ```sql
+//! CREATE FUNCTION notify_employees() RETURNS trigger AS $$
+//! BEGIN
+//!   IF TG_OP = 'DELETE' THEN
+//!     PERFORM pg_notify('employees_changes',
+//!       json_build_object('op', 'delete', 'key', OLD.employee_id::text)::text);
+//!   ELSE
+//!     PERFORM pg_notify('employees_changes', json_build_object('row', row_to_json(NEW))::text);
+//!   END IF;
+//!   RETURN NULL;
+//! END $$ LANGUAGE plpgsql;
+//! ```
+//!
+//! Alternatively, when `cdc_slot` is set, changes are read from a logical replication
+//! slot using the `pgoutput` plugin, so no trigger is needed and no change is lost while
+//! disconnected. Changes are streamed with `START_REPLICATION`, and the position up to
+//! which they were applied is confirmed to the server every `cdc_status_interval_ms`
+//! and whenever it asks for it:
+//!
+//! ```sql
+//! CREATE PUBLICATION employees_pub FOR TABLE employees;
+//! SELECT pg_create_logical_replication_slot('prism_employees', 'pgoutput');
+//! ```
 
-use datafusion::arrow::{
-    array::{BooleanArray, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray},
-    datatypes::DataType,
-};
+use async_trait::async_trait;
+use futures::StreamExt;
 use serde_json::Value;
 use std::collections::HashMap;
-use tokio::sync::Mutex;
-use tracing::{debug, trace};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{OnceCell, RwLock};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use tracing::{debug, info, trace, warn};
 
-use crate::storage::{DatabaseAdapter, StorageError, StorageResult, assert_required_settings};
+use super::pgoutput::{self, Message, Relation, Tuple, TupleValue};
+use super::replication::{ReplicationMessage, ReplicationStream};
+use crate::storage::invalidation::{CacheUpdate, Invalidation};
+use crate::storage::resilience::backoff_delay;
+use crate::storage::{
+    DatabaseAdapter, StorageError, StorageResult, WritableAdapter, assert_required_settings,
+};
 
 const USER_KEY: &str = "user";
 const PASSWORD_KEY: &str = "password";
@@ -17,12 +55,91 @@ const HOST_KEY: &str = "host";
 const PORT_KEY: &str = "port";
 const DBNAME_KEY: &str = "dbname";
 const FIELDS_KEY: &str = "fields";
+const TABLE_KEY: &str = "table";
+const ID_FIELD_KEY: &str = "id_field";
+const NOTIFY_CHANNEL_KEY: &str = "notify_channel";
+const CDC_SLOT_KEY: &str = "cdc_slot";
+const CDC_PUBLICATION_KEY: &str = "cdc_publication";
+const CDC_START_LSN_KEY: &str = "cdc_start_lsn";
+const CDC_CREATE_SLOT_KEY: &str = "cdc_create_slot";
+const CDC_STATUS_INTERVAL_KEY: &str = "cdc_status_interval_ms";
+
+/// Default interval between two status updates sent to the replication server
+const DEFAULT_CDC_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+const ADVANCE_SLOT_QUERY: &str = "SELECT pg_replication_slot_advance($1, $2::text::pg_lsn)";
+const CREATE_SLOT_QUERY: &str = "SELECT pg_create_logical_replication_slot($1, 'pgoutput') \
+    WHERE NOT EXISTS (SELECT 1 FROM pg_replication_slots WHERE slot_name = $1)";
+const SLOT_LSN_QUERY: &str =
+    "SELECT confirmed_flush_lsn::text FROM pg_replication_slots WHERE slot_name = $1";
+const KEY_TYPE_QUERY: &str = "SELECT format_type(atttypid, atttypmod) FROM pg_attribute \
+    WHERE attrelid = $1::text::regclass AND attname = $2 AND NOT attisdropped";
+
+/// Delays between two attempts to reconnect the change listener
+const LISTENER_BACKOFF_BASE: Duration = Duration::from_millis(200);
+const LISTENER_BACKOFF_MAX: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub struct PostgresAdapter {
-    //connection: Mutex<Connection>,
+    conn_str: String,
+    /// Shared client, connected on first use and reconnected once closed
+    client: RwLock<Option<Arc<Client>>>,
+    /// Table to read from, the entity name if not configured
+    table: Option<String>,
     id_field: String,
     fields: String,
+    /// Channel the change listener subscribes to, if enabled
+    notify_channel: Option<String>,
+    /// Logical replication slot to follow, if enabled
+    replication: Option<ReplicationConfig>,
+    /// Progress of the slot, updated by the task following it
+    replication_progress: Arc<ReplicationProgress>,
+    /// SQL type of the key column, looked up on first use
+    key_type: OnceCell<String>,
+}
+
+/// Settings of the logical replication slot a provider follows.
+#[derive(Debug, Clone)]
+struct ReplicationConfig {
+    slot: String,
+    publication: String,
+    /// Position to advance a new slot to before reading changes
+    start_lsn: Option<u64>,
+    create_slot: bool,
+    /// Interval between two confirmations of the applied position
+    status_interval: Duration,
+}
+
+impl ReplicationConfig {
+    fn from_settings(settings: &HashMap<String, String>) -> StorageResult<Option<Self>> {
+        let Some(slot) = settings.get(CDC_SLOT_KEY) else {
+            return Ok(None);
+        };
+        assert_required_settings(settings, &[CDC_PUBLICATION_KEY, TABLE_KEY])?;
+        let start_lsn = settings
+            .get(CDC_START_LSN_KEY)
+            .map(|lsn| {
+                pgoutput::parse_lsn(lsn).ok_or_else(|| {
+                    StorageError::ConfigError(format!("Invalid {}: {}", CDC_START_LSN_KEY, lsn))
+                })
+            })
+            .transpose()?;
+        let status_interval = match settings.get(CDC_STATUS_INTERVAL_KEY) {
+            Some(ms) => Duration::from_millis(ms.parse().map_err(|_| {
+                StorageError::ConfigError(format!("Invalid {}: {}", CDC_STATUS_INTERVAL_KEY, ms))
+            })?),
+            None => DEFAULT_CDC_STATUS_INTERVAL,
+        };
+        Ok(Some(Self {
+            slot: slot.clone(),
+            publication: settings.get(CDC_PUBLICATION_KEY).unwrap().clone(),
+            start_lsn,
+            create_slot: settings
+                .get(CDC_CREATE_SLOT_KEY)
+                .is_some_and(|create| create == "true"),
+            status_interval,
+        }))
+    }
 }
 
 impl PostgresAdapter {
@@ -47,22 +164,869 @@ impl PostgresAdapter {
             settings.get(DBNAME_KEY).unwrap()
         );
         Ok(Self {
-            //connection: Mutex::new(conn),
-            id_field: "employee_id".to_string(),
+            conn_str,
+            client: RwLock::new(None),
+            table: settings.get(TABLE_KEY).cloned(),
+            id_field: settings
+                .get(ID_FIELD_KEY)
+                .cloned()
+                .unwrap_or_else(|| "employee_id".to_string()),
             fields: fields.to_string(),
+            notify_channel: settings.get(NOTIFY_CHANNEL_KEY).cloned(),
+            replication: ReplicationConfig::from_settings(settings)?,
+            replication_progress: Arc::default(),
+            key_type: OnceCell::new(),
+        })
+    }
+
+    /// Starts the change listener and the replication slot reader, if configured.
+    ///
+    /// Returns the stream of cache updates derived from the changes.
+    pub fn subscribe(&self) -> Option<UnboundedReceiver<CacheUpdate>> {
+        if self.notify_channel.is_none() && self.replication.is_none() {
+            return None;
+        }
+        let (updates, receiver) = mpsc::unbounded_channel();
+        if let Some(channel) = self.notify_channel.clone() {
+            tokio::spawn(listen(
+                self.conn_str.clone(),
+                channel,
+                self.id_field.clone(),
+                parse_fields(&self.fields),
+                updates.clone(),
+            ));
+        }
+        if let Some(replication) = self.replication.clone() {
+            // The table is required with a replication slot
+            let changes = ReplicatedChanges::new(
+                self.table.as_deref().unwrap_or_default(),
+                &self.id_field,
+                &self.fields,
+            );
+            tokio::spawn(replicate(
+                self.conn_str.clone(),
+                replication,
+                changes,
+                updates,
+                Arc::clone(&self.replication_progress),
+            ));
+        }
+        Some(receiver)
+    }
+
+    /// Returns the position up to which replicated changes were applied, if enabled.
+    pub fn replication_lsn(&self) -> Option<u64> {
+        self.replication
+            .as_ref()
+            .map(|_| self.replication_progress.applied.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many bytes of WAL the server wrote beyond the applied changes, if
+    /// replication is enabled.
+    pub fn replication_lag(&self) -> Option<u64> {
+        self.replication.as_ref().map(|_| {
+            let progress = &self.replication_progress;
+            let server_end = progress.server_end.load(Ordering::Relaxed);
+            server_end.saturating_sub(progress.applied.load(Ordering::Relaxed))
         })
     }
+
+    /// Checks that the database can be reached with a trivial query.
+    pub async fn ping(&self) -> StorageResult<()> {
+        self.client()
+            .await?
+            .simple_query("SELECT 1")
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Postgres query error: {}", e)))?;
+        Ok(())
+    }
+
+    /// Returns a page of the key values of the table, in key order.
+    pub async fn scan_keys(
+        &self,
+        entity: &str,
+        offset: usize,
+        limit: usize,
+    ) -> StorageResult<Vec<String>> {
+        let query = format!(
+            "SELECT {0}::text FROM {1} ORDER BY {0} LIMIT $1 OFFSET $2",
+            self.id_field,
+            self.table.as_deref().unwrap_or(entity)
+        );
+        let rows = self
+            .client()
+            .await?
+            .query(&query, &[&(limit as i64), &(offset as i64)])
+            .await
+            .map_err(|e| StorageError::DatabaseError(format!("Postgres query error: {}", e)))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Returns the SQL type of the key column, which lookup keys are cast to.
+    ///
+    /// Casting the key rather than the column keeps the column's index usable.
+    async fn key_type(&self, table: &str) -> StorageResult<&str> {
+        self.key_type
+            .get_or_try_init(|| async {
+                self.client()
+                    .await?
+                    .query_opt(KEY_TYPE_QUERY, &[&table, &self.id_field])
+                    .await
+                    .map_err(|e| {
+                        StorageError::DatabaseError(format!("Postgres query error: {}", e))
+                    })?
+                    .map(|row| row.get(0))
+                    .ok_or_else(|| {
+                        StorageError::ConfigError(format!(
+                            "Column {} not found in table {}",
+                            self.id_field, table
+                        ))
+                    })
+            })
+            .await
+            .map(String::as_str)
+    }
+
+    /// Returns a connected client, connecting if needed.
+    async fn client(&self) -> StorageResult<Arc<Client>> {
+        if let Some(client) = self.client.read().await.as_ref()
+            && !client.is_closed()
+        {
+            return Ok(Arc::clone(client));
+        }
+
+        let mut slot = self.client.write().await;
+        // Another caller may have reconnected in the meantime
+        if let Some(client) = slot.as_ref()
+            && !client.is_closed()
+        {
+            return Ok(Arc::clone(client));
+        }
+
+        let (client, connection) = tokio_postgres::connect(&self.conn_str, NoTls)
+            .await
+            .map_err(|e| {
+                StorageError::DatabaseError(format!("Postgres connection error: {}", e))
+            })?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                warn!("Postgres connection closed: {}", e);
+            }
+        });
+        debug!("Connected to Postgres");
+
+        let client = Arc::new(client);
+        *slot = Some(Arc::clone(&client));
+        Ok(client)
+    }
 }
 
 #[async_trait]
 impl DatabaseAdapter for PostgresAdapter {
     async fn fetch_record(&self, entity: &str, id: &str) -> StorageResult<Vec<Value>> {
-        trace!("Fetching record for entity: {}", entity);
+        trace!(
+            "Fetching record for entity: {} ({} = {}, fields: {})",
+            entity, self.id_field, id, self.fields
+        );
+
+        let table = self.table.as_deref().unwrap_or(entity);
+        let query = format!(
+            "SELECT row_to_json(t)::text FROM (SELECT {} FROM {} WHERE {} = $1::text::{}) t",
+            self.fields,
+            table,
+            self.id_field,
+            self.key_type(table).await?
+        );
+        let rows = match self.client().await?.query(&query, &[&id]).await {
+            Ok(rows) => rows,
+            Err(e) if is_invalid_key(&e) => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(StorageError::DatabaseError(format!(
+                    "Postgres query error: {}",
+                    e
+                )));
+            }
+        };
 
-        //let json_value = record_batch_to_json(record);
-        //return Ok(vec![json_value]);
-        //trace!("No record found");
-        Ok(vec![])
+        rows.iter()
+            .map(|row| {
+                let json: String = row.get(0);
+                serde_json::from_str(&json)
+                    .map_err(|e| StorageError::DatabaseError(format!("Invalid row JSON: {}", e)))
+            })
+            .collect()
+    }
+
+    async fn record_exists(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        let table = self.table.as_deref().unwrap_or(entity);
+        let query = format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE {} = $1::text::{})",
+            table,
+            self.id_field,
+            self.key_type(table).await?
+        );
+        match self.client().await?.query_one(&query, &[&id]).await {
+            Ok(row) => Ok(row.get(0)),
+            Err(e) if is_invalid_key(&e) => Ok(false),
+            Err(e) => Err(StorageError::DatabaseError(format!(
+                "Postgres query error: {}",
+                e
+            ))),
+        }
+    }
+}
+
+/// Listens for notifications on a channel and forwards them as cache updates.
+///
+/// Reconnects with backoff when the connection is lost. Notifications sent while
+/// disconnected are lost, so the whole provider is flushed after every outage.
+async fn listen(
+    conn_str: String,
+    channel: String,
+    id_field: String,
+    fields: Option<Vec<String>>,
+    updates: UnboundedSender<CacheUpdate>,
+) {
+    let mut attempt = 0;
+    let mut had_outage = false;
+    while !updates.is_closed() {
+        match tokio_postgres::connect(&conn_str, NoTls).await {
+            Ok((client, mut connection)) => {
+                // Notifications are only delivered by polling the connection directly
+                let (notifications, mut received) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    let mut messages =
+                        futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+                    while let Some(message) = messages.next().await {
+                        match message {
+                            Ok(AsyncMessage::Notification(notification)) => {
+                                if notifications.send(notification).is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => {
+                                warn!("Postgres listener connection failed: {}", e);
+                                return;
+                            }
+                        }
+                    }
+                });
+
+                match client
+                    .batch_execute(&format!("LISTEN \"{}\"", channel))
+                    .await
+                {
+                    Ok(()) => {
+                        info!("Listening for changes on Postgres channel {}", channel);
+                        attempt = 0;
+                        if had_outage {
+                            info!("Flushing provider after listener outage on {}", channel);
+                            let _ = updates.send(CacheUpdate::Invalidate(Invalidation::All));
+                        }
+                        while let Some(notification) = received.recv().await {
+                            match parse_notification(
+                                notification.payload(),
+                                &id_field,
+                                fields.as_deref(),
+                            ) {
+                                Some(update) => {
+                                    if updates.send(update).is_err() {
+                                        return;
+                                    }
+                                }
+                                None => warn!(
+                                    "Ignoring unparseable notification on {}: {}",
+                                    channel,
+                                    notification.payload()
+                                ),
+                            }
+                        }
+                        warn!("Lost Postgres listener connection for channel {}", channel);
+                    }
+                    Err(e) => warn!("Failed to LISTEN on channel {}: {}", channel, e),
+                }
+            }
+            Err(e) => warn!("Postgres listener failed to connect: {}", e),
+        }
+
+        had_outage = true;
+        let delay = backoff_delay(attempt, LISTENER_BACKOFF_BASE, LISTENER_BACKOFF_MAX);
+        attempt = attempt.saturating_add(1);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[async_trait]
+impl WritableAdapter for PostgresAdapter {
+    /// Upserts the record with `INSERT ... ON CONFLICT (id_field) DO UPDATE`.
+    ///
+    /// JSON values are converted to the column types by `json_populate_record`; only
+    /// the columns present in the record are written.
+    async fn upsert_record(&self, entity: &str, id: &str, record: &Value) -> StorageResult<Value> {
+        let mut record = record.clone();
+        let Some(columns) = record.as_object_mut() else {
+            return Err(StorageError::InvalidRecord(
+                "Records must be JSON objects".to_string(),
+            ));
+        };
+        columns.insert(self.id_field.clone(), Value::String(id.to_string()));
+
+        let table = self.table.as_deref().unwrap_or(entity);
+        let quoted: Vec<String> = columns.keys().map(|column| quote_ident(column)).collect();
+        let updates: Vec<String> = quoted
+            .iter()
+            .map(|column| format!("{} = EXCLUDED.{}", column, column))
+            .collect();
+        let query = format!(
+            "WITH written AS (INSERT INTO {table} ({columns}) \
+             SELECT {columns} FROM json_populate_record(NULL::{table}, $1::text::json) \
+             ON CONFLICT ({id_field}) DO UPDATE SET {updates} RETURNING *) \
+             SELECT row_to_json(t)::text FROM (SELECT {fields} FROM written) t",
+            table = table,
+            columns = quoted.join(", "),
+            id_field = self.id_field,
+            updates = updates.join(", "),
+            fields = self.fields,
+        );
+        trace!("Writing record for entity: {} ({} = {})", entity, self.id_field, id);
+
+        let row = self
+            .client()
+            .await?
+            .query_one(&query, &[&record.to_string()])
+            .await
+            .map_err(write_error)?;
+        let json: String = row.get(0);
+        serde_json::from_str(&json)
+            .map_err(|e| StorageError::DatabaseError(format!("Invalid row JSON: {}", e)))
+    }
+
+    /// Casts the record to the table's row type without writing it.
+    ///
+    /// Returns the fields of the record with their values as Postgres reads them.
+    async fn validate_record(
+        &self,
+        entity: &str,
+        id: &str,
+        record: &Value,
+    ) -> StorageResult<Value> {
+        let Some(fields) = record.as_object() else {
+            return Err(StorageError::InvalidRecord(
+                "Records must be JSON objects".to_string(),
+            ));
+        };
+        let table = self.table.as_deref().unwrap_or(entity);
+        let query = format!(
+            "SELECT row_to_json(r)::text FROM json_populate_record(NULL::{}, $1::text::json) r",
+            table
+        );
+        let row = self
+            .client()
+            .await?
+            .query_one(&query, &[&record.to_string()])
+            .await
+            .map_err(write_error)?;
+        let json: String = row.get(0);
+        let Ok(Value::Object(mut cast)) = serde_json::from_str(&json) else {
+            return Err(StorageError::DatabaseError(format!("Invalid row JSON: {}", json)));
+        };
+        trace!("Validated record for entity: {} ({} = {})", entity, self.id_field, id);
+
+        // Fields without a column are dropped by the cast, but fail the write
+        let mut validated = serde_json::Map::new();
+        for name in fields.keys() {
+            let value = cast.remove(name).ok_or_else(|| {
+                StorageError::InvalidRecord(format!("Unknown column {} in {}", name, table))
+            })?;
+            validated.insert(name.clone(), value);
+        }
+        Ok(Value::Object(validated))
     }
 }
 
+/// Maps an error of a write; errors the record causes fail again on every retry.
+fn write_error(e: tokio_postgres::Error) -> StorageError {
+    // Data exceptions, constraint violations and unknown columns
+    let code = e.code().map(|code| code.code()).unwrap_or_default();
+    if code.starts_with("22") || code.starts_with("23") || code == "42703" {
+        StorageError::InvalidRecord(format!("Postgres rejected the record: {}", e))
+    } else {
+        StorageError::DatabaseError(format!("Postgres write error: {}", e))
+    }
+}
+
+/// Quotes a column name for use in SQL.
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Follows a logical replication slot and forwards its changes as cache updates.
+///
+/// Reconnects with backoff on errors. Changes stay in the slot until they were
+/// forwarded, so none are lost while disconnected.
+async fn replicate(
+    conn_str: String,
+    mut config: ReplicationConfig,
+    mut changes: ReplicatedChanges,
+    updates: UnboundedSender<CacheUpdate>,
+    progress: Arc<ReplicationProgress>,
+) {
+    let mut attempt = 0;
+    while !updates.is_closed() {
+        let result = follow_slot(
+            &conn_str,
+            &mut config,
+            &mut changes,
+            &updates,
+            &progress,
+            &mut attempt,
+        )
+        .await;
+        if let Err(e) = result {
+            warn!("Replication from slot {} failed: {}", config.slot, e);
+        }
+
+        let delay = backoff_delay(attempt, LISTENER_BACKOFF_BASE, LISTENER_BACKOFF_MAX);
+        attempt = attempt.saturating_add(1);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Streams the slot's changes until the connection fails or the receiver is dropped.
+async fn follow_slot(
+    conn_str: &str,
+    config: &mut ReplicationConfig,
+    changes: &mut ReplicatedChanges,
+    updates: &UnboundedSender<CacheUpdate>,
+    progress: &ReplicationProgress,
+    attempt: &mut u32,
+) -> Result<(), String> {
+    let (client, connection) = tokio_postgres::connect(conn_str, NoTls)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("Postgres replication connection closed: {}", e);
+        }
+    });
+
+    if config.create_slot {
+        client
+            .execute(CREATE_SLOT_QUERY, &[&config.slot])
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(start_lsn) = config.start_lsn.take() {
+        let start = pgoutput::format_lsn(start_lsn);
+        if let Err(e) = client
+            .execute(ADVANCE_SLOT_QUERY, &[&config.slot, &start])
+            .await
+        {
+            warn!("Failed to advance slot {} to {}: {}", config.slot, start, e);
+        }
+    }
+    let lsn: Option<String> = client
+        .query_opt(SLOT_LSN_QUERY, &[&config.slot])
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("replication slot {} does not exist", config.slot))?
+        .get(0);
+    let mut confirmed = lsn.as_deref().and_then(pgoutput::parse_lsn).unwrap_or(0);
+    progress.applied.store(confirmed, Ordering::Relaxed);
+
+    let mut stream = ReplicationStream::connect(conn_str).await?;
+    stream
+        .start(
+            &config.slot,
+            &config.publication,
+            &pgoutput::format_lsn(confirmed),
+        )
+        .await?;
+    info!("Streaming changes from replication slot {}", config.slot);
+    *attempt = 0;
+
+    let mut status = tokio::time::interval(config.status_interval);
+    // Keepalives may only be confirmed between transactions
+    let mut in_transaction = false;
+    loop {
+        let message = tokio::select! {
+            message = stream.next() => message?,
+            _ = status.tick() => {
+                stream.confirm(confirmed).await?;
+                continue;
+            }
+            _ = updates.closed() => return Ok(()),
+        };
+        let applied = match message {
+            ReplicationMessage::XLogData { wal_end, data } => {
+                progress.server_end.fetch_max(wal_end, Ordering::Relaxed);
+                match pgoutput::decode(&data) {
+                    Ok(Message::Begin) => {
+                        in_transaction = true;
+                        None
+                    }
+                    Ok(Message::Commit { end_lsn }) => {
+                        in_transaction = false;
+                        Some(end_lsn)
+                    }
+                    Ok(message) => {
+                        for update in changes.apply(message) {
+                            if updates.send(update).is_err() {
+                                return Ok(());
+                            }
+                        }
+                        None
+                    }
+                    Err(e) => {
+                        warn!("Skipping change from slot {}: {}", config.slot, e);
+                        None
+                    }
+                }
+            }
+            ReplicationMessage::Keepalive { wal_end, reply } => {
+                progress.server_end.fetch_max(wal_end, Ordering::Relaxed);
+                if reply {
+                    stream.confirm(confirmed).await?;
+                }
+                (!in_transaction).then_some(wal_end)
+            }
+        };
+
+        // Changes were forwarded, so the slot can release them
+        if let Some(lsn) = applied.filter(|lsn| *lsn > confirmed) {
+            confirmed = lsn;
+            progress.applied.store(lsn, Ordering::Relaxed);
+            trace!(
+                "Replication slot {} applied up to {}",
+                config.slot,
+                pgoutput::format_lsn(lsn)
+            );
+        }
+    }
+}
+
+/// Progress of a followed replication slot.
+#[derive(Debug, Default)]
+struct ReplicationProgress {
+    /// Position up to which the slot's changes were applied
+    applied: AtomicU64,
+    /// End of the server's WAL, as last reported by the server
+    server_end: AtomicU64,
+}
+
+/// Turns decoded replication messages of one table into cache updates.
+#[derive(Debug)]
+struct ReplicatedChanges {
+    /// Table name, optionally qualified with its schema
+    table: String,
+    id_field: String,
+    /// Columns cached records hold, all of them if `None`
+    fields: Option<Vec<String>>,
+    /// Relations of the table, by relation id
+    relations: HashMap<u32, Relation>,
+}
+
+impl ReplicatedChanges {
+    fn new(table: &str, id_field: &str, fields: &str) -> Self {
+        Self {
+            table: table.to_string(),
+            id_field: id_field.to_string(),
+            fields: parse_fields(fields),
+            relations: HashMap::new(),
+        }
+    }
+
+    /// Returns the cache updates a message leads to.
+    fn apply(&mut self, message: Message) -> Vec<CacheUpdate> {
+        match message {
+            Message::Relation(relation) => {
+                let qualified = format!("{}.{}", relation.namespace, relation.name);
+                if self.table == relation.name || self.table == qualified {
+                    self.relations.insert(relation.id, relation);
+                }
+                vec![]
+            }
+            Message::Insert { relation, new } => self.row_updates(relation, None, &new),
+            Message::Update { relation, old, new } => {
+                self.row_updates(relation, old.as_ref(), &new)
+            }
+            Message::Delete { relation, old } => {
+                let Some(relation) = self.relations.get(&relation) else {
+                    return vec![];
+                };
+                vec![CacheUpdate::Invalidate(match self.key(relation, &old) {
+                    Some(key) => Invalidation::Keys(vec![key]),
+                    None => Invalidation::All,
+                })]
+            }
+            Message::Truncate { relations } => {
+                if relations.iter().any(|id| self.relations.contains_key(id)) {
+                    vec![CacheUpdate::Invalidate(Invalidation::All)]
+                } else {
+                    vec![]
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Updates for an inserted or changed row, invalidating its old key if it changed.
+    fn row_updates(&self, relation: u32, old: Option<&Tuple>, new: &Tuple) -> Vec<CacheUpdate> {
+        let Some(relation) = self.relations.get(&relation) else {
+            return vec![];
+        };
+        let Some(key) = self.key(relation, new) else {
+            return vec![CacheUpdate::Invalidate(Invalidation::All)];
+        };
+
+        let mut updates = vec![];
+        if let Some(old_key) = old.and_then(|old| self.key(relation, old))
+            && old_key != key
+        {
+            updates.push(CacheUpdate::Invalidate(Invalidation::Keys(vec![old_key])));
+        }
+        // Rows whose cached columns cannot all be decoded are refetched instead
+        let fields = self.fields.as_deref();
+        updates.push(
+            match pgoutput::tuple_to_json(relation, new, fields)
+                .and_then(|row| project_row(row, fields))
+            {
+                Some(value) => CacheUpdate::Upsert { key, value },
+                None => CacheUpdate::Invalidate(Invalidation::Keys(vec![key])),
+            },
+        );
+        updates
+    }
+
+    /// Returns the cache key of a row, if the row holds the id column.
+    fn key(&self, relation: &Relation, tuple: &Tuple) -> Option<String> {
+        let index = relation
+            .columns
+            .iter()
+            .position(|column| column.name == self.id_field)?;
+        match tuple.get(index)? {
+            TupleValue::Text(key) => Some(key.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the configured column list, `None` standing for all columns.
+fn parse_fields(fields: &str) -> Option<Vec<String>> {
+    (fields.trim() != "*").then(|| {
+        fields
+            .split(',')
+            .map(|field| field.trim().to_string())
+            .collect()
+    })
+}
+
+/// Keeps the configured columns of a row, or `None` if one is missing.
+fn project_row(
+    mut row: serde_json::Map<String, Value>,
+    fields: Option<&[String]>,
+) -> Option<Value> {
+    let Some(fields) = fields else {
+        return Some(Value::Object(row));
+    };
+    fields
+        .iter()
+        .map(|field| row.remove(field).map(|value| (field.clone(), value)))
+        .collect::<Option<serde_json::Map<_, _>>>()
+        .map(Value::Object)
+}
+
+/// Whether a query failed because the key could not be cast to the key column's type.
+///
+/// Such keys cannot exist in the table, so they are treated as missing records.
+fn is_invalid_key(error: &tokio_postgres::Error) -> bool {
+    error
+        .code()
+        .is_some_and(|code| code.code().starts_with("22"))
+}
+
+/// Parses a notification payload into a cache update.
+///
+/// The payload is either a bare key or a JSON object holding the `key`, the changed
+/// `row`, or both. A row updates the cached record, a key alone or an `op` of
+/// `delete` invalidates it. Rows are narrowed to the configured `fields`; a row
+/// missing one of them only invalidates the key, so the record is refetched.
+fn parse_notification(
+    payload: &str,
+    id_field: &str,
+    fields: Option<&[String]>,
+) -> Option<CacheUpdate> {
+    let payload = payload.trim();
+    let Ok(Value::Object(mut object)) = serde_json::from_str::<Value>(payload) else {
+        return (!payload.is_empty())
+            .then(|| CacheUpdate::Invalidate(Invalidation::Keys(vec![payload.to_string()])));
+    };
+
+    let row = match object.remove("row") {
+        Some(Value::Object(row)) => Some(row),
+        _ => None,
+    };
+    let key = object
+        .get("key")
+        .or_else(|| row.as_ref().and_then(|row| row.get(id_field)))
+        .and_then(key_to_string)?;
+    let delete = object
+        .get("op")
+        .and_then(Value::as_str)
+        .is_some_and(|op| op.eq_ignore_ascii_case("delete"));
+
+    match row.filter(|_| !delete).and_then(|row| project_row(row, fields)) {
+        Some(value) => Some(CacheUpdate::Upsert { key, value }),
+        None => Some(CacheUpdate::Invalidate(Invalidation::Keys(vec![key]))),
+    }
+}
+
+/// Renders a key value the way it appears in cache ids.
+fn key_to_string(key: &Value) -> Option<String> {
+    match key {
+        Value::String(key) => Some(key.clone()),
+        Value::Number(key) => Some(key.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::database::pgoutput::tests::{relation_message, tuple_data};
+    use serde_json::json;
+
+    fn employees_relation() -> Message {
+        pgoutput::decode(&relation_message(
+            16384,
+            "employees",
+            &[
+                ("employee_id", 23, true),
+                ("first_name", 25, false),
+                ("email", 25, false),
+            ],
+        ))
+        .unwrap()
+    }
+
+    fn row_message(tag: u8, old: Option<&[Option<&str>]>, new: &[Option<&str>]) -> Message {
+        let mut data = vec![tag];
+        data.extend_from_slice(&16384u32.to_be_bytes());
+        if let Some(old) = old {
+            data.push(b'K');
+            data.extend(tuple_data(old));
+        }
+        if tag != b'D' {
+            data.push(b'N');
+            data.extend(tuple_data(new));
+        }
+        pgoutput::decode(&data).unwrap()
+    }
+
+    #[test]
+    fn test_replicated_changes() {
+        let mut changes =
+            ReplicatedChanges::new("public.employees", "employee_id", "employee_id, email");
+        assert!(changes.apply(employees_relation()).is_empty());
+
+        let insert = row_message(b'I', None, &[Some("7"), Some("Ada"), Some("ada@x.io")]);
+        assert_eq!(
+            changes.apply(insert),
+            vec![CacheUpdate::Upsert {
+                key: "7".to_string(),
+                value: json!({ "employee_id": 7, "email": "ada@x.io" }),
+            }]
+        );
+
+        // A changed key invalidates the old record
+        let update = row_message(
+            b'U',
+            Some(&[Some("7"), None, None]),
+            &[Some("8"), Some("Ada"), Some("ada@x.io")],
+        );
+        let updates = changes.apply(update);
+        assert_eq!(
+            updates[0],
+            CacheUpdate::Invalidate(Invalidation::Keys(vec!["7".to_string()]))
+        );
+        assert!(matches!(&updates[1], CacheUpdate::Upsert { key, .. } if key == "8"));
+
+        let delete = row_message(b'D', Some(&[Some("8"), None, None]), &[]);
+        assert_eq!(
+            changes.apply(delete),
+            vec![CacheUpdate::Invalidate(Invalidation::Keys(vec![
+                "8".to_string()
+            ]))]
+        );
+    }
+
+    #[test]
+    fn test_replicated_changes_of_other_tables_are_ignored() {
+        let mut changes = ReplicatedChanges::new("managers", "employee_id", "*");
+        changes.apply(employees_relation());
+
+        let insert = row_message(b'I', None, &[Some("7"), Some("Ada"), None]);
+        assert!(changes.apply(insert).is_empty());
+    }
+
+    #[test]
+    fn test_parse_bare_key() {
+        assert_eq!(
+            parse_notification(" 42 ", "employee_id", None),
+            Some(CacheUpdate::Invalidate(Invalidation::Keys(vec![
+                "42".to_string()
+            ])))
+        );
+        assert_eq!(parse_notification("", "employee_id", None), None);
+    }
+
+    #[test]
+    fn test_parse_row_payload() {
+        let payload = r#"{"row": {"employee_id": 7, "first_name": "Ada"}}"#;
+        assert_eq!(
+            parse_notification(payload, "employee_id", None),
+            Some(CacheUpdate::Upsert {
+                key: "7".to_string(),
+                value: json!({ "employee_id": 7, "first_name": "Ada" }),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_row_payload_keeps_configured_fields() {
+        let fields = parse_fields("employee_id, first_name");
+        let payload = r#"{"row": {"employee_id": 7, "first_name": "Ada", "salary": 10}}"#;
+        assert_eq!(
+            parse_notification(payload, "employee_id", fields.as_deref()),
+            Some(CacheUpdate::Upsert {
+                key: "7".to_string(),
+                value: json!({ "employee_id": 7, "first_name": "Ada" }),
+            })
+        );
+
+        // A row missing a cached column only invalidates the record
+        let payload = r#"{"row": {"employee_id": 7}}"#;
+        assert_eq!(
+            parse_notification(payload, "employee_id", fields.as_deref()),
+            Some(CacheUpdate::Invalidate(Invalidation::Keys(vec![
+                "7".to_string()
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_parse_delete_payload() {
+        let payload = r#"{"op": "DELETE", "key": "7", "row": {"employee_id": 7}}"#;
+        assert_eq!(
+            parse_notification(payload, "employee_id", None),
+            Some(CacheUpdate::Invalidate(Invalidation::Keys(vec![
+                "7".to_string()
+            ])))
+        );
+        assert_eq!(
+            parse_notification(r#"{"op": "delete"}"#, "employee_id", None),
+            None
+        );
+    }
+}
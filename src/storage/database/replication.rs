@@ -0,0 +1,312 @@
+//! Minimal client for the Postgres streaming replication protocol.
+//!
+//! The regular client library cannot enter the copy-both mode replication streams
+//! run in, so this connects in logical replication mode itself, authenticates with
+//! the same credentials, and exchanges raw protocol messages. Only plain TCP
+//! connections are supported, like the rest of the adapter.
+
+use bytes::{BufMut, BytesMut};
+use postgres_protocol::authentication::md5_hash;
+use postgres_protocol::authentication::sasl::{ChannelBinding, SCRAM_SHA_256, ScramSha256};
+use postgres_protocol::message::frontend;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_postgres::Config;
+use tokio_postgres::config::Host;
+
+/// Seconds between the Unix epoch and the Postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_OFFSET: Duration = Duration::from_secs(946_684_800);
+
+/// A message of a replication stream.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationMessage {
+    /// WAL data, here a single pgoutput message, with the end of the server's WAL.
+    XLogData { wal_end: u64, data: Vec<u8> },
+    /// Heartbeat of the server, which asks for a status update if `reply` is set.
+    Keepalive { wal_end: u64, reply: bool },
+}
+
+/// A connection streaming the changes of a logical replication slot.
+pub struct ReplicationStream {
+    stream: TcpStream,
+    read: BytesMut,
+}
+
+impl ReplicationStream {
+    /// Connects in logical replication mode and authenticates.
+    pub async fn connect(conn_str: &str) -> Result<Self, String> {
+        let config: Config = conn_str.parse().map_err(|e| format!("{}", e))?;
+        let host = match config.get_hosts().first() {
+            Some(Host::Tcp(host)) => host.clone(),
+            _ => return Err("replication requires a TCP host".to_string()),
+        };
+        let port = config.get_ports().first().copied().unwrap_or(5432);
+        let user = config.get_user().unwrap_or_default();
+        let stream = TcpStream::connect((host.as_str(), port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut connection = Self {
+            stream,
+            read: BytesMut::new(),
+        };
+
+        // Values are rendered like the ones read through the regular client
+        let mut parameters = vec![
+            ("user", user),
+            ("replication", "database"),
+            ("client_encoding", "UTF8"),
+            ("DateStyle", "ISO"),
+        ];
+        if let Some(dbname) = config.get_dbname() {
+            parameters.push(("database", dbname));
+        }
+        let mut buf = BytesMut::new();
+        frontend::startup_message(parameters, &mut buf).map_err(|e| e.to_string())?;
+        connection.send(&buf).await?;
+        connection
+            .authenticate(user, config.get_password().unwrap_or_default())
+            .await?;
+        connection.wait_ready().await?;
+        Ok(connection)
+    }
+
+    /// Starts streaming the changes of a slot that follow `lsn`.
+    pub async fn start(&mut self, slot: &str, publication: &str, lsn: &str) -> Result<(), String> {
+        let command = format!(
+            "START_REPLICATION SLOT \"{}\" LOGICAL {} \
+             (proto_version '1', publication_names '{}')",
+            slot.replace('"', "\"\""),
+            lsn,
+            publication.replace('\'', "''")
+        );
+        let mut buf = BytesMut::new();
+        frontend::query(&command, &mut buf).map_err(|e| e.to_string())?;
+        self.send(&buf).await?;
+        loop {
+            let (tag, body) = self.receive().await?;
+            match tag {
+                // CopyBothResponse
+                b'W' => return Ok(()),
+                b'E' => return Err(error_message(&body)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for the next message of the stream.
+    ///
+    /// Cancel safe: a message cut short by cancellation is read again on the next call.
+    pub async fn next(&mut self) -> Result<ReplicationMessage, String> {
+        loop {
+            let (tag, body) = self.receive().await?;
+            match tag {
+                b'd' => {
+                    if let Some(message) = parse_copy_data(&body)? {
+                        return Ok(message);
+                    }
+                }
+                b'E' => return Err(error_message(&body)),
+                b'c' => return Err("replication stream ended".to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a standby status update reporting every change up to `lsn` as applied.
+    ///
+    /// The server releases the WAL of applied changes and resumes after them on the
+    /// next connection.
+    pub async fn confirm(&mut self, lsn: u64) -> Result<(), String> {
+        let mut status = BytesMut::with_capacity(34);
+        status.put_u8(b'r');
+        // Written, flushed and applied positions
+        for _ in 0..3 {
+            status.put_u64(lsn);
+        }
+        status.put_i64(postgres_now());
+        status.put_u8(0);
+        let mut buf = BytesMut::new();
+        frontend::CopyData::new(&status[..])
+            .map_err(|e| e.to_string())?
+            .write(&mut buf);
+        self.send(&buf).await
+    }
+
+    async fn authenticate(&mut self, user: &str, password: &[u8]) -> Result<(), String> {
+        let mut scram: Option<ScramSha256> = None;
+        loop {
+            let (tag, body) = self.receive().await?;
+            match tag {
+                b'R' => {}
+                b'E' => return Err(error_message(&body)),
+                other => return Err(format!("unexpected message {:?}", other as char)),
+            }
+            let code = u32::from_be_bytes(read_bytes(&body, 0, 4)?.try_into().unwrap());
+            let data = &body[4..];
+            let mut buf = BytesMut::new();
+            match code {
+                // AuthenticationOk
+                0 => return Ok(()),
+                // Cleartext password
+                3 => frontend::password_message(password, &mut buf),
+                // MD5 password
+                5 => {
+                    let salt = read_bytes(data, 0, 4)?.try_into().unwrap();
+                    let hash = md5_hash(user.as_bytes(), password, salt);
+                    frontend::password_message(hash.as_bytes(), &mut buf)
+                }
+                // SASL, offering its mechanisms
+                10 => {
+                    let offered = data
+                        .split(|b| *b == 0)
+                        .any(|mechanism| mechanism == SCRAM_SHA_256.as_bytes());
+                    if !offered {
+                        return Err("no supported SASL mechanism offered".to_string());
+                    }
+                    let client = ScramSha256::new(password, ChannelBinding::unsupported());
+                    let result =
+                        frontend::sasl_initial_response(SCRAM_SHA_256, client.message(), &mut buf);
+                    scram = Some(client);
+                    result
+                }
+                // SASL continue
+                11 => {
+                    let client = scram.as_mut().ok_or("unexpected SASL message")?;
+                    client.update(data).map_err(|e| e.to_string())?;
+                    frontend::sasl_response(client.message(), &mut buf)
+                }
+                // SASL final
+                12 => {
+                    let client = scram.as_mut().ok_or("unexpected SASL message")?;
+                    client.finish(data).map_err(|e| e.to_string())?;
+                    continue;
+                }
+                other => return Err(format!("unsupported authentication method {}", other)),
+            }
+            .map_err(|e| e.to_string())?;
+            self.send(&buf).await?;
+        }
+    }
+
+    /// Skips the session parameters sent after authentication.
+    async fn wait_ready(&mut self) -> Result<(), String> {
+        loop {
+            match self.receive().await? {
+                (b'Z', _) => return Ok(()),
+                (b'E', body) => return Err(error_message(&body)),
+                _ => {}
+            }
+        }
+    }
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), String> {
+        self.stream.write_all(buf).await.map_err(|e| e.to_string())
+    }
+
+    /// Reads the next backend message, as its tag and body.
+    async fn receive(&mut self) -> Result<(u8, BytesMut), String> {
+        loop {
+            if self.read.len() >= 5 {
+                let len = u32::from_be_bytes(self.read[1..5].try_into().unwrap()) as usize;
+                if len < 4 {
+                    return Err("invalid message length".to_string());
+                }
+                if self.read.len() > len {
+                    let mut message = self.read.split_to(len + 1);
+                    let body = message.split_off(5);
+                    return Ok((message[0], body));
+                }
+            }
+            let read = self
+                .stream
+                .read_buf(&mut self.read)
+                .await
+                .map_err(|e| e.to_string())?;
+            if read == 0 {
+                return Err("connection closed".to_string());
+            }
+        }
+    }
+}
+
+/// Parses the payload of a CopyData message, skipping unknown messages.
+fn parse_copy_data(body: &[u8]) -> Result<Option<ReplicationMessage>, String> {
+    match body.first() {
+        // XLogData: start and end of the WAL data, send time, then the data
+        Some(b'w') => Ok(Some(ReplicationMessage::XLogData {
+            wal_end: u64::from_be_bytes(read_bytes(body, 9, 8)?.try_into().unwrap()),
+            data: read_bytes(body, 25, body.len().saturating_sub(25))?.to_vec(),
+        })),
+        // Primary keepalive: end of the WAL, send time, reply request
+        Some(b'k') => Ok(Some(ReplicationMessage::Keepalive {
+            wal_end: u64::from_be_bytes(read_bytes(body, 1, 8)?.try_into().unwrap()),
+            reply: read_bytes(body, 17, 1)?[0] == 1,
+        })),
+        _ => Ok(None),
+    }
+}
+
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    data.get(offset..offset + len)
+        .ok_or_else(|| "message is truncated".to_string())
+}
+
+/// Returns the message of an ErrorResponse.
+fn error_message(body: &[u8]) -> String {
+    // Fields are a type byte followed by a string; `M` holds the message
+    body.split(|b| *b == 0)
+        .find_map(|field| field.strip_prefix(b"M"))
+        .map(|message| String::from_utf8_lossy(message).into_owned())
+        .unwrap_or_else(|| "unknown server error".to_string())
+}
+
+/// Returns the current time in microseconds since the Postgres epoch.
+fn postgres_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH + POSTGRES_EPOCH_OFFSET)
+        .map(|elapsed| elapsed.as_micros() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_copy_data() {
+        let mut xlog = vec![b'w'];
+        xlog.extend_from_slice(&[0; 8]);
+        xlog.extend_from_slice(&7u64.to_be_bytes());
+        xlog.extend_from_slice(&[0; 8]);
+        xlog.extend_from_slice(b"B...");
+        assert_eq!(
+            parse_copy_data(&xlog).unwrap(),
+            Some(ReplicationMessage::XLogData {
+                wal_end: 7,
+                data: b"B...".to_vec()
+            })
+        );
+
+        let mut keepalive = vec![b'k'];
+        keepalive.extend_from_slice(&42u64.to_be_bytes());
+        keepalive.extend_from_slice(&0i64.to_be_bytes());
+        keepalive.push(1);
+        assert_eq!(
+            parse_copy_data(&keepalive).unwrap(),
+            Some(ReplicationMessage::Keepalive {
+                wal_end: 42,
+                reply: true
+            })
+        );
+
+        assert!(parse_copy_data(&[b'k', 0]).is_err());
+        assert_eq!(parse_copy_data(b"x").unwrap(), None);
+    }
+
+    #[test]
+    fn test_error_message() {
+        let body = b"SERROR\0C42704\0Mreplication slot \"s\" does not exist\0\0";
+        assert_eq!(error_message(body), "replication slot \"s\" does not exist");
+    }
+}
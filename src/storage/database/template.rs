@@ -0,0 +1,168 @@
+//! Query template adapter, serving a parameterized query as a virtual provider.
+//!
+//! The id of a lookup is split into the template's parameters, which are parsed into
+//! their declared types and bound to the query's placeholders. The whole result set
+//! is returned as a single record, a JSON array of rows, so it is cached, expired and
+//! invalidated like any other record.
+
+use async_trait::async_trait;
+use datafusion::common::ScalarValue;
+use datafusion::prelude::SessionContext;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::config::{TemplateConfig, TemplateParam};
+use crate::storage::database::az_delta::{query_table, read_only};
+use crate::storage::query::normalize_sql;
+use crate::storage::{DatabaseAdapter, StorageError, StorageResult};
+
+/// Query template adapter over the session of an AzDelta provider
+pub struct TemplateAdapter {
+    session: SessionContext,
+    sql: String,
+    params: Vec<TemplateParam>,
+    /// Most rows a result set may hold
+    max_rows: usize,
+}
+
+impl TemplateAdapter {
+    /// Creates the adapter, planning the query once so that errors surface at startup.
+    pub async fn new(
+        session: SessionContext,
+        template: &TemplateConfig,
+        max_rows: usize,
+    ) -> StorageResult<Self> {
+        let invalid =
+            |e: String| StorageError::ConfigError(format!("Invalid query template: {}", e));
+        let sql = normalize_sql(&template.sql).map_err(|e| invalid(e.to_string()))?;
+        session
+            .sql_with_options(&sql, read_only())
+            .await
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(Self {
+            session,
+            sql,
+            params: template.params.clone(),
+            max_rows,
+        })
+    }
+
+    /// Parses the parameter values held by an id.
+    fn bind(&self, id: &str) -> StorageResult<Vec<ScalarValue>> {
+        if self.params.is_empty() {
+            return Ok(Vec::new());
+        }
+        let values: Vec<&str> = id.splitn(self.params.len(), ':').collect();
+        if values.len() != self.params.len() {
+            return Err(StorageError::InvalidQuery(format!(
+                "expected {} parameters separated by ':', got {}",
+                self.params.len(),
+                values.len()
+            )));
+        }
+        self.params
+            .iter()
+            .zip(values)
+            .map(|(param, value)| parse_param(*param, value))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl DatabaseAdapter for TemplateAdapter {
+    async fn fetch_record(&self, entity: &str, id: &str) -> StorageResult<Vec<Value>> {
+        let params = self.bind(id)?;
+        debug!("Running query template {} for {}", entity, id);
+        // One row more than allowed tells a full result from a truncated one
+        let rows = query_table(&self.session, &self.sql, params, self.max_rows + 1).await?;
+        if rows.len() > self.max_rows {
+            return Err(StorageError::LimitExceeded(format!(
+                "{}:{} returned more than {} rows",
+                entity, id, self.max_rows
+            )));
+        }
+        // No rows means the key does not exist
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(vec![Value::Array(rows)])
+    }
+}
+
+/// Parses a parameter value into its declared type.
+fn parse_param(param: TemplateParam, value: &str) -> StorageResult<ScalarValue> {
+    let invalid = || StorageError::InvalidQuery(format!("'{}' is not a valid {:?}", value, param));
+    Ok(match param {
+        TemplateParam::String => ScalarValue::Utf8(Some(value.to_string())),
+        TemplateParam::Int => ScalarValue::Int64(Some(value.parse().map_err(|_| invalid())?)),
+        TemplateParam::Float => ScalarValue::Float64(Some(value.parse().map_err(|_| invalid())?)),
+        TemplateParam::Bool => ScalarValue::Boolean(Some(value.parse().map_err(|_| invalid())?)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::database::test_support::flights_session;
+
+    fn template(sql: &str, params: Vec<TemplateParam>) -> TemplateConfig {
+        TemplateConfig {
+            source: "flights".to_string(),
+            sql: sql.to_string(),
+            params,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_template_binds_params() {
+        let config = template(
+            "SELECT \"FLIGHT_NUMBER\" FROM flights \
+             WHERE \"ORIGIN_AIRPORT\" = $1 AND \"YEAR\" >= $2 ORDER BY \"FLIGHT_NUMBER\"",
+            vec![TemplateParam::String, TemplateParam::Int],
+        );
+        let adapter = TemplateAdapter::new(flights_session(), &config, 10)
+            .await
+            .unwrap();
+
+        let records = adapter
+            .fetch_record("by_airport", "SFO:2015")
+            .await
+            .unwrap();
+        assert_eq!(records, vec![serde_json::json!([{ "FLIGHT_NUMBER": 3 }])]);
+        assert!(
+            adapter
+                .fetch_record("by_airport", "SFO:2020")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        for id in ["SFO", "SFO:twenty"] {
+            let result = adapter.fetch_record("by_airport", id).await;
+            assert!(matches!(result, Err(StorageError::InvalidQuery(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_template_limits_rows() {
+        let config = template(
+            "SELECT * FROM flights WHERE \"YEAR\" > $1",
+            vec![TemplateParam::Int],
+        );
+        let adapter = TemplateAdapter::new(flights_session(), &config, 2)
+            .await
+            .unwrap();
+        assert_eq!(adapter.fetch_record("t", "2014").await.unwrap().len(), 1);
+        let result = adapter.fetch_record("t", "2000").await;
+        assert!(matches!(result, Err(StorageError::LimitExceeded(_))));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_template() {
+        for sql in ["DELETE FROM flights", "SELECT * FROM missing WHERE id = $1"] {
+            let config = template(sql, vec![TemplateParam::String]);
+            let result = TemplateAdapter::new(flights_session(), &config, 10).await;
+            assert!(matches!(result, Err(StorageError::ConfigError(_))));
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! Fixtures shared by the tests of the database adapters.
+
+use datafusion::arrow::array::{Int64Array, StringArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::MemTable;
+use datafusion::prelude::SessionContext;
+use std::sync::Arc;
+
+/// Returns a session with a small in-memory `flights` table registered.
+pub(crate) fn flights_session() -> SessionContext {
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("FLIGHT_NUMBER", DataType::Int64, false),
+        Field::new("YEAR", DataType::Int64, false),
+        Field::new("ORIGIN_AIRPORT", DataType::Utf8, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(Int64Array::from(vec![1, 2, 3])),
+            Arc::new(Int64Array::from(vec![2014, 2015, 2016])),
+            Arc::new(StringArray::from(vec!["LAX", "JFK", "SFO"])),
+        ],
+    )
+    .unwrap();
+    let ctx = SessionContext::new();
+    let table = MemTable::try_new(schema, vec![vec![batch]]).unwrap();
+    ctx.register_table("flights", Arc::new(table)).unwrap();
+    ctx
+}
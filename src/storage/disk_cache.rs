@@ -0,0 +1,891 @@
+//! On-disk second-tier cache.
+//!
+//! This module provides a cache adapter that keeps records in append-only segment
+//! files on local disk. It holds the records evicted from the in-memory cache and is
+//! consulted after an in-memory miss, before going to the provider.
+//!
+//! Every record is framed as `length (u32) | crc32 (u32) | JSON payload`. An
+//! in-memory index maps keys to their latest location. When the total size exceeds
+//! the configured cap, the oldest segments are dropped as a whole.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{debug, info, warn};
+
+use crate::config::DiskCacheConfig;
+use crate::storage::scan::{ScanIndex, ScanKey};
+use crate::storage::{
+    CacheAdapter, CacheUsage, CachedRecord, IdPredicate, StorageError, StorageResult,
+};
+
+/// Size of the frame header in bytes
+const FRAME_HEADER_LEN: u64 = 8;
+/// File extension of segment files
+const SEGMENT_EXTENSION: &str = "seg";
+
+/// Cache key type combining entity and id
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct CacheKey {
+    entity: String,
+    id: String,
+}
+
+/// A record as stored on disk; a missing value marks a removed key
+#[derive(Debug, Serialize, Deserialize)]
+struct DiskRecord {
+    entity: String,
+    id: String,
+    value: Option<Value>,
+    /// Expiration time in unix milliseconds, `None` if the record never expires
+    expires_at_ms: Option<u64>,
+}
+
+/// Location of the latest version of a record
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u64,
+    expires_at_ms: Option<u64>,
+}
+
+impl Location {
+    fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at_ms.is_some_and(|at| at <= now_ms)
+    }
+}
+
+/// Mutable state of the disk cache, guarded by a mutex
+struct DiskState {
+    dir: PathBuf,
+    index: HashMap<CacheKey, Location>,
+    /// Keys of the index in scan order
+    scan: ScanIndex,
+    /// Segment ids mapped to their size in bytes
+    segments: BTreeMap<u64, u64>,
+    active: File,
+    active_id: u64,
+    total_bytes: u64,
+    max_bytes: u64,
+    segment_bytes: u64,
+}
+
+/// Tickets of the evicted records queued for the disk, by key
+#[derive(Default)]
+struct Tickets {
+    last: u64,
+    queued: HashMap<CacheKey, u64>,
+}
+
+/// A record evicted from memory, waiting to be written to disk
+pub struct Evicted {
+    pub record: CachedRecord,
+    ticket: u64,
+}
+
+/// Queue moving records evicted from memory to the disk cache.
+///
+/// Each queued record holds a ticket for its key. Invalidating the key on disk voids
+/// the ticket, so a record evicted before an invalidation is not written after it.
+#[derive(Clone)]
+pub struct Overflow {
+    sender: UnboundedSender<Evicted>,
+    tickets: Arc<Mutex<Tickets>>,
+}
+
+impl Overflow {
+    /// Queues an evicted record for the disk cache.
+    pub fn send(&self, record: CachedRecord) {
+        let ticket = {
+            let mut tickets = self.tickets.lock().unwrap();
+            tickets.last += 1;
+            let ticket = tickets.last;
+            let key = CacheKey {
+                entity: record.entity.clone(),
+                id: record.id.clone(),
+            };
+            tickets.queued.insert(key, ticket);
+            ticket
+        };
+        let _ = self.sender.send(Evicted { record, ticket });
+    }
+}
+
+/// Second-tier cache adapter storing records on local disk.
+pub struct DiskCache {
+    state: Arc<Mutex<DiskState>>,
+    /// Tickets of the queued evicted records, checked and voided with `state` locked
+    tickets: Arc<Mutex<Tickets>>,
+    /// Time to live of records inserted without an explicit TTL
+    default_ttl: Duration,
+}
+
+impl DiskCache {
+    /// Opens the disk cache, rebuilding the index from existing segments.
+    pub fn open(config: &DiskCacheConfig) -> StorageResult<Self> {
+        let dir = PathBuf::from(&config.path);
+        std::fs::create_dir_all(&dir).map_err(disk_error)?;
+
+        let mut segments = BTreeMap::new();
+        for entry in std::fs::read_dir(&dir).map_err(disk_error)? {
+            let path = entry.map_err(disk_error)?.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION)
+                && let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                segments.insert(id, 0);
+            }
+        }
+
+        let mut index = HashMap::new();
+        for (id, size) in segments.iter_mut() {
+            *size = scan_segment(&segment_path(&dir, *id), *id, &mut index)?;
+        }
+        let now_ms = unix_millis(SystemTime::now());
+        index.retain(|_, location: &mut Location| !location.is_expired(now_ms));
+        let mut scan = ScanIndex::default();
+        for key in index.keys() {
+            scan.insert(&key.entity, &key.id, 0);
+        }
+
+        // Always append to a fresh segment so a torn tail is never extended
+        let active_id = segments.keys().next_back().map_or(0, |id| id + 1);
+        let active = open_segment(&dir, active_id)?;
+        segments.insert(active_id, 0);
+        let total_bytes = segments.values().sum();
+
+        info!(
+            "Opened disk cache at {} with {} records in {} segments",
+            dir.display(),
+            index.len(),
+            segments.len()
+        );
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(DiskState {
+                dir,
+                index,
+                scan,
+                segments,
+                active,
+                active_id,
+                total_bytes,
+                max_bytes: config.max_bytes,
+                segment_bytes: config.segment_bytes.max(1),
+            })),
+            tickets: Arc::default(),
+            default_ttl: Duration::from_secs(config.ttl_seconds),
+        })
+    }
+
+    /// Creates the queue feeding records evicted from memory to `write_evicted`.
+    pub fn overflow(&self) -> (Overflow, UnboundedReceiver<Evicted>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let overflow = Overflow {
+            sender,
+            tickets: Arc::clone(&self.tickets),
+        };
+        (overflow, receiver)
+    }
+
+    /// Writes an evicted record unless its key was invalidated since it was queued.
+    ///
+    /// The record never outlives the disk cache TTL. Returns whether it was written.
+    pub async fn write_evicted(&self, evicted: Evicted) -> StorageResult<bool> {
+        let Evicted { record, ticket } = evicted;
+        let ttl = record.ttl.map_or(self.default_ttl, |ttl| ttl.min(self.default_ttl));
+        let record = DiskRecord {
+            entity: record.entity,
+            id: record.id,
+            value: Some(record.value),
+            expires_at_ms: Some(expiration_ms(ttl)?),
+        };
+        let tickets = Arc::clone(&self.tickets);
+        self.with_state(move |state| {
+            {
+                let mut tickets = tickets.lock().unwrap();
+                let key = CacheKey {
+                    entity: record.entity.clone(),
+                    id: record.id.clone(),
+                };
+                // A voided or superseded ticket means the record is out of date
+                if tickets.queued.get(&key) != Some(&ticket) {
+                    return Ok(false);
+                }
+                tickets.queued.remove(&key);
+            }
+            state.append(record)?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Removes a record and returns it with its remaining lifetime if it was present and live.
+    ///
+    /// Used to promote records back into memory, so each key lives in one tier only.
+    pub async fn take_record(&self, entity: &str, id: &str) -> StorageResult<CachedRecord> {
+        let key = CacheKey {
+            entity: entity.to_string(),
+            id: id.to_string(),
+        };
+        let missing = format!("Cache Key {:?} not found in disk cache", key);
+        self.with_state(move |state| {
+            let Some(value) = state.read(&key)? else {
+                return Err(StorageError::RecordNotFoundInCache(missing));
+            };
+            let expires_at_ms = state.index[&key].expires_at_ms;
+            state.append(DiskRecord {
+                entity: key.entity.clone(),
+                id: key.id.clone(),
+                value: None,
+                expires_at_ms: None,
+            })?;
+            Ok(CachedRecord {
+                entity: key.entity,
+                id: key.id,
+                value,
+                ttl: expires_at_ms.map(|at| remaining_ttl(at, SystemTime::now())),
+            })
+        })
+        .await
+    }
+
+    /// Runs a blocking operation on the disk state.
+    async fn with_state<T, F>(&self, f: F) -> StorageResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut DiskState) -> StorageResult<T> + Send + 'static,
+    {
+        let state = Arc::clone(&self.state);
+        tokio::task::spawn_blocking(move || f(&mut state.lock().unwrap()))
+            .await
+            .map_err(disk_error)?
+    }
+}
+
+impl DiskState {
+    /// Appends a record to the active segment and updates the index.
+    fn append(&mut self, record: DiskRecord) -> StorageResult<()> {
+        let payload = serde_json::to_vec(&record).map_err(disk_error)?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN as usize + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        let offset = self.segments[&self.active_id];
+        self.active.write_all(&frame).map_err(disk_error)?;
+        *self.segments.get_mut(&self.active_id).unwrap() += frame.len() as u64;
+        self.total_bytes += frame.len() as u64;
+
+        let key = CacheKey {
+            entity: record.entity,
+            id: record.id,
+        };
+        if record.value.is_some() {
+            self.scan.insert(&key.entity, &key.id, 0);
+            self.index.insert(
+                key,
+                Location {
+                    segment: self.active_id,
+                    offset: offset + FRAME_HEADER_LEN,
+                    len: payload.len() as u64,
+                    expires_at_ms: record.expires_at_ms,
+                },
+            );
+        } else {
+            self.scan.remove(&key.entity, &key.id, 0);
+            self.index.remove(&key);
+        }
+
+        if self.segments[&self.active_id] >= self.segment_bytes {
+            self.rotate()?;
+        }
+        self.enforce_size_cap();
+        Ok(())
+    }
+
+    /// Starts a new active segment.
+    fn rotate(&mut self) -> StorageResult<()> {
+        self.active_id += 1;
+        self.active = open_segment(&self.dir, self.active_id)?;
+        self.segments.insert(self.active_id, 0);
+        Ok(())
+    }
+
+    /// Deletes every segment, continuing in a fresh empty one.
+    fn clear(&mut self) -> StorageResult<()> {
+        self.rotate()?;
+        let active_id = self.active_id;
+        for segment in self.segments.keys().filter(|id| **id != active_id) {
+            if let Err(e) = std::fs::remove_file(segment_path(&self.dir, *segment)) {
+                warn!("Failed to remove disk cache segment {}: {}", segment, e);
+            }
+        }
+        self.segments.retain(|id, _| *id == active_id);
+        self.index.clear();
+        self.scan.clear();
+        self.total_bytes = 0;
+        debug!("Cleared the disk cache");
+        Ok(())
+    }
+
+    /// Drops the oldest segments until the total size is within the cap.
+    fn enforce_size_cap(&mut self) {
+        while self.total_bytes > self.max_bytes && self.segments.len() > 1 {
+            let (oldest, size) = self.segments.pop_first().unwrap();
+            self.total_bytes -= size;
+            self.index.retain(|key, location| {
+                let dropped = location.segment == oldest;
+                if dropped {
+                    self.scan.remove(&key.entity, &key.id, 0);
+                }
+                !dropped
+            });
+            if let Err(e) = std::fs::remove_file(segment_path(&self.dir, oldest)) {
+                warn!("Failed to remove disk cache segment {}: {}", oldest, e);
+            }
+            debug!("Dropped disk cache segment {} ({} bytes)", oldest, size);
+        }
+    }
+
+    /// Reads the live record stored under a key.
+    fn read(&mut self, key: &CacheKey) -> StorageResult<Option<Value>> {
+        let Some(location) = self.index.get(key).copied() else {
+            return Ok(None);
+        };
+        if location.is_expired(unix_millis(SystemTime::now())) {
+            self.scan.remove(&key.entity, &key.id, 0);
+            self.index.remove(key);
+            return Ok(None);
+        }
+
+        let mut file = File::open(segment_path(&self.dir, location.segment)).map_err(disk_error)?;
+        file.seek(SeekFrom::Start(location.offset))
+            .map_err(disk_error)?;
+        let mut payload = vec![0; location.len as usize];
+        file.read_exact(&mut payload).map_err(disk_error)?;
+
+        let record: DiskRecord = serde_json::from_slice(&payload).map_err(disk_error)?;
+        Ok(record.value)
+    }
+}
+
+#[async_trait]
+impl CacheAdapter for DiskCache {
+    async fn get_record(&self, entity: &str, id: &str) -> StorageResult<Value> {
+        let key = CacheKey {
+            entity: entity.to_string(),
+            id: id.to_string(),
+        };
+        let missing = format!("Cache Key {:?} not found in disk cache", key);
+        self.with_state(move |state| state.read(&key))
+            .await?
+            .ok_or(StorageError::RecordNotFoundInCache(missing))
+    }
+
+    async fn get_stale_record(&self, entity: &str, id: &str) -> StorageResult<Value> {
+        Err(StorageError::RecordNotFoundInCache(format!(
+            "The disk cache keeps no stale entry for {}:{}",
+            entity, id
+        )))
+    }
+
+    async fn set_record(&self, entity: &str, id: &str, data: &Value) -> StorageResult<()> {
+        self.set_record_with_ttl(entity, id, data, Some(self.default_ttl))
+            .await
+    }
+
+    async fn set_record_with_ttl(
+        &self,
+        entity: &str,
+        id: &str,
+        data: &Value,
+        ttl: Option<Duration>,
+    ) -> StorageResult<()> {
+        let record = DiskRecord {
+            entity: entity.to_string(),
+            id: id.to_string(),
+            value: Some(data.clone()),
+            expires_at_ms: ttl.map(expiration_ms).transpose()?,
+        };
+        self.with_state(move |state| state.append(record)).await
+    }
+
+    async fn exists(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        match self.get_record(entity, id).await {
+            Ok(_) => Ok(true),
+            Err(StorageError::RecordNotFoundInCache(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn entries(&self) -> StorageResult<Vec<CachedRecord>> {
+        self.with_state(|state| {
+            let now = SystemTime::now();
+            let keys: Vec<_> = state.index.keys().cloned().collect();
+            let mut records = Vec::with_capacity(keys.len());
+            for key in keys {
+                let expires_at_ms = state.index[&key].expires_at_ms;
+                if let Some(value) = state.read(&key)? {
+                    records.push(CachedRecord {
+                        entity: key.entity,
+                        id: key.id,
+                        value,
+                        ttl: expires_at_ms.map(|at| remaining_ttl(at, now)),
+                    });
+                }
+            }
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn usage(&self) -> StorageResult<CacheUsage> {
+        self.with_state(|state| {
+            Ok(CacheUsage {
+                entries: state.index.len() as u64,
+                weighted_size: state.total_bytes,
+            })
+        })
+        .await
+    }
+
+    async fn scan(
+        &self,
+        entity: Option<&str>,
+        cursor: u64,
+        count: usize,
+    ) -> StorageResult<Vec<ScanKey>> {
+        let entity = entity.map(str::to_string);
+        self.with_state(move |state| {
+            let now_ms = unix_millis(SystemTime::now());
+            Ok(state.scan.page(entity.as_deref(), cursor, count, |key| {
+                let key = CacheKey {
+                    entity: key.entity.clone(),
+                    id: key.id.clone(),
+                };
+                state
+                    .index
+                    .get(&key)
+                    .is_some_and(|location| !location.is_expired(now_ms))
+            }))
+        })
+        .await
+    }
+
+    async fn ttl(&self, entity: &str, id: &str) -> StorageResult<Option<Option<Duration>>> {
+        let key = CacheKey {
+            entity: entity.to_string(),
+            id: id.to_string(),
+        };
+        self.with_state(move |state| {
+            let now = SystemTime::now();
+            Ok(state
+                .index
+                .get(&key)
+                .filter(|location| !location.is_expired(unix_millis(now)))
+                .map(|location| location.expires_at_ms.map(|at| remaining_ttl(at, now))))
+        })
+        .await
+    }
+
+    async fn expire(&self, entity: &str, id: &str, ttl: Option<Duration>) -> StorageResult<bool> {
+        let expires_at_ms = ttl.map(expiration_ms).transpose()?;
+        let key = CacheKey {
+            entity: entity.to_string(),
+            id: id.to_string(),
+        };
+        self.with_state(move |state| {
+            let Some(value) = state.read(&key)? else {
+                return Ok(false);
+            };
+            state.append(DiskRecord {
+                entity: key.entity,
+                id: key.id,
+                value: Some(value),
+                expires_at_ms,
+            })?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn set_missing(&self, _entity: &str, _id: &str) -> StorageResult<()> {
+        // Missing keys are only remembered in memory
+        Ok(())
+    }
+
+    async fn is_missing(&self, _entity: &str, _id: &str) -> StorageResult<bool> {
+        Ok(false)
+    }
+
+    async fn invalidate(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        let key = CacheKey {
+            entity: entity.to_string(),
+            id: id.to_string(),
+        };
+        let tickets = Arc::clone(&self.tickets);
+        self.with_state(move |state| {
+            // An evicted record still queued must not land on disk after this call
+            tickets.lock().unwrap().queued.remove(&key);
+            let now_ms = unix_millis(SystemTime::now());
+            let live = state
+                .index
+                .get(&key)
+                .is_some_and(|location| !location.is_expired(now_ms));
+            if live {
+                state.append(DiskRecord {
+                    entity: key.entity,
+                    id: key.id,
+                    value: None,
+                    expires_at_ms: None,
+                })?;
+            }
+            Ok(live)
+        })
+        .await
+    }
+
+    async fn invalidate_matching(
+        &self,
+        entity: &str,
+        matches: IdPredicate,
+    ) -> StorageResult<usize> {
+        let entity = entity.to_string();
+        let tickets = Arc::clone(&self.tickets);
+        self.with_state(move |state| {
+            // Evicted records still queued must not land on disk after this call
+            tickets
+                .lock()
+                .unwrap()
+                .queued
+                .retain(|key, _| key.entity != entity || !matches(&key.id));
+
+            let now_ms = unix_millis(SystemTime::now());
+            let keys: Vec<_> = state
+                .index
+                .iter()
+                .filter(|(key, location)| {
+                    key.entity == entity && matches(&key.id) && !location.is_expired(now_ms)
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            let removed = keys.len();
+            for key in keys {
+                state.append(DiskRecord {
+                    entity: key.entity,
+                    id: key.id,
+                    value: None,
+                    expires_at_ms: None,
+                })?;
+            }
+            Ok(removed)
+        })
+        .await
+    }
+
+    async fn clear(&self) -> StorageResult<()> {
+        let tickets = Arc::clone(&self.tickets);
+        self.with_state(move |state| {
+            tickets.lock().unwrap().queued.clear();
+            state.clear()
+        })
+        .await
+    }
+}
+
+/// Scans a segment, updating the index with the records it holds.
+///
+/// Returns the number of valid bytes; a torn or corrupt tail is ignored.
+fn scan_segment(
+    path: &Path,
+    segment: u64,
+    index: &mut HashMap<CacheKey, Location>,
+) -> StorageResult<u64> {
+    let data = std::fs::read(path).map_err(disk_error)?;
+    let mut offset = 0u64;
+    while offset + FRAME_HEADER_LEN <= data.len() as u64 {
+        let start = offset as usize;
+        let len = u32::from_le_bytes(data[start..start + 4].try_into().unwrap()) as u64;
+        let checksum = u32::from_le_bytes(data[start + 4..start + 8].try_into().unwrap());
+        let payload_start = offset + FRAME_HEADER_LEN;
+        let Some(payload) = data.get(payload_start as usize..(payload_start + len) as usize) else {
+            break;
+        };
+        if crc32fast::hash(payload) != checksum {
+            warn!(
+                "Corrupt record in disk cache segment {} at offset {}, ignoring the rest",
+                path.display(),
+                offset
+            );
+            break;
+        }
+        let Ok(record) = serde_json::from_slice::<DiskRecord>(payload) else {
+            break;
+        };
+
+        let key = CacheKey {
+            entity: record.entity,
+            id: record.id,
+        };
+        if record.value.is_some() {
+            index.insert(
+                key,
+                Location {
+                    segment,
+                    offset: payload_start,
+                    len,
+                    expires_at_ms: record.expires_at_ms,
+                },
+            );
+        } else {
+            index.remove(&key);
+        }
+        offset = payload_start + len;
+    }
+    Ok(offset)
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn open_segment(dir: &Path, id: u64) -> StorageResult<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, id))
+        .map_err(disk_error)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Returns when a time to live starting now ends, in unix milliseconds.
+fn expiration_ms(ttl: Duration) -> StorageResult<u64> {
+    SystemTime::now()
+        .checked_add(ttl)
+        .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+        .and_then(|at| u64::try_from(at.as_millis()).ok())
+        .ok_or_else(|| StorageError::InvalidExpireTime(format!("{}s from now", ttl.as_secs())))
+}
+
+/// Returns the time left until an expiration time in unix milliseconds.
+fn remaining_ttl(expires_at_ms: u64, now: SystemTime) -> Duration {
+    (UNIX_EPOCH + Duration::from_millis(expires_at_ms))
+        .duration_since(now)
+        .unwrap_or_default()
+}
+
+fn disk_error(e: impl std::fmt::Display) -> StorageError {
+    StorageError::CacheError(format!("Disk cache error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(name: &str, max_bytes: u64, segment_bytes: u64) -> DiskCacheConfig {
+        let path = std::env::temp_dir().join(format!("prism_l2_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        DiskCacheConfig {
+            path: path.to_string_lossy().into_owned(),
+            max_bytes,
+            segment_bytes,
+            ttl_seconds: 60,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_get_and_take() {
+        let config = config("basic", 1 << 20, 1 << 16);
+        let cache = DiskCache::open(&config).unwrap();
+
+        cache
+            .set_record("users", "1", &json!({ "name": "John" }))
+            .await
+            .unwrap();
+        assert!(cache.exists("users", "1").await.unwrap());
+        assert!(!cache.exists("users", "2").await.unwrap());
+
+        let record = cache.take_record("users", "1").await.unwrap();
+        assert_eq!(record.value["name"], "John");
+        assert!(!cache.exists("users", "1").await.unwrap());
+
+        // The remaining lifetime comes along with the record
+        let ttl = Duration::from_secs(30);
+        cache
+            .set_record_with_ttl("users", "3", &json!({}), Some(ttl))
+            .await
+            .unwrap();
+        let record = cache.take_record("users", "3").await.unwrap();
+        assert!(record.ttl.is_some_and(|left| left <= ttl && left > ttl / 2));
+
+        cache.set_record("users", "2", &json!({})).await.unwrap();
+        cache.set_record("users", "3", &json!({})).await.unwrap();
+        let removed = cache.invalidate_matching("users", Arc::new(|id| id == "2")).await.unwrap();
+        assert_eq!(removed, 1);
+        assert!(!cache.exists("users", "2").await.unwrap());
+        assert!(cache.exists("users", "3").await.unwrap());
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalidation_voids_queued_evictions() {
+        let config = config("overflow", 1 << 20, 1 << 16);
+        let cache = DiskCache::open(&config).unwrap();
+        let (overflow, mut evicted) = cache.overflow();
+        let record = |id: &str| CachedRecord {
+            entity: "users".to_string(),
+            id: id.to_string(),
+            value: json!({ "id": id }),
+            ttl: None,
+        };
+
+        // Evict, invalidate, drain the queue, then read
+        overflow.send(record("1"));
+        overflow.send(record("2"));
+        overflow.send(record("3"));
+        assert!(!cache.invalidate("users", "1").await.unwrap());
+        cache.invalidate_matching("users", Arc::new(|id| id == "2")).await.unwrap();
+        while let Ok(queued) = evicted.try_recv() {
+            cache.write_evicted(queued).await.unwrap();
+        }
+        assert!(!cache.exists("users", "1").await.unwrap());
+        assert!(!cache.exists("users", "2").await.unwrap());
+        assert_eq!(cache.get_record("users", "3").await.unwrap()["id"], "3");
+        // Records evicted without a TTL get the disk cache one
+        let ttl = cache.ttl("users", "3").await.unwrap().flatten();
+        assert!(ttl.is_some_and(|ttl| ttl <= Duration::from_secs(60)));
+
+        // Of two evictions of a key, only the latest is written
+        overflow.send(CachedRecord {
+            value: json!({ "v": 1 }),
+            ..record("4")
+        });
+        overflow.send(CachedRecord {
+            value: json!({ "v": 2 }),
+            ..record("4")
+        });
+        let first = evicted.try_recv().unwrap();
+        let second = evicted.try_recv().unwrap();
+        assert!(!cache.write_evicted(first).await.unwrap());
+        assert!(cache.write_evicted(second).await.unwrap());
+        assert_eq!(cache.get_record("users", "4").await.unwrap()["v"], 2);
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_records_are_not_served() {
+        let config = config("ttl", 1 << 20, 1 << 16);
+        let cache = DiskCache::open(&config).unwrap();
+
+        cache
+            .set_record_with_ttl("users", "1", &json!({}), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.get_record("users", "1").await.is_err());
+
+        cache.set_record("users", "2", &json!({})).await.unwrap();
+        let result = cache.expire("users", "2", Some(Duration::MAX)).await;
+        assert!(matches!(result, Err(StorageError::InvalidExpireTime(_))));
+        assert!(cache.expire("users", "2", None).await.unwrap());
+        assert_eq!(cache.ttl("users", "2").await.unwrap(), Some(None));
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_size_cap_drops_oldest_segments() {
+        let config = config("cap", 2048, 512);
+        let cache = DiskCache::open(&config).unwrap();
+
+        for i in 0..50 {
+            let id = i.to_string();
+            cache
+                .set_record(
+                    "users",
+                    &id,
+                    &json!({ "id": id, "padding": "x".repeat(64) }),
+                )
+                .await
+                .unwrap();
+        }
+
+        // The oldest records were dropped, the newest are still there
+        assert!(!cache.exists("users", "0").await.unwrap());
+        assert!(cache.exists("users", "49").await.unwrap());
+        let total: u64 = std::fs::read_dir(&config.path)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(total <= 2048);
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen_rebuilds_index() {
+        let config = config("reopen", 1 << 20, 1 << 16);
+        {
+            let cache = DiskCache::open(&config).unwrap();
+            cache
+                .set_record("users", "1", &json!({ "v": 1 }))
+                .await
+                .unwrap();
+            cache
+                .set_record("users", "1", &json!({ "v": 2 }))
+                .await
+                .unwrap();
+            cache
+                .set_record("users", "2", &json!({ "v": 1 }))
+                .await
+                .unwrap();
+            cache.take_record("users", "2").await.unwrap();
+        }
+
+        // Append a torn frame to the last segment
+        let last = std::fs::read_dir(&config.path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .max()
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(last).unwrap();
+        file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+
+        let cache = DiskCache::open(&config).unwrap();
+        assert_eq!(cache.get_record("users", "1").await.unwrap()["v"], 2);
+        assert!(!cache.exists("users", "2").await.unwrap());
+        assert_eq!(cache.entries().await.unwrap().len(), 1);
+        let keys = cache.scan(None, 0, 10).await.unwrap();
+        assert_eq!(keys, vec![ScanKey::new("users", "1")]);
+
+        // Clearing deletes the segments rather than writing tombstones
+        cache.clear().await.unwrap();
+        assert!(cache.entries().await.unwrap().is_empty());
+        assert!(cache.scan(None, 0, 10).await.unwrap().is_empty());
+        let files: Vec<_> = std::fs::read_dir(&config.path).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let cache = DiskCache::open(&config).unwrap();
+        assert!(!cache.exists("users", "1").await.unwrap());
+
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+}
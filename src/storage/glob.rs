@@ -0,0 +1,101 @@
+//! Redis-style glob patterns, used to select cached records by id.
+//!
+//! Supports `*`, `?`, character classes such as `[abc]`, `[a-z]` and `[^a]`, and `\`
+//! to escape a special character.
+
+/// Returns whether `text` matches the glob `pattern`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it is matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match_class(&pattern, p, text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(c) => (*c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            // Let the last `*` absorb one more character and retry
+            (None, Some((star, matched))) => {
+                backtrack = Some((star, matched + 1));
+                p = star;
+                t = matched + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches a character against the class starting at `pattern[start]`.
+///
+/// Returns the pattern position after the class if it matches.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut i = start + 1;
+    let negated = pattern.get(i) == Some(&'^');
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != ']' {
+        if pattern[i] == '\\' && i + 1 < pattern.len() {
+            i += 1;
+            matched |= pattern[i] == c;
+            i += 1;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= low <= c && c <= high;
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    // An unterminated class runs to the end of the pattern
+    (matched != negated).then_some((i + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("12*", "1234"));
+        assert!(!glob_match("12*", "0123"));
+        assert!(glob_match("*34", "1234"));
+        assert!(glob_match("1?3*", "1234"));
+        assert!(!glob_match("1?3", "13"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("id[0-9]", "id7"));
+        assert!(!glob_match("id[0-9]", "idx"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+}
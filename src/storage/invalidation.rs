@@ -0,0 +1,108 @@
+//! Cache invalidation driven by changes in the backing tables.
+
+use serde_json::Value;
+
+/// Cached records to drop after a provider's data changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Invalidation {
+    /// Every cached record of the provider.
+    All,
+    /// Records whose key falls in one of the ranges.
+    KeyRanges(Vec<KeyRange>),
+    /// Records with one of the keys.
+    Keys(Vec<String>),
+}
+
+/// A change pushed by a provider, applied to its cached records.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheUpdate {
+    /// Drop the matching cached records.
+    Invalidate(Invalidation),
+    /// Replace the cached record of a key with its new value.
+    Upsert { key: String, value: Value },
+}
+
+impl Invalidation {
+    /// Returns whether the record with the given key is affected.
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Invalidation::All => true,
+            Invalidation::KeyRanges(ranges) => ranges.iter().any(|range| range.contains(key)),
+            Invalidation::Keys(keys) => keys.iter().any(|k| k == key),
+        }
+    }
+}
+
+/// Inclusive range of key values, as found in file statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRange {
+    pub min: Value,
+    pub max: Value,
+}
+
+impl KeyRange {
+    /// Returns whether a key, as used in cache ids, may fall in the range.
+    ///
+    /// Bounds of an unexpected type match every key.
+    pub fn contains(&self, key: &str) -> bool {
+        match (&self.min, &self.max) {
+            (Value::Number(min), Value::Number(max)) => {
+                let (Some(min), Some(max)) = (min.as_f64(), max.as_f64()) else {
+                    return true;
+                };
+                key.parse::<f64>()
+                    .is_ok_and(|key| min <= key && key <= max)
+            }
+            (Value::String(min), Value::String(max)) => {
+                // String maximums may be truncated, so keys extending the bound match too
+                min.as_str() <= key && (key <= max.as_str() || key.starts_with(max.as_str()))
+            }
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_numeric_key_range() {
+        let range = KeyRange {
+            min: json!(100),
+            max: json!(200),
+        };
+        assert!(range.contains("100"));
+        assert!(range.contains("150"));
+        assert!(!range.contains("201"));
+        assert!(!range.contains("abc"));
+    }
+
+    #[test]
+    fn test_string_key_range() {
+        let range = KeyRange {
+            min: json!("b"),
+            max: json!("d"),
+        };
+        assert!(range.contains("c"));
+        assert!(range.contains("dz"));
+        assert!(!range.contains("a"));
+        assert!(!range.contains("e"));
+    }
+
+    #[test]
+    fn test_invalidation_matches() {
+        let ranges = Invalidation::KeyRanges(vec![KeyRange {
+            min: json!(1),
+            max: json!(5),
+        }]);
+        assert!(ranges.matches("3"));
+        assert!(!ranges.matches("6"));
+        assert!(Invalidation::All.matches("6"));
+
+        let keys = Invalidation::Keys(vec!["7".to_string()]);
+        assert!(keys.matches("7"));
+        assert!(!keys.matches("70"));
+    }
+}
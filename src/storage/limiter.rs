@@ -0,0 +1,224 @@
+//! Concurrency and rate limiting for provider calls.
+//!
+//! Each provider gets a limiter combining a semaphore bounding the number of
+//! in-flight backend calls, a bounded wait queue in front of it and a token
+//! bucket spacing out calls to the backend.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::LimitsConfig;
+use crate::storage::{StorageError, StorageResult};
+
+/// Token bucket rate limiter.
+///
+/// Tokens may go negative: every caller reserves a token and is told how long to
+/// wait for it, which spaces out queued callers at the configured rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate_per_second: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Creates a full token bucket.
+    pub fn new(rate_per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate_per_second,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Reserves a token and returns how long the caller has to wait for it.
+    pub fn reserve(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let (tokens, last) = *state;
+        let refilled = (tokens + (now - last).as_secs_f64() * self.rate_per_second).min(self.burst);
+        let remaining = refilled - 1.0;
+        *state = (remaining, now);
+
+        if remaining >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-remaining / self.rate_per_second)
+        }
+    }
+}
+
+/// Place in the wait queue, left on drop.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Permit for a single backend call, released on drop.
+pub struct LimiterPermit<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+/// Limits concurrent and per-second calls into a provider.
+#[derive(Debug)]
+pub struct ProviderLimiter {
+    /// Bounds the number of in-flight calls, if configured
+    semaphore: Option<Semaphore>,
+    /// Number of permits of the semaphore
+    max_in_flight: usize,
+    /// Maximum number of calls waiting for a semaphore permit
+    max_queued: usize,
+    /// Number of calls currently waiting for a semaphore permit
+    queued: AtomicUsize,
+    /// Spaces out backend calls, if configured
+    bucket: Option<TokenBucket>,
+}
+
+impl ProviderLimiter {
+    /// Creates a limiter from the provider limits configuration.
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            semaphore: (config.max_in_flight > 0).then(|| Semaphore::new(config.max_in_flight)),
+            max_in_flight: config.max_in_flight,
+            max_queued: config.max_queued,
+            queued: AtomicUsize::new(0),
+            bucket: (config.rate_per_second > 0.0)
+                .then(|| TokenBucket::new(config.rate_per_second, config.burst)),
+        }
+    }
+
+    /// Waits for a free call slot and a rate limit token.
+    ///
+    /// Fails with `StorageError::Busy` if all slots are taken and the wait queue is full.
+    /// Cancel safe: a caller dropped while waiting leaves the queue.
+    pub async fn acquire(&self, provider_name: &str) -> StorageResult<LimiterPermit<'_>> {
+        let permit = match &self.semaphore {
+            Some(semaphore) => Some(match semaphore.try_acquire() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    if self.queued.fetch_add(1, Ordering::AcqRel) >= self.max_queued {
+                        self.queued.fetch_sub(1, Ordering::AcqRel);
+                        return Err(StorageError::Busy(format!(
+                            "provider {} is at capacity ({} in flight, {} queued)",
+                            provider_name,
+                            self.in_flight(),
+                            self.max_queued
+                        )));
+                    }
+                    let _queued = Queued(&self.queued);
+                    semaphore
+                        .acquire()
+                        .await
+                        .map_err(|e| StorageError::DatabaseError(e.to_string()))?
+                }
+            }),
+            None => None,
+        };
+
+        if let Some(bucket) = &self.bucket {
+            let delay = bucket.reserve();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        Ok(LimiterPermit { _permit: permit })
+    }
+
+    /// Returns the number of calls currently holding a slot.
+    pub fn in_flight(&self) -> usize {
+        // Without a semaphore nothing is tracked
+        self.semaphore.as_ref().map_or(0, |semaphore| {
+            self.max_in_flight
+                .saturating_sub(semaphore.available_permits())
+        })
+    }
+
+    /// Returns the number of calls waiting for a slot.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn limits(max_in_flight: usize, max_queued: usize) -> LimitsConfig {
+        LimitsConfig {
+            max_in_flight,
+            max_queued,
+            rate_per_second: 0.0,
+            burst: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_when_queue_is_full() {
+        let limiter = Arc::new(ProviderLimiter::new(&limits(1, 1)));
+
+        let held = limiter.acquire("flights").await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+
+        // The second caller waits in the queue
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            tokio::spawn(async move { limiter.acquire("flights").await.is_ok() })
+        };
+        while limiter.queued() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // The third caller finds the queue full
+        match limiter.acquire("flights").await {
+            Err(StorageError::Busy(msg)) => assert!(msg.contains("flights")),
+            _ => panic!("Expected Busy"),
+        }
+
+        drop(held);
+        assert!(waiter.await.unwrap());
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_leaves_queue() {
+        let limiter = ProviderLimiter::new(&limits(1, 1));
+        let _held = limiter.acquire("flights").await.unwrap();
+
+        let waited =
+            tokio::time::timeout(Duration::from_millis(10), limiter.acquire("flights")).await;
+        assert!(waited.is_err());
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unlimited_by_default() {
+        let limiter = ProviderLimiter::new(&limits(0, 0));
+
+        let _a = limiter.acquire("products").await.unwrap();
+        let _b = limiter.acquire("products").await.unwrap();
+        assert_eq!(limiter.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_token_bucket_spaces_out_calls() {
+        let bucket = TokenBucket::new(10.0, 2);
+
+        // The burst is available immediately
+        assert_eq!(bucket.reserve(), Duration::ZERO);
+        assert_eq!(bucket.reserve(), Duration::ZERO);
+
+        // Further calls wait roughly one token interval each
+        let first = bucket.reserve();
+        let second = bucket.reserve();
+        assert!(first > Duration::from_millis(80) && first <= Duration::from_millis(100));
+        assert!(second > first);
+    }
+}
@@ -4,18 +4,52 @@
 //! from different storage backends.
 
 pub mod database;
+pub mod disk_cache;
+pub mod glob;
+pub mod invalidation;
+pub mod limiter;
 pub mod moka_cache;
+pub mod preload;
+pub mod provider;
+pub mod query;
+pub mod resilience;
+pub mod scan;
+pub mod snapshot;
+pub mod time_travel;
+pub mod write_behind;
 
 use async_trait::async_trait;
-use serde_json::{Value, json};
+use futures::StreamExt;
+use datafusion::execution::SendableRecordBatchStream;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use thiserror::Error;
 use tracing::{debug, info, trace, warn};
 use std::collections::HashMap;
 
-use crate::config::{AppConfig, DataProviderConfig};
-use database::{DatabaseType, create_database};
+use crate::config::{AppConfig, DataProviderConfig, DatabaseProvider};
+use crate::stats::Stats;
+use database::{DatabaseType, TemplateAdapter, create_database};
+use disk_cache::DiskCache;
+use invalidation::{CacheUpdate, Invalidation};
 use moka_cache::MokaBasedCache;
+use provider::{Provider, ProviderGauges, ProviderReadiness, ProviderStatus};
+use resilience::backoff_delay;
+use scan::ScanKey;
+use time_travel::{AsOf, split_provider, versioned_entity};
+
+/// Delays between two attempts to flush queued writes
+const WRITE_BEHIND_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const WRITE_BEHIND_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Number of keys KEYS reads at a time while matching
+const KEYS_PAGE_SIZE: usize = 1000;
+/// Interval between two runs of the cache's pending bookkeeping
+const CACHE_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of records fetched concurrently by adapters without a batched lookup
+const FETCH_CONCURRENCY: usize = 16;
 
 /// Type alias for storage results.
 pub type StorageResult<T> = Result<T, StorageError>;
@@ -44,6 +78,7 @@ pub enum StorageError {
     EntityNotFound(String),
 
     /// Field not found.
+    #[allow(dead_code)]
     #[error("Field not found: {0}")]
     FieldNotFound(String),
 
@@ -54,6 +89,38 @@ pub enum StorageError {
     /// Provider not found.
     #[error("Provider not found: {0}")]
     ProviderNotFound(String),
+
+    /// Provider call timed out.
+    #[error("Provider timeout: {0}")]
+    Timeout(String),
+
+    /// Provider circuit breaker is open.
+    #[error("Circuit open for provider: {0}")]
+    CircuitOpen(String),
+
+    /// Provider has no free capacity for another call.
+    #[error("Provider busy: {0}")]
+    Busy(String),
+
+    /// Provider does not accept writes.
+    #[error("Provider is read-only: {0}")]
+    ReadOnly(String),
+
+    /// Request would return more than the configured limit.
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+
+    /// Query is not an allowed read-only query.
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    /// Record was rejected by the backend for its data, so writing it again fails again.
+    #[error("Invalid record: {0}")]
+    InvalidRecord(String),
+
+    /// Expiration time is out of the range the cache can represent.
+    #[error("Invalid expire time: {0}")]
+    InvalidExpireTime(String),
 }
 
 /// Database adapter trait for interacting with different database backends.
@@ -69,8 +136,83 @@ pub trait DatabaseAdapter: Send + Sync {
         entity: &str,
         id: &str,
     ) -> StorageResult<Vec<Value>>;
+
+    /// Checks whether a record exists in the database.
+    ///
+    /// Adapters should override this with a query that does not read the whole record.
+    async fn record_exists(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        match self.fetch_record(entity, id).await {
+            Ok(records) => Ok(!records.is_empty()),
+            Err(StorageError::RecordNotInDatabase(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Fetches the records of several ids, returning the ones that exist with their id.
+    ///
+    /// Adapters should override this with a single query; the default fetches each id.
+    async fn fetch_records(
+        &self,
+        entity: &str,
+        ids: &[String],
+    ) -> StorageResult<Vec<(String, Value)>> {
+        fetch_each(self, entity, ids).await
+    }
+}
+
+/// Database adapter that can also write records, implemented by writable backends.
+#[async_trait]
+pub trait WritableAdapter: Send + Sync {
+    /// Inserts the record with the given id or replaces the existing one.
+    ///
+    /// The adapter sets the record's key column to `id`. Returns the record as it
+    /// is read back, so it can be cached.
+    async fn upsert_record(&self, entity: &str, id: &str, record: &Value) -> StorageResult<Value>;
+
+    /// Checks that a record can be written, before it is queued for a later write.
+    ///
+    /// Fails with `InvalidRecord` where `upsert_record` would. Returns the record as
+    /// it will be written; the default only requires a JSON object.
+    async fn validate_record(
+        &self,
+        _entity: &str,
+        _id: &str,
+        record: &Value,
+    ) -> StorageResult<Value> {
+        if !record.is_object() {
+            return Err(StorageError::InvalidRecord(
+                "Records must be JSON objects".to_string(),
+            ));
+        }
+        Ok(record.clone())
+    }
 }
 
+/// A record held in the cache together with its remaining lifetime.
+#[derive(Debug, Clone)]
+pub struct CachedRecord {
+    /// Entity (provider name) of the record
+    pub entity: String,
+    /// Id of the record
+    pub id: String,
+    /// The cached record
+    pub value: Value,
+    /// Remaining time to live, `None` if the record never expires
+    pub ttl: Option<Duration>,
+}
+
+/// Number and total size of the records held by a cache tier.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheUsage {
+    /// Number of records
+    pub entries: u64,
+    /// Total weight of the records: the entry count in memory, bytes on disk
+    pub weighted_size: u64,
+}
+
+/// Predicate selecting cached records by id.
+pub type IdPredicate = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
 /// Cache adapter trait.
 ///
 /// This trait defines the interface for cache adapters.
@@ -82,12 +224,78 @@ pub trait CacheAdapter: Send + Sync {
     /// If fields is empty, returns all fields.
     async fn get_record(&self, entity: &str, id: &str) -> StorageResult<Value>;
 
+    /// Gets an expired record that is still within the stale-if-error window.
+    async fn get_stale_record(&self, entity: &str, id: &str) -> StorageResult<Value>;
+
     /// Sets fields in the cache.
     async fn set_record(&self, entity: &str, id: &str, data: &Value) -> StorageResult<()>;
 
+    /// Sets fields in the cache with an explicit time to live (`None` never expires).
+    async fn set_record_with_ttl(
+        &self,
+        entity: &str,
+        id: &str,
+        data: &Value,
+        ttl: Option<Duration>,
+    ) -> StorageResult<()>;
+
     /// Checks if an entity exists in the cache.
-    #[allow(dead_code)]
     async fn exists(&self, entity: &str, id: &str) -> StorageResult<bool>;
+
+    /// Returns all live records in the cache.
+    async fn entries(&self) -> StorageResult<Vec<CachedRecord>>;
+
+    /// Returns the number and total size of the cached records.
+    async fn usage(&self) -> StorageResult<CacheUsage>;
+
+    /// Returns up to `count` live keys in scan order from the position `cursor` on,
+    /// optionally of one entity only.
+    ///
+    /// Keys sharing the position of the last one are all returned, so the next page
+    /// can start after it.
+    async fn scan(
+        &self,
+        entity: Option<&str>,
+        cursor: u64,
+        count: usize,
+    ) -> StorageResult<Vec<ScanKey>>;
+
+    /// Returns the remaining time to live of a record.
+    ///
+    /// Returns `None` if the record is not cached and `Some(None)` if it never expires.
+    async fn ttl(&self, entity: &str, id: &str) -> StorageResult<Option<Option<Duration>>>;
+
+    /// Changes the time to live of a cached record (`None` never expires).
+    ///
+    /// Returns whether the record was cached.
+    async fn expire(&self, entity: &str, id: &str, ttl: Option<Duration>) -> StorageResult<bool>;
+
+    /// Remembers that a record does not exist in the backend, if negative caching is enabled.
+    ///
+    /// Caching or invalidating the record forgets it again.
+    async fn set_missing(&self, entity: &str, id: &str) -> StorageResult<()>;
+
+    /// Returns whether a record is known not to exist in the backend.
+    async fn is_missing(&self, entity: &str, id: &str) -> StorageResult<bool>;
+
+    /// Removes a record from the cache.
+    ///
+    /// Returns whether a live record was removed.
+    async fn invalidate(&self, entity: &str, id: &str) -> StorageResult<bool>;
+
+    /// Removes the records of an entity whose id matches the predicate.
+    ///
+    /// Returns the number of live records removed.
+    async fn invalidate_matching(&self, entity: &str, matches: IdPredicate)
+    -> StorageResult<usize>;
+
+    /// Removes every record from the cache.
+    async fn clear(&self) -> StorageResult<()>;
+
+    /// Applies pending bookkeeping such as usage counts and expirations.
+    ///
+    /// Run periodically in the background; caches that keep none need not override it.
+    async fn run_maintenance(&self) {}
 }
 
 /// Storage service that combines database and cache adapters.
@@ -95,10 +303,24 @@ pub trait CacheAdapter: Send + Sync {
 /// This service provides a unified interface for storing and retrieving data
 /// from different storage backends.
 pub struct StorageService {
-    /// Database adapters mapped by provider name
-    providers: HashMap<String, Arc<DatabaseType>>,
+    /// Providers mapped by provider name
+    providers: HashMap<String, Arc<Provider>>,
+    /// Template providers over each source provider.
+    templates: HashMap<String, Vec<String>>,
     /// Cache adapter.
     cache: Arc<dyn CacheAdapter>,
+    /// Second-tier on-disk cache, consulted after an in-memory miss.
+    disk: Option<Arc<DiskCache>>,
+    /// Number of stale records served because the backend failed.
+    stale_served: AtomicU64,
+    /// Cache snapshot file, if snapshots are enabled.
+    snapshot_path: Option<PathBuf>,
+    /// Most keys a KEYS command may return.
+    keys_limit: usize,
+    /// Runtime statistics, shared with the server.
+    stats: Arc<Stats>,
+    /// Optional providers that failed to initialize, with the error.
+    failed_providers: HashMap<String, String>,
 }
 
 impl StorageService {
@@ -108,16 +330,44 @@ impl StorageService {
     /// provided configuration.
     pub async fn new(config: &AppConfig) -> StorageResult<Self> {
         info!("Initializing storage service with configuration");
+        let stats = Arc::new(Stats::new());
 
         // Initialize database adapters based on configuration
         let mut providers = HashMap::new();
-        for provider_config in &config.database.providers {
+        let mut failed_providers = HashMap::new();
+        // Templates run over the session of their source, so sources are created first
+        let (templates, sources): (Vec<_>, Vec<_>) = config
+            .database
+            .providers
+            .iter()
+            .partition(|p| matches!(p.provider, DatabaseProvider::Template));
+        for provider_config in sources.into_iter().chain(templates) {
             info!("Initializing provider: {}", provider_config.name);
-            let db = create_database(
-                &provider_config.provider,
-                provider_config.settings.clone(),
-            ).await?;
-            providers.insert(provider_config.name.clone(), Arc::new(db));
+            let database = match provider_config.provider {
+                DatabaseProvider::Template => create_template(provider_config, &providers).await,
+                _ => {
+                    create_database(&provider_config.provider, provider_config.settings.clone())
+                        .await
+                }
+            };
+            let provider = database.and_then(|db| {
+                let stats = stats.register_provider(&provider_config.name);
+                Provider::new(provider_config, db, stats)
+            });
+            match provider {
+                Ok(provider) => {
+                    providers.insert(provider_config.name.clone(), Arc::new(provider));
+                }
+                // An optional provider stays unavailable instead of failing startup
+                Err(e) if provider_config.optional => {
+                    warn!(
+                        "Optional provider {} failed to initialize: {}",
+                        provider_config.name, e
+                    );
+                    failed_providers.insert(provider_config.name.clone(), e.to_string());
+                }
+                Err(e) => return Err(e),
+            }
         }
 
         // Initialize cache adapter using Moka
@@ -125,15 +375,150 @@ impl StorageService {
             "Initializing Moka cache with max entries: {}, TTL: {} seconds",
             config.cache.max_entries, config.cache.ttl_seconds
         );
-        let cache = Arc::new(MokaBasedCache::new(config.cache.clone()));
+        let (cache, disk): (Arc<dyn CacheAdapter>, _) = match &config.cache.disk {
+            Some(disk_config) => {
+                let disk = Arc::new(DiskCache::open(disk_config)?);
+                let (overflow, mut evicted) = disk.overflow();
+                let cache = MokaBasedCache::with_overflow(
+                    config.cache.clone(),
+                    Some(overflow),
+                    Some(Arc::clone(&stats)),
+                );
+
+                // Move records evicted from memory to disk, unless invalidated meanwhile
+                let writer = Arc::clone(&disk);
+                tokio::spawn(async move {
+                    while let Some(record) = evicted.recv().await {
+                        let key = format!("{}:{}", record.record.entity, record.record.id);
+                        if let Err(e) = writer.write_evicted(record).await {
+                            warn!("Failed to move {} to the disk cache: {}", key, e);
+                        }
+                    }
+                });
+                (Arc::new(cache), Some(disk))
+            }
+            None => {
+                let stats = Some(Arc::clone(&stats));
+                let cache = MokaBasedCache::with_overflow(config.cache.clone(), None, stats);
+                (Arc::new(cache), None)
+            }
+        };
+
+        // Warm up the cache from the last snapshot and keep snapshotting periodically
+        let snapshot_path = config.cache.snapshot_path.as_ref().map(PathBuf::from);
+        if let Some(path) = &snapshot_path {
+            restore_snapshot(cache.as_ref(), path, &providers).await;
+
+            if config.cache.snapshot_interval_seconds > 0 {
+                let cache = Arc::clone(&cache);
+                let path = path.clone();
+                let period = Duration::from_secs(config.cache.snapshot_interval_seconds);
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    // The first tick completes immediately
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = save_snapshot(cache.as_ref(), &path).await {
+                            warn!("Periodic cache snapshot failed: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+
+        // Keep usage counts current, so INFO and metrics scrapes need not wait for them
+        {
+            let cache = Arc::clone(&cache);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(CACHE_MAINTENANCE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    cache.run_maintenance().await;
+                }
+            });
+        }
+
+        // Load the tables of preloaded providers in the background
+        for provider in providers.values() {
+            let provider = Arc::clone(provider);
+            let cache = Arc::clone(&cache);
+            let max_entries = config.cache.max_entries as u64;
+            tokio::spawn(async move { provider.preload(cache.as_ref(), max_entries).await });
+        }
+
+        // Apply the changes pushed by providers to their cached records
+        for (name, provider) in &providers {
+            if let Some(mut updates) = provider.subscribe() {
+                let name = name.clone();
+                let cache = Arc::clone(&cache);
+                let disk = disk.clone();
+                tokio::spawn(async move {
+                    while let Some(update) = updates.recv().await {
+                        apply_update(cache.as_ref(), disk.as_deref(), &name, update).await;
+                    }
+                });
+            }
+        }
+
+        // Results of templates are dropped whenever the table of their source changes
+        let mut templates: HashMap<String, Vec<String>> = HashMap::new();
+        for provider_config in &config.database.providers {
+            if let Some(template) = &provider_config.template
+                && providers.contains_key(&provider_config.name)
+            {
+                templates
+                    .entry(template.source.clone())
+                    .or_default()
+                    .push(provider_config.name.clone());
+            }
+        }
 
-        Ok(Self { providers, cache })
+        // Flush queued writes of write-behind providers in batches
+        for (name, provider) in &providers {
+            if provider.is_write_behind() {
+                tokio::spawn(flush_write_behind(
+                    name.clone(),
+                    templates.get(name).cloned().unwrap_or_default(),
+                    Arc::clone(provider),
+                    Arc::clone(&cache),
+                    disk.clone(),
+                ));
+            }
+        }
+
+        // Watch versioned tables for new commits and drop the records they change,
+        // along with the results of the templates over them
+        for (name, provider) in &providers {
+            if let Some(period) = provider.poll_interval() {
+                let templates = templates.get(name).cloned().unwrap_or_default();
+                let name = name.clone();
+                let provider = Arc::clone(provider);
+                let cache = Arc::clone(&cache);
+                let disk = disk.clone();
+                tokio::spawn(watch_table_changes(
+                    name, templates, provider, period, cache, disk,
+                ));
+            }
+        }
+
+        Ok(Self {
+            providers,
+            templates,
+            cache,
+            disk,
+            stale_served: AtomicU64::new(0),
+            snapshot_path,
+            keys_limit: config.cache.keys_limit,
+            stats,
+            failed_providers,
+        })
     }
 
     /// Fetches a record from the storage.
     ///
-    /// This method first tries to get the record from the cache.
-    /// If the record is not found in the cache, it falls back to the database.
+    /// This method first tries to get the record from the cache, then from the
+    /// disk cache if configured. If the record is not found, it falls back to the database.
     /// If the record is found in the database, it is stored in the cache.
     pub async fn fetch_record(
         &self,
@@ -142,15 +527,374 @@ impl StorageService {
     ) -> StorageResult<Value> {
         debug!("Fetching record from provider: {}, id: {}", provider_name, id);
 
+        // Versioned provider names such as flights@v42 read a historical table version
+        if let (provider, Some(as_of)) = split_provider(provider_name) {
+            let as_of = AsOf::parse(as_of)
+                .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+            return self.fetch_record_as_of(provider, id, &as_of).await;
+        }
+
+        let cached = self.fetch_cached(provider_name, id).await;
+        if let Some(stats) = self.stats.provider(provider_name) {
+            let counter = if cached.is_some() {
+                &stats.cache_hits
+            } else {
+                &stats.cache_misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(data) = cached {
+            return Ok(data);
+        }
+
+        // Fetch from database
+        self.fetch_from_database(provider_name, id).await
+    }
+
+    /// Fetches the records of several ids of a provider, in request order.
+    ///
+    /// Cached records are served from the cache and the others are loaded from the
+    /// provider in a single lookup, then cached. Ids that do not exist are skipped.
+    pub async fn fetch_records(
+        &self,
+        provider_name: &str,
+        ids: &[String],
+    ) -> StorageResult<Vec<Value>> {
+        // Historical records are looked up one version at a time
+        if split_provider(provider_name).1.is_some() {
+            let mut records = Vec::with_capacity(ids.len());
+            for id in ids {
+                match self.fetch_record(provider_name, id).await {
+                    Ok(record) => records.push(record),
+                    Err(StorageError::RecordNotInDatabase(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(records);
+        }
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+
+        let mut found = Vec::with_capacity(ids.len());
+        let mut misses = Vec::new();
+        for id in ids {
+            let cached = self.fetch_cached(provider_name, id).await;
+            if let Some(stats) = self.stats.provider(provider_name) {
+                let counter = if cached.is_some() {
+                    &stats.cache_hits
+                } else {
+                    &stats.cache_misses
+                };
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+            // Keys recently found missing are not looked up again
+            if cached.is_none()
+                && !self.cache.is_missing(provider_name, id).await.unwrap_or(false)
+                && !misses.contains(id)
+            {
+                misses.push(id.clone());
+            }
+            found.push(cached);
+        }
+        if misses.is_empty() {
+            return Ok(found.into_iter().flatten().collect());
+        }
+
+        let mut loaded = HashMap::new();
+        match provider.fetch_records(&misses).await {
+            Ok(records) => {
+                for (id, record) in records {
+                    loaded.entry(id).or_insert(record);
+                }
+                for id in &misses {
+                    match loaded.get(id) {
+                        Some(record) => {
+                            if let Err(e) = self.cache.set_record(provider_name, id, record).await {
+                                warn!("Failed to cache record: {}", e);
+                            }
+                        }
+                        None => self.remember_missing(provider_name, id).await,
+                    }
+                }
+            }
+            // Like single lookups, fall back to stale data if the backend fails
+            Err(
+                e @ (StorageError::DatabaseError(_)
+                | StorageError::Timeout(_)
+                | StorageError::CircuitOpen(_)),
+            ) => {
+                for id in &misses {
+                    let Ok(record) = self.cache.get_stale_record(provider_name, id).await else {
+                        return Err(e);
+                    };
+                    loaded.insert(id.clone(), record);
+                }
+                let served = self
+                    .stale_served
+                    .fetch_add(misses.len() as u64, Ordering::Relaxed)
+                    + misses.len() as u64;
+                warn!(
+                    "Backend error for {} ids of {}, serving stale records \
+                     (stale responses served: {}): {}",
+                    misses.len(),
+                    provider_name,
+                    served,
+                    e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+        let records = ids
+            .iter()
+            .zip(found)
+            .filter_map(|(id, cached)| cached.or_else(|| loaded.get(id).cloned()))
+            .collect();
+        Ok(records)
+    }
+
+    /// Fetches a record as of a table version or point in time.
+    ///
+    /// Historical records are cached under the versioned entity, e.g. `flights@v42`.
+    pub async fn fetch_record_as_of(
+        &self,
+        provider_name: &str,
+        id: &str,
+        as_of: &AsOf,
+    ) -> StorageResult<Value> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        let version = provider.resolve_version(as_of).await?;
+        let entity = versioned_entity(provider_name, version);
+        debug!("Resolved {}@{} to {}", provider_name, as_of, entity);
+
+        if let Some(data) = self.fetch_cached(&entity, id).await {
+            return Ok(data);
+        }
+
+        let record = provider
+            .fetch_record_at(id, version)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                StorageError::RecordNotInDatabase(format!("Record not found: {}:{}", entity, id))
+            })?;
+        if let Err(e) = self.cache.set_record(&entity, id, &record).await {
+            warn!("Failed to cache record: {}", e);
+        }
+        Ok(record)
+    }
+
+    /// Checks whether a record exists, in the cache or else in the provider.
+    ///
+    /// The provider is asked with an existence query rather than a record fetch, and
+    /// missing keys feed the negative cache.
+    pub async fn record_exists(&self, provider_name: &str, id: &str) -> StorageResult<bool> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        if self.is_cached(provider_name, id).await? {
+            return Ok(true);
+        }
+        if self.cache.is_missing(provider_name, id).await? {
+            return Ok(false);
+        }
+
+        let exists = provider.record_exists(id).await?;
+        if !exists {
+            self.remember_missing(provider_name, id).await;
+        }
+        Ok(exists)
+    }
+
+    /// Checks whether a record is cached, in memory or in the disk tier.
+    pub async fn is_cached(&self, provider_name: &str, id: &str) -> StorageResult<bool> {
+        if self.cache.exists(provider_name, id).await? {
+            return Ok(true);
+        }
+        match &self.disk {
+            Some(disk) => disk.exists(provider_name, id).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the readiness of every configured provider, sorted by name.
+    ///
+    /// The service is ready once every provider that is not optional is ready.
+    pub async fn readiness(&self, ping: bool) -> (bool, Vec<(String, ProviderReadiness)>) {
+        let checks = self.providers.iter().map(|(name, provider)| async move {
+            (name.clone(), provider.readiness(ping).await)
+        });
+        let mut providers = futures::future::join_all(checks).await;
+        providers.extend(self.failed_providers.iter().map(|(name, error)| {
+            let readiness = ProviderReadiness {
+                ready: false,
+                optional: true,
+                preload: None,
+                circuit: None,
+                error: Some(format!("failed to initialize: {}", error)),
+            };
+            (name.clone(), readiness)
+        }));
+        providers.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let ready = providers
+            .iter()
+            .all(|(_, readiness)| readiness.ready || readiness.optional);
+        (ready, providers)
+    }
+
+    /// Returns the runtime statistics, shared with the server.
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Returns the number of stale records served because a backend failed.
+    pub fn stale_served(&self) -> u64 {
+        self.stale_served.load(Ordering::Relaxed)
+    }
+
+    /// Applies the cache's pending bookkeeping now instead of on the next interval.
+    #[cfg(test)]
+    pub async fn run_cache_maintenance(&self) {
+        self.cache.run_maintenance().await;
+    }
+
+    /// Returns the usage of the in-memory cache and of the disk tier, if configured.
+    ///
+    /// Counts of the in-memory cache may lag by up to a second.
+    pub async fn cache_usage(&self) -> StorageResult<(CacheUsage, Option<CacheUsage>)> {
+        let memory = self.cache.usage().await?;
+        let disk = match &self.disk {
+            Some(disk) => Some(disk.usage().await?),
+            None => None,
+        };
+        Ok((memory, disk))
+    }
+
+    /// Returns a page of cached keys as `provider:id`, optionally of one provider only.
+    ///
+    /// Returns the cursor of the next page, 0 once every key was visited.
+    pub async fn scan_cached(
+        &self,
+        provider_name: Option<&str>,
+        cursor: u64,
+        count: usize,
+    ) -> StorageResult<(u64, Vec<String>)> {
+        let mut pages = vec![self.cache.scan(provider_name, cursor, count).await?];
+        if let Some(disk) = &self.disk {
+            pages.push(disk.scan(provider_name, cursor, count).await?);
+        }
+        Ok(scan::merge_pages(pages, count))
+    }
+
+    /// Returns every cached key matching a glob pattern, optionally of one provider only.
+    ///
+    /// Fails as soon as more keys than the configured limit match, as SCAN should be
+    /// used then.
+    pub async fn keys_matching(
+        &self,
+        provider_name: Option<&str>,
+        pattern: &str,
+    ) -> StorageResult<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, page) = self
+                .scan_cached(provider_name, cursor, KEYS_PAGE_SIZE)
+                .await?;
+            keys.extend(page.into_iter().filter(|key| glob::glob_match(pattern, key)));
+            if keys.len() > self.keys_limit {
+                return Err(StorageError::LimitExceeded(format!(
+                    "more keys match than the KEYS limit of {}; use SCAN instead",
+                    self.keys_limit
+                )));
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Returns a page of keys of a provider's backing table as `provider:id`.
+    ///
+    /// The cursor is the offset of the page in key order; the returned cursor is 0
+    /// once the last page was read.
+    pub async fn scan_source(
+        &self,
+        provider_name: &str,
+        cursor: usize,
+        count: usize,
+    ) -> StorageResult<(usize, Vec<String>)> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        let ids = provider.scan_keys(cursor, count).await?;
+        let next = if ids.len() < count {
+            0
+        } else {
+            cursor + ids.len()
+        };
+        let keys = ids
+            .into_iter()
+            .map(|id| format!("{}:{}", provider_name, id))
+            .collect();
+        Ok((next, keys))
+    }
+
+    /// Streams the rows of a provider's table matching a SQL predicate, bypassing the cache.
+    pub async fn scan_filtered(
+        &self,
+        provider_name: &str,
+        filter: &str,
+    ) -> StorageResult<SendableRecordBatchStream> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        provider.scan_filtered(filter).await
+    }
+
+    /// Runs a read-only SQL query against a provider's table, caching the result.
+    pub async fn query(
+        &self,
+        provider_name: &str,
+        sql: &str,
+        ttl: Option<Duration>,
+    ) -> StorageResult<Arc<Vec<Value>>> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        provider.query(sql, ttl).await
+    }
+
+    /// Adds a key missing from its provider to the negative cache.
+    async fn remember_missing(&self, provider_name: &str, id: &str) {
+        if let Err(e) = self.cache.set_missing(provider_name, id).await {
+            warn!("Failed to cache missing key {}:{}: {}", provider_name, id, e);
+        }
+    }
+
+    /// Looks a record up in the cache, then in the disk tier if configured.
+    async fn fetch_cached(&self, entity: &str, id: &str) -> Option<Value> {
         // Try to get from cache first
-        let cache_key = format!("{}:{}", provider_name, id);
-        match self.cache.get_record(provider_name, id).await {
+        match self.cache.get_record(entity, id).await {
             Ok(data) => {
-                trace!("Cache hit for {}:{}", provider_name, id);
-                return Ok(data);
+                trace!("Cache hit for {}:{}", entity, id);
+                return Some(data);
             }
             Err(StorageError::RecordNotFoundInCache(_)) => {
-                trace!("Cache miss for {}:{}", provider_name, id);
+                trace!("Cache miss for {}:{}", entity, id);
             }
             Err(e) => {
                 warn!("Cache error: {}", e);
@@ -158,8 +902,30 @@ impl StorageService {
             }
         }
 
-        // Fetch from database
-        self.fetch_from_database(provider_name, id).await
+        // Then the disk tier, promoting hits back into memory
+        let disk = self.disk.as_ref()?;
+        match disk.take_record(entity, id).await {
+            Ok(record) => {
+                trace!("Disk cache hit for {}:{}", entity, id);
+                // Keep the remaining lifetime, which may have been changed by EXPIRE or PERSIST
+                if let Err(e) = self
+                    .cache
+                    .set_record_with_ttl(entity, id, &record.value, record.ttl)
+                    .await
+                {
+                    warn!("Failed to promote record from disk cache: {}", e);
+                }
+                Some(record.value)
+            }
+            Err(StorageError::RecordNotFoundInCache(_)) => {
+                trace!("Disk cache miss for {}:{}", entity, id);
+                None
+            }
+            Err(e) => {
+                warn!("Disk cache error: {}", e);
+                None
+            }
+        }
     }
 
     /// Fetches a record from the database.
@@ -174,14 +940,32 @@ impl StorageService {
         let provider = self.providers.get(provider_name)
             .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
 
-        // Fetch from database
-        let records = provider.fetch_record(provider_name, id).await?;
-        
+        // Keys recently found missing are not looked up again
+        if self.cache.is_missing(provider_name, id).await.unwrap_or(false) {
+            trace!("Negative cache hit for {}:{}", provider_name, id);
+            return Err(not_in_database(provider_name, id));
+        }
+
+        // Fetch from database, falling back to stale data if the backend fails
+        let records = match provider.fetch_record(id).await {
+            Ok(records) => records,
+            Err(
+                e @ (StorageError::DatabaseError(_)
+                | StorageError::Timeout(_)
+                | StorageError::CircuitOpen(_)),
+            ) => {
+                return self.fetch_stale(provider_name, id, e).await;
+            }
+            Err(e @ StorageError::RecordNotInDatabase(_)) => {
+                self.remember_missing(provider_name, id).await;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
         if records.is_empty() {
-            return Err(StorageError::RecordNotInDatabase(format!(
-                "Record not found: {}:{}",
-                provider_name, id
-            )));
+            self.remember_missing(provider_name, id).await;
+            return Err(not_in_database(provider_name, id));
         }
 
         // Take the first record
@@ -194,6 +978,535 @@ impl StorageService {
 
         Ok(record)
     }
+
+    /// Serves an expired record from the cache after a backend failure.
+    ///
+    /// Returns the original error if no stale copy is available.
+    async fn fetch_stale(
+        &self,
+        provider_name: &str,
+        id: &str,
+        error: StorageError,
+    ) -> StorageResult<Value> {
+        match self.cache.get_stale_record(provider_name, id).await {
+            Ok(record) => {
+                let served = self.stale_served.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Backend error for {}:{}, serving stale record (stale responses served: {}): {}",
+                    provider_name, id, served, error
+                );
+                Ok(record)
+            }
+            Err(_) => Err(error),
+        }
+    }
+
+    /// Writes the cache contents to the configured snapshot file, if any.
+    pub async fn save_snapshot(&self) -> StorageResult<()> {
+        match &self.snapshot_path {
+            Some(path) => save_snapshot(self.cache.as_ref(), path).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a record through to the provider's backend, then caches the stored record.
+    ///
+    /// Only the fields of the record are written; others keep their stored values. A
+    /// queued write is cached over the cached copy of the record, as the flush will set
+    /// its fields over the stored one.
+    pub async fn write_record(
+        &self,
+        provider_name: &str,
+        id: &str,
+        record: &Value,
+    ) -> StorageResult<Value> {
+        let provider = self
+            .providers
+            .get(provider_name)
+            .ok_or_else(|| StorageError::ProviderNotFound(provider_name.to_string()))?;
+        let stored = provider.upsert_record(id, record).await?;
+        let cached = if provider.is_write_behind() {
+            // Without a cached copy, the next read sets the fields over the stored record
+            self.fetch_cached(provider_name, id)
+                .await
+                .map(|cached| match (cached, &stored) {
+                    (Value::Object(mut cached), Value::Object(fields)) => {
+                        cached.extend(fields.clone());
+                        Value::Object(cached)
+                    }
+                    _ => stored.clone(),
+                })
+        } else {
+            Some(stored.clone())
+        };
+
+        // The write succeeded, so a failure here only leaves an outdated copy to refetch
+        if let Some(disk) = &self.disk
+            && let Err(e) = disk.invalidate(provider_name, id).await
+        {
+            warn!("Failed to invalidate {}:{} in the disk cache: {}", provider_name, id, e);
+        }
+        match cached {
+            Some(cached) => {
+                if let Err(e) = self.cache.set_record(provider_name, id, &cached).await {
+                    warn!("Failed to cache record: {}", e);
+                    self.cache.invalidate(provider_name, id).await?;
+                }
+            }
+            None => {
+                self.cache.invalidate(provider_name, id).await?;
+            }
+        }
+        if !provider.is_write_behind() {
+            self.invalidate_templates(provider_name).await;
+        }
+        Ok(stored)
+    }
+
+    /// Sets fields of a record, writing only those fields through to the backend.
+    ///
+    /// A missing record is created. Returns the number of fields that were not set before.
+    pub async fn write_fields(
+        &self,
+        provider_name: &str,
+        id: &str,
+        fields: serde_json::Map<String, Value>,
+    ) -> StorageResult<usize> {
+        let added = match self.fetch_record(provider_name, id).await {
+            Ok(Value::Object(record)) => {
+                fields.keys().filter(|field| !record.contains_key(*field)).count()
+            }
+            Ok(_) | Err(StorageError::RecordNotInDatabase(_)) => fields.len(),
+            Err(e) => return Err(e),
+        };
+        self.write_record(provider_name, id, &Value::Object(fields))
+            .await?;
+        Ok(added)
+    }
+
+    /// Flushes the queued writes of every write-behind provider.
+    pub async fn flush_writes(&self) -> StorageResult<()> {
+        for (name, provider) in &self.providers {
+            if provider.flush_writes().await? > 0 {
+                self.invalidate_templates(name).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the cached results of the templates over a provider whose table was written.
+    async fn invalidate_templates(&self, provider_name: &str) {
+        if let Some(templates) = self.templates.get(provider_name) {
+            invalidate_templates(self.cache.as_ref(), self.disk.as_deref(), templates).await;
+        }
+    }
+
+    /// Evicts a record from the cache tiers without touching the backend.
+    ///
+    /// Returns whether a live record was evicted.
+    pub async fn evict_record(&self, provider_name: &str, id: &str) -> StorageResult<bool> {
+        let mut evicted = false;
+        if let Some(disk) = &self.disk {
+            evicted |= disk.invalidate(provider_name, id).await?;
+        }
+        evicted |= self.cache.invalidate(provider_name, id).await?;
+        Ok(evicted)
+    }
+
+    /// Returns the remaining cache lifetime of a record, from memory or the disk tier.
+    ///
+    /// Returns `None` if the record is not cached and `Some(None)` if it never expires.
+    pub async fn record_ttl(
+        &self,
+        provider_name: &str,
+        id: &str,
+    ) -> StorageResult<Option<Option<Duration>>> {
+        if let Some(ttl) = self.cache.ttl(provider_name, id).await? {
+            return Ok(Some(ttl));
+        }
+        match &self.disk {
+            Some(disk) => disk.ttl(provider_name, id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Overrides the cache lifetime of a record (`None` keeps it until evicted).
+    ///
+    /// Returns whether the record was cached.
+    pub async fn expire_record(
+        &self,
+        provider_name: &str,
+        id: &str,
+        ttl: Option<Duration>,
+    ) -> StorageResult<bool> {
+        if self.cache.expire(provider_name, id, ttl).await? {
+            return Ok(true);
+        }
+        match &self.disk {
+            Some(disk) => disk.expire(provider_name, id, ttl).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Evicts every cached record of a provider, or those whose id matches a glob pattern.
+    ///
+    /// Returns the number of live records evicted.
+    pub async fn evict_matching(
+        &self,
+        provider_name: &str,
+        pattern: Option<&str>,
+    ) -> StorageResult<usize> {
+        if !self.providers.contains_key(provider_name) {
+            return Err(StorageError::ProviderNotFound(provider_name.to_string()));
+        }
+        // A pattern without special characters names a single record
+        if let Some(id) = pattern.filter(|pattern| !pattern.contains(['*', '?', '[', '\\'])) {
+            return Ok(usize::from(self.evict_record(provider_name, id).await?));
+        }
+        let matches: IdPredicate = match pattern {
+            Some(pattern) => {
+                let pattern = pattern.to_string();
+                Arc::new(move |id| glob::glob_match(&pattern, id))
+            }
+            None => Arc::new(|_| true),
+        };
+
+        let mut evicted = 0;
+        if let Some(disk) = &self.disk {
+            evicted += disk.invalidate_matching(provider_name, Arc::clone(&matches)).await?;
+        }
+        evicted += self.cache.invalidate_matching(provider_name, matches).await?;
+        info!(
+            "Evicted {} cached records of {} matching {}",
+            evicted,
+            provider_name,
+            pattern.unwrap_or("*")
+        );
+        Ok(evicted)
+    }
+
+    /// Drops every cached record and query result, or those of one provider, without
+    /// touching the backend.
+    pub async fn flush(&self, provider_name: Option<&str>) -> StorageResult<()> {
+        match provider_name {
+            Some(provider_name) => {
+                self.evict_matching(provider_name, None).await?;
+                if let Some(provider) = self.providers.get(provider_name) {
+                    provider.clear_queries();
+                }
+            }
+            None => {
+                if let Some(disk) = &self.disk {
+                    disk.clear().await?;
+                }
+                self.cache.clear().await?;
+                for provider in self.providers.values() {
+                    provider.clear_queries();
+                }
+                info!("Flushed the cache");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the names of the configured providers, sorted.
+    pub fn provider_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.providers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Returns whether a provider is configured.
+    pub fn has_provider(&self, provider_name: &str) -> bool {
+        self.providers.contains_key(provider_name)
+    }
+
+    /// Waits until all provider preloads have finished.
+    pub async fn wait_ready(&self) {
+        for provider in self.providers.values() {
+            provider.wait_ready().await;
+        }
+    }
+
+    /// Returns the status of one provider, or of all providers sorted by name.
+    pub fn provider_status(
+        &self,
+        provider_name: Option<&str>,
+    ) -> StorageResult<Vec<(String, ProviderStatus)>> {
+        if let Some(name) = provider_name {
+            let provider = self
+                .providers
+                .get(name)
+                .ok_or_else(|| StorageError::ProviderNotFound(name.to_string()))?;
+            return Ok(vec![(name.to_string(), provider.status())]);
+        }
+
+        let mut statuses: Vec<_> = self
+            .providers
+            .iter()
+            .map(|(name, provider)| (name.clone(), provider.status()))
+            .collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(statuses)
+    }
+
+    /// Returns the gauges of every provider, sorted by name.
+    pub fn provider_gauges(&self) -> Vec<(String, ProviderGauges)> {
+        let mut gauges: Vec<_> = self
+            .providers
+            .iter()
+            .map(|(name, provider)| (name.clone(), provider.gauges()))
+            .collect();
+        gauges.sort_by(|a, b| a.0.cmp(&b.0));
+        gauges
+    }
+}
+
+/// Creates the adapter of a template provider over the session of its source.
+async fn create_template(
+    config: &DataProviderConfig,
+    providers: &HashMap<String, Arc<Provider>>,
+) -> StorageResult<DatabaseType> {
+    let template = config.template.as_ref().ok_or_else(|| {
+        StorageError::ConfigError(format!("Template provider {} has no template", config.name))
+    })?;
+    let session = providers
+        .get(&template.source)
+        .and_then(|source| source.session())
+        .ok_or_else(|| {
+            StorageError::ConfigError(format!(
+                "Template provider {} needs an AzDelta source, {} is not one",
+                config.name, template.source
+            ))
+        })?;
+    let adapter = TemplateAdapter::new(session, template, config.query.max_rows).await?;
+    Ok(DatabaseType::Template(Box::new(adapter)))
+}
+
+/// Polls a provider's table for new versions and invalidates the records they change.
+async fn watch_table_changes(
+    name: String,
+    templates: Vec<String>,
+    provider: Arc<Provider>,
+    period: Duration,
+    cache: Arc<dyn CacheAdapter>,
+    disk: Option<Arc<DiskCache>>,
+) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match provider.poll_changes().await {
+            Ok(Some(change)) => {
+                provider.clear_queries();
+                let mut removed =
+                    invalidate(cache.as_ref(), disk.as_deref(), &name, &change.invalidation).await;
+                removed += invalidate_templates(cache.as_ref(), disk.as_deref(), &templates).await;
+                info!(
+                    "Provider {} moved to table version {}, invalidated {} cached records",
+                    name, change.version, removed
+                );
+            }
+            Ok(None) => trace!("No new table version for provider {}", name),
+            Err(e) => warn!("Failed to poll provider {} for changes: {}", name, e),
+        }
+    }
+}
+
+/// Flushes the queued writes of a write-behind provider whenever a batch is due, then
+/// drops the results of the templates over it.
+///
+/// Failed flushes keep their writes queued and are retried with backoff.
+async fn flush_write_behind(
+    name: String,
+    templates: Vec<String>,
+    provider: Arc<Provider>,
+    cache: Arc<dyn CacheAdapter>,
+    disk: Option<Arc<DiskCache>>,
+) {
+    let mut attempt = 0;
+    loop {
+        if attempt == 0 {
+            provider.wait_for_flush().await;
+        } else {
+            tokio::time::sleep(backoff_delay(
+                attempt - 1,
+                WRITE_BEHIND_BACKOFF_BASE,
+                WRITE_BEHIND_BACKOFF_MAX,
+            ))
+            .await;
+        }
+
+        match provider.flush_writes().await {
+            Ok(flushed) => {
+                if flushed > 0 {
+                    debug!("Flushed {} queued writes to {}", flushed, name);
+                    invalidate_templates(cache.as_ref(), disk.as_deref(), &templates).await;
+                }
+                attempt = 0;
+            }
+            Err(e) => {
+                warn!("Flushing queued writes to {} failed: {}", name, e);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Drops every cached result of the given template providers, returning how many.
+async fn invalidate_templates(
+    cache: &dyn CacheAdapter,
+    disk: Option<&DiskCache>,
+    templates: &[String],
+) -> usize {
+    let mut removed = 0;
+    for template in templates {
+        removed += invalidate(cache, disk, template, &Invalidation::All).await;
+    }
+    removed
+}
+
+/// Returns the error for a record that is not in its provider.
+fn not_in_database(provider_name: &str, id: &str) -> StorageError {
+    StorageError::RecordNotInDatabase(format!("Record not found: {}:{}", provider_name, id))
+}
+
+/// Applies a change pushed by a provider to its cached records.
+///
+/// Upserts only refresh records that are cached in memory; the provider is not
+/// loaded into the cache record by record.
+async fn apply_update(
+    cache: &dyn CacheAdapter,
+    disk: Option<&DiskCache>,
+    entity: &str,
+    update: CacheUpdate,
+) {
+    match update {
+        CacheUpdate::Invalidate(invalidation) => {
+            let removed = invalidate(cache, disk, entity, &invalidation).await;
+            debug!("Invalidated {} cached records of {}", removed, entity);
+        }
+        CacheUpdate::Upsert { key, value } => {
+            if let Some(disk) = disk
+                && let Err(e) = disk.invalidate(entity, &key).await
+            {
+                warn!("Failed to invalidate {}:{} in the disk cache: {}", entity, key, e);
+            }
+            match cache.exists(entity, &key).await {
+                Ok(true) => {
+                    if let Err(e) = cache.set_record(entity, &key, &value).await {
+                        warn!("Failed to update {}:{}: {}", entity, key, e);
+                    }
+                }
+                // Still drop any stale copy of the record
+                _ => {
+                    let invalidation = Invalidation::Keys(vec![key]);
+                    invalidate(cache, None, entity, &invalidation).await;
+                }
+            }
+        }
+    }
+}
+
+/// Drops the cached records of a provider affected by a change, in memory and on disk.
+///
+/// Keys are dropped one by one; only ranges and whole providers scan the caches.
+/// Returns the number of records removed from memory.
+async fn invalidate(
+    cache: &dyn CacheAdapter,
+    disk: Option<&DiskCache>,
+    entity: &str,
+    invalidation: &Invalidation,
+) -> usize {
+    if let Invalidation::Keys(keys) = invalidation {
+        let mut removed = 0;
+        for key in keys {
+            if let Some(disk) = disk
+                && let Err(e) = disk.invalidate(entity, key).await
+            {
+                warn!("Failed to invalidate {}:{} in the disk cache: {}", entity, key, e);
+            }
+            match cache.invalidate(entity, key).await {
+                Ok(true) => removed += 1,
+                Ok(false) => {}
+                Err(e) => warn!("Failed to invalidate {}:{} in the cache: {}", entity, key, e),
+            }
+        }
+        return removed;
+    }
+
+    let invalidation = invalidation.clone();
+    let matches: IdPredicate = Arc::new(move |id| invalidation.matches(id));
+    if let Some(disk) = disk
+        && let Err(e) = disk.invalidate_matching(entity, Arc::clone(&matches)).await
+    {
+        warn!("Failed to invalidate {} in the disk cache: {}", entity, e);
+    }
+    match cache.invalidate_matching(entity, matches).await {
+        Ok(removed) => removed,
+        Err(e) => {
+            warn!("Failed to invalidate {} in the cache: {}", entity, e);
+            0
+        }
+    }
+}
+
+/// Restores cached records from a snapshot file.
+///
+/// Records of providers that are no longer configured are skipped, including
+/// historical records of such providers. A missing or
+/// corrupt snapshot is logged and ignored so it never prevents startup.
+async fn restore_snapshot(
+    cache: &dyn CacheAdapter,
+    path: &Path,
+    providers: &HashMap<String, Arc<Provider>>,
+) {
+    if !path.exists() {
+        info!("No cache snapshot found at {}", path.display());
+        return;
+    }
+
+    let records = match snapshot::read_snapshot(path).await {
+        Ok(records) => records,
+        Err(e) => {
+            warn!("Ignoring cache snapshot {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    let total = records.len();
+    let mut restored = 0;
+    for record in records
+        .into_iter()
+        .filter(|record| providers.contains_key(split_provider(&record.entity).0))
+    {
+        match cache
+            .set_record_with_ttl(&record.entity, &record.id, &record.value, record.ttl)
+            .await
+        {
+            Ok(()) => restored += 1,
+            Err(e) => warn!("Failed to restore {}:{}: {}", record.entity, record.id, e),
+        }
+    }
+    info!(
+        "Restored {} of {} live records from cache snapshot {}",
+        restored,
+        total,
+        path.display()
+    );
+}
+
+/// Writes all live cache records to a snapshot file.
+async fn save_snapshot(cache: &dyn CacheAdapter, path: &Path) -> StorageResult<()> {
+    let records = cache.entries().await?;
+    snapshot::write_snapshot(path, &records).await?;
+    info!(
+        "Saved {} cached records to snapshot {}",
+        records.len(),
+        path.display()
+    );
+    Ok(())
 }
 
 /// Extracts required keys from a HashMap and reports any missing keys
@@ -217,7 +1530,33 @@ pub fn assert_required_settings(
     Ok(())
 }
 
+/// Fetches the records of several ids with one lookup per id, skipping missing ids.
+pub(crate) async fn fetch_each<A: DatabaseAdapter + ?Sized>(
+    adapter: &A,
+    entity: &str,
+    ids: &[String],
+) -> StorageResult<Vec<(String, Value)>> {
+    let results: Vec<_> = futures::stream::iter(ids.iter().cloned())
+        .map(|id| async move {
+            let result = adapter.fetch_record(entity, &id).await;
+            (id, result)
+        })
+        .buffered(FETCH_CONCURRENCY)
+        .collect()
+        .await;
+    let mut records = Vec::with_capacity(results.len());
+    for (id, result) in results {
+        match result {
+            Ok(found) => records.extend(found.into_iter().next().map(|record| (id, record))),
+            Err(StorageError::RecordNotInDatabase(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
 /// Example function that demonstrates how to use extract_required_settings
+#[allow(dead_code)]
 pub fn validate_connection_settings(settings: &HashMap<String, String>) -> StorageResult<()> {
     // Define the required keys for a database connection
     let required_keys = ["host", "port", "user", "password", "database"];
@@ -227,12 +1566,10 @@ pub fn validate_connection_settings(settings: &HashMap<String, String>) -> Stora
     
     // Additional validation could be done here
     // For example, checking if port is a valid number
-    if let Some(port) = settings.get("port") {
-        if port.parse::<u16>().is_err() {
-            return Err(StorageError::ConfigError(
-                "Port must be a valid number between 0 and 65535".to_string()
-            ));
-        }
+    if let Some(port) = settings.get("port") && port.parse::<u16>().is_err() {
+        return Err(StorageError::ConfigError(
+            "Port must be a valid number between 0 and 65535".to_string()
+        ));
     }
     
     Ok(())
@@ -241,6 +1578,7 @@ pub fn validate_connection_settings(settings: &HashMap<String, String>) -> Stora
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::CacheConfig;
     
     #[test]
     fn test_extract_required_settings() {
@@ -262,4 +1600,355 @@ mod tests {
         let result = assert_required_settings(&settings, &required_keys);
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_snapshot_restore_skips_unknown_providers() {
+        let path = std::env::temp_dir().join(format!(
+            "prism_service_{}.snapshot",
+            std::process::id()
+        ));
+        let record = |entity: &str, id: &str| CachedRecord {
+            entity: entity.to_string(),
+            id: id.to_string(),
+            value: serde_json::json!({ "id": id }),
+            ttl: Some(Duration::from_secs(60)),
+        };
+        let records = [
+            record("users", "123"),
+            record("users@v3", "123"),
+            record("removed", "1"),
+            record("removed@v3", "1"),
+        ];
+        snapshot::write_snapshot(&path, &records).await.unwrap();
+
+        let mut config = AppConfig::default();
+        config.cache.snapshot_path = Some(path.to_string_lossy().into_owned());
+        let storage = StorageService::new(&config).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(storage.cache.exists("users", "123").await.unwrap());
+        assert!(storage.cache.exists("users@v3", "123").await.unwrap());
+        assert!(!storage.cache.exists("removed", "1").await.unwrap());
+        assert!(!storage.cache.exists("removed@v3", "1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_apply_update_refreshes_cached_records_only() {
+        let cache = MokaBasedCache::new(CacheConfig::default());
+        cache
+            .set_record("employees", "1", &serde_json::json!({ "name": "old" }))
+            .await
+            .unwrap();
+
+        let upsert = |key: &str| CacheUpdate::Upsert {
+            key: key.to_string(),
+            value: serde_json::json!({ "name": "new" }),
+        };
+        apply_update(&cache, None, "employees", upsert("1")).await;
+        apply_update(&cache, None, "employees", upsert("2")).await;
+
+        assert_eq!(cache.get_record("employees", "1").await.unwrap()["name"], "new");
+        assert!(!cache.exists("employees", "2").await.unwrap());
+
+        let delete = CacheUpdate::Invalidate(Invalidation::Keys(vec!["1".to_string()]));
+        apply_update(&cache, None, "employees", delete).await;
+        assert!(!cache.exists("employees", "1").await.unwrap());
+
+        for id in ["3", "4"] {
+            cache.set_record("employees", id, &serde_json::json!({})).await.unwrap();
+        }
+        let keys = Invalidation::Keys(vec!["3".to_string(), "5".to_string()]);
+        assert_eq!(invalidate(&cache, None, "employees", &keys).await, 1);
+        assert!(cache.exists("employees", "4").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_time_travel_requires_versioned_provider() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+
+        // The mock provider has no table history
+        assert!(matches!(
+            storage.fetch_record("users@v1", "123").await,
+            Err(StorageError::ConfigError(_))
+        ));
+        assert!(matches!(
+            storage.fetch_record("users@latest", "123").await,
+            Err(StorageError::ProviderNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_evict_records() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        for id in ["123", "124", "200"] {
+            storage.cache.set_record("users", id, &serde_json::json!({})).await.unwrap();
+        }
+
+        assert!(storage.evict_record("users", "200").await.unwrap());
+        assert!(!storage.evict_record("users", "200").await.unwrap());
+        assert_eq!(storage.evict_matching("users", Some("12*")).await.unwrap(), 2);
+        assert!(!storage.cache.exists("users", "123").await.unwrap());
+        storage.cache.set_record("users", "300", &serde_json::json!({})).await.unwrap();
+        assert_eq!(storage.evict_matching("users", Some("300")).await.unwrap(), 1);
+        assert_eq!(storage.evict_matching("users", Some("300")).await.unwrap(), 0);
+        assert!(matches!(
+            storage.evict_matching("unknown", None).await,
+            Err(StorageError::ProviderNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_record_exists() {
+        let mut config = AppConfig::default();
+        config.cache.negative_ttl_seconds = 60;
+        let storage = StorageService::new(&config).await.unwrap();
+
+        assert!(storage.record_exists("users", "123").await.unwrap());
+        assert!(!storage.is_cached("users", "123").await.unwrap());
+        assert!(!storage.record_exists("users", "999").await.unwrap());
+        assert!(storage.cache.is_missing("users", "999").await.unwrap());
+        assert!(matches!(
+            storage.record_exists("unknown", "1").await,
+            Err(StorageError::ProviderNotFound(_))
+        ));
+
+        storage.fetch_record("users", "123").await.unwrap();
+        assert!(storage.is_cached("users", "123").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_scan_and_keys() {
+        let mut config = AppConfig::default();
+        config.cache.keys_limit = 2;
+        let storage = StorageService::new(&config).await.unwrap();
+        for id in ["1", "2", "3"] {
+            storage.cache.set_record("users", id, &serde_json::json!({})).await.unwrap();
+        }
+
+        let (cursor, page) = storage.scan_cached(Some("users"), 0, 2).await.unwrap();
+        assert_eq!(page.len(), 2);
+        let (cursor, rest) = storage.scan_cached(None, cursor, 2).await.unwrap();
+        assert_eq!((cursor, rest.len()), (0, 1));
+
+        assert_eq!(
+            storage.keys_matching(None, "users:[12]").await.unwrap().len(),
+            2
+        );
+        assert!(matches!(
+            storage.keys_matching(None, "*").await,
+            Err(StorageError::LimitExceeded(_))
+        ));
+
+        let (cursor, keys) = storage.scan_source("users", 0, 1).await.unwrap();
+        assert_eq!((cursor, keys), (1, vec!["users:123".to_string()]));
+        let (cursor, keys) = storage.scan_source("users", 1, 5).await.unwrap();
+        assert_eq!((cursor, keys), (0, vec!["users:456".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_stats() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        storage.fetch_record("users", "123").await.unwrap();
+        storage.fetch_record("users", "123").await.unwrap();
+        assert!(storage.fetch_record("users", "999").await.is_err());
+
+        let stats = storage.stats().provider("users").unwrap();
+        assert_eq!(stats.cache_hits.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.cache_misses.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.backend_calls.load(Ordering::Relaxed), 2);
+        // A record that does not exist is not a backend error
+        assert_eq!(stats.backend_errors.load(Ordering::Relaxed), 0);
+        storage.run_cache_maintenance().await;
+        assert_eq!(storage.cache_usage().await.unwrap().0.entries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_records() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        storage.fetch_record("users", "123").await.unwrap();
+        let ids: Vec<String> = ["456", "999", "123"].iter().map(|id| id.to_string()).collect();
+        let records = storage.fetch_records("users", &ids).await.unwrap();
+        let found: Vec<&Value> = records.iter().map(|record| &record["id"]).collect();
+        assert_eq!(found, vec!["456", "123"]);
+
+        // The misses were loaded in a single call and the found record cached
+        let stats = storage.stats().provider("users").unwrap();
+        assert_eq!(stats.backend_calls.load(Ordering::Relaxed), 2);
+        assert!(storage.is_cached("users", "456").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_record_ttl() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        assert_eq!(storage.record_ttl("users", "123").await.unwrap(), None);
+        assert!(!storage.expire_record("users", "123", None).await.unwrap());
+
+        storage.fetch_record("users", "123").await.unwrap();
+        assert!(matches!(
+            storage.record_ttl("users", "123").await.unwrap(),
+            Some(Some(_))
+        ));
+        assert!(storage.expire_record("users", "123", None).await.unwrap());
+        assert_eq!(storage.record_ttl("users", "123").await.unwrap(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn test_template_needs_delta_source() {
+        let mut config = AppConfig::default();
+        let mut template = config.database.providers[0].clone();
+        template.name = "users_by_name".to_string();
+        template.provider = DatabaseProvider::Template;
+        config.database.providers.insert(0, template);
+        assert!(matches!(
+            StorageService::new(&config).await,
+            Err(StorageError::ConfigError(_))
+        ));
+
+        config.database.providers[0].template = Some(crate::config::TemplateConfig {
+            source: "users".to_string(),
+            sql: "SELECT * FROM users WHERE name = $1".to_string(),
+            params: vec![crate::config::TemplateParam::String],
+        });
+        assert!(matches!(
+            StorageService::new(&config).await,
+            Err(StorageError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_readiness_with_optional_provider() {
+        let mut config = AppConfig::default();
+        let mut orders = config.database.providers[0].clone();
+        orders.name = "orders".to_string();
+        orders.provider = crate::config::DatabaseProvider::Postgres;
+        config.database.providers.push(orders);
+        assert!(StorageService::new(&config).await.is_err());
+
+        config.database.providers[1].optional = true;
+        let storage = StorageService::new(&config).await.unwrap();
+        let (ready, providers) = storage.readiness(true).await;
+        assert!(ready);
+        assert_eq!(providers[0].0, "orders");
+        assert!(!providers[0].1.ready);
+        assert!(providers[0].1.error.is_some());
+        assert!(providers[1].1.ready);
+        assert!(matches!(
+            storage.fetch_record("orders", "1").await,
+            Err(StorageError::ProviderNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_validation() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        assert!(matches!(
+            storage.query("users", "DELETE FROM users", None).await,
+            Err(StorageError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            storage.query("users", "SELECT * FROM users", None).await,
+            Err(StorageError::ConfigError(_))
+        ));
+        assert!(matches!(
+            storage.query("orders", "SELECT 1", None).await,
+            Err(StorageError::ProviderNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_writes() {
+        let mut config = AppConfig::default();
+        let storage = StorageService::new(&config).await.unwrap();
+        let record = serde_json::json!({ "name": "Ada" });
+        assert!(matches!(
+            storage.write_record("users", "1", &record).await,
+            Err(StorageError::ReadOnly(_))
+        ));
+
+        config.database.providers[0].writable = true;
+        let mut storage = StorageService::new(&config).await.unwrap();
+        // Results of templates over the written provider are dropped
+        let templates = vec!["users_by_name".to_string()];
+        storage.templates.insert("users".to_string(), templates);
+        let result = serde_json::json!([{ "id": "123" }]);
+        storage.cache.set_record("users_by_name", "Ada", &result).await.unwrap();
+        let stored = storage.write_record("users", "1", &record).await.unwrap();
+        assert_eq!(stored["id"], "1");
+        assert_eq!(storage.cache.get_record("users", "1").await.unwrap(), stored);
+        assert!(!storage.cache.exists("users_by_name", "Ada").await.unwrap());
+
+        let fields = serde_json::json!({ "name": "Ada L.", "age": 36 });
+        let added = storage
+            .write_fields("users", "1", fields.as_object().unwrap().clone())
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        storage.flush(None).await.unwrap();
+        let record = storage.fetch_record("users", "1").await.unwrap();
+        assert_eq!(record["name"], "Ada L.");
+        assert_eq!(record["age"], 36);
+    }
+
+    #[tokio::test]
+    async fn test_write_behind() {
+        let dir = std::env::temp_dir().join("prism_wal_test_service");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = AppConfig::default();
+        config.database.providers[0].writable = true;
+        config.database.providers[0].write_behind = Some(crate::config::WriteBehindConfig {
+            path: dir.to_string_lossy().into_owned(),
+            flush_interval_ms: 3_600_000,
+            ..Default::default()
+        });
+        let storage = StorageService::new(&config).await.unwrap();
+        let provider = &storage.providers["users"];
+
+        let record = serde_json::json!({ "name": "Ada" });
+        storage.write_record("users", "1", &record).await.unwrap();
+        // Queued writes are served even once evicted from the cache
+        storage.flush(None).await.unwrap();
+        assert_eq!(storage.fetch_record("users", "1").await.unwrap(), record);
+        assert_eq!(provider.flush_writes().await.unwrap(), 1);
+        assert_eq!(provider.flush_writes().await.unwrap(), 0);
+
+        storage.flush(None).await.unwrap();
+        let flushed = storage.fetch_record("users", "1").await.unwrap();
+        assert_eq!(flushed["name"], "Ada");
+        assert_eq!(flushed["id"], "1");
+
+        // Queued fields are set over the stored record, and only they are written
+        let fields = serde_json::json!({ "age": 36 });
+        let added = storage
+            .write_fields("users", "1", fields.as_object().unwrap().clone())
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        let record = storage.fetch_record("users", "1").await.unwrap();
+        assert_eq!((&record["name"], &record["age"]), (&"Ada".into(), &36.into()));
+        assert_eq!(provider.flush_writes().await.unwrap(), 1);
+        let record = storage.fetch_record("users", "1").await.unwrap();
+        assert_eq!(record["name"], "Ada");
+
+        // Records the backend would reject never enter the queue
+        let result = storage.write_record("users", "2", &serde_json::json!("Ada")).await;
+        assert!(matches!(result, Err(StorageError::InvalidRecord(_))));
+        assert_eq!(provider.flush_writes().await.unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        storage.fetch_record("users", "123").await.unwrap();
+        let record = serde_json::json!({});
+        storage.cache.set_record("users@v1", "123", &record).await.unwrap();
+
+        storage.flush(Some("users")).await.unwrap();
+        assert!(!storage.cache.exists("users", "123").await.unwrap());
+        assert!(storage.cache.exists("users@v1", "123").await.unwrap());
+
+        storage.flush(None).await.unwrap();
+        assert!(!storage.cache.exists("users@v1", "123").await.unwrap());
+    }
 }
@@ -4,13 +4,24 @@
 //! using the Moka caching library.
 
 use async_trait::async_trait;
+use moka::Expiry;
 use moka::future::Cache as MokaCache;
-use serde_json::{Value, json};
-use std::time::Duration;
-use tracing::debug;
+use moka::notification::RemovalCause;
+use moka::ops::compute::{CompResult, Op};
+use moka::sync::Cache as NegativeCache;
+use moka::sync::Cache as StaleCache;
+use serde_json::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::config::CacheConfig;
-use crate::storage::{CacheAdapter, StorageError, StorageResult};
+use crate::stats::Stats;
+use crate::storage::disk_cache::Overflow;
+use crate::storage::scan::{ScanIndex, ScanKey};
+use crate::storage::{
+    CacheAdapter, CacheUsage, CachedRecord, IdPredicate, StorageError, StorageResult,
+};
 
 /// Cache key type combining entity and id
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -19,30 +30,165 @@ struct CacheKey {
     id: String,
 }
 
+/// Cached value together with its expiration time
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    value: Value,
+    /// When the entry expires, `None` if it never does
+    expires_at: Option<Instant>,
+    /// Number of the write that cached the value, telling it from later writes of the key
+    generation: u64,
+}
+
+/// Expiry policy that reads the expiration time stored in each entry
+struct EntryExpiry;
+
+impl Expiry<CacheKey, CacheEntry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &CacheKey,
+        value: &CacheEntry,
+        created_at: Instant,
+    ) -> Option<Duration> {
+        value
+            .expires_at
+            .map(|at| at.saturating_duration_since(created_at))
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        value: &CacheEntry,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value
+            .expires_at
+            .map(|at| at.saturating_duration_since(updated_at))
+    }
+}
+
 /// Moka-based cache adapter that provides concurrent caching with automatic eviction.
 ///
 /// This adapter uses Moka's high-performance concurrent cache implementation with:
-/// - Time-based expiration (TTL)
+/// - Time-based expiration (per-entry TTL, defaulting to the configured TTL)
 /// - Size-based eviction (LRU)
 /// - Thread-safe operations
 /// - Asynchronous API
+///
+/// When `stale_if_error_seconds` is configured, expired entries are moved into a
+/// secondary holding area where they stay available for the stale-if-error window.
+/// Entries evicted because the cache is full can be handed to an overflow channel,
+/// which feeds the on-disk second tier.
+///
+/// When `negative_ttl_seconds` is configured, keys known to be missing from the
+/// backend are remembered for that long, until the key is cached or invalidated.
 pub struct MokaBasedCache {
     /// The underlying Moka cache instance
-    cache: MokaCache<CacheKey, Value>,
+    cache: MokaCache<CacheKey, CacheEntry>,
+    /// Holding area for expired entries, only present when stale-if-error is enabled
+    stale: Option<StaleCache<CacheKey, Value>>,
+    /// Keys missing from the backend, only present when negative caching is enabled
+    negative: Option<NegativeCache<CacheKey, ()>>,
+    /// Time to live of entries inserted without an explicit TTL
+    default_ttl: Duration,
+    /// Keys in scan order, removed by the eviction listener
+    scan: Arc<Mutex<ScanIndex>>,
+    /// Generation of the last cached value
+    generation: AtomicU64,
 }
 
 impl MokaBasedCache {
     /// Creates a new Moka-based cache with the given configuration
+    #[cfg(test)]
     pub fn new(config: CacheConfig) -> Self {
-        let cache = MokaCache::builder()
+        Self::with_overflow(config, None, None)
+    }
+
+    /// Creates a new Moka-based cache that sends entries evicted for size to `overflow`
+    /// and counts evictions and expirations per provider in `stats`
+    pub fn with_overflow(
+        config: CacheConfig,
+        overflow: Option<Overflow>,
+        stats: Option<Arc<Stats>>,
+    ) -> Self {
+        let stale = (config.stale_if_error_seconds > 0).then(|| {
+            StaleCache::builder()
+                .max_capacity(config.max_entries as u64)
+                .time_to_live(Duration::from_secs(config.stale_if_error_seconds))
+                .build()
+        });
+
+        let negative = (config.negative_ttl_seconds > 0).then(|| {
+            NegativeCache::builder()
+                .max_capacity(config.max_entries as u64)
+                .time_to_live(Duration::from_secs(config.negative_ttl_seconds))
+                .support_invalidation_closures()
+                .build()
+        });
+
+        let builder = MokaCache::builder()
             // Set the maximum cache size
             .max_capacity(config.max_entries as u64)
-            // Set the time-to-live (TTL)
-            .time_to_live(Duration::from_secs(config.ttl_seconds))
-            // Build the cache
-            .build();
+            // Expire every entry at the time stored alongside it
+            .expire_after(EntryExpiry)
+            // Allow evicting entries by predicate
+            .support_invalidation_closures();
 
-        Self { cache }
+        // Drop removed keys from the scan index, keep expired entries around so they can
+        // be served if the backend fails, hand entries evicted for size to the overflow
+        // tier and count both
+        let scan = Arc::new(Mutex::new(ScanIndex::default()));
+        let listener_scan = Arc::clone(&scan);
+        let listener_stale = stale.clone();
+        let builder = builder.eviction_listener(
+            move |key: Arc<CacheKey>, entry: CacheEntry, cause: RemovalCause| {
+                // A replaced entry stays cached under the same key
+                if cause != RemovalCause::Replaced {
+                    listener_scan
+                        .lock()
+                        .unwrap()
+                        .remove(&key.entity, &key.id, entry.generation);
+                }
+                let provider = stats.as_ref().and_then(|stats| stats.provider(&key.entity));
+                match cause {
+                    RemovalCause::Expired => {
+                        if let Some(provider) = &provider {
+                            provider.expirations.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if let Some(stale) = &listener_stale {
+                            stale.insert((*key).clone(), entry.value);
+                        }
+                    }
+                    RemovalCause::Size => {
+                        if let Some(provider) = &provider {
+                            provider.evictions.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if let Some(overflow) = &overflow {
+                            let now = Instant::now();
+                            if entry.expires_at.is_none_or(|at| at > now) {
+                                overflow.send(CachedRecord {
+                                    entity: key.entity.clone(),
+                                    id: key.id.clone(),
+                                    value: entry.value,
+                                    ttl: entry.expires_at.map(|at| at - now),
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            },
+        );
+
+        Self {
+            cache: builder.build(),
+            stale,
+            negative,
+            default_ttl: Duration::from_secs(config.ttl_seconds),
+            scan,
+            generation: AtomicU64::new(0),
+        }
     }
 
     /// Creates a cache key from entity and id
@@ -59,7 +205,7 @@ impl CacheAdapter for MokaBasedCache {
     async fn get_record(&self, entity: &str, id: &str) -> StorageResult<Value> {
         let key = Self::create_key(entity, id);
         if let Some(entry) = self.cache.get(&key).await {
-            return Ok(entry);
+            Ok(entry.value)
         } else {
             Err(StorageError::RecordNotFoundInCache(format!(
                 "Cache Key {:?} not found in Cache",
@@ -68,9 +214,55 @@ impl CacheAdapter for MokaBasedCache {
         }
     }
 
+    async fn get_stale_record(&self, entity: &str, id: &str) -> StorageResult<Value> {
+        let key = Self::create_key(entity, id);
+        let Some(stale) = &self.stale else {
+            return Err(StorageError::RecordNotFoundInCache(format!(
+                "Stale-if-error is disabled, no stale entry for {:?}",
+                key
+            )));
+        };
+
+        // Expired entries reach the holding area with the next maintenance run, which
+        // the storage service schedules, so none is forced on this failure path
+        stale.get(&key).ok_or_else(|| {
+            StorageError::RecordNotFoundInCache(format!(
+                "Cache Key {:?} not found in stale holding area",
+                key
+            ))
+        })
+    }
+
     async fn set_record(&self, entity: &str, id: &str, data: &Value) -> StorageResult<()> {
+        self.set_record_with_ttl(entity, id, data, Some(self.default_ttl))
+            .await
+    }
+
+    async fn set_record_with_ttl(
+        &self,
+        entity: &str,
+        id: &str,
+        data: &Value,
+        ttl: Option<Duration>,
+    ) -> StorageResult<()> {
         let key = Self::create_key(entity, id);
-        self.cache.insert(key, data.clone()).await;
+        if let Some(stale) = &self.stale {
+            stale.invalidate(&key);
+        }
+        if let Some(negative) = &self.negative {
+            negative.invalidate(&key);
+        }
+        let entry = CacheEntry {
+            value: data.clone(),
+            expires_at: expiration(ttl)?,
+            generation: self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+        };
+        // Indexed first, so an eviction of the new entry always finds its key
+        self.scan
+            .lock()
+            .unwrap()
+            .insert(entity, id, entry.generation);
+        self.cache.insert(key, entry).await;
         Ok(())
     }
 
@@ -78,17 +270,169 @@ impl CacheAdapter for MokaBasedCache {
         let key = Self::create_key(entity, id);
         Ok(self.cache.get(&key).await.is_some())
     }
+
+    async fn entries(&self) -> StorageResult<Vec<CachedRecord>> {
+        let now = Instant::now();
+        let records = self
+            .cache
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| at > now))
+            .map(|(key, entry)| CachedRecord {
+                entity: key.entity.clone(),
+                id: key.id.clone(),
+                value: entry.value,
+                ttl: entry.expires_at.map(|at| at - now),
+            })
+            .collect();
+        Ok(records)
+    }
+
+    async fn usage(&self) -> StorageResult<CacheUsage> {
+        // Counts are updated by the periodic maintenance, not on this request path
+        Ok(CacheUsage {
+            entries: self.cache.entry_count(),
+            weighted_size: self.cache.weighted_size(),
+        })
+    }
+
+    async fn scan(
+        &self,
+        entity: Option<&str>,
+        cursor: u64,
+        count: usize,
+    ) -> StorageResult<Vec<ScanKey>> {
+        Ok(self.scan.lock().unwrap().page(entity, cursor, count, |key| {
+            self.cache
+                .contains_key(&Self::create_key(&key.entity, &key.id))
+        }))
+    }
+
+    async fn ttl(&self, entity: &str, id: &str) -> StorageResult<Option<Option<Duration>>> {
+        let key = Self::create_key(entity, id);
+        let now = Instant::now();
+        Ok(self
+            .cache
+            .get(&key)
+            .await
+            .filter(|entry| entry.expires_at.is_none_or(|at| at > now))
+            .map(|entry| entry.expires_at.map(|at| at - now)))
+    }
+
+    async fn expire(&self, entity: &str, id: &str, ttl: Option<Duration>) -> StorageResult<bool> {
+        let expires_at = expiration(ttl)?;
+        // Replacing the entry makes the expiry policy pick up the new expiration time;
+        // the entry is updated atomically, so one evicted meanwhile is not brought back
+        let result = self
+            .cache
+            .entry(Self::create_key(entity, id))
+            .and_compute_with(|entry| async move {
+                match entry {
+                    Some(entry) => Op::Put(CacheEntry {
+                        expires_at,
+                        ..entry.into_value()
+                    }),
+                    None => Op::Nop,
+                }
+            })
+            .await;
+        Ok(matches!(result, CompResult::ReplacedWith(_)))
+    }
+
+    async fn set_missing(&self, entity: &str, id: &str) -> StorageResult<()> {
+        if let Some(negative) = &self.negative {
+            negative.insert(Self::create_key(entity, id), ());
+        }
+        Ok(())
+    }
+
+    async fn is_missing(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        Ok(self
+            .negative
+            .as_ref()
+            .is_some_and(|negative| negative.contains_key(&Self::create_key(entity, id))))
+    }
+
+    async fn invalidate(&self, entity: &str, id: &str) -> StorageResult<bool> {
+        let key = Self::create_key(entity, id);
+        if let Some(stale) = &self.stale {
+            stale.invalidate(&key);
+        }
+        if let Some(negative) = &self.negative {
+            negative.invalidate(&key);
+        }
+        Ok(self.cache.remove(&key).await.is_some())
+    }
+
+    async fn invalidate_matching(
+        &self,
+        entity: &str,
+        matches: IdPredicate,
+    ) -> StorageResult<usize> {
+        let entity = entity.to_string();
+        let is_match = move |key: &CacheKey| key.entity == entity && matches(&key.id);
+
+        // Stale copies of changed records must not be served either
+        if let Some(stale) = &self.stale {
+            for (key, _) in stale.iter().filter(|(key, _)| is_match(key)) {
+                stale.invalidate(key.as_ref());
+            }
+        }
+        // Changed keys may exist now
+        if let Some(negative) = &self.negative {
+            let is_match = is_match.clone();
+            negative
+                .invalidate_entries_if(move |key, _| is_match(key))
+                .map_err(|e| StorageError::CacheError(format!("Failed to invalidate: {}", e)))?;
+        }
+
+        // Matching entries are hidden right away and removed in the background
+        let removed = self.cache.iter().filter(|(key, _)| is_match(key)).count();
+        self.cache
+            .invalidate_entries_if(move |key, _| is_match(key))
+            .map_err(|e| StorageError::CacheError(format!("Failed to invalidate: {}", e)))?;
+        Ok(removed)
+    }
+
+    async fn run_maintenance(&self) {
+        self.cache.run_pending_tasks().await;
+    }
+
+    async fn clear(&self) -> StorageResult<()> {
+        if let Some(stale) = &self.stale {
+            stale.invalidate_all();
+        }
+        if let Some(negative) = &self.negative {
+            negative.invalidate_all();
+        }
+        self.scan.lock().unwrap().clear();
+        self.cache.invalidate_all();
+        Ok(())
+    }
+}
+
+/// Returns when a time to live starting now ends.
+fn expiration(ttl: Option<Duration>) -> StorageResult<Option<Instant>> {
+    ttl.map(|ttl| {
+        Instant::now().checked_add(ttl).ok_or_else(|| {
+            StorageError::InvalidExpireTime(format!("{}s from now", ttl.as_secs()))
+        })
+    })
+    .transpose()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::DiskCacheConfig;
+    use crate::storage::disk_cache::DiskCache;
+    use serde_json::json;
 
     #[tokio::test]
     async fn test_basic_cache_operations() {
         let config = CacheConfig {
             max_entries: 100,
             ttl_seconds: 60,
+            ..Default::default()
         };
         let cache = MokaBasedCache::new(config);
 
@@ -116,6 +460,7 @@ mod tests {
         let config = CacheConfig {
             max_entries: 100,
             ttl_seconds: 1, // 1 second TTL for testing
+            ..Default::default()
         };
         let cache = MokaBasedCache::new(config);
 
@@ -136,4 +481,245 @@ mod tests {
         // Verify it's gone
         assert!(!cache.exists("users", "1").await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_stale_if_error_holding_area() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 1,
+            stale_if_error_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+        let data = json!({ "name": "Test User" });
+
+        cache.set_record("users", "1", &data).await.unwrap();
+
+        // Fresh entries are not served from the holding area
+        assert!(cache.get_stale_record("users", "1").await.is_err());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // The entry expired and the maintenance run moved it to the holding area
+        assert!(cache.get_record("users", "1").await.is_err());
+        cache.run_maintenance().await;
+        let stale = cache.get_stale_record("users", "1").await.unwrap();
+        assert_eq!(stale["name"], "Test User");
+
+        // Refreshing the entry drops the stale copy
+        cache.set_record("users", "1", &data).await.unwrap();
+        assert!(cache.get_stale_record("users", "1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_if_error_disabled() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 1,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+
+        cache.set_record("users", "1", &json!({})).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert!(cache.get_stale_record("users", "1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_entries_report_remaining_ttl() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+
+        cache.set_record("users", "1", &json!({ "id": "1" })).await.unwrap();
+        cache
+            .set_record_with_ttl("users", "2", &json!({ "id": "2" }), Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        cache
+            .set_record_with_ttl("users", "3", &json!({ "id": "3" }), None)
+            .await
+            .unwrap();
+
+        let mut entries = cache.entries().await.unwrap();
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].ttl.unwrap() > Duration::from_secs(55));
+        assert!(entries[1].ttl.unwrap() <= Duration::from_secs(5));
+        assert_eq!(entries[2].ttl, None);
+        assert_eq!(entries[2].value["id"], "3");
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_matching() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+        for id in ["1", "2", "3"] {
+            cache.set_record("users", id, &json!({})).await.unwrap();
+        }
+        cache.set_record("products", "1", &json!({})).await.unwrap();
+
+        let removed = cache
+            .invalidate_matching("users", Arc::new(|id| id != "3"))
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 2);
+        assert!(!cache.exists("users", "1").await.unwrap());
+        assert!(cache.exists("users", "3").await.unwrap());
+        assert!(cache.exists("products", "1").await.unwrap());
+
+        assert!(cache.invalidate("products", "1").await.unwrap());
+        assert!(!cache.invalidate("products", "1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_size_evictions_overflow() {
+        let config = CacheConfig {
+            max_entries: 2,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("prism_l2_overflow_{}", std::process::id()));
+        let disk = DiskCache::open(&DiskCacheConfig {
+            path: path.to_string_lossy().into_owned(),
+            max_bytes: 1 << 20,
+            segment_bytes: 1 << 16,
+            ttl_seconds: 60,
+        })
+        .unwrap();
+        let (tx, mut rx) = disk.overflow();
+        let cache = MokaBasedCache::with_overflow(config, Some(tx), None);
+
+        for i in 0..10 {
+            let id = i.to_string();
+            cache.set_record("users", &id, &json!({ "id": id })).await.unwrap();
+            cache.cache.run_pending_tasks().await;
+        }
+
+        let mut evicted = Vec::new();
+        while let Ok(queued) = rx.try_recv() {
+            assert!(queued.record.ttl.unwrap() <= Duration::from_secs(60));
+            evicted.push(queued.record.id);
+        }
+        assert!(!evicted.is_empty());
+        for id in &evicted {
+            assert!(!cache.exists("users", id).await.unwrap());
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_entry_ttl_overrides_default() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+
+        cache
+            .set_record_with_ttl("users", "1", &json!({}), Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        assert!(!cache.exists("users", "1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 60,
+            negative_ttl_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+        cache.set_missing("users", "1").await.unwrap();
+        cache.set_missing("users", "2").await.unwrap();
+        assert!(cache.is_missing("users", "1").await.unwrap());
+
+        // Caching or invalidating a key forgets that it was missing
+        cache.set_record("users", "1", &json!({})).await.unwrap();
+        assert!(!cache.is_missing("users", "1").await.unwrap());
+        cache
+            .invalidate_matching("users", Arc::new(|id| id == "2"))
+            .await
+            .unwrap();
+        assert!(!cache.is_missing("users", "2").await.unwrap());
+
+        // Disabled by default
+        let cache = MokaBasedCache::new(CacheConfig::default());
+        cache.set_missing("users", "1").await.unwrap();
+        assert!(!cache.is_missing("users", "1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_scan_follows_removals() {
+        let cache = MokaBasedCache::new(CacheConfig::default());
+        for id in ["1", "2", "3"] {
+            cache.set_record("users", id, &json!({})).await.unwrap();
+        }
+        cache.invalidate("users", "1").await.unwrap();
+        // Changing the expiry keeps the key, as does caching it again after a removal
+        cache.expire("users", "2", None).await.unwrap();
+        cache.invalidate("users", "3").await.unwrap();
+        cache.set_record("users", "3", &json!({})).await.unwrap();
+        cache.cache.run_pending_tasks().await;
+
+        let mut ids: Vec<String> = cache
+            .scan(None, 0, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|key| key.id)
+            .collect();
+        ids.sort();
+        assert_eq!(ids, ["2", "3"]);
+        let indexed = cache.scan.lock().unwrap().page(None, 0, 10, |_| true);
+        assert_eq!(indexed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist() {
+        let config = CacheConfig {
+            max_entries: 100,
+            ttl_seconds: 60,
+            ..Default::default()
+        };
+        let cache = MokaBasedCache::new(config);
+        assert_eq!(cache.ttl("users", "1").await.unwrap(), None);
+        assert!(!cache.expire("users", "1", None).await.unwrap());
+
+        cache.set_record("users", "1", &json!({})).await.unwrap();
+        let ttl = cache.ttl("users", "1").await.unwrap().flatten().unwrap();
+        assert!(ttl > Duration::from_secs(59));
+
+        assert!(cache.expire("users", "1", None).await.unwrap());
+        assert_eq!(cache.ttl("users", "1").await.unwrap(), Some(None));
+
+        assert!(
+            cache
+                .expire("users", "1", Some(Duration::from_secs(1)))
+                .await
+                .unwrap()
+        );
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(cache.ttl("users", "1").await.unwrap(), None);
+
+        cache.set_record("users", "2", &json!({})).await.unwrap();
+        let result = cache.expire("users", "2", Some(Duration::MAX)).await;
+        assert!(matches!(result, Err(StorageError::InvalidExpireTime(_))));
+        assert!(cache.ttl("users", "2").await.unwrap().flatten().is_some());
+    }
 }
@@ -0,0 +1,182 @@
+//! Bulk preload of provider tables into the cache.
+//!
+//! A preload scans a whole table in streaming batches and inserts every row into the
+//! cache, keyed by the configured key column, so lookups never hit the backend.
+//! A preload stops once the cache is full, since further rows would only evict the
+//! ones loaded before them.
+
+use datafusion::execution::SendableRecordBatchStream;
+use futures::StreamExt;
+use std::fmt;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::storage::database::record_batch_row_to_json;
+use crate::storage::{CacheAdapter, StorageError, StorageResult};
+
+/// Number of rows between two progress log lines
+const PROGRESS_INTERVAL: u64 = 100_000;
+
+/// Progress of a provider's preload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreloadState {
+    /// No preload is configured.
+    Disabled,
+    /// The preload is still running.
+    Running,
+    /// The preload finished after loading the given number of rows.
+    Done(u64),
+    /// The preload failed; records are fetched per key instead.
+    Failed(String),
+}
+
+impl PreloadState {
+    /// Returns whether the preload finished, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        *self != PreloadState::Running
+    }
+}
+
+impl fmt::Display for PreloadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreloadState::Disabled => f.write_str("disabled"),
+            PreloadState::Running => f.write_str("running"),
+            PreloadState::Done(rows) => write!(f, "done ({} rows)", rows),
+            PreloadState::Failed(e) => write!(f, "failed: {}", e),
+        }
+    }
+}
+
+/// Inserts the rows of the stream into the cache under `entity`, with the given time
+/// to live (`None` never expires).
+///
+/// Rows are keyed by the value of `key_column`. At most `capacity` rows are loaded, the
+/// free room in the cache; the rest are left to be fetched per key. Returns the number
+/// of rows loaded.
+pub async fn preload_records(
+    entity: &str,
+    key_column: &str,
+    mut stream: SendableRecordBatchStream,
+    cache: &dyn CacheAdapter,
+    ttl: Option<Duration>,
+    capacity: u64,
+) -> StorageResult<u64> {
+    let mut loaded = 0u64;
+    while let Some(batch) = stream.next().await {
+        let batch =
+            batch.map_err(|e| StorageError::DatabaseError(format!("Scan error: {}", e)))?;
+        for row in 0..batch.num_rows() {
+            if loaded >= capacity {
+                warn!(
+                    "Preloading {} stopped after {} rows: the table is larger than the free \
+                     room in the cache, raise cache.max_entries to preload all of it",
+                    entity, loaded
+                );
+                return Ok(loaded);
+            }
+            let record = record_batch_row_to_json(&batch, row);
+            let Some(id) = record.get(key_column).and_then(|id| id.as_str()) else {
+                return Err(StorageError::ConfigError(format!(
+                    "Key column {} not found in the rows of {}",
+                    key_column, entity
+                )));
+            };
+            cache.set_record_with_ttl(entity, id, &record, ttl).await?;
+
+            loaded += 1;
+            if loaded.is_multiple_of(PROGRESS_INTERVAL) {
+                info!("Preloading {}: {} rows loaded", entity, loaded);
+            }
+        }
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::storage::moka_cache::MokaBasedCache;
+    use datafusion::arrow::array::{Int64Array, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::datasource::MemTable;
+    use datafusion::prelude::SessionContext;
+    use std::sync::Arc;
+
+    async fn flights_stream() -> SendableRecordBatchStream {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("FLIGHT_NUMBER", DataType::Int64, false),
+            Field::new("ORIGIN_AIRPORT", DataType::Utf8, false),
+        ]));
+        let batch = |ids: Vec<i64>, origins: Vec<&str>| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![
+                    Arc::new(Int64Array::from(ids)),
+                    Arc::new(StringArray::from(origins)),
+                ],
+            )
+            .unwrap()
+        };
+        // Two partitions so the rows arrive in several batches
+        let table = MemTable::try_new(
+            schema.clone(),
+            vec![
+                vec![batch(vec![1, 2], vec!["LAX", "JFK"])],
+                vec![batch(vec![3], vec!["SFO"])],
+            ],
+        )
+        .unwrap();
+        let ctx = SessionContext::new();
+        ctx.register_table("flights", Arc::new(table)).unwrap();
+        ctx.sql("SELECT * FROM flights")
+            .await
+            .unwrap()
+            .execute_stream()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_preload_inserts_every_row() {
+        let cache = MokaBasedCache::new(CacheConfig::default());
+
+        let stream = flights_stream().await;
+        let loaded = preload_records("flights", "FLIGHT_NUMBER", stream, &cache, None, 10)
+            .await
+            .unwrap();
+
+        assert_eq!(loaded, 3);
+        let record = cache.get_record("flights", "3").await.unwrap();
+        assert_eq!(record["ORIGIN_AIRPORT"], "SFO");
+        // Preloaded records stay until evicted or invalidated
+        assert_eq!(cache.ttl("flights", "3").await.unwrap(), Some(None));
+    }
+
+    #[tokio::test]
+    async fn test_preload_stops_when_cache_is_full() {
+        let cache = MokaBasedCache::new(CacheConfig::default());
+        let ttl = Some(Duration::from_secs(600));
+
+        let stream = flights_stream().await;
+        let loaded = preload_records("flights", "FLIGHT_NUMBER", stream, &cache, ttl, 2)
+            .await
+            .unwrap();
+
+        assert_eq!(loaded, 2);
+        let key = cache.scan(Some("flights"), 0, 1).await.unwrap().remove(0);
+        let left = cache.ttl("flights", &key.id).await.unwrap().unwrap();
+        assert!(left.is_some() && left <= ttl);
+    }
+
+    #[tokio::test]
+    async fn test_preload_fails_without_key_column() {
+        let cache = MokaBasedCache::new(CacheConfig::default());
+
+        let stream = flights_stream().await;
+        let result = preload_records("flights", "id", stream, &cache, None, 10).await;
+        assert!(matches!(result, Err(StorageError::ConfigError(_))));
+    }
+}
@@ -0,0 +1,757 @@
+//! Guarded access to a configured data provider.
+//!
+//! A provider bundles a database adapter with the policies applied to every call
+//! into it: a per-call timeout, bounded retries with backoff, a circuit breaker and
+//! concurrency and rate limits. Providers with a preload configured load their whole
+//! table into the cache at startup.
+
+use datafusion::error::DataFusionError;
+use datafusion::execution::SendableRecordBatchStream;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::prelude::SessionContext;
+use futures::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::config::{
+    DataProviderConfig, DatabaseProvider, PreloadConfig, QueryConfig, ResilienceConfig,
+};
+use std::path::Path;
+use crate::stats::ProviderStats;
+use crate::storage::database::{DatabaseType, TableChange, format_lsn};
+use crate::storage::invalidation::CacheUpdate;
+use crate::storage::limiter::{LimiterPermit, ProviderLimiter};
+use crate::storage::preload::{PreloadState, preload_records};
+use crate::storage::query::{QueryCache, normalize_sql};
+use crate::storage::resilience::{CircuitBreaker, CircuitState, backoff_delay};
+use crate::storage::time_travel::AsOf;
+use crate::storage::write_behind::{QueueCounts, WriteBehindQueue};
+use crate::storage::{
+    CacheAdapter, DatabaseAdapter, StorageError, StorageResult, WritableAdapter,
+};
+
+/// Shortest time a batch flush may take before it is abandoned
+const WRITE_BEHIND_MIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Status of a provider as field/value pairs.
+pub type ProviderStatus = Vec<(&'static str, String)>;
+
+/// Readiness of a provider, as reported by the readiness probe.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProviderReadiness {
+    /// Whether the provider can serve requests
+    pub ready: bool,
+    /// Whether the provider is left out of the overall readiness
+    pub optional: bool,
+    /// Progress of the preload, if the provider was initialized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preload: Option<String>,
+    /// State of the circuit breaker, if the provider was initialized
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit: Option<String>,
+    /// Why the provider is not ready
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Current values of a provider, exported as metrics gauges.
+#[derive(Debug, Clone)]
+pub struct ProviderGauges {
+    /// State of the circuit breaker
+    pub circuit: CircuitState,
+    /// Confirmed position and lag in bytes of the replication slot, if followed
+    pub replication: Option<(u64, u64)>,
+    /// Counts of the write-behind queue, if writes are queued
+    pub write_behind: Option<QueueCounts>,
+}
+
+/// A named data provider with its resilience policies.
+pub struct Provider {
+    /// Name of the provider, used as the entity for adapter calls
+    name: String,
+    /// Type of the underlying database
+    kind: DatabaseProvider,
+    /// The database adapter
+    adapter: DatabaseType,
+    /// Timeout and retry settings
+    resilience: ResilienceConfig,
+    /// Circuit breaker guarding the adapter
+    breaker: CircuitBreaker,
+    /// Concurrency and rate limiter for backend calls
+    limiter: ProviderLimiter,
+    /// Bulk preload settings, if the table is loaded at startup
+    preload: Option<PreloadConfig>,
+    /// Progress of the preload, watched to wait for readiness
+    preload_state: watch::Sender<PreloadState>,
+    /// Whether writes are passed through to the backend
+    writable: bool,
+    /// Queue of writes waiting to be flushed, for write-behind providers
+    write_behind: Option<WriteBehindQueue>,
+    /// Call counts and latencies of the backend
+    stats: Arc<ProviderStats>,
+    /// Whether the provider is left out of the overall readiness
+    optional: bool,
+    /// Limits of ad-hoc SQL queries
+    query: QueryConfig,
+    /// Cached results of ad-hoc SQL queries
+    queries: QueryCache,
+}
+
+impl Provider {
+    /// Creates a provider around an initialized database adapter.
+    ///
+    /// Opens the write-behind queue, if configured, replaying unflushed writes.
+    pub fn new(
+        config: &DataProviderConfig,
+        adapter: DatabaseType,
+        stats: Arc<ProviderStats>,
+    ) -> StorageResult<Self> {
+        let breaker = CircuitBreaker::new(
+            config.resilience.failure_threshold,
+            Duration::from_secs(config.resilience.open_seconds),
+        );
+        let write_behind = match &config.write_behind {
+            Some(write_behind) => {
+                let dir = Path::new(&write_behind.path).join(&config.name);
+                Some(WriteBehindQueue::open(&dir, write_behind)?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            kind: config.provider.clone(),
+            adapter,
+            resilience: config.resilience.clone(),
+            breaker,
+            limiter: ProviderLimiter::new(&config.limits),
+            preload: config.preload.clone(),
+            preload_state: watch::Sender::new(if config.preload.is_some() {
+                PreloadState::Running
+            } else {
+                PreloadState::Disabled
+            }),
+            writable: config.writable,
+            write_behind,
+            stats,
+            optional: config.optional,
+            query: config.query.clone(),
+            queries: QueryCache::new(config.query.max_cached_rows),
+        })
+    }
+
+    /// Loads the whole table into the cache, if a preload is configured.
+    ///
+    /// A failed preload is logged; the provider then serves records per key.
+    pub async fn preload(&self, cache: &dyn CacheAdapter, max_entries: u64) {
+        let Some(preload) = &self.preload else {
+            return;
+        };
+
+        info!("Preloading provider {} into the cache", self.name);
+        let started = Instant::now();
+        let ttl = preload.ttl_seconds.map(Duration::from_secs);
+        let result = match (self.adapter.scan(preload).await, cache.usage().await) {
+            (Ok(stream), Ok(usage)) => {
+                let capacity = max_entries.saturating_sub(usage.entries);
+                let key_column = &preload.key_column;
+                preload_records(&self.name, key_column, stream, cache, ttl, capacity).await
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+
+        let state = match result {
+            Ok(rows) => {
+                info!(
+                    "Preloaded {} rows of provider {} in {:?}",
+                    rows,
+                    self.name,
+                    started.elapsed()
+                );
+                PreloadState::Done(rows)
+            }
+            Err(e) => {
+                warn!("Preload of provider {} failed: {}", self.name, e);
+                PreloadState::Failed(e.to_string())
+            }
+        };
+        self.preload_state.send_replace(state);
+    }
+
+    /// Returns the interval between checks for changes in the backing table, if any.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.adapter.poll_interval()
+    }
+
+    /// Starts listening for changes pushed by the backend, if configured.
+    pub fn subscribe(&self) -> Option<UnboundedReceiver<CacheUpdate>> {
+        self.adapter.subscribe()
+    }
+
+    /// Checks the backing table for changes since the last poll.
+    pub async fn poll_changes(&self) -> StorageResult<Option<TableChange>> {
+        self.adapter.poll_changes().await
+    }
+
+    /// Waits until the preload of this provider, if any, has finished.
+    pub async fn wait_ready(&self) {
+        let mut state = self.preload_state.subscribe();
+        // The sender lives as long as the provider, so this cannot fail
+        let _ = state.wait_for(PreloadState::is_finished).await;
+    }
+
+    /// Fetches a record through the adapter, applying timeout, retries and the circuit breaker.
+    ///
+    /// Fields of queued writes that were not flushed yet are set over the stored record,
+    /// as the flush will set them.
+    pub async fn fetch_record(&self, id: &str) -> StorageResult<Vec<Value>> {
+        let pending = self
+            .write_behind
+            .as_ref()
+            .and_then(|queue| queue.pending_record(id));
+        let mut records = match self
+            .guarded(id, || self.adapter.fetch_record(&self.name, id))
+            .await
+        {
+            // The queued write creates the record
+            Err(StorageError::RecordNotInDatabase(_)) if pending.is_some() => Vec::new(),
+            result => result?,
+        };
+        if let Some(pending) = pending {
+            match (records.first_mut(), pending) {
+                (Some(Value::Object(stored)), Value::Object(fields)) => stored.extend(fields),
+                (_, pending) => records = vec![pending],
+            }
+        }
+        Ok(records)
+    }
+
+    /// Checks whether a record exists in the backend, with the same policies as `fetch_record`.
+    pub async fn record_exists(&self, id: &str) -> StorageResult<bool> {
+        if self
+            .write_behind
+            .as_ref()
+            .is_some_and(|queue| queue.pending_record(id).is_some())
+        {
+            return Ok(true);
+        }
+        self.guarded(id, || self.adapter.record_exists(&self.name, id))
+            .await
+    }
+
+    /// Returns a page of key values of the backing table, with the same policies as `fetch_record`.
+    pub async fn scan_keys(&self, offset: usize, limit: usize) -> StorageResult<Vec<String>> {
+        self.guarded("*", || self.adapter.scan_keys(&self.name, offset, limit))
+            .await
+    }
+
+    /// Fetches the records of several ids that exist, with the same policies as `fetch_record`.
+    pub async fn fetch_records(&self, ids: &[String]) -> StorageResult<Vec<(String, Value)>> {
+        let mut records = self
+            .guarded("*", || self.adapter.fetch_records(&self.name, ids))
+            .await?;
+        let Some(queue) = &self.write_behind else {
+            return Ok(records);
+        };
+        for id in ids {
+            let Some(pending) = queue.pending_record(id) else {
+                continue;
+            };
+            let stored = records.iter_mut().find(|(found, _)| found == id);
+            match (stored.map(|(_, record)| record), pending) {
+                (Some(Value::Object(stored)), Value::Object(fields)) => stored.extend(fields),
+                (Some(record), pending) => *record = pending,
+                // The queued write creates the record
+                (None, pending) => records.push((id.clone(), pending)),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Streams the rows of the backing table matching a SQL filter expression, with the
+    /// same policies as `fetch_record` for starting the scan.
+    ///
+    /// The stream fails with `LimitExceeded` once more than `query.max_rows` rows match.
+    pub async fn scan_filtered(&self, filter: &str) -> StorageResult<SendableRecordBatchStream> {
+        let max_rows = self.query.max_rows;
+        // One extra row tells whether the filter matches more rows than allowed
+        let stream = self
+            .guarded("*", || self.adapter.scan_filtered(filter, max_rows + 1))
+            .await?;
+        let schema = stream.schema();
+        let mut rows = 0;
+        let capped = stream.map(move |batch| {
+            let batch = batch?;
+            rows += batch.num_rows();
+            if rows > max_rows {
+                return Err(DataFusionError::External(Box::new(
+                    StorageError::LimitExceeded(format!(
+                        "filter matches more than {} rows",
+                        max_rows
+                    )),
+                )));
+            }
+            Ok(batch)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, capped)))
+    }
+
+    /// Fetches a record from a historical table version, with the same policies as `fetch_record`.
+    pub async fn fetch_record_at(&self, id: &str, version: i64) -> StorageResult<Vec<Value>> {
+        self.guarded(id, || self.adapter.fetch_record_at(id, version))
+            .await
+    }
+
+    /// Writes a record through the adapter, with the same policies as `fetch_record`.
+    ///
+    /// Write-behind providers queue the record instead, once the adapter validated it,
+    /// so that a record the backend rejects never enters the queue. Returns the record
+    /// as stored by the backend, or as queued.
+    pub async fn upsert_record(&self, id: &str, record: &Value) -> StorageResult<Value> {
+        if !self.writable {
+            return Err(StorageError::ReadOnly(format!(
+                "{} is not configured as writable",
+                self.name
+            )));
+        }
+        let writer = self.adapter.writer().ok_or_else(|| {
+            StorageError::ReadOnly(format!(
+                "{} ({:?}) does not support writes",
+                self.name, self.kind
+            ))
+        })?;
+        if let Some(queue) = &self.write_behind {
+            let record = self.validate_record(writer, id, record).await?;
+            queue.append(id, &record).await?;
+            return Ok(record);
+        }
+        let stored = self
+            .guarded(id, || writer.upsert_record(&self.name, id, record))
+            .await?;
+        self.clear_queries();
+        Ok(stored)
+    }
+
+    /// Validates a record before it is queued.
+    ///
+    /// The queue takes writes while the backend is down, so a record that cannot be
+    /// validated in time is queued as it is; the flush then moves it to the dead
+    /// letter file if the backend rejects it.
+    async fn validate_record(
+        &self,
+        writer: &dyn WritableAdapter,
+        id: &str,
+        record: &Value,
+    ) -> StorageResult<Value> {
+        let timeout = Duration::from_millis(self.resilience.timeout_ms);
+        match tokio::time::timeout(timeout, writer.validate_record(&self.name, id, record)).await {
+            Ok(Ok(record)) => Ok(record),
+            Ok(Err(e)) if !is_transient(&e) => Err(e),
+            Ok(Err(e)) => {
+                warn!("Queueing {}:{} without validation: {}", self.name, id, e);
+                Ok(record.clone())
+            }
+            Err(_) => {
+                warn!("Queueing {}:{} without validation: validation timed out", self.name, id);
+                Ok(record.clone())
+            }
+        }
+    }
+
+    /// Returns whether writes are queued and flushed in batches.
+    pub fn is_write_behind(&self) -> bool {
+        self.write_behind.is_some()
+    }
+
+    /// Waits until the queued writes are due for a flush.
+    pub async fn wait_for_flush(&self) {
+        match &self.write_behind {
+            Some(queue) => queue.wait_for_flush().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Flushes the queued writes to the backend in one batch.
+    ///
+    /// Waits for a flush that is already running. If the backend rejects the batch
+    /// because of its records, they are written one at a time and the rejected ones
+    /// are moved to the dead letter file. Returns the number of records written.
+    pub async fn flush_writes(&self) -> StorageResult<usize> {
+        let Some(queue) = &self.write_behind else {
+            return Ok(0);
+        };
+        let _flushing = queue.lock_flush().await;
+        let Some(batch) = queue.take_batch()? else {
+            return Ok(0);
+        };
+
+        let mut result = self.write_batch(&batch.records).await.map(|()| 0);
+        if matches!(result, Err(StorageError::InvalidRecord(_))) {
+            result = self.write_each(queue, &batch.records).await;
+        }
+        match result {
+            Ok(rejected) => {
+                let flushed = batch.records.len() - rejected;
+                queue.complete(batch, flushed);
+                self.clear_queries();
+                Ok(flushed)
+            }
+            Err(e) => {
+                queue.fail(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes a batch of records with the flush timeout.
+    async fn write_batch(&self, records: &[(String, Value)]) -> StorageResult<()> {
+        let timeout = Duration::from_millis(self.resilience.timeout_ms);
+        let started = Instant::now();
+        let result = match tokio::time::timeout(
+            timeout.max(WRITE_BEHIND_MIN_TIMEOUT),
+            self.adapter.write_batch(&self.name, records),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(StorageError::Timeout(format!(
+                "{} did not complete the flush in time",
+                self.name
+            ))),
+        };
+        self.record_call(started, &result);
+        result
+    }
+
+    /// Writes records one at a time, moving those the backend rejects to the dead letters.
+    ///
+    /// Returns the number of rejected records.
+    async fn write_each(
+        &self,
+        queue: &WriteBehindQueue,
+        records: &[(String, Value)],
+    ) -> StorageResult<usize> {
+        let mut rejected = 0;
+        for (id, record) in records {
+            match self.write_batch(&[(id.clone(), record.clone())]).await {
+                Ok(()) => {}
+                Err(StorageError::InvalidRecord(e)) => {
+                    warn!("{} rejected the queued write of {}: {}", self.name, id, e);
+                    queue.dead_letter(id, record, &e)?;
+                    rejected += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(rejected)
+    }
+
+    /// Runs a read-only SQL query against the backing table, serving cached results.
+    ///
+    /// The result is cached for `ttl`, or the configured time to live if unset. Queries
+    /// are neither retried nor subject to the call timeout, but to the query timeout,
+    /// which includes waiting for the limiter.
+    pub async fn query(&self, sql: &str, ttl: Option<Duration>) -> StorageResult<Arc<Vec<Value>>> {
+        let sql = normalize_sql(sql)?;
+        if let Some(rows) = self.queries.get(&sql).await {
+            debug!("Query result of {} served from cache: {}", self.name, sql);
+            return Ok(rows);
+        }
+
+        let timeout = Duration::from_millis(self.query.timeout_ms);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let permit = self.acquire_until(deadline, self.query.timeout_ms).await?;
+        let started = Instant::now();
+        // One extra row tells whether the result exceeds the limit
+        let limit = self.query.max_rows + 1;
+        let query = self.adapter.query(&sql, limit);
+        let result = match tokio::time::timeout_at(deadline, query).await {
+            Ok(result) => result,
+            Err(_) => Err(StorageError::Timeout(format!(
+                "Query against {} did not complete within {}ms",
+                self.name, self.query.timeout_ms
+            ))),
+        };
+        self.record_call(started, &result);
+        drop(permit);
+
+        let rows = result?;
+        if rows.len() > self.query.max_rows {
+            return Err(StorageError::LimitExceeded(format!(
+                "query returned more than {} rows, add a LIMIT",
+                self.query.max_rows
+            )));
+        }
+        let rows = Arc::new(rows);
+        let ttl = ttl.unwrap_or(Duration::from_secs(self.query.ttl_seconds));
+        if !ttl.is_zero() {
+            self.queries.insert(sql, Arc::clone(&rows), ttl).await;
+        }
+        Ok(rows)
+    }
+
+    /// Drops the cached query results, after the backing table changed.
+    pub fn clear_queries(&self) {
+        self.queries.clear();
+    }
+
+    /// Returns the session of the backing table, for query templates over it.
+    pub fn session(&self) -> Option<SessionContext> {
+        self.adapter.session()
+    }
+
+    /// Resolves a point in the table's history to a table version.
+    pub async fn resolve_version(&self, as_of: &AsOf) -> StorageResult<i64> {
+        self.adapter.resolve_version(as_of).await
+    }
+
+    /// Waits for a limiter permit until the deadline of a call.
+    ///
+    /// Fails with `Busy` if no slot or rate limit token frees up in time.
+    async fn acquire_until(
+        &self,
+        deadline: tokio::time::Instant,
+        timeout_ms: u64,
+    ) -> StorageResult<LimiterPermit<'_>> {
+        match tokio::time::timeout_at(deadline, self.limiter.acquire(&self.name)).await {
+            Ok(result) => result,
+            Err(_) => Err(StorageError::Busy(format!(
+                "no call slot of {} freed up within {}ms",
+                self.name, timeout_ms
+            ))),
+        }
+    }
+
+    /// Runs an adapter call, applying timeout, retries and the circuit breaker.
+    async fn guarded<T, F, Fut>(&self, id: &str, call: F) -> StorageResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = StorageResult<T>>,
+    {
+        if !self.breaker.try_acquire() {
+            return Err(StorageError::CircuitOpen(self.name.clone()));
+        }
+
+        let timeout = Duration::from_millis(self.resilience.timeout_ms);
+        let mut attempt = 0;
+        loop {
+            // Waiting for the limiter counts against the timeout of the attempt, and the
+            // permit is held for this attempt only, not across backoff sleeps
+            let deadline = tokio::time::Instant::now() + timeout;
+            let permit = match self.acquire_until(deadline, self.resilience.timeout_ms).await {
+                Ok(permit) => permit,
+                Err(e) => {
+                    // Rejected before reaching the backend, so a half-open probe is not used up
+                    self.breaker.release_probe();
+                    return Err(e);
+                }
+            };
+            let started = Instant::now();
+            let result = match tokio::time::timeout_at(deadline, call()).await {
+                Ok(result) => result,
+                Err(_) => Err(StorageError::Timeout(format!(
+                    "{} did not respond within {}ms",
+                    self.name, self.resilience.timeout_ms
+                ))),
+            };
+            self.record_call(started, &result);
+            drop(permit);
+
+            match result {
+                Err(e) if is_transient(&e) => {
+                    if attempt < self.resilience.max_retries {
+                        let delay = backoff_delay(
+                            attempt,
+                            Duration::from_millis(self.resilience.backoff_base_ms),
+                            Duration::from_millis(self.resilience.backoff_max_ms),
+                        );
+                        debug!(
+                            "Retrying {}:{} in {:?} after transient error: {}",
+                            self.name, id, delay, e
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                        continue;
+                    }
+                    warn!(
+                        "Provider {} failed after {} attempts: {}",
+                        self.name,
+                        attempt + 1,
+                        e
+                    );
+                    self.breaker.record_failure();
+                    return Err(e);
+                }
+                other => {
+                    // Any answer from the backend, including "not found", counts as healthy
+                    self.breaker.record_success();
+                    return other;
+                }
+            }
+        }
+    }
+
+    /// Counts a backend call and its latency; records that were not found are no error,
+    /// and requests the backend rejects for their content are client errors.
+    fn record_call<T>(&self, started: Instant, result: &StorageResult<T>) {
+        self.stats.backend_calls.fetch_add(1, Ordering::Relaxed);
+        self.stats.latency.record(started.elapsed());
+        let counter = match result {
+            Ok(_)
+            | Err(StorageError::RecordNotInDatabase(_) | StorageError::EntityNotFound(_)) => {
+                return;
+            }
+            Err(
+                StorageError::InvalidQuery(_)
+                | StorageError::LimitExceeded(_)
+                | StorageError::InvalidRecord(_),
+            ) => &self.stats.client_errors,
+            Err(_) => &self.stats.backend_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns whether the provider is ready: preloaded and, if `ping` is set, reachable.
+    pub async fn readiness(&self, ping: bool) -> ProviderReadiness {
+        let preload = self.preload_state.borrow().clone();
+        let mut error = (!preload.is_finished()).then(|| "preload is running".to_string());
+        if ping && error.is_none() {
+            let timeout = Duration::from_millis(self.resilience.timeout_ms);
+            error = match tokio::time::timeout(timeout, self.adapter.ping()).await {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(format!("ping timed out after {}ms", self.resilience.timeout_ms)),
+            };
+        }
+        ProviderReadiness {
+            ready: error.is_none(),
+            optional: self.optional,
+            preload: Some(preload.to_string()),
+            circuit: Some(self.breaker.state().to_string()),
+            error,
+        }
+    }
+
+    /// Returns the values exported as metrics gauges.
+    pub fn gauges(&self) -> ProviderGauges {
+        let replication = self
+            .adapter
+            .replication_lsn()
+            .map(|lsn| (lsn, self.adapter.replication_lag().unwrap_or_default()));
+        ProviderGauges {
+            circuit: self.breaker.state(),
+            replication,
+            write_behind: self.write_behind.as_ref().map(WriteBehindQueue::counts),
+        }
+    }
+
+    /// Returns the provider status as field/value pairs.
+    pub fn status(&self) -> ProviderStatus {
+        let mut status = vec![
+            ("type", format!("{:?}", self.kind)),
+            ("circuit", self.breaker.state().to_string()),
+            (
+                "consecutive_failures",
+                self.breaker.consecutive_failures().to_string(),
+            ),
+            ("in_flight", self.limiter.in_flight().to_string()),
+            ("queued", self.limiter.queued().to_string()),
+            ("preload", self.preload_state.borrow().to_string()),
+        ];
+        if let Some(version) = self.adapter.table_version() {
+            status.push(("table_version", version.to_string()));
+        }
+        if let Some(lsn) = self.adapter.replication_lsn() {
+            status.push(("replication_lsn", format_lsn(lsn)));
+        }
+        if let Some(lag) = self.adapter.replication_lag() {
+            status.push(("replication_lag_bytes", lag.to_string()));
+        }
+        if let Some(queue) = &self.write_behind {
+            status.extend(queue.status());
+        }
+        status
+    }
+}
+
+/// Returns whether an error is worth retrying and counts against the circuit breaker.
+fn is_transient(error: &StorageError) -> bool {
+    matches!(
+        error,
+        StorageError::DatabaseError(_) | StorageError::Timeout(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::storage::StorageService;
+
+    #[tokio::test]
+    async fn test_record_call_counts_client_errors() {
+        let storage = StorageService::new(&AppConfig::default()).await.unwrap();
+        let provider = &storage.providers["users"];
+        let invalid = || StorageError::InvalidQuery("no such column".to_string());
+        provider.record_call(Instant::now(), &Err::<(), _>(invalid()));
+        let limit = StorageError::LimitExceeded("too many rows".to_string());
+        provider.record_call(Instant::now(), &Err::<(), _>(limit));
+        let down = StorageError::DatabaseError("connection refused".to_string());
+        provider.record_call(Instant::now(), &Err::<(), _>(down));
+
+        let stats = storage.stats().provider("users").unwrap();
+        assert_eq!(stats.backend_calls.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.client_errors.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.backend_errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_limiter_wait_counts_against_timeout() {
+        let mut config = AppConfig::default();
+        config.database.providers[0].limits.max_in_flight = 1;
+        config.database.providers[0].limits.max_queued = 10;
+        config.database.providers[0].resilience.timeout_ms = 50;
+        let storage = StorageService::new(&config).await.unwrap();
+        let provider = &storage.providers["users"];
+
+        let _held = provider.limiter.acquire("users").await.unwrap();
+        let started = Instant::now();
+        let result = provider.fetch_record("123").await;
+        assert!(matches!(result, Err(StorageError::Busy(_))));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(provider.limiter.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_queued_write_is_read_from_cache() {
+        let dir = std::env::temp_dir().join("prism_wal_test_cached_write");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut config = AppConfig::default();
+        config.database.providers[0].writable = true;
+        config.database.providers[0].limits.max_in_flight = 1;
+        config.database.providers[0].resilience.timeout_ms = 50;
+        config.database.providers[0].write_behind = Some(crate::config::WriteBehindConfig {
+            path: dir.to_string_lossy().into_owned(),
+            flush_interval_ms: 3_600_000,
+            ..Default::default()
+        });
+        let storage = StorageService::new(&config).await.unwrap();
+        let provider = &storage.providers["users"];
+        storage.fetch_record("users", "123").await.unwrap();
+
+        // With the backend unavailable, the queued fields are served over the cached copy
+        let _held = provider.limiter.acquire("users").await.unwrap();
+        let record = serde_json::json!({ "age": 31 });
+        storage.write_record("users", "123", &record).await.unwrap();
+        let record = storage.fetch_record("users", "123").await.unwrap();
+        assert_eq!((&record["name"], &record["age"]), (&"John Doe".into(), &31.into()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! Ad-hoc SQL queries and the cache of their results.
+//!
+//! Results are keyed by the normalized SQL, so queries differing only in whitespace,
+//! comments or a trailing semicolon share a cached result. The cache is bounded by the
+//! total number of rows it holds rather than by its number of results.
+
+use moka::Expiry;
+use moka::future::Cache;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::storage::{StorageError, StorageResult};
+
+/// A cached result with its own time to live.
+#[derive(Clone)]
+struct CachedResult {
+    rows: Arc<Vec<Value>>,
+    ttl: Duration,
+}
+
+/// Expiry policy that reads the time to live stored in each result
+struct ResultExpiry;
+
+impl Expiry<String, CachedResult> for ResultExpiry {
+    fn expire_after_create(
+        &self,
+        _sql: &String,
+        value: &CachedResult,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(value.ttl)
+    }
+}
+
+/// Results of the queries against a provider, keyed by normalized SQL.
+pub struct QueryCache {
+    results: Cache<String, CachedResult>,
+}
+
+impl QueryCache {
+    /// Creates a cache holding results of at most `max_rows` rows in total.
+    pub fn new(max_rows: u64) -> Self {
+        Self {
+            results: Cache::builder()
+                .max_capacity(max_rows)
+                // An empty result still takes an entry
+                .weigher(|_sql, result: &CachedResult| {
+                    result.rows.len().clamp(1, u32::MAX as usize) as u32
+                })
+                .expire_after(ResultExpiry)
+                .build(),
+        }
+    }
+
+    /// Returns the cached rows of a normalized query.
+    pub async fn get(&self, sql: &str) -> Option<Arc<Vec<Value>>> {
+        self.results.get(sql).await.map(|result| result.rows)
+    }
+
+    /// Caches the rows of a normalized query for `ttl`.
+    pub async fn insert(&self, sql: String, rows: Arc<Vec<Value>>, ttl: Duration) {
+        self.results.insert(sql, CachedResult { rows, ttl }).await;
+    }
+
+    /// Drops every cached result, e.g. after the table changed.
+    pub fn clear(&self) {
+        self.results.invalidate_all();
+    }
+}
+
+/// Normalizes a query: comments are removed, whitespace outside of quotes is collapsed
+/// into single spaces and trailing semicolons are removed.
+///
+/// Fails unless the query is a single SELECT statement, optionally with a WITH clause.
+pub fn normalize_sql(sql: &str) -> StorageResult<String> {
+    let sql = strip_comments(sql);
+    let mut normalized = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut statements = 1;
+    for c in sql
+        .trim()
+        .trim_end_matches([';', ' ', '\t', '\r', '\n'])
+        .chars()
+    {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ';' => statements += 1,
+            None if c.is_whitespace() => {
+                if !normalized.ends_with(' ') {
+                    normalized.push(' ');
+                }
+                continue;
+            }
+            None => {}
+        }
+        normalized.push(c);
+    }
+
+    let keyword = normalized
+        .split([' ', '('])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    if statements > 1 {
+        return Err(StorageError::InvalidQuery(
+            "only a single statement is allowed".to_string(),
+        ));
+    }
+    if keyword != "SELECT" && keyword != "WITH" {
+        return Err(StorageError::InvalidQuery(
+            "only SELECT queries are allowed".to_string(),
+        ));
+    }
+    Ok(normalized)
+}
+
+/// Replaces the `--` and `/* */` comments outside of quotes with a space.
+fn strip_comments(sql: &str) -> String {
+    let mut stripped = String::with_capacity(sql.len());
+    let mut quote = None;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c, chars.peek()) {
+            (Some(q), _, _) if c == q => quote = None,
+            (Some(_), _, _) => {}
+            (None, '\'' | '"', _) => quote = Some(c),
+            (None, '-', Some('-')) => {
+                // A line comment runs to the end of the line, which is kept
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                stripped.push(' ');
+                continue;
+            }
+            (None, '/', Some('*')) => {
+                chars.next();
+                let mut previous = None;
+                for c in chars.by_ref() {
+                    if previous == Some('*') && c == '/' {
+                        break;
+                    }
+                    previous = Some(c);
+                }
+                stripped.push(' ');
+                continue;
+            }
+            (None, _, _) => {}
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_sql() {
+        assert_eq!(
+            normalize_sql("  SELECT *\n  FROM  flights\tWHERE x = 'a  b' ;\n").unwrap(),
+            "SELECT * FROM flights WHERE x = 'a  b'"
+        );
+        assert_eq!(
+            normalize_sql("with t as (select 1) select * from t").unwrap(),
+            "with t as (select 1) select * from t"
+        );
+        assert_eq!(normalize_sql("SELECT ';' AS s").unwrap(), "SELECT ';' AS s");
+        assert!(matches!(
+            normalize_sql("DROP TABLE flights"),
+            Err(StorageError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            normalize_sql("SELECT 1; DROP TABLE flights"),
+            Err(StorageError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn test_normalize_sql_strips_comments() {
+        assert_eq!(
+            normalize_sql("-- by year\nSELECT * /* all */ FROM flights; -- done").unwrap(),
+            "SELECT * FROM flights"
+        );
+        assert_eq!(
+            normalize_sql("SELECT '--', \"/*\" FROM t").unwrap(),
+            "SELECT '--', \"/*\" FROM t"
+        );
+        assert!(matches!(
+            normalize_sql("/* SELECT */ DROP TABLE flights"),
+            Err(StorageError::InvalidQuery(_))
+        ));
+        assert!(matches!(
+            normalize_sql("SELECT 1 /* ; */; DROP TABLE flights -- ;"),
+            Err(StorageError::InvalidQuery(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_capacity_counts_rows() {
+        let cache = QueryCache::new(10);
+        let rows = |n: usize| Arc::new(vec![serde_json::json!({ "n": 1 }); n]);
+        let ttl = Duration::from_secs(60);
+        cache.insert("SELECT 1".to_string(), rows(6), ttl).await;
+        cache.insert("SELECT 2".to_string(), rows(6), ttl).await;
+        cache.results.run_pending_tasks().await;
+        // Two results of 6 rows do not fit in 10 rows
+        assert_eq!(cache.results.entry_count(), 1);
+        assert_eq!(cache.results.weighted_size(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_results_expire() {
+        let cache = QueryCache::new(10);
+        let rows = Arc::new(vec![serde_json::json!({ "n": 1 })]);
+        cache
+            .insert(
+                "SELECT 1".to_string(),
+                Arc::clone(&rows),
+                Duration::from_millis(50),
+            )
+            .await;
+        assert_eq!(cache.get("SELECT 1").await, Some(rows));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cache.get("SELECT 1").await.is_none());
+    }
+}
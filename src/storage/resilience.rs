@@ -0,0 +1,226 @@
+//! Resilience primitives for provider calls.
+//!
+//! This module provides the circuit breaker and the jittered exponential backoff
+//! used to guard calls into the database adapters.
+
+use rand::Rng;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// State of a circuit breaker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through to the provider.
+    Closed,
+    /// Calls fail fast without reaching the provider.
+    Open,
+    /// A single probe call is allowed to test whether the provider recovered.
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Mutable state of a circuit breaker.
+#[derive(Debug)]
+struct BreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    probe_started_at: Option<Instant>,
+}
+
+/// Circuit breaker that opens after repeated failures.
+///
+/// While open, calls are rejected until `open_for` has elapsed. After that a single
+/// half-open probe is let through; its outcome closes or re-opens the circuit.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    inner: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed circuit breaker.
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            inner: Mutex::new(BreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                opened_at: None,
+                probe_started_at: None,
+            }),
+        }
+    }
+
+    /// Returns whether a call may proceed, moving an expired open circuit to half-open.
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let elapsed = inner.opened_at.is_some_and(|at| now - at >= self.open_for);
+                if elapsed {
+                    inner.state = CircuitState::HalfOpen;
+                    inner.probe_started_at = Some(now);
+                }
+                elapsed
+            }
+            CircuitState::HalfOpen => {
+                // Allow a new probe if the previous one never reported back
+                let stuck = inner
+                    .probe_started_at
+                    .is_none_or(|at| now - at >= self.open_for);
+                if stuck {
+                    inner.probe_started_at = Some(now);
+                }
+                stuck
+            }
+        }
+    }
+
+    /// Records a successful call, closing the circuit.
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = CircuitState::Closed;
+        inner.consecutive_failures = 0;
+        inner.opened_at = None;
+        inner.probe_started_at = None;
+    }
+
+    /// Records a failed call, opening the circuit once the threshold is reached.
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        if inner.state == CircuitState::HalfOpen
+            || inner.consecutive_failures >= self.failure_threshold
+        {
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(Instant::now());
+            inner.probe_started_at = None;
+        }
+    }
+
+    /// Hands back a half-open probe that never reached the provider, so the next call
+    /// may probe instead of waiting for the stuck probe to time out.
+    pub fn release_probe(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state == CircuitState::HalfOpen {
+            inner.probe_started_at = None;
+        }
+    }
+
+    /// Returns the current state of the circuit.
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Returns the number of consecutive failed calls.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.inner.lock().unwrap().consecutive_failures
+    }
+}
+
+/// Computes the delay before retry number `attempt` (starting at 0).
+///
+/// The delay grows exponentially from `base` up to `max`, with full jitter applied.
+pub fn backoff_delay(attempt: u32, base: Duration, max: Duration) -> Duration {
+    let exp = base.saturating_mul(2u32.saturating_pow(attempt)).min(max);
+    let millis = exp.as_millis() as u64;
+    if millis == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire());
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.try_acquire());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures(), 1);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // The open period elapsed, so a probe is let through
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+
+        // A failed probe re-opens the circuit
+        breaker.record_failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // A successful probe closes it
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_allows_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50));
+
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+
+        // A probe rejected before reaching the provider is handed back
+        breaker.release_probe();
+        assert!(breaker.try_acquire());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn test_backoff_delay_is_bounded() {
+        let base = Duration::from_millis(10);
+        let max = Duration::from_millis(100);
+
+        for attempt in 0..10 {
+            let cap = base.saturating_mul(2u32.pow(attempt)).min(max);
+            assert!(backoff_delay(attempt, base, max) <= cap);
+        }
+        assert_eq!(backoff_delay(3, Duration::ZERO, max), Duration::ZERO);
+    }
+}
@@ -0,0 +1,211 @@
+//! Cursor-based iteration over the cached keyspace.
+//!
+//! Keys are visited in the order of a hash of the key, and the cursor is the hash
+//! to resume from. The order does not depend on what else is cached, so a key that
+//! stays cached for a whole iteration is returned at least once, like with Redis.
+//!
+//! Each cache tier keeps its keys in a `ScanIndex` ordered by that hash, so a page
+//! is read from the cursor on without visiting the rest of the keyspace.
+
+use std::collections::BTreeMap;
+
+/// Returns the position of a key in the iteration order.
+pub fn key_hash(key: &str) -> u64 {
+    crc32fast::hash(key.as_bytes()) as u64
+}
+
+/// A cached key at its position in the iteration order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ScanKey {
+    /// Hash of `entity:id`
+    pub position: u64,
+    pub entity: String,
+    pub id: String,
+}
+
+impl ScanKey {
+    pub fn new(entity: &str, id: &str) -> Self {
+        Self {
+            position: key_hash(&format!("{}:{}", entity, id)),
+            entity: entity.to_string(),
+            id: id.to_string(),
+        }
+    }
+}
+
+/// Keys of a cache tier in iteration order.
+///
+/// Each key carries the generation of the record it was indexed for, so that a late
+/// removal of an older record of the key leaves the entry of a newer one alone.
+#[derive(Debug, Default)]
+pub struct ScanIndex {
+    keys: BTreeMap<ScanKey, u64>,
+}
+
+impl ScanIndex {
+    /// Adds a key, or moves it to a newer generation.
+    pub fn insert(&mut self, entity: &str, id: &str, generation: u64) {
+        self.keys.insert(ScanKey::new(entity, id), generation);
+    }
+
+    /// Removes a key if it is still indexed for the given generation.
+    pub fn remove(&mut self, entity: &str, id: &str, generation: u64) {
+        let key = ScanKey::new(entity, id);
+        if self.keys.get(&key) == Some(&generation) {
+            self.keys.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+
+    /// Returns up to `count` live keys from `cursor` on, optionally of one entity only.
+    ///
+    /// A page may hold more than `count` keys when keys share a position, so none of
+    /// them is skipped.
+    pub fn page(
+        &self,
+        entity: Option<&str>,
+        cursor: u64,
+        count: usize,
+        is_live: impl Fn(&ScanKey) -> bool,
+    ) -> Vec<ScanKey> {
+        let start = ScanKey {
+            position: cursor,
+            entity: String::new(),
+            id: String::new(),
+        };
+        let mut page: Vec<ScanKey> = Vec::new();
+        for key in self.keys.range(start..).map(|(key, _)| key) {
+            if page.len() >= count.max(1)
+                && page.last().is_some_and(|last| last.position != key.position)
+            {
+                break;
+            }
+            if entity.is_none_or(|entity| key.entity == entity) && is_live(key) {
+                page.push(key.clone());
+            }
+        }
+        page
+    }
+}
+
+/// Merges the pages read from each cache tier with the same cursor and count.
+///
+/// Returns up to `count` keys as `entity:id` and the cursor of the next page, 0 once
+/// every key was visited. A tier that filled its page may hold further keys, so the
+/// merged page ends where the first full page ends.
+pub fn merge_pages(pages: Vec<Vec<ScanKey>>, count: usize) -> (u64, Vec<String>) {
+    let count = count.max(1);
+    let read_up_to = pages
+        .iter()
+        .filter(|page| page.len() >= count)
+        .filter_map(|page| page.last().map(|key| key.position))
+        .min();
+    let mut keys: Vec<ScanKey> = pages
+        .into_iter()
+        .flatten()
+        .filter(|key| read_up_to.is_none_or(|end| key.position <= end))
+        .collect();
+    // A key may briefly be in several tiers while it is promoted
+    keys.sort_unstable();
+    keys.dedup();
+
+    let mut end = count.min(keys.len());
+    while end > 0 && end < keys.len() && keys[end].position == keys[end - 1].position {
+        end += 1;
+    }
+    let next = if end < keys.len() || read_up_to.is_some() {
+        keys[end - 1].position + 1
+    } else {
+        0
+    };
+    keys.truncate(end);
+    let keys = keys
+        .into_iter()
+        .map(|key| format!("{}:{}", key.entity, key.id))
+        .collect();
+    (next, keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(ids: impl Iterator<Item = usize>) -> ScanIndex {
+        let mut index = ScanIndex::default();
+        for id in ids {
+            index.insert("users", &id.to_string(), 0);
+        }
+        index
+    }
+
+    fn scan(indexes: &[ScanIndex], cursor: u64, count: usize) -> (u64, Vec<String>) {
+        let pages = indexes
+            .iter()
+            .map(|index| index.page(None, cursor, count, |_| true))
+            .collect();
+        merge_pages(pages, count)
+    }
+
+    fn expected(ids: impl Iterator<Item = usize>) -> Vec<String> {
+        let mut keys: Vec<String> = ids.map(|id| format!("users:{}", id)).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_scan_visits_every_key_once() {
+        // Keys spread over two tiers, one of them also in both
+        let indexes = [index(0..60), index(50..95)];
+        let mut cursor = 0;
+        let mut seen = Vec::new();
+        loop {
+            let (next, page) = scan(&indexes, cursor, 10);
+            assert!(page.len() <= 10);
+            seen.extend(page);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        seen.sort();
+        assert_eq!(seen, expected(0..95));
+    }
+
+    #[test]
+    fn test_cursor_survives_changes() {
+        let mut indexes = [index(0..50)];
+        let (cursor, first) = scan(&indexes, 0, 10);
+        // Removing visited keys and adding new ones does not skip remaining keys
+        for id in 0..60 {
+            let key = format!("users:{}", id);
+            if first.contains(&key) {
+                indexes[0].remove("users", &id.to_string(), 0);
+            } else {
+                indexes[0].insert("users", &id.to_string(), 0);
+            }
+        }
+        let (_, rest) = scan(&indexes, cursor, 100);
+        for key in expected(0..50).iter().filter(|key| !first.contains(key)) {
+            assert!(rest.contains(key));
+        }
+    }
+
+    #[test]
+    fn test_page_skips_filtered_keys() {
+        let mut index = index(0..10);
+        index.insert("orders", "1", 0);
+        let page = index.page(Some("orders"), 0, 5, |_| true);
+        assert_eq!(page, vec![ScanKey::new("orders", "1")]);
+        assert!(index.page(None, 0, 100, |key| key.id != "3").len() == 10);
+
+        // Removing an older generation keeps the key
+        index.insert("orders", "1", 2);
+        index.remove("orders", "1", 1);
+        assert_eq!(index.page(Some("orders"), 0, 5, |_| true).len(), 1);
+        index.remove("orders", "1", 2);
+        assert!(index.page(Some("orders"), 0, 5, |_| true).is_empty());
+    }
+}
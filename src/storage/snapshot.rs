@@ -0,0 +1,205 @@
+//! Persistent cache snapshots.
+//!
+//! Snapshots let a restarted server start with a warm cache. The on-disk format is
+//! a fixed header followed by a payload of JSON lines, one per cached record:
+//!
+//! ```text
+//! magic (8 bytes) | version (u32) | entry count (u64) | payload length (u64) | payload crc32 (u32) | payload
+//! ```
+//!
+//! All integers are little endian. Expiration times are stored as wall-clock unix
+//! milliseconds so the time a server spent down counts against the records' TTL.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::storage::{CachedRecord, StorageError, StorageResult};
+
+/// Magic bytes identifying a snapshot file
+const MAGIC: &[u8; 8] = b"PRISMSNP";
+/// Current snapshot format version
+const VERSION: u32 = 1;
+/// Size of the fixed header in bytes
+const HEADER_LEN: usize = 8 + 4 + 8 + 8 + 4;
+
+/// A single record stored in a snapshot
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry {
+    entity: String,
+    id: String,
+    value: Value,
+    /// Expiration time in unix milliseconds, `None` if the record never expires
+    expires_at_ms: Option<u64>,
+}
+
+/// Writes the given records to a snapshot file.
+///
+/// The snapshot is written to a temporary file first and renamed into place, so a
+/// crash while writing never leaves a truncated snapshot behind.
+pub async fn write_snapshot(path: &Path, records: &[CachedRecord]) -> StorageResult<()> {
+    let now = SystemTime::now();
+    let mut payload = Vec::new();
+    for record in records {
+        let entry = SnapshotEntry {
+            entity: record.entity.clone(),
+            id: record.id.clone(),
+            value: record.value.clone(),
+            expires_at_ms: record.ttl.map(|ttl| unix_millis(now + ttl)),
+        };
+        serde_json::to_writer(&mut payload, &entry).map_err(snapshot_error)?;
+        payload.push(b'\n');
+    }
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    data.extend_from_slice(&(records.len() as u64).to_le_bytes());
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    data.extend_from_slice(&payload);
+
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let tmp_path = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, &path)
+    })
+    .await
+    .map_err(snapshot_error)?
+    .map_err(snapshot_error)
+}
+
+/// Reads the records of a snapshot file that have not expired yet.
+///
+/// Fails if the file is missing, truncated, of an unknown version or fails its checksum.
+pub async fn read_snapshot(path: &Path) -> StorageResult<Vec<CachedRecord>> {
+    let data = tokio::fs::read(path).await.map_err(snapshot_error)?;
+    if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+        return Err(StorageError::CacheError(
+            "Snapshot header is missing or invalid".to_string(),
+        ));
+    }
+
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if version != VERSION {
+        return Err(StorageError::CacheError(format!(
+            "Unsupported snapshot version {}",
+            version
+        )));
+    }
+    let count = u64::from_le_bytes(data[12..20].try_into().unwrap());
+    let payload_len = u64::from_le_bytes(data[20..28].try_into().unwrap());
+    let checksum = u32::from_le_bytes(data[28..32].try_into().unwrap());
+
+    let payload = &data[HEADER_LEN..];
+    if payload.len() as u64 != payload_len || crc32fast::hash(payload) != checksum {
+        return Err(StorageError::CacheError(
+            "Snapshot payload is corrupt".to_string(),
+        ));
+    }
+
+    let now = SystemTime::now();
+    let mut records = Vec::new();
+    let mut parsed = 0;
+    for line in payload.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+        let entry: SnapshotEntry = serde_json::from_slice(line).map_err(snapshot_error)?;
+        parsed += 1;
+        let ttl = match entry.expires_at_ms {
+            Some(at) => match (UNIX_EPOCH + Duration::from_millis(at)).duration_since(now) {
+                Ok(ttl) if !ttl.is_zero() => Some(ttl),
+                _ => continue,
+            },
+            None => None,
+        };
+        records.push(CachedRecord {
+            entity: entry.entity,
+            id: entry.id,
+            value: entry.value,
+            ttl,
+        });
+    }
+
+    if parsed != count {
+        return Err(StorageError::CacheError(format!(
+            "Snapshot holds {} entries, header announced {}",
+            parsed, count
+        )));
+    }
+    Ok(records)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn snapshot_error(e: impl std::fmt::Display) -> StorageError {
+    StorageError::CacheError(format!("Snapshot error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("prism_{}_{}.snapshot", name, std::process::id()))
+    }
+
+    fn record(id: &str, ttl: Option<Duration>) -> CachedRecord {
+        CachedRecord {
+            entity: "users".to_string(),
+            id: id.to_string(),
+            value: json!({ "id": id }),
+            ttl,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_roundtrip() {
+        let path = temp_path("roundtrip");
+        let records = vec![
+            record("1", Some(Duration::from_secs(60))),
+            record("2", None),
+            record("3", Some(Duration::ZERO)),
+        ];
+
+        write_snapshot(&path, &records).await.unwrap();
+        let mut restored = read_snapshot(&path).await.unwrap();
+        restored.sort_by(|a, b| a.id.cmp(&b.id));
+        std::fs::remove_file(&path).unwrap();
+
+        // The record that already expired is skipped
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].value["id"], "1");
+        assert!(restored[0].ttl.unwrap() > Duration::from_secs(55));
+        assert_eq!(restored[1].ttl, None);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_snapshot_is_rejected() {
+        let path = temp_path("corrupt");
+        write_snapshot(&path, &[record("1", None)]).await.unwrap();
+
+        let mut data = std::fs::read(&path).unwrap();
+        let last = data.len() - 2;
+        data[last] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+
+        let result = read_snapshot(&path).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(StorageError::CacheError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_missing_snapshot_is_an_error() {
+        assert!(read_snapshot(&temp_path("missing")).await.is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Time travel over versioned tables.
+//!
+//! A record can be requested as of a table version or a point in time, either with a
+//! versioned provider name such as `flights@v42` or `flights@2024-05-01`, or with the
+//! `PRISM.GETASOF` command. Historical records are cached under the versioned entity
+//! `provider@v<version>`, so they never collide with current records.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use std::fmt;
+
+/// Point of a table's history a record is read at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// A table version.
+    Version(i64),
+    /// The latest version committed at or before the given time.
+    Timestamp(DateTime<Utc>),
+}
+
+impl AsOf {
+    /// Parses `v42`, `42`, an RFC 3339 timestamp or a `YYYY-MM-DD` date (midnight UTC).
+    pub fn parse(value: &str) -> Option<Self> {
+        let version = value.strip_prefix('v').unwrap_or(value);
+        if let Ok(version) = version.parse::<i64>() {
+            return (version >= 0).then_some(AsOf::Version(version));
+        }
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+            return Some(AsOf::Timestamp(timestamp.with_timezone(&Utc)));
+        }
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .map(|midnight| AsOf::Timestamp(midnight.and_utc()))
+    }
+}
+
+impl fmt::Display for AsOf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsOf::Version(version) => write!(f, "v{}", version),
+            AsOf::Timestamp(timestamp) => write!(f, "{}", timestamp.to_rfc3339()),
+        }
+    }
+}
+
+/// Splits a possibly versioned provider name such as `flights@v42` into its parts.
+pub fn split_provider(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('@') {
+        Some((provider, as_of)) => (provider, Some(as_of)),
+        None => (name, None),
+    }
+}
+
+/// Splits a `provider:id` key, where the provider may be versioned.
+///
+/// Timestamps contain colons themselves, so for a versioned provider the id starts
+/// after the first colon that ends a valid version or point in time, as in
+/// `flights@2024-05-01T00:00:00Z:42`.
+pub fn split_key(key: &str) -> Option<(&str, &str)> {
+    let (provider, rest) = key.split_once(':')?;
+    let Some(at) = provider.find('@') else {
+        return Some((provider, rest));
+    };
+    key.match_indices(':')
+        .map(|(colon, _)| colon)
+        .find(|&colon| AsOf::parse(&key[at + 1..colon]).is_some())
+        .map(|colon| (&key[..colon], &key[colon + 1..]))
+        .or(Some((provider, rest)))
+}
+
+/// Returns the cache entity of a provider's records at a table version.
+pub fn versioned_entity(provider: &str, version: i64) -> String {
+    format!("{}@v{}", provider, version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_as_of() {
+        assert_eq!(AsOf::parse("v42"), Some(AsOf::Version(42)));
+        assert_eq!(AsOf::parse("7"), Some(AsOf::Version(7)));
+        assert_eq!(AsOf::parse("v-1"), None);
+        assert_eq!(AsOf::parse("yesterday"), None);
+
+        let expected = "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            AsOf::parse("2024-05-01T02:00:00+02:00"),
+            Some(AsOf::Timestamp(expected))
+        );
+        assert_eq!(AsOf::parse("2024-05-01"), Some(AsOf::Timestamp(expected)));
+    }
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!(split_key("flights:42"), Some(("flights", "42")));
+        assert_eq!(split_key("by_airport:SFO:2015"), Some(("by_airport", "SFO:2015")));
+        assert_eq!(split_key("flights@v3:42"), Some(("flights@v3", "42")));
+        assert_eq!(
+            split_key("flights@2024-01-01T00:00:00Z:42"),
+            Some(("flights@2024-01-01T00:00:00Z", "42"))
+        );
+        assert_eq!(
+            split_key("flights@2024-01-01T02:00:00+02:00:a:b"),
+            Some(("flights@2024-01-01T02:00:00+02:00", "a:b"))
+        );
+        // An invalid point in time is left for the provider lookup to reject
+        assert_eq!(split_key("flights@soon:42"), Some(("flights@soon", "42")));
+        assert_eq!(split_key("flights"), None);
+    }
+
+    #[test]
+    fn test_split_provider() {
+        assert_eq!(split_provider("flights@v42"), ("flights", Some("v42")));
+        assert_eq!(split_provider("flights"), ("flights", None));
+        assert_eq!(versioned_entity("flights", 42), "flights@v42");
+    }
+}
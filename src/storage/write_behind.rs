@@ -0,0 +1,463 @@
+//! Write-behind queue for providers whose backend should not see one write per command.
+//!
+//! Writes are appended to a local write-ahead log and kept in memory until they are
+//! flushed to the backend in batches, once enough writes are queued or the flush
+//! interval elapses. The log is split into files that are deleted once the writes
+//! they hold are flushed; unflushed files are replayed on restart. Writes the backend
+//! rejects on their own are moved to a dead letter file, one JSON object per line, so
+//! they do not hold back the rest of the queue.
+//!
+//! Every write is framed as `length (u32) | crc32 (u32) | JSON payload`, like the
+//! segments of the disk cache.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard, Notify};
+use tracing::{info, warn};
+
+use crate::config::WriteBehindConfig;
+use crate::storage::{StorageError, StorageResult};
+
+/// Size of the frame header in bytes
+const FRAME_HEADER_LEN: usize = 8;
+/// File extension of log files
+const LOG_EXTENSION: &str = "wal";
+/// Name of the file holding the writes the backend rejected
+const DEAD_LETTER_FILE: &str = "dead_letters.jsonl";
+
+/// A queued write as stored in the log
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord {
+    id: String,
+    record: Value,
+}
+
+/// A write the backend rejected, as stored in the dead letter file
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    id: String,
+    record: Value,
+    error: String,
+}
+
+/// Mutable state of the queue, guarded by a mutex
+struct QueueState {
+    dir: PathBuf,
+    active: File,
+    active_id: u64,
+    /// Bytes written to the active file
+    active_len: u64,
+    /// Unflushed fields of each id, merged across its writes
+    pending: HashMap<String, Value>,
+}
+
+/// Writes taken from the queue to be flushed together.
+pub struct FlushBatch {
+    /// Records to write, as id and record
+    pub records: Vec<(String, Value)>,
+    /// Log files before this one only hold writes of the batch
+    up_to: u64,
+}
+
+/// Counts of a write-behind queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueCounts {
+    /// Records waiting to be flushed
+    pub pending: u64,
+    /// Records flushed since startup
+    pub flushed: u64,
+    /// Flushes that failed since the last successful one
+    pub failures: u64,
+    /// Records moved to the dead letter file since startup
+    pub dead_letters: u64,
+}
+
+/// Durable queue of writes waiting to be flushed to a provider.
+pub struct WriteBehindQueue {
+    state: Arc<Mutex<QueueState>>,
+    /// Number of queued writes that triggers a flush
+    batch_size: usize,
+    /// Longest time a write waits for a flush
+    flush_interval: Duration,
+    /// Wakes the flusher once a batch is full
+    batch_full: Notify,
+    /// Held for the duration of a flush, so batches are flushed one at a time
+    flushing: AsyncMutex<()>,
+    /// Number of flushes that failed since the last successful one
+    failures: AtomicU64,
+    /// Number of records flushed since startup
+    flushed: AtomicU64,
+    /// Number of records moved to the dead letter file since startup
+    dead_letters: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl WriteBehindQueue {
+    /// Opens the queue in `dir`, replaying the writes of unflushed log files.
+    pub fn open(dir: &Path, config: &WriteBehindConfig) -> StorageResult<Self> {
+        std::fs::create_dir_all(dir).map_err(log_error)?;
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir).map_err(log_error)? {
+            let path = entry.map_err(log_error)?.path();
+            if path.extension().is_some_and(|ext| ext == LOG_EXTENSION)
+                && let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                files.push(id);
+            }
+        }
+        files.sort_unstable();
+
+        let mut pending = HashMap::new();
+        for id in &files {
+            for record in read_log(&log_path(dir, *id))? {
+                queue_write(&mut pending, record.id, record.record);
+            }
+        }
+
+        // Always append to a fresh file so a torn tail is never extended
+        let active_id = files.last().map_or(0, |id| id + 1);
+        let active = open_log(dir, active_id)?;
+        if !pending.is_empty() {
+            info!(
+                "Replayed {} unflushed writes from {}",
+                pending.len(),
+                dir.display()
+            );
+        }
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(QueueState {
+                dir: dir.to_path_buf(),
+                active,
+                active_id,
+                active_len: 0,
+                pending,
+            })),
+            batch_size: config.batch_size.max(1),
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            batch_full: Notify::new(),
+            flushing: AsyncMutex::new(()),
+            failures: AtomicU64::new(0),
+            flushed: AtomicU64::new(0),
+            dead_letters: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        })
+    }
+
+    /// Durably appends a write to the log and queues it for the next flush.
+    pub async fn append(&self, id: &str, record: &Value) -> StorageResult<()> {
+        let log_record = LogRecord {
+            id: id.to_string(),
+            record: record.clone(),
+        };
+        let state = Arc::clone(&self.state);
+        let pending = tokio::task::spawn_blocking(move || {
+            let mut state = state.lock().unwrap();
+            let payload = serde_json::to_vec(&log_record).map_err(log_error)?;
+            let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+            frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+            frame.extend_from_slice(&payload);
+            state.active.write_all(&frame).map_err(log_error)?;
+            state.active.sync_data().map_err(log_error)?;
+            state.active_len += frame.len() as u64;
+
+            queue_write(&mut state.pending, log_record.id, log_record.record);
+            Ok::<_, StorageError>(state.pending.len())
+        })
+        .await
+        .map_err(log_error)??;
+
+        if pending >= self.batch_size {
+            self.batch_full.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Returns the queued fields of an id, if they were not flushed yet.
+    pub fn pending_record(&self, id: &str) -> Option<Value> {
+        self.state.lock().unwrap().pending.get(id).cloned()
+    }
+
+    /// Waits until a batch is full or the flush interval elapsed.
+    pub async fn wait_for_flush(&self) {
+        tokio::select! {
+            _ = self.batch_full.notified() => {}
+            _ = tokio::time::sleep(self.flush_interval) => {}
+        }
+    }
+
+    /// Waits for a running flush to end; the next one starts once the guard is dropped.
+    pub async fn lock_flush(&self) -> AsyncMutexGuard<'_, ()> {
+        self.flushing.lock().await
+    }
+
+    /// Takes every queued write for a flush, or `None` if the queue is empty.
+    ///
+    /// Writes stay queued until the batch is completed. Callers hold `lock_flush`,
+    /// so that no two flushes write the same records.
+    pub fn take_batch(&self) -> StorageResult<Option<FlushBatch>> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.is_empty() {
+            return Ok(None);
+        }
+
+        // Later writes go to a new file, so the current ones can be deleted once flushed
+        if state.active_len > 0 {
+            let next = state.active_id + 1;
+            state.active = open_log(&state.dir, next)?;
+            state.active_id = next;
+            state.active_len = 0;
+        }
+        let records = state
+            .pending
+            .iter()
+            .map(|(id, record)| (id.clone(), record.clone()))
+            .collect();
+        Ok(Some(FlushBatch {
+            records,
+            up_to: state.active_id,
+        }))
+    }
+
+    /// Removes the writes of a flushed batch from the queue and deletes their log files.
+    ///
+    /// `flushed` is the number of its writes the backend took.
+    pub fn complete(&self, batch: FlushBatch, flushed: usize) {
+        let mut state = self.state.lock().unwrap();
+        for (id, record) in &batch.records {
+            // A record written again during the flush stays queued
+            if state.pending.get(id) == Some(record) {
+                state.pending.remove(id);
+            }
+        }
+        for id in log_ids(&state.dir).into_iter().filter(|id| *id < batch.up_to) {
+            if let Err(e) = std::fs::remove_file(log_path(&state.dir, id)) {
+                warn!("Failed to remove write-behind log {}: {}", id, e);
+            }
+        }
+        drop(state);
+
+        self.flushed.fetch_add(flushed as u64, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = None;
+    }
+
+    /// Moves a write the backend rejected to the dead letter file.
+    ///
+    /// The write leaves the queue with the batch it was taken in.
+    pub fn dead_letter(&self, id: &str, record: &Value, error: &str) -> StorageResult<()> {
+        let letter = DeadLetter {
+            id: id.to_string(),
+            record: record.clone(),
+            error: error.to_string(),
+        };
+        let mut line = serde_json::to_vec(&letter).map_err(log_error)?;
+        line.push(b'\n');
+
+        let state = self.state.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state.dir.join(DEAD_LETTER_FILE))
+            .map_err(log_error)?;
+        file.write_all(&line).map_err(log_error)?;
+        file.sync_data().map_err(log_error)?;
+        drop(state);
+
+        self.dead_letters.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records a failed flush; its writes stay queued for the next attempt.
+    pub fn fail(&self, error: &StorageError) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+
+    /// Returns the counts of the queue.
+    pub fn counts(&self) -> QueueCounts {
+        QueueCounts {
+            pending: self.state.lock().unwrap().pending.len() as u64,
+            flushed: self.flushed.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            dead_letters: self.dead_letters.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the queue status as field/value pairs.
+    pub fn status(&self) -> Vec<(&'static str, String)> {
+        let counts = self.counts();
+        let mut status = vec![
+            ("write_behind_pending", counts.pending.to_string()),
+            ("write_behind_flushed", counts.flushed.to_string()),
+            ("write_behind_failures", counts.failures.to_string()),
+            ("write_behind_dead_letters", counts.dead_letters.to_string()),
+        ];
+        if let Some(error) = self.last_error.lock().unwrap().clone() {
+            status.push(("write_behind_last_error", error));
+        }
+        status
+    }
+}
+
+/// Queues a write; later writes of an id set their fields over the earlier ones.
+fn queue_write(pending: &mut HashMap<String, Value>, id: String, record: Value) {
+    match (pending.get_mut(&id), record) {
+        (Some(Value::Object(queued)), Value::Object(fields)) => queued.extend(fields),
+        (_, record) => {
+            pending.insert(id, record);
+        }
+    }
+}
+
+/// Reads the writes of a log file; a torn or corrupt tail is ignored.
+fn read_log(path: &Path) -> StorageResult<Vec<LogRecord>> {
+    let data = std::fs::read(path).map_err(log_error)?;
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset + FRAME_HEADER_LEN <= data.len() {
+        let len = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap());
+        let start = offset + FRAME_HEADER_LEN;
+        let Some(payload) = data.get(start..start + len) else {
+            break;
+        };
+        if crc32fast::hash(payload) != checksum {
+            warn!(
+                "Corrupt write in log {} at offset {}, ignoring the rest",
+                path.display(),
+                offset
+            );
+            break;
+        }
+        let Ok(record) = serde_json::from_slice::<LogRecord>(payload) else {
+            break;
+        };
+        records.push(record);
+        offset = start + len;
+    }
+    Ok(records)
+}
+
+/// Returns the ids of the log files in a directory.
+fn log_ids(dir: &Path) -> Vec<u64> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == LOG_EXTENSION))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+        .collect()
+}
+
+fn log_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:010}.{}", id, LOG_EXTENSION))
+}
+
+fn open_log(dir: &Path, id: u64) -> StorageResult<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(dir, id))
+        .map_err(log_error)
+}
+
+fn log_error(e: impl std::fmt::Display) -> StorageError {
+    StorageError::DatabaseError(format!("Write-behind log error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config() -> WriteBehindConfig {
+        WriteBehindConfig {
+            batch_size: 2,
+            ..Default::default()
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("prism_wal_test_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_flush_removes_written_records() {
+        let dir = temp_dir("flush");
+        let queue = WriteBehindQueue::open(&dir, &config()).unwrap();
+        queue.append("1", &json!({ "v": 1, "w": 1 })).await.unwrap();
+        queue.append("1", &json!({ "v": 2 })).await.unwrap();
+
+        // Writes of an id are merged, later fields winning
+        let batch = queue.take_batch().unwrap().unwrap();
+        assert_eq!(batch.records, vec![("1".to_string(), json!({ "v": 2, "w": 1 }))]);
+
+        // Written during the flush, so it must survive its completion
+        queue.append("2", &json!({ "v": 3 })).await.unwrap();
+        queue.complete(batch, 1);
+        assert_eq!(queue.pending_record("1"), None);
+        assert_eq!(queue.pending_record("2"), Some(json!({ "v": 3 })));
+        assert_eq!(log_ids(&dir).len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_writes_are_dead_lettered() {
+        let dir = temp_dir("dead_letter");
+        let queue = WriteBehindQueue::open(&dir, &config()).unwrap();
+        queue.append("1", &json!({ "v": "one" })).await.unwrap();
+        queue.append("2", &json!({ "v": 2 })).await.unwrap();
+
+        let batch = queue.take_batch().unwrap().unwrap();
+        queue.dead_letter("1", &json!({ "v": "one" }), "not a number").unwrap();
+        queue.complete(batch, 1);
+        assert_eq!(queue.pending_record("1"), None);
+
+        let letters = std::fs::read_to_string(dir.join(DEAD_LETTER_FILE)).unwrap();
+        let letter: DeadLetter = serde_json::from_str(letters.trim_end()).unwrap();
+        assert_eq!((letter.id.as_str(), letter.error.as_str()), ("1", "not a number"));
+        let status = queue.status();
+        assert!(status.contains(&("write_behind_dead_letters", "1".to_string())));
+        assert!(status.contains(&("write_behind_flushed", "1".to_string())));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unflushed_writes_are_replayed() {
+        let dir = temp_dir("replay");
+        {
+            let queue = WriteBehindQueue::open(&dir, &config()).unwrap();
+            queue.append("1", &json!({ "v": 1 })).await.unwrap();
+            let batch = queue.take_batch().unwrap().unwrap();
+            queue.fail(&StorageError::DatabaseError("down".to_string()));
+            assert_eq!(batch.records.len(), 1);
+            queue.append("2", &json!({ "v": 2 })).await.unwrap();
+            queue.append("1", &json!({ "w": 1 })).await.unwrap();
+        }
+
+        let queue = WriteBehindQueue::open(&dir, &config()).unwrap();
+        assert_eq!(queue.pending_record("1"), Some(json!({ "v": 1, "w": 1 })));
+        assert_eq!(queue.pending_record("2"), Some(json!({ "v": 2 })));
+        assert_eq!(queue.take_batch().unwrap().unwrap().records.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}